@@ -1,7 +1,8 @@
-use crate::frame::{self, Frame};
+use crate::frame::{self, Decoder, Frame, Limits};
 
-use bytes::{Buf, BytesMut};
+use bytes::BytesMut;
 use std::io::{self, Cursor};
+use std::iter;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
 
@@ -16,7 +17,9 @@ use tokio::net::TcpStream;
 /// the `Connection` creates the frame and returns it to the caller.
 ///
 /// When sending frames, the frame is first encoded into the write buffer.
-/// The contents of the write buffer are then written to the socket.
+/// The contents of the write buffer are then written to the socket, either
+/// right away with `write_frame`, or with `feed_frame` followed by a single
+/// `flush` for a whole batch of frames.
 #[derive(Debug)]
 pub struct Connection {
     // The `TcpStream`. It is decorated with a `BufWriter`, which provides write
@@ -26,12 +29,22 @@ pub struct Connection {
 
     // The buffer for reading frames.
     buffer: BytesMut,
+
+    // Decodes the frames read into `buffer`. It holds on to the part of a
+    // frame decoded so far until the rest arrives.
+    decoder: Decoder,
 }
 
 impl Connection {
     /// Create a new `Connection`, backed by `socket`. Read and write buffers
     /// are initialized.
     pub fn new(socket: TcpStream) -> Connection {
+        Connection::with_limits(socket, Limits::default())
+    }
+
+    /// Create a new `Connection`, backed by `socket`, that rejects frames
+    /// exceeding `limits`.
+    pub fn with_limits(socket: TcpStream, limits: Limits) -> Connection {
         dlog!("Connection::new - allocating read buffer");
         Connection {
             stream: BufWriter::new(socket),
@@ -40,6 +53,7 @@ impl Connection {
             // value to their specific use case. There is a high likelihood that
             // a larger read buffer will work better.
             buffer: BytesMut::with_capacity(4 * 1024),
+            decoder: Decoder::new(limits),
         }
     }
 
@@ -71,14 +85,19 @@ impl Connection {
             // There is not enough buffered data to read a frame. Attempt to
             // read more data from the socket.
             //
+            // Frames split off the buffer may still share its allocation, in
+            // which case it has no spare capacity left. Make room for a decent
+            // amount of data rather than reading it a few bytes at a time.
+            self.buffer.reserve(4 * 1024);
+
             // On success, the number of bytes is returned. `0` indicates "end
             // of stream".
             if 0 == self.stream.read_buf(&mut self.buffer).await? {
                 // The remote closed the connection. For this to be a clean
-                // shutdown, there should be no data in the read buffer. If
-                // there is, this means that the peer closed the socket while
-                // sending a frame.
-                if self.buffer.is_empty() {
+                // shutdown, there should be no data in the read buffer nor a
+                // frame in progress. If there is, this means that the peer
+                // closed the socket while sending a frame.
+                if self.buffer.is_empty() && self.decoder.pending_len() == 0 {
                     dlog!("read_frame - EOF clean");
                     return Ok(None);
                 } else {
@@ -99,49 +118,17 @@ impl Connection {
     fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
         use frame::Error::Incomplete;
 
-        // Cursor is used to track the "current" location in the
-        // buffer. Cursor also implements `Buf` from the `bytes` crate
-        // which provides a number of helpful utilities for working
-        // with bytes.
-        let mut buf = Cursor::new(&self.buffer[..]);
-
-        // The first step is to check if enough data has been buffered to parse
-        // a single frame. This step is usually much faster than doing a full
-        // parse of the frame, and allows us to skip allocating data structures
-        // to hold the frame data unless we know the full frame has been
-        // received.
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                // The `check` function will have advanced the cursor until the
-                // end of the frame. Since the cursor had position set to zero
-                // before `Frame::check` was called, we obtain the length of the
-                // frame by checking the cursor position.
-                let len = buf.position() as usize;
-
-                dlog!("parse_frame - full frame available length={}", len);
-
-                // Reset the position to zero before passing the cursor to
-                // `Frame::parse`.
-                buf.set_position(0);
-
-                // Parse the frame from the buffer. This allocates the necessary
-                // structures to represent the frame and returns the frame
-                // value.
-                //
-                // If the encoded frame representation is invalid, an error is
-                // returned. This should terminate the **current** connection
-                // but should not impact any other connected client.
-                let frame = Frame::parse(&mut buf)?;
-
-                // Discard the parsed data from the read buffer.
-                //
-                // When `advance` is called on the read buffer, all of the data
-                // up to `len` is discarded. The details of how this works is
-                // left to `BytesMut`. This is often done by moving an internal
-                // cursor, but it may be done by reallocating and copying data.
-                self.buffer.advance(len);
-
-                // Return the parsed frame to the caller.
+        // The decoder consumes whatever it can from the read buffer and picks
+        // up where it left off on the next call, so each byte is only looked
+        // at once. Bulk payloads are split off the read buffer rather than
+        // copied out of it.
+        //
+        // If the encoded frame representation is invalid, an error is
+        // returned. This should terminate the **current** connection but
+        // should not impact any other connected client.
+        match self.decoder.decode(&mut self.buffer) {
+            Ok(frame) => {
+                dlog!("parse_frame - decoded a full frame");
                 Ok(Some(frame))
             }
             // There is not enough data present in the read buffer to parse a
@@ -168,45 +155,55 @@ impl Connection {
         }
     }
 
-    /// Write a single `Frame` value to the underlying stream.
+    /// Write a single `Frame` value to the underlying stream and flush it.
+    ///
+    /// This is `feed_frame` followed by `flush`. When writing several frames
+    /// in a row, feeding them all and flushing once saves a syscall per frame.
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.feed_frame(frame).await?;
+        self.flush().await
+    }
+
+    /// Encode a single `Frame` value into the write buffer without flushing
+    /// it.
     ///
     /// The `Frame` value is written to the socket using the various `write_*`
     /// functions provided by `AsyncWrite`. Calling these functions directly on
     /// a `TcpStream` is **not** advised, as this will result in a large number of
     /// syscalls. However, it is fine to call these functions on a *buffered*
     /// write stream. The data will be written to the buffer. Once the buffer is
-    /// full, it is flushed to the underlying socket.
-    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
-        dlog!("write_frame - writing frame: {:?}", frame);
-        // Arrays are encoded by encoding each entry. All other frame types are
-        // considered literals. For now, mini-redis is not able to encode
-        // recursive frame structures. See below for more details.
-        match frame {
-            Frame::Array(val) => {
-                // Encode the frame type prefix. For an array, it is `*`.
-                self.stream.write_u8(b'*').await?;
-
-                // Encode the length of the array.
-                self.write_decimal(val.len() as u64).await?;
+    /// full, it is flushed to the underlying socket. Anything still buffered
+    /// is only sent on the next call to `flush`.
+    pub async fn feed_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        dlog!("feed_frame - writing frame: {:?}", frame);
+        // Aggregates may be nested arbitrarily deep. Async fns cannot recurse
+        // without boxing every level, so the aggregates being encoded are kept
+        // on an explicit stack instead, each with the entries still to write.
+        let mut stack: Vec<Entries<'_>> = vec![Box::new(iter::once(frame))];
+        while let Some(entries) = stack.last_mut() {
+            let Some(entry) = entries.next() else {
+                // Done with this aggregate, carry on with the enclosing one.
+                stack.pop();
+                continue;
+            };
 
-                // Iterate and encode each entry in the array.
-                for entry in &**val {
-                    self.write_value(entry).await?;
-                }
+            self.write_value(entry).await?;
+            if let Some(entries) = entries_of(entry) {
+                stack.push(entries);
             }
-            // The frame type is a literal. Encode the value directly.
-            _ => self.write_value(frame).await?,
         }
 
-        // Ensure the encoded frame is written to the socket. The calls above
-        // are to the buffered stream and writes. Calling `flush` writes the
-        // remaining contents of the buffer to the socket.
+        Ok(())
+    }
+
+    /// Write everything in the write buffer to the socket.
+    pub async fn flush(&mut self) -> io::Result<()> {
         self.stream.flush().await?;
-        dlog!("write_frame - flush complete");
+        dlog!("flush - complete");
         Ok(())
     }
 
-    /// Write a frame literal to the stream
+    /// Write a frame literal, or the header of an aggregate, to the stream
     async fn write_value(&mut self, frame: &Frame) -> io::Result<()> {
         dlog!("write_value - {:?}", frame);
         match frame {
@@ -227,26 +224,56 @@ impl Connection {
             Frame::Null => {
                 self.stream.write_all(b"$-1\r\n").await?;
             }
-            Frame::Bulk(val) => {
-                let len = val.len();
-
-                self.stream.write_u8(b'$').await?;
-                self.write_decimal(len as u64).await?;
+            Frame::Bulk(val) => self.write_bulk(b'$', &[], val).await?,
+            Frame::Double(val) => {
+                self.stream.write_u8(b',').await?;
+                self.stream
+                    .write_all(frame::format_double(*val).as_bytes())
+                    .await?;
+                self.stream.write_all(b"\r\n").await?;
+            }
+            Frame::Boolean(val) => {
+                let val: &[u8] = if *val { b"#t\r\n" } else { b"#f\r\n" };
                 self.stream.write_all(val).await?;
+            }
+            Frame::BigNumber(val) => {
+                self.stream.write_u8(b'(').await?;
+                self.stream.write_all(val.as_bytes()).await?;
                 self.stream.write_all(b"\r\n").await?;
             }
-            // Encoding an `Array` from within a value cannot be done using a
-            // recursive strategy. In general, async fns do not support
-            // recursion. Mini-redis has not needed to encode nested arrays yet,
-            // so for now it is skipped.
-            Frame::Array(_val) => unreachable!(),
+            Frame::VerbatimString { format, text } => {
+                self.write_bulk(b'=', &[&format[..], b":"].concat(), text)
+                    .await?;
+            }
+            // Only the header is written here; `feed_frame` takes care of the
+            // entries.
+            Frame::Array(val) => self.write_header(b'*', val.len()).await?,
+            Frame::Set(val) => self.write_header(b'~', val.len()).await?,
+            Frame::Push(val) => self.write_header(b'>', val.len()).await?,
+            Frame::Map(val) => self.write_header(b'%', val.len()).await?,
+            Frame::Attribute(val, _) => self.write_header(b'|', val.len()).await?,
         }
 
         Ok(())
     }
 
+    /// Write a string prefixed with its length, and with `prefix` in front of
+    /// its contents.
+    async fn write_bulk(&mut self, kind: u8, prefix: &[u8], val: &[u8]) -> io::Result<()> {
+        self.write_header(kind, prefix.len() + val.len()).await?;
+        self.stream.write_all(prefix).await?;
+        self.stream.write_all(val).await?;
+        self.stream.write_all(b"\r\n").await
+    }
+
+    /// Write a type byte followed by a length.
+    async fn write_header(&mut self, kind: u8, len: usize) -> io::Result<()> {
+        self.stream.write_u8(kind).await?;
+        self.write_decimal(len as i64).await
+    }
+
     /// Write a decimal frame to the stream
-    async fn write_decimal(&mut self, val: u64) -> io::Result<()> {
+    async fn write_decimal(&mut self, val: i64) -> io::Result<()> {
         use std::io::Write;
 
         // Convert the value to a string
@@ -261,3 +288,65 @@ impl Connection {
         Ok(())
     }
 }
+
+/// Frames still to be written for an aggregate.
+type Entries<'a> = Box<dyn Iterator<Item = &'a Frame> + Send + 'a>;
+
+/// Returns the frames to write after the header of `frame` when it is an
+/// aggregate.
+fn entries_of(frame: &Frame) -> Option<Entries<'_>> {
+    match frame {
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => {
+            Some(Box::new(items.iter()))
+        }
+        Frame::Map(items) => Some(Box::new(pairs(items))),
+        Frame::Attribute(items, frame) => Some(Box::new(pairs(items).chain(iter::once(&**frame)))),
+        _ => None,
+    }
+}
+
+/// Flatten key-value pairs.
+fn pairs(pairs: &[(Frame, Frame)]) -> impl Iterator<Item = &Frame> + Send {
+    pairs.iter().flat_map(|(key, value)| [key, value])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    /// Send `frame` over a loopback socket and return what the other end
+    /// decodes. The receiving end accepts any nesting depth.
+    async fn round_trip(frame: &Frame) -> Frame {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let limits = Limits {
+            max_depth: usize::MAX,
+            ..Limits::default()
+        };
+        let mut server = Connection::with_limits(listener.accept().await.unwrap().0, limits);
+
+        client.write_frame(frame).await.unwrap();
+        server.read_frame().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn nested_arrays_round_trip() {
+        // Every level holds a few literals around the next level down.
+        let mut frame = Frame::Array(vec![]);
+        for depth in 0..200 {
+            frame = Frame::Array(vec![
+                Frame::Integer(depth),
+                Frame::Bulk(Bytes::from(format!("level {depth}"))),
+                frame,
+                Frame::Null,
+                Frame::Array(vec![Frame::Simple("OK".to_string())]),
+            ]);
+        }
+
+        let decoded = round_trip(&frame).await;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", frame));
+    }
+}
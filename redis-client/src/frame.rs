@@ -1,10 +1,13 @@
 //! Provides a type representing a Redis protocol frame as well as utilities for
 //! parsing frames from a byte array.
+//!
+//! Frames cover both RESP2 and RESP3. A server only sends RESP3 types once the
+//! client asked for them with `HELLO 3`.
 
-use bytes::{Buf, Bytes};
-use std::convert::TryInto;
+use bytes::{Buf, Bytes, BytesMut};
 use std::fmt;
 use std::io::Cursor;
+use std::iter;
 use std::num::TryFromIntError;
 use std::string::FromUtf8Error;
 
@@ -13,12 +16,71 @@ use std::string::FromUtf8Error;
 pub enum Frame {
     Simple(String),
     Error(String),
-    Integer(u64),
+    Integer(i64),
     Bulk(Bytes),
+
+    /// The absence of a value: `_` in RESP3, a null bulk string in RESP2.
     Null,
+
     Array(Vec<Frame>),
+
+    /// Key-value pairs, in order.
+    Map(Vec<(Frame, Frame)>),
+
+    /// An unordered collection of distinct frames.
+    Set(Vec<Frame>),
+
+    Double(f64),
+    Boolean(bool),
+
+    /// An integer of any size, as its decimal digits.
+    BigNumber(String),
+
+    /// Text along with its format, such as `txt` or `mkd`.
+    VerbatimString {
+        format: [u8; 3],
+        text: Bytes,
+    },
+
+    /// Out-of-band information about the frame that follows it.
+    Attribute(Vec<(Frame, Frame)>, Box<Frame>),
+
+    /// Data the server sent without it being a reply to a command, such as
+    /// Pub/Sub messages.
+    Push(Vec<Frame>),
+}
+
+/// Bounds on the replies accepted from a server, so that a bogus length
+/// prefix cannot make the client allocate arbitrary amounts of memory.
+///
+/// A frame exceeding any of them is a protocol error.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum length of a bulk string.
+    pub max_bulk_len: usize,
+
+    /// Maximum number of entries in an array.
+    pub max_array_len: usize,
+
+    /// Maximum number of arrays nested in one another.
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    /// The same bounds the server applies to requests.
+    fn default() -> Limits {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: i32::MAX as usize,
+            max_depth: 128,
+        }
+    }
 }
 
+/// Arrays are only preallocated up to this many entries, since the length
+/// prefix is not trustworthy until the entries have actually arrived.
+const MAX_ARRAY_PREALLOC: usize = 1024;
+
 #[derive(Debug)]
 pub enum Error {
     /// Not enough data is available to parse a message
@@ -30,7 +92,7 @@ pub enum Error {
 
 impl Frame {
     /// Returns an empty array
-    pub(crate) fn array() -> Frame {
+    pub fn array() -> Frame {
         Frame::Array(vec![])
     }
 
@@ -39,7 +101,7 @@ impl Frame {
     /// # Panics
     ///
     /// panics if `self` is not an array
-    pub(crate) fn push_bulk(&mut self, bytes: Bytes) {
+    pub fn push_bulk(&mut self, bytes: Bytes) {
         match self {
             Frame::Array(vec) => {
                 vec.push(Frame::Bulk(bytes));
@@ -53,7 +115,7 @@ impl Frame {
     /// # Panics
     ///
     /// panics if `self` is not an array
-    pub(crate) fn push_int(&mut self, value: u64) {
+    pub fn push_int(&mut self, value: i64) {
         match self {
             Frame::Array(vec) => {
                 vec.push(Frame::Integer(value));
@@ -62,8 +124,14 @@ impl Frame {
         }
     }
 
-    /// Checks if an entire message can be decoded from `src`
-    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+    /// Checks if an entire message can be decoded from `src`, without
+    /// exceeding `limits`.
+    pub fn check(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+        Frame::check_nested(src, limits, 0)
+    }
+
+    /// `check` for a frame nested in `depth` arrays.
+    fn check_nested(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
         let pos = src.position();
         if src.remaining() > 0 {
             dlog!(
@@ -73,7 +141,8 @@ impl Frame {
             );
         }
 
-        match get_u8(src)? {
+        let first = get_u8(src)?;
+        match first {
             b'+' => {
                 get_line(src)?;
                 Ok(())
@@ -83,27 +152,34 @@ impl Frame {
                 Ok(())
             }
             b':' => {
-                let _ = get_decimal(src)?;
+                let _ = get_integer(src)?;
                 Ok(())
             }
-            b'$' => {
+            // The rest of the RESP3 types that fit on one line are validated
+            // by `parse`.
+            b'_' | b',' | b'#' | b'(' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b'$' | b'=' => {
                 if b'-' == peek_u8(src)? {
                     // Skip '-1\r\n'
                     skip(src, 4)
                 } else {
                     // Read the bulk string
-                    let len: usize = get_decimal(src)?.try_into()?;
+                    let len = get_bulk_len(src, limits)?;
 
                     // skip that number of bytes + 2 (\r\n).
                     skip(src, len + 2)
                 }
             }
-            b'*' => {
-                let len = get_decimal(src)?;
-                dlog!("Frame::check - array length={}", len);
+            b'*' | b'%' | b'~' | b'|' | b'>' => {
+                let kind = Aggregate::from_type_byte(first);
+                let len = kind.entry_count(get_array_len(src, limits, depth)?);
+                dlog!("Frame::check - aggregate entries={}", len);
 
                 for _ in 0..len {
-                    Frame::check(src)?;
+                    Frame::check_nested(src, limits, depth + 1)?;
                 }
 
                 Ok(())
@@ -112,8 +188,21 @@ impl Frame {
         }
     }
 
-    /// The message has already been validated with `check`.
-    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+    /// The message has already been validated with `check`. The `limits` are
+    /// enforced again all the same, as `parse` allocates based on what it
+    /// reads.
+    ///
+    /// Bulk payloads are copied out of `src`; a `Decoder` avoids that.
+    pub fn parse(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<Frame, Error> {
+        Frame::parse_nested(src, limits, 0)
+    }
+
+    /// `parse` for a frame nested in `depth` arrays.
+    fn parse_nested(
+        src: &mut Cursor<&[u8]>,
+        limits: &Limits,
+        depth: usize,
+    ) -> Result<Frame, Error> {
         let start = src.position();
         dlog!(
             "Frame::parse - at pos={} remaining={} first={:?}",
@@ -126,7 +215,8 @@ impl Frame {
             }
         );
 
-        match get_u8(src)? {
+        let first = get_u8(src)?;
+        match first {
             b'+' => {
                 // Read the line and convert it to `Vec<u8>`
                 let line = get_line(src)?.to_vec();
@@ -146,11 +236,12 @@ impl Frame {
                 Ok(Frame::Error(string))
             }
             b':' => {
-                let len = get_decimal(src)?;
-                Ok(Frame::Integer(len))
+                let value = get_integer(src)?;
+                Ok(Frame::Integer(value))
             }
-            b'$' => {
-                if b'-' == peek_u8(src)? {
+            b'_' | b',' | b'#' | b'(' => scalar(first, get_line(src)?),
+            b'$' | b'=' => {
+                if first == b'$' && b'-' == peek_u8(src)? {
                     let line = get_line(src)?;
 
                     if line != b"-1" {
@@ -160,38 +251,47 @@ impl Frame {
                     Ok(Frame::Null)
                 } else {
                     // Read the bulk string
-                    let len = get_decimal(src)?.try_into()?;
+                    let len = get_bulk_len(src, limits)?;
                     let n = len + 2;
 
                     if src.remaining() < n {
                         return Err(Error::Incomplete);
                     }
 
+                    if &src.chunk()[len..n] != b"\r\n" {
+                        return Err("protocol error; bulk data not followed by CRLF".into());
+                    }
+
                     let data = Bytes::copy_from_slice(&src.chunk()[..len]);
 
                     // skip that number of bytes + 2 (\r\n).
                     skip(src, n)?;
 
-                    Ok(Frame::Bulk(data))
+                    if first == b'=' {
+                        verbatim(data)
+                    } else {
+                        Ok(Frame::Bulk(data))
+                    }
                 }
             }
-            b'*' => {
-                let len = get_decimal(src)?.try_into()?;
-                dlog!("Frame::parse - parsing array len={}", len);
-                let mut out = Vec::with_capacity(len);
+            b'*' | b'%' | b'~' | b'|' | b'>' => {
+                let kind = Aggregate::from_type_byte(first);
+                let len = kind.entry_count(get_array_len(src, limits, depth)?);
+                dlog!("Frame::parse - parsing aggregate entries={}", len);
+                let mut entries = Vec::with_capacity(len.min(MAX_ARRAY_PREALLOC));
 
                 for _ in 0..len {
-                    out.push(Frame::parse(src)?);
+                    entries.push(Frame::parse_nested(src, limits, depth + 1)?);
                 }
 
-                Ok(Frame::Array(out))
+                Ok(PartialAggregate { kind, len, entries }.into_frame())
             }
-            _ => unimplemented!(),
+            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
         }
     }
 
     /// Converts the frame to an "unexpected frame" error
-    pub(crate) fn to_error(&self) -> crate::Error {
+    pub fn to_error(&self) -> crate::Error {
         anyhow::anyhow!("unexpected frame: {}", self)
     }
 }
@@ -219,7 +319,7 @@ impl fmt::Display for Frame {
                 Err(_) => write!(fmt, "{:?}", msg),
             },
             Frame::Null => "(nil)".fmt(fmt),
-            Frame::Array(parts) => {
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
                 for (i, part) in parts.iter().enumerate() {
                     if i > 0 {
                         // use space as the array element display separator
@@ -231,10 +331,334 @@ impl fmt::Display for Frame {
 
                 Ok(())
             }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+
+                    write!(fmt, "{} {}", key, value)?;
+                }
+
+                Ok(())
+            }
+            Frame::Double(value) => format_double(*value).fmt(fmt),
+            Frame::Boolean(value) => value.fmt(fmt),
+            Frame::BigNumber(digits) => digits.fmt(fmt),
+            Frame::VerbatimString { text, .. } => String::from_utf8_lossy(text).fmt(fmt),
+            Frame::Attribute(_, frame) => frame.fmt(fmt),
+        }
+    }
+}
+
+/// Format a double the way RESP3 spells it.
+pub(crate) fn format_double(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Decodes the frames received on a connection.
+///
+/// Data is consumed from the read buffer as soon as it has been decoded, and
+/// the frame in progress is kept in the decoder: the aggregates still waiting
+/// for entries and the length of a bulk string whose payload has not fully
+/// arrived. When more data comes in, decoding resumes where it left off, so
+/// every byte is scanned once however many reads a frame is spread over.
+///
+/// After an error, the rest of the input cannot be made sense of and the
+/// decoder should be dropped along with the connection.
+#[derive(Debug)]
+pub struct Decoder {
+    limits: Limits,
+
+    /// Aggregates of the frame in progress still waiting for entries, the
+    /// innermost last.
+    aggregates: Vec<PartialAggregate>,
+
+    /// Type byte and length of the string whose payload is being waited for.
+    blob: Option<(u8, usize)>,
+
+    /// How much of the read buffer was already searched for the end of the
+    /// current line.
+    scanned: usize,
+
+    /// Number of bytes of the frame in progress consumed so far.
+    consumed: usize,
+}
+
+#[derive(Debug)]
+struct PartialAggregate {
+    kind: Aggregate,
+
+    /// Number of frames to collect, as given by `Aggregate::entry_count`.
+    len: usize,
+
+    entries: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Attribute,
+    Push,
+}
+
+impl Decoder {
+    /// Create a decoder rejecting frames that exceed `limits`.
+    pub fn new(limits: Limits) -> Decoder {
+        Decoder {
+            limits,
+            aggregates: Vec::new(),
+            blob: None,
+            scanned: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Returns the number of bytes of the frame in progress that were already
+    /// consumed from the read buffer.
+    pub fn pending_len(&self) -> usize {
+        self.consumed
+    }
+
+    /// Decode a frame off the front of `buf`.
+    ///
+    /// Bulk payloads are split off the buffer rather than copied, so they
+    /// share its allocation. If `buf` does not complete a frame, everything
+    /// that can be decoded is consumed and `Incomplete` is returned.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Frame, Error> {
+        loop {
+            let value = match self.blob {
+                Some((first, len)) => self.blob(buf, first, len)?,
+                None => match self.header(buf)? {
+                    Some(value) => value,
+                    // An aggregate or a string was started.
+                    None => continue,
+                },
+            };
+
+            if let Some(frame) = self.complete(value) {
+                dlog!("Decoder::decode - frame complete");
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Decode the line starting the next value. Returns the value if that line
+    /// holds all of it.
+    fn header(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let Some(&first) = buf.first() else {
+            return Err(Error::Incomplete);
+        };
+
+        if !matches!(
+            first,
+            b'+' | b'-'
+                | b':'
+                | b'$'
+                | b'*'
+                | b'_'
+                | b','
+                | b'#'
+                | b'('
+                | b'='
+                | b'%'
+                | b'~'
+                | b'|'
+                | b'>'
+        ) {
+            return Err(format!("protocol error; invalid frame type byte `{}`", first).into());
+        }
+
+        let line = self.line(buf)?;
+        let line = &line[1..];
+        match first {
+            b'+' => Ok(Some(Frame::Simple(String::from_utf8(line.to_vec())?))),
+            b'-' => Ok(Some(Frame::Error(String::from_utf8(line.to_vec())?))),
+            b':' => Ok(Some(Frame::Integer(parse_integer(line)?))),
+            b'_' | b',' | b'#' | b'(' => Ok(Some(scalar(first, line)?)),
+            b'$' | b'=' => {
+                if first == b'$' && line.first() == Some(&b'-') {
+                    if line != b"-1" {
+                        return Err("protocol error; invalid frame format".into());
+                    }
+
+                    return Ok(Some(Frame::Null));
+                }
+
+                self.blob = Some((first, bulk_len(line, &self.limits)?));
+                Ok(None)
+            }
+            _ => {
+                let kind = Aggregate::from_type_byte(first);
+                let len = kind.entry_count(array_len(line, &self.limits, self.aggregates.len())?);
+                dlog!("Decoder::header - aggregate entries={}", len);
+                let aggregate = PartialAggregate {
+                    kind,
+                    len,
+                    entries: Vec::with_capacity(len.min(MAX_ARRAY_PREALLOC)),
+                };
+                if len == 0 {
+                    return Ok(Some(aggregate.into_frame()));
+                }
+
+                self.aggregates.push(aggregate);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Decode the payload of a bulk or verbatim string of `len` bytes.
+    fn blob(&mut self, buf: &mut BytesMut, first: u8, len: usize) -> Result<Frame, Error> {
+        if buf.len() < len + 2 {
+            dlog!(
+                "Decoder::blob - waiting for {} more bytes",
+                len + 2 - buf.len()
+            );
+            return Err(Error::Incomplete);
+        }
+
+        if &buf[len..len + 2] != b"\r\n" {
+            return Err("protocol error; bulk data not followed by CRLF".into());
+        }
+
+        let data = buf.split_to(len).freeze();
+        buf.advance(2);
+        self.consumed += len + 2;
+        self.blob = None;
+
+        if first == b'=' {
+            verbatim(data)
+        } else {
+            Ok(Frame::Bulk(data))
+        }
+    }
+
+    /// Add a decoded value to the innermost aggregate in progress, closing
+    /// every aggregate it completes. Returns the frame once the outermost one
+    /// is done.
+    fn complete(&mut self, mut value: Frame) -> Option<Frame> {
+        while let Some(aggregate) = self.aggregates.last_mut() {
+            aggregate.entries.push(value);
+            if aggregate.entries.len() < aggregate.len {
+                return None;
+            }
+
+            value = self.aggregates.pop().unwrap().into_frame();
+        }
+
+        self.consumed = 0;
+        Some(value)
+    }
+
+    /// Split a CRLF terminated line off `buf`, returning it without the CRLF.
+    /// The search for the CRLF resumes where the previous call gave up.
+    fn line(&mut self, buf: &mut BytesMut) -> Result<BytesMut, Error> {
+        let Some(i) = buf[self.scanned..]
+            .windows(2)
+            .position(|window| window == b"\r\n")
+        else {
+            // The last byte may be the first half of the CRLF.
+            self.scanned = buf.len().saturating_sub(1);
+            return Err(Error::Incomplete);
+        };
+
+        let line = buf.split_to(self.scanned + i);
+        buf.advance(2);
+        self.consumed += line.len() + 2;
+        self.scanned = 0;
+        Ok(line)
+    }
+}
+
+impl Aggregate {
+    /// Returns the aggregate introduced by `first`, which must be one of the
+    /// aggregate type bytes.
+    fn from_type_byte(first: u8) -> Aggregate {
+        match first {
+            b'*' => Aggregate::Array,
+            b'%' => Aggregate::Map,
+            b'~' => Aggregate::Set,
+            b'|' => Aggregate::Attribute,
+            b'>' => Aggregate::Push,
+            _ => unreachable!("not an aggregate type byte"),
+        }
+    }
+
+    /// Returns how many frames make up an aggregate of length `len`. Maps
+    /// hold both halves of every pair, and attributes also hold the frame
+    /// they are attached to.
+    fn entry_count(self, len: usize) -> usize {
+        match self {
+            Aggregate::Map => len.saturating_mul(2),
+            Aggregate::Attribute => len.saturating_mul(2).saturating_add(1),
+            _ => len,
+        }
+    }
+}
+
+impl PartialAggregate {
+    fn into_frame(self) -> Frame {
+        let mut entries = self.entries;
+        match self.kind {
+            Aggregate::Array => Frame::Array(entries),
+            Aggregate::Set => Frame::Set(entries),
+            Aggregate::Push => Frame::Push(entries),
+            Aggregate::Map => Frame::Map(pairs(entries)),
+            Aggregate::Attribute => {
+                let frame = entries.pop().unwrap();
+                Frame::Attribute(pairs(entries), Box::new(frame))
+            }
+        }
+    }
+}
+
+/// Group `entries` two by two.
+fn pairs(entries: Vec<Frame>) -> Vec<(Frame, Frame)> {
+    let mut entries = entries.into_iter();
+    iter::from_fn(|| Some((entries.next()?, entries.next()?))).collect()
+}
+
+/// Parse a RESP3 value held entirely on the `line` following its type byte.
+fn scalar(first: u8, line: &[u8]) -> Result<Frame, Error> {
+    let invalid = || Error::from("protocol error; invalid frame format");
+
+    match (first, line) {
+        (b'_', b"") => Ok(Frame::Null),
+        (b'#', b"t") => Ok(Frame::Boolean(true)),
+        (b'#', b"f") => Ok(Frame::Boolean(false)),
+        (b',', _) => std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.parse().ok())
+            .map(Frame::Double)
+            .ok_or_else(invalid),
+        (b'(', _) => {
+            let digits = line.strip_prefix(b"-").unwrap_or(line);
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                return Err(invalid());
+            }
+            Ok(Frame::BigNumber(String::from_utf8(line.to_vec())?))
         }
+        _ => Err(invalid()),
     }
 }
 
+/// Split the format off the payload of a verbatim string.
+fn verbatim(mut data: Bytes) -> Result<Frame, Error> {
+    if data.len() < 4 || data[3] != b':' {
+        return Err("protocol error; invalid verbatim string".into());
+    }
+
+    let format = data[..3].try_into().unwrap();
+    data.advance(4);
+    Ok(Frame::VerbatimString { format, text: data })
+}
+
 fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
     if !src.has_remaining() {
         return Err(Error::Incomplete);
@@ -260,10 +684,44 @@ fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
     Ok(())
 }
 
-/// Read a new-line terminated decimal
-fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+/// Read the length prefix of a bulk string.
+fn get_bulk_len(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<usize, Error> {
+    bulk_len(get_line(src)?, limits)
+}
+
+/// Read the length prefix of an array nested in `depth` other arrays.
+fn get_array_len(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<usize, Error> {
+    array_len(get_line(src)?, limits, depth)
+}
+
+/// Read a new-line terminated, possibly negative, integer
+fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    parse_integer(get_line(src)?)
+}
+
+/// Parse the length of a bulk string.
+fn bulk_len(line: &[u8], limits: &Limits) -> Result<usize, Error> {
+    match usize::try_from(parse_decimal(line)?) {
+        Ok(len) if len <= limits.max_bulk_len => Ok(len),
+        _ => Err("protocol error; invalid bulk length".into()),
+    }
+}
+
+/// Parse the length of an array nested in `depth` other arrays.
+fn array_len(line: &[u8], limits: &Limits, depth: usize) -> Result<usize, Error> {
+    if depth >= limits.max_depth {
+        return Err("protocol error; too many nested arrays".into());
+    }
+
+    match usize::try_from(parse_decimal(line)?) {
+        Ok(len) if len <= limits.max_array_len => Ok(len),
+        _ => Err("protocol error; invalid multibulk length".into()),
+    }
+}
+
+/// Parse a decimal
+fn parse_decimal(line: &[u8]) -> Result<u64, Error> {
     use atoi::atoi;
-    let line = get_line(src)?;
     match atoi::<u64>(line) {
         Some(v) => Ok(v),
         None => Err(Error::Other(anyhow::anyhow!(
@@ -272,12 +730,23 @@ fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
     }
 }
 
+/// Parse a possibly negative integer
+fn parse_integer(line: &[u8]) -> Result<i64, Error> {
+    use atoi::atoi;
+    match atoi::<i64>(line) {
+        Some(v) => Ok(v),
+        None => Err(Error::Other(anyhow::anyhow!(
+            "protocol error; invalid frame format"
+        ))),
+    }
+}
+
 /// Find a line
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
     // Scan the bytes directly
     let start = src.position() as usize;
     // Scan to the second to last byte
-    let end = src.get_ref().len() - 1;
+    let end = src.get_ref().len().saturating_sub(1);
 
     for i in start..end {
         if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
@@ -326,3 +795,72 @@ impl fmt::Display for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_negative_integers() {
+        let mut src = Cursor::new(&b":-5\r\n"[..]);
+        Frame::check(&mut src, &Limits::default()).unwrap();
+
+        src.set_position(0);
+        assert!(matches!(
+            Frame::parse(&mut src, &Limits::default()).unwrap(),
+            Frame::Integer(-5)
+        ));
+    }
+
+    #[test]
+    fn decoder_resumes_one_byte_at_a_time() {
+        let encoded = b"*3\r\n$3\r\nfoo\r\n$-1\r\n*2\r\n+OK\r\n:-1\r\n";
+        let limits = Limits::default();
+        let parsed = Frame::parse(&mut Cursor::new(&encoded[..]), &limits).unwrap();
+
+        let mut decoder = Decoder::new(limits);
+        let mut buf = BytesMut::new();
+        let (last, head) = encoded.split_last().unwrap();
+        for &byte in head {
+            buf.extend_from_slice(&[byte]);
+            assert!(matches!(decoder.decode(&mut buf), Err(Error::Incomplete)));
+        }
+        buf.extend_from_slice(&[*last]);
+        let decoded = decoder.decode(&mut buf).unwrap();
+
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", parsed));
+        assert!(buf.is_empty());
+        assert_eq!(decoder.pending_len(), 0);
+    }
+
+    #[test]
+    fn parses_resp3_types() {
+        let encoded = b"|1\r\n+ttl\r\n:3\r\n%2\r\n+a\r\n~2\r\n,1.5\r\n#f\r\n\
+                        +b\r\n>3\r\n_\r\n(-12345678901234567890\r\n=8\r\ntxt:some\r\n";
+        let limits = Limits::default();
+        let mut src = Cursor::new(&encoded[..]);
+        Frame::check(&mut src, &limits).unwrap();
+        assert_eq!(src.position() as usize, encoded.len());
+
+        src.set_position(0);
+        let parsed = Frame::parse(&mut src, &limits).unwrap();
+        let decoded = Decoder::new(limits)
+            .decode(&mut BytesMut::from(&encoded[..]))
+            .unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", parsed));
+
+        let Frame::Attribute(attributes, data) = parsed else {
+            panic!("expected an attribute, got {:?}", parsed);
+        };
+        assert_eq!(attributes[0].0, "ttl");
+        assert_eq!(
+            data.to_string(),
+            "a 1.5 false b (nil) -12345678901234567890 some"
+        );
+
+        for invalid in [&b"#x\r\n"[..], b",one\r\n", b"(1-2\r\n", b"=3\r\ntxt\r\n"] {
+            let mut buf = BytesMut::from(invalid);
+            assert!(Decoder::new(limits).decode(&mut buf).is_err());
+        }
+    }
+}
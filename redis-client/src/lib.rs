@@ -8,6 +8,7 @@ macro_rules! dlog {
 pub mod connection;
 pub mod frame;
 pub mod parse;
+pub mod pipeline;
 
 pub type Error = anyhow::Error;
 pub type Result<T> = std::result::Result<T, Error>;
@@ -13,18 +13,11 @@ fn build_bulk<S: AsRef<[u8]>>(s: S) -> Frame {
 }
 
 fn build_set_frame(key: &str, value: &str) -> Frame {
-    let mut parts = Vec::with_capacity(3);
-    parts.push(build_bulk("SET"));
-    parts.push(build_bulk(key));
-    parts.push(build_bulk(value));
-    Frame::Array(parts)
+    Frame::Array(vec![build_bulk("SET"), build_bulk(key), build_bulk(value)])
 }
 
 fn build_get_frame(key: &str) -> Frame {
-    let mut parts = Vec::with_capacity(2);
-    parts.push(build_bulk("GET"));
-    parts.push(build_bulk(key));
-    Frame::Array(parts)
+    Frame::Array(vec![build_bulk("GET"), build_bulk(key)])
 }
 
 /*
@@ -44,10 +37,8 @@ async fn main() -> redis_client::Result<()> {
     let mut addr = default_addr.clone();
 
     // Detect if first argument looks like host:port
-    if let Some(first) = args.first() {
-        if first.contains(':') {
-            addr = args.remove(0);
-        }
+    if args.first().is_some_and(|first| first.contains(':')) {
+        addr = args.remove(0);
     }
 
     if args.is_empty() {
@@ -9,7 +9,7 @@ use std::{fmt, str, vec};
 /// cursor-like API. Each command struct includes a `parse_frame` method that
 /// uses a `Parse` to extract its fields.
 #[derive(Debug)]
-pub(crate) struct Parse {
+pub struct Parse {
     /// Array frame iterator.
     parts: vec::IntoIter<Frame>,
 }
@@ -19,7 +19,7 @@ pub(crate) struct Parse {
 /// Only `EndOfStream` errors are handled at runtime. All other errors result in
 /// the connection being terminated.
 #[derive(Debug)]
-pub(crate) enum ParseError {
+pub enum ParseError {
     /// Attempting to extract a value failed due to the frame being fully
     /// consumed.
     EndOfStream,
@@ -32,7 +32,7 @@ impl Parse {
     /// Create a new `Parse` to parse the contents of `frame`.
     ///
     /// Returns `Err` if `frame` is not an array frame.
-    pub(crate) fn new(frame: Frame) -> Result<Parse, ParseError> {
+    pub fn new(frame: Frame) -> Result<Parse, ParseError> {
         let array = match frame {
             Frame::Array(array) => {
                 dlog!("Parse::new - array len={}", array.len());
@@ -62,7 +62,7 @@ impl Parse {
     /// Return the next entry as a string.
     ///
     /// If the next entry cannot be represented as a String, then an error is returned.
-    pub(crate) fn next_string(&mut self) -> Result<String, ParseError> {
+    pub fn next_string(&mut self) -> Result<String, ParseError> {
         match self.next()? {
             // Both `Simple` and `Bulk` representation may be strings. Strings
             // are parsed to UTF-8.
@@ -93,7 +93,7 @@ impl Parse {
     ///
     /// If the next entry cannot be represented as raw bytes, an error is
     /// returned.
-    pub(crate) fn next_bytes(&mut self) -> Result<Bytes, ParseError> {
+    pub fn next_bytes(&mut self) -> Result<Bytes, ParseError> {
         match self.next()? {
             Frame::Simple(s) => {
                 let b = Bytes::from(s.clone().into_bytes());
@@ -118,7 +118,7 @@ impl Parse {
     ///
     /// If the next entry cannot be represented as an integer, then an error is
     /// returned.
-    pub(crate) fn next_int(&mut self) -> Result<u64, ParseError> {
+    pub fn next_int(&mut self) -> Result<i64, ParseError> {
         use atoi::atoi;
 
         const MSG: &str = "protocol error; invalid number";
@@ -132,14 +132,14 @@ impl Parse {
             // Simple and bulk frames must be parsed as integers. If the parsing
             // fails, an error is returned.
             Frame::Simple(data) => {
-                let v = atoi::<u64>(data.as_bytes())
+                let v = atoi::<i64>(data.as_bytes())
                     .ok_or_else(|| ParseError::Other(anyhow::anyhow!(MSG)))?;
                 dlog!("Parse::next_int - simple parsed={}", v);
                 Ok(v)
             }
             Frame::Bulk(data) => {
                 let v =
-                    atoi::<u64>(&data).ok_or_else(|| ParseError::Other(anyhow::anyhow!(MSG)))?;
+                    atoi::<i64>(&data).ok_or_else(|| ParseError::Other(anyhow::anyhow!(MSG)))?;
                 dlog!("Parse::next_int - bulk parsed={}", v);
                 Ok(v)
             }
@@ -151,7 +151,7 @@ impl Parse {
     }
 
     /// Ensure there are no more entries in the array
-    pub(crate) fn finish(&mut self) -> Result<(), ParseError> {
+    pub fn finish(&mut self) -> Result<(), ParseError> {
         if self.parts.next().is_none() {
             dlog!("Parse::finish - end of frame");
             Ok(())
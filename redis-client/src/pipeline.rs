@@ -0,0 +1,131 @@
+use crate::connection::Connection;
+use crate::frame::Frame;
+
+use bytes::Bytes;
+
+/// A batch of commands sent to the server in one go.
+///
+/// Every queued command is encoded into the connection's write buffer and the
+/// whole batch is flushed once, instead of waiting for each reply before
+/// sending the next command. The replies are then read back in order.
+///
+/// ```no_run
+/// # async fn run(conn: &mut redis_client::connection::Connection) -> redis_client::Result<()> {
+/// let replies = conn
+///     .pipeline()
+///     .cmd(["SET", "foo", "bar"])
+///     .cmd(["GET", "foo"])
+///     .execute()
+///     .await?;
+/// assert_eq!(replies.len(), 2);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Pipeline<'a> {
+    conn: &'a mut Connection,
+    commands: Vec<Frame>,
+}
+
+impl Connection {
+    /// Start building a pipeline on this connection.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            conn: self,
+            commands: Vec::new(),
+        }
+    }
+}
+
+impl Pipeline<'_> {
+    /// Queue a command made of the given arguments, the first being the
+    /// command name.
+    pub fn cmd<I, A>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<[u8]>,
+    {
+        let args = args
+            .into_iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_ref())))
+            .collect();
+        self.commands.push(Frame::Array(args));
+        self
+    }
+
+    /// Returns the number of queued commands.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Send every queued command and collect their replies, in the order the
+    /// commands were queued.
+    ///
+    /// Error replies are returned like any other reply; only I/O errors and
+    /// the server closing the connection early fail the whole pipeline.
+    pub async fn execute(self) -> crate::Result<Vec<Frame>> {
+        dlog!("pipeline - sending {} commands", self.commands.len());
+        for command in &self.commands {
+            self.conn.feed_frame(command).await?;
+        }
+        self.conn.flush().await?;
+
+        let mut replies = Vec::with_capacity(self.commands.len());
+        while replies.len() < self.commands.len() {
+            match self.conn.read_frame().await? {
+                Some(reply) => replies.push(reply),
+                None => anyhow::bail!(
+                    "connection closed after {} of {} replies",
+                    replies.len(),
+                    self.commands.len()
+                ),
+            }
+        }
+
+        Ok(replies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn replies_come_back_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A server that numbers the requests it receives.
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut conn = Connection::new(socket);
+            let mut count = 0;
+            while let Some(Frame::Array(_)) = conn.read_frame().await.unwrap() {
+                count += 1;
+                conn.write_frame(&Frame::Integer(count)).await.unwrap();
+            }
+        });
+
+        let mut conn = Connection::new(TcpStream::connect(addr).await.unwrap());
+        let mut pipeline = conn.pipeline();
+        for i in 0..100 {
+            pipeline = pipeline.cmd(["ECHO", &i.to_string()]);
+        }
+        assert_eq!(pipeline.len(), 100);
+
+        let replies = pipeline.execute().await.unwrap();
+        let replies: Vec<i64> = replies
+            .into_iter()
+            .map(|reply| match reply {
+                Frame::Integer(n) => n,
+                reply => panic!("unexpected reply {:?}", reply),
+            })
+            .collect();
+        assert_eq!(replies, (1..=100).collect::<Vec<_>>());
+    }
+}
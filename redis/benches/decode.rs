@@ -0,0 +1,84 @@
+//! Measures how fast the server's `Decoder` turns requests into frames, both
+//! when a request is fully buffered and when it arrives in many reads.
+//!
+//! The decoder does no logging, so the numbers are those of decoding alone.
+//!
+//! Run with `cargo bench --bench decode`.
+
+use bytes::BytesMut;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use redis::frame::{Decoder, Error, Limits};
+
+/// Size of the reads a large request arrives in.
+const READ_SIZE: usize = 4 * 1024;
+
+/// Encode `SET key <value>` with a value of `len` bytes.
+fn set_request(len: usize) -> Vec<u8> {
+    let mut request = format!("*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n${}\r\n", len).into_bytes();
+    request.resize(request.len() + len, b'x');
+    request.extend_from_slice(b"\r\n");
+    request
+}
+
+/// Encode `RPUSH key` followed by `count` small values.
+fn rpush_request(count: usize) -> Vec<u8> {
+    let mut request = format!("*{}\r\n$5\r\nRPUSH\r\n$3\r\nkey\r\n", count + 2).into_bytes();
+    for i in 0..count {
+        let value = format!("{:08}", i);
+        request.extend_from_slice(format!("${}\r\n{}\r\n", value.len(), value).as_bytes());
+    }
+    request
+}
+
+/// Requests that are fully buffered by the time they are decoded.
+fn buffered(c: &mut Criterion) {
+    let limits = Limits::default();
+    let mut group = c.benchmark_group("buffered");
+
+    for len in [16, 1024, 1024 * 1024] {
+        let request = set_request(len);
+        group.throughput(Throughput::Bytes(request.len() as u64));
+
+        // Every iteration starts from a freshly filled read buffer, as a
+        // connection would.
+        group.bench_with_input(BenchmarkId::new("set", len), &request, |b, request| {
+            b.iter_batched(
+                || (Decoder::new(limits), BytesMut::from(&request[..])),
+                |(mut decoder, mut buf)| decoder.decode(&mut buf).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// A request of about 1 MB made of many small values, arriving `READ_SIZE`
+/// bytes at a time with a decoding attempt after every read.
+fn chunked(c: &mut Criterion) {
+    let limits = Limits::default();
+    let request = rpush_request(64 * 1024);
+    let mut group = c.benchmark_group("chunked");
+    group.throughput(Throughput::Bytes(request.len() as u64));
+
+    group.bench_function("rpush", |b| {
+        b.iter(|| {
+            let mut decoder = Decoder::new(limits);
+            let mut buf = BytesMut::new();
+            for chunk in request.chunks(READ_SIZE) {
+                buf.extend_from_slice(chunk);
+                match decoder.decode(&mut buf) {
+                    Ok(frame) => return frame,
+                    Err(Error::Incomplete) => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            unreachable!("the request is complete");
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, buffered, chunked);
+criterion_main!(benches);
@@ -0,0 +1,624 @@
+//! Access control lists.
+//!
+//! Clients authenticate as a user, and each user may only run some of the
+//! commands, on some of the keys and Pub/Sub channels. Users are set up with
+//! rules, as in Redis:
+//!
+//! ```text
+//! ACL SETUSER alice on >secret ~cache:* &news +@read +set -@dangerous
+//! ```
+//!
+//! * `on`, `off` enable or disable logging in as the user
+//! * `>password`, `<password` add or remove a password, `#hash`, `!hash` do
+//!   the same with the SHA-256 of one, `nopass` lets any password in and
+//!   `resetpass` forgets every password
+//! * `+command`, `-command`, `+@category`, `-@category` allow or deny
+//!   commands, `allcommands` and `nocommands` standing for `+@all` and
+//!   `-@all`
+//! * `~pattern` allows the keys matching a glob pattern, `allkeys` any key
+//!   and `resetkeys` none
+//! * `&pattern`, `allchannels` and `resetchannels` do the same for channels
+//! * `reset` takes the user back to a new one's state: off, with no password,
+//!   command, key or channel
+//!
+//! Permissions are checked on whole commands: subcommands such as
+//! `CONFIG GET` cannot be allowed separately from the rest of `CONFIG`.
+
+use crate::frame::Frame;
+use crate::glob::glob_match;
+
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+/// Categories commands are sorted into, in the order `ACL CAT` lists them.
+pub(crate) const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "string",
+    "list",
+    "set",
+    "sortedset",
+    "hash",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+];
+
+/// Where the keys of a command are among its arguments, the name of the
+/// command being the first one.
+#[derive(Debug, Clone, Copy)]
+enum Keys {
+    None,
+
+    /// From `first` to `last`, every `step` arguments. A negative `last`
+    /// counts from the end.
+    Range(usize, isize, usize),
+
+    /// The arguments following the one at this index, which is their count.
+    Counted(usize),
+}
+
+use Keys::{Counted, Range};
+
+/// Every command with its categories and keys.
+const COMMANDS: &[(&str, &str, Keys)] = &[
+    ("ping", "connection fast", Keys::None),
+    ("echo", "connection fast", Keys::None),
+    ("quit", "connection fast", Keys::None),
+    ("hello", "connection fast", Keys::None),
+    ("auth", "connection fast", Keys::None),
+    ("get", "read string fast", Range(1, 1, 1)),
+    ("set", "write string slow", Range(1, 1, 1)),
+    ("incr", "write string fast", Range(1, 1, 1)),
+    ("decr", "write string fast", Range(1, 1, 1)),
+    ("incrby", "write string fast", Range(1, 1, 1)),
+    ("decrby", "write string fast", Range(1, 1, 1)),
+    ("incrbyfloat", "write string fast", Range(1, 1, 1)),
+    ("append", "write string fast", Range(1, 1, 1)),
+    ("strlen", "read string fast", Range(1, 1, 1)),
+    ("getrange", "read string slow", Range(1, 1, 1)),
+    ("setrange", "write string slow", Range(1, 1, 1)),
+    ("getdel", "write string fast", Range(1, 1, 1)),
+    ("getex", "write string fast", Range(1, 1, 1)),
+    ("mget", "read string fast", Range(1, -1, 1)),
+    ("mset", "write string slow", Range(1, -1, 2)),
+    ("msetnx", "write string slow", Range(1, -1, 2)),
+    ("del", "keyspace write slow", Range(1, -1, 1)),
+    ("exists", "keyspace read fast", Range(1, -1, 1)),
+    ("expire", "keyspace write fast", Range(1, 1, 1)),
+    ("pexpire", "keyspace write fast", Range(1, 1, 1)),
+    ("expireat", "keyspace write fast", Range(1, 1, 1)),
+    ("pexpireat", "keyspace write fast", Range(1, 1, 1)),
+    ("ttl", "keyspace read fast", Range(1, 1, 1)),
+    ("pttl", "keyspace read fast", Range(1, 1, 1)),
+    ("persist", "keyspace write fast", Range(1, 1, 1)),
+    ("type", "keyspace read fast", Range(1, 1, 1)),
+    ("lpush", "write list fast", Range(1, 1, 1)),
+    ("rpush", "write list fast", Range(1, 1, 1)),
+    ("lpushx", "write list fast", Range(1, 1, 1)),
+    ("rpushx", "write list fast", Range(1, 1, 1)),
+    ("lpop", "write list fast", Range(1, 1, 1)),
+    ("rpop", "write list fast", Range(1, 1, 1)),
+    ("lrange", "read list slow", Range(1, 1, 1)),
+    ("llen", "read list fast", Range(1, 1, 1)),
+    ("lindex", "read list slow", Range(1, 1, 1)),
+    ("lset", "write list slow", Range(1, 1, 1)),
+    ("ltrim", "write list slow", Range(1, 1, 1)),
+    ("linsert", "write list slow", Range(1, 1, 1)),
+    ("lrem", "write list slow", Range(1, 1, 1)),
+    ("lmove", "write list slow", Range(1, 2, 1)),
+    ("rpoplpush", "write list slow", Range(1, 2, 1)),
+    ("blpop", "write list slow blocking", Range(1, -2, 1)),
+    ("brpop", "write list slow blocking", Range(1, -2, 1)),
+    ("blmove", "write list slow blocking", Range(1, 2, 1)),
+    ("brpoplpush", "write list slow blocking", Range(1, 2, 1)),
+    ("hset", "write hash fast", Range(1, 1, 1)),
+    ("hmset", "write hash fast", Range(1, 1, 1)),
+    ("hsetnx", "write hash fast", Range(1, 1, 1)),
+    ("hget", "read hash fast", Range(1, 1, 1)),
+    ("hmget", "read hash fast", Range(1, 1, 1)),
+    ("hgetall", "read hash slow", Range(1, 1, 1)),
+    ("hkeys", "read hash slow", Range(1, 1, 1)),
+    ("hvals", "read hash slow", Range(1, 1, 1)),
+    ("hlen", "read hash fast", Range(1, 1, 1)),
+    ("hdel", "write hash fast", Range(1, 1, 1)),
+    ("hexists", "read hash fast", Range(1, 1, 1)),
+    ("hincrby", "write hash fast", Range(1, 1, 1)),
+    ("hincrbyfloat", "write hash fast", Range(1, 1, 1)),
+    ("hrandfield", "read hash slow", Range(1, 1, 1)),
+    ("sadd", "write set fast", Range(1, 1, 1)),
+    ("srem", "write set fast", Range(1, 1, 1)),
+    ("smembers", "read set slow", Range(1, 1, 1)),
+    ("sismember", "read set fast", Range(1, 1, 1)),
+    ("smismember", "read set fast", Range(1, 1, 1)),
+    ("scard", "read set fast", Range(1, 1, 1)),
+    ("spop", "write set fast", Range(1, 1, 1)),
+    ("srandmember", "read set slow", Range(1, 1, 1)),
+    ("sinter", "read set slow", Range(1, -1, 1)),
+    ("sunion", "read set slow", Range(1, -1, 1)),
+    ("sdiff", "read set slow", Range(1, -1, 1)),
+    ("sinterstore", "write set slow", Range(1, -1, 1)),
+    ("sunionstore", "write set slow", Range(1, -1, 1)),
+    ("sdiffstore", "write set slow", Range(1, -1, 1)),
+    ("sintercard", "read set slow", Counted(1)),
+    ("zadd", "write sortedset fast", Range(1, 1, 1)),
+    ("zincrby", "write sortedset fast", Range(1, 1, 1)),
+    ("zrem", "write sortedset fast", Range(1, 1, 1)),
+    ("zscore", "read sortedset fast", Range(1, 1, 1)),
+    ("zrank", "read sortedset fast", Range(1, 1, 1)),
+    ("zrevrank", "read sortedset fast", Range(1, 1, 1)),
+    ("zcard", "read sortedset fast", Range(1, 1, 1)),
+    ("zcount", "read sortedset fast", Range(1, 1, 1)),
+    ("zrange", "read sortedset slow", Range(1, 1, 1)),
+    ("zrevrange", "read sortedset slow", Range(1, 1, 1)),
+    ("zrangebyscore", "read sortedset slow", Range(1, 1, 1)),
+    ("zrevrangebyscore", "read sortedset slow", Range(1, 1, 1)),
+    ("subscribe", "pubsub slow", Keys::None),
+    ("psubscribe", "pubsub slow", Keys::None),
+    ("unsubscribe", "pubsub slow", Keys::None),
+    ("punsubscribe", "pubsub slow", Keys::None),
+    ("publish", "pubsub fast", Keys::None),
+    ("pubsub", "pubsub slow", Keys::None),
+    ("multi", "transaction fast", Keys::None),
+    ("exec", "transaction slow", Keys::None),
+    ("discard", "transaction fast", Keys::None),
+    ("watch", "transaction fast", Range(1, -1, 1)),
+    ("unwatch", "transaction fast", Keys::None),
+    ("save", "admin slow dangerous", Keys::None),
+    ("bgsave", "admin slow dangerous", Keys::None),
+    ("lastsave", "admin fast dangerous", Keys::None),
+    ("bgrewriteaof", "admin slow dangerous", Keys::None),
+    ("config", "admin slow dangerous", Keys::None),
+    ("shutdown", "admin slow dangerous", Keys::None),
+    ("acl", "admin slow dangerous", Keys::None),
+    ("replicaof", "admin slow dangerous", Keys::None),
+    ("slaveof", "admin slow dangerous", Keys::None),
+    ("psync", "admin slow dangerous", Keys::None),
+    ("replconf", "admin slow dangerous", Keys::None),
+    ("role", "admin fast dangerous", Keys::None),
+    ("info", "slow dangerous", Keys::None),
+    ("wait", "connection slow", Keys::None),
+];
+
+/// Commands any client may run, authenticated or not.
+pub(crate) const NO_AUTH_COMMANDS: &[&str] = &["auth", "hello", "quit"];
+
+/// Returns the commands in `category`, or `None` if there is no such
+/// category.
+pub(crate) fn category_commands(category: &str) -> Option<Vec<&'static str>> {
+    if category.eq_ignore_ascii_case("all") {
+        return Some(COMMANDS.iter().map(|(name, ..)| *name).collect());
+    }
+    let category = CATEGORIES
+        .iter()
+        .find(|known| known.eq_ignore_ascii_case(category))?;
+    Some(
+        COMMANDS
+            .iter()
+            .filter(|(_, categories, _)| categories.split(' ').any(|c| c == *category))
+            .map(|(name, ..)| *name)
+            .collect(),
+    )
+}
+
+/// Returns the SHA-256 of `password`, hex encoded, which is what is kept of
+/// passwords.
+fn hash(password: &[u8]) -> String {
+    Sha256::digest(password)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// A user clients may authenticate as.
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    name: String,
+
+    /// Whether clients may authenticate as the user. Clients already
+    /// authenticated stay so when it is disabled.
+    enabled: bool,
+
+    /// Set when any password is accepted.
+    nopass: bool,
+
+    /// Hashes of the passwords accepted.
+    passwords: Vec<String>,
+
+    /// The commands the user may run.
+    commands: HashSet<&'static str>,
+
+    /// Glob patterns of the keys the user may access.
+    keys: Vec<String>,
+
+    /// Glob patterns of the channels the user may publish or subscribe to.
+    channels: Vec<String>,
+}
+
+impl User {
+    /// Create a user that cannot do anything, not even log in.
+    pub(crate) fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            commands: HashSet::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    /// Create the `default` user clients are authenticated as when they
+    /// connect, which may do anything and needs no password.
+    pub(crate) fn default_user() -> User {
+        let mut user = User::new("default");
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            user.apply_rule(rule).expect("invalid default rule");
+        }
+        user
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` if a client may authenticate as the user with
+    /// `password`.
+    pub(crate) fn accepts(&self, password: &[u8]) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash(password)))
+    }
+
+    /// Returns `true` if clients log in as the user without a password.
+    pub(crate) fn is_open(&self) -> bool {
+        self.enabled && self.nopass
+    }
+
+    /// Returns `true` if the user has a password.
+    pub(crate) fn has_password(&self) -> bool {
+        !self.nopass && !self.passwords.is_empty()
+    }
+
+    /// Change the user as `rule` says.
+    pub(crate) fn apply_rule(&mut self, rule: &str) -> Result<(), String> {
+        let fail = |reason: &str| format!("Error in ACL SETUSER modifier '{}': {}", rule, reason);
+        let unknown = || fail("Unknown command or category name in ACL");
+
+        match &rule.to_lowercase()[..] {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".to_string()],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec!["*".to_string()],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => return self.apply_rule("+@all"),
+            "nocommands" => return self.apply_rule("-@all"),
+            "reset" => *self = User::new(&self.name),
+            _ => {
+                let (prefix, rest) = rule.split_at(rule.chars().next().map_or(0, char::len_utf8));
+                match prefix {
+                    ">" => {
+                        let hashed = hash(rest.as_bytes());
+                        if !self.passwords.contains(&hashed) {
+                            self.passwords.push(hashed);
+                        }
+                        self.nopass = false;
+                    }
+                    "<" => {
+                        let hashed = hash(rest.as_bytes());
+                        self.passwords.retain(|known| *known != hashed);
+                    }
+                    "#" => {
+                        if rest.len() != 64 || !rest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                            return Err(fail(
+                                "The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters",
+                            ));
+                        }
+                        let hashed = rest.to_lowercase();
+                        if !self.passwords.contains(&hashed) {
+                            self.passwords.push(hashed);
+                        }
+                        self.nopass = false;
+                    }
+                    "!" => {
+                        let hashed = rest.to_lowercase();
+                        self.passwords.retain(|known| *known != hashed);
+                    }
+                    "~" => self.keys.push(rest.to_string()),
+                    "&" => self.channels.push(rest.to_string()),
+                    "+" | "-" => {
+                        let commands = match rest.strip_prefix('@') {
+                            Some(category) => category_commands(category).ok_or_else(unknown)?,
+                            None => {
+                                let name = rest.to_lowercase();
+                                let (name, ..) = COMMANDS
+                                    .iter()
+                                    .find(|(known, ..)| *known == name)
+                                    .ok_or_else(unknown)?;
+                                vec![*name]
+                            }
+                        };
+                        if prefix == "+" {
+                            self.commands.extend(commands);
+                        } else {
+                            for name in commands {
+                                self.commands.remove(name);
+                            }
+                        }
+                    }
+                    _ => return Err(fail("Syntax error")),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the user may run the command `args`, the first of which is
+    /// the name of the command. Commands that do not exist are let through,
+    /// for them to fail as such.
+    pub(crate) fn check(&self, args: &[Frame]) -> Result<(), String> {
+        let Some(name) = args.first().and_then(arg) else {
+            return Ok(());
+        };
+        let name = String::from_utf8_lossy(name).to_lowercase();
+        let Some((name, _, keys)) = COMMANDS.iter().find(|(known, ..)| *known == name) else {
+            return Ok(());
+        };
+
+        if !self.commands.contains(name) {
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                self.name, name
+            ));
+        }
+
+        let len = args.len();
+        let positions: Vec<usize> = match *keys {
+            Keys::None => Vec::new(),
+            Keys::Range(first, last, step) => {
+                let last = if last < 0 { len as isize + last } else { last };
+                (first..=last.max(0) as usize)
+                    .step_by(step)
+                    .filter(|&i| i < len)
+                    .collect()
+            }
+            Keys::Counted(at) => {
+                let count = args
+                    .get(at)
+                    .and_then(arg)
+                    .and_then(crate::parse::parse_int)
+                    .unwrap_or(0)
+                    .max(0) as usize;
+                (at + 1..len.min(at + 1 + count)).collect()
+            }
+        };
+        let allowed = |patterns: &[String], name: &[u8]| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), name))
+        };
+        for key in positions.into_iter().filter_map(|i| arg(&args[i])) {
+            if !allowed(&self.keys, key) {
+                return Err("NOPERM No permissions to access a key".to_string());
+            }
+        }
+
+        // Patterns subscribed to have to be allowed as they are, since they
+        // may match any channel.
+        let channels = match *name {
+            "publish" => args.get(1..2).unwrap_or_default(),
+            "subscribe" | "psubscribe" => &args[1..],
+            _ => &[],
+        };
+        for channel in channels.iter().filter_map(arg) {
+            let permitted = if *name == "psubscribe" {
+                self.channels
+                    .iter()
+                    .any(|pattern| pattern == "*" || pattern.as_bytes() == channel)
+            } else {
+                allowed(&self.channels, channel)
+            };
+            if !permitted {
+                return Err("NOPERM No permissions to access a channel".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the flags of the user, for `ACL GETUSER`.
+    pub(crate) fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub(crate) fn password_hashes(&self) -> &[String] {
+        &self.passwords
+    }
+
+    /// Returns the rules granting the commands the user may run.
+    pub(crate) fn command_rules(&self) -> String {
+        if self.commands.len() == COMMANDS.len() {
+            return "+@all".to_string();
+        }
+        let mut rules = vec!["-@all".to_string()];
+        for (name, ..) in COMMANDS {
+            if self.commands.contains(name) {
+                rules.push(format!("+{}", name));
+            }
+        }
+        rules.join(" ")
+    }
+
+    pub(crate) fn key_rules(&self) -> String {
+        patterns('~', &self.keys)
+    }
+
+    pub(crate) fn channel_rules(&self) -> String {
+        patterns('&', &self.channels)
+    }
+
+    /// Describe the user as the rules that would set it up, for `ACL LIST`.
+    pub(crate) fn describe(&self) -> String {
+        let mut rules = vec![format!("user {}", self.name)];
+        rules.extend(self.flags().iter().map(|flag| flag.to_string()));
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        if !self.keys.is_empty() {
+            rules.push(self.key_rules());
+        }
+        if self.channels.is_empty() {
+            rules.push("resetchannels".to_string());
+        } else {
+            rules.push(self.channel_rules());
+        }
+        rules.push(self.command_rules());
+        rules.join(" ")
+    }
+}
+
+/// Returns the bytes of a command argument.
+fn arg(frame: &Frame) -> Option<&[u8]> {
+    match frame {
+        Frame::Bulk(data) => Some(data),
+        Frame::Simple(s) => Some(s.as_bytes()),
+        _ => None,
+    }
+}
+
+fn patterns(prefix: char, patterns: &[String]) -> String {
+    patterns
+        .iter()
+        .map(|pattern| format!("{}{}", prefix, pattern))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn request(args: &[&str]) -> Vec<Frame> {
+        args.iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+            .collect()
+    }
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::new("alice");
+        for rule in rules {
+            user.apply_rule(rule).unwrap();
+        }
+        user
+    }
+
+    #[test]
+    fn passwords() {
+        let mut alice = user(&[">secret", ">other"]);
+        assert!(!alice.accepts(b"secret"), "disabled users cannot log in");
+        alice.apply_rule("on").unwrap();
+        assert!(alice.accepts(b"secret"));
+        assert!(!alice.accepts(b"wrong"));
+
+        alice.apply_rule("<secret").unwrap();
+        assert!(!alice.accepts(b"secret"));
+        alice.apply_rule(&format!("#{}", hash(b"third"))).unwrap();
+        assert!(alice.accepts(b"third"));
+        assert!(alice.accepts(b"other"));
+
+        alice.apply_rule("nopass").unwrap();
+        assert!(alice.accepts(b"anything"));
+        assert!(alice.apply_rule("#123").is_err());
+    }
+
+    #[test]
+    fn commands_and_categories() {
+        let alice = user(&["+@read", "-get", "+set", "allkeys"]);
+        assert!(alice.check(&request(&["SET", "k", "v"])).is_ok());
+        assert!(alice.check(&request(&["mget", "a", "b"])).is_ok());
+        assert_eq!(
+            alice.check(&request(&["GET", "k"])).unwrap_err(),
+            "NOPERM User alice has no permissions to run the 'get' command"
+        );
+        assert!(alice.check(&request(&["DEL", "k"])).is_err());
+        // Unknown commands fail as such later on.
+        assert!(alice.check(&request(&["NOPE"])).is_ok());
+
+        let admin = user(&["allcommands", "-@dangerous"]);
+        assert!(admin.check(&request(&["CONFIG", "GET", "*"])).is_err());
+        assert_eq!(admin.command_rules().split(' ').next(), Some("-@all"));
+        assert!(User::new("x").apply_rule("+nope").is_err());
+        assert!(User::new("x").apply_rule("+@nope").is_err());
+    }
+
+    #[test]
+    fn keys_and_channels() {
+        let alice = user(&["allcommands", "~cache:*", "~user:1", "&news.*"]);
+        assert!(alice.check(&request(&["GET", "cache:a"])).is_ok());
+        assert!(
+            alice
+                .check(&request(&["MSET", "cache:a", "1", "user:1", "2"]))
+                .is_ok()
+        );
+        assert!(
+            alice
+                .check(&request(&["MSET", "cache:a", "other", "other", "2"]))
+                .is_err()
+        );
+        assert!(
+            alice
+                .check(&request(&["BLPOP", "cache:a", "other", "0"]))
+                .is_err()
+        );
+        assert!(
+            alice
+                .check(&request(&["SINTERCARD", "1", "cache:a", "LIMIT", "1"]))
+                .is_ok()
+        );
+        assert_eq!(
+            alice
+                .check(&request(&["SINTERCARD", "2", "cache:a", "x"]))
+                .unwrap_err(),
+            "NOPERM No permissions to access a key"
+        );
+
+        assert!(
+            alice
+                .check(&request(&["PUBLISH", "news.tech", "hi"]))
+                .is_ok()
+        );
+        assert!(
+            alice
+                .check(&request(&["SUBSCRIBE", "news.a", "sports"]))
+                .is_err()
+        );
+        assert!(alice.check(&request(&["PSUBSCRIBE", "news.*"])).is_ok());
+        assert!(alice.check(&request(&["PSUBSCRIBE", "news.a*"])).is_err());
+
+        assert_eq!(
+            alice.describe(),
+            "user alice off ~cache:* ~user:1 &news.* +@all"
+        );
+        assert_eq!(
+            User::default_user().describe(),
+            "user default on nopass ~* &* +@all"
+        );
+    }
+}
@@ -0,0 +1,634 @@
+//! Command dispatch.
+//!
+//! Every request sent by a client is an array frame whose first entry is the
+//! command name. `Command::from_frame` turns such a frame into one of the
+//! command structs below, and `Command::apply` executes it against the shared
+//! `Db`, producing the reply frame.
+
+mod acl;
+mod connection;
+mod hash;
+mod keys;
+mod list;
+mod pubsub;
+mod replication;
+mod server;
+mod set;
+mod string;
+mod transaction;
+mod zset;
+
+pub(crate) use acl::{Acl, Auth, login, wrong_pass};
+pub(crate) use connection::{Echo, Hello, Ping, Quit};
+pub(crate) use hash::{
+    HDel, HExists, HGet, HGetAll, HIncrBy, HIncrByFloat, HKeys, HLen, HMGet, HRandField, HSet,
+    HSetNx,
+};
+pub(crate) use keys::{Del, Exists, Expire, Persist, Ttl, Type};
+pub(crate) use list::{
+    BLMove, BPop, End, LIndex, LInsert, LLen, LMove, LRange, LRem, LSet, LTrim, Pop, Push,
+};
+pub(crate) use pubsub::{PubSub, Publish, Subscribe, Subscriber, Unsubscribe};
+pub(crate) use replication::{Info, Psync, ReplConf, ReplicaOf, Role, Wait, replicate};
+pub(crate) use server::{BgRewriteAof, BgSave, Config, LastSave, Save, Shutdown};
+pub(crate) use set::{
+    SAdd, SCard, SInterCard, SIsMember, SMembers, SPop, SRandMember, SRem, SetCombine, SetOp,
+};
+pub(crate) use string::{
+    Append, Get, GetDel, GetEx, GetRange, IncrBy, IncrByFloat, MGet, MSet, Set, SetRange, Strlen,
+};
+pub(crate) use transaction::{Discard, Exec, Multi, Transaction, Unwatch, Watch};
+pub(crate) use zset::{RangeForm, ZAdd, ZCard, ZCount, ZRange, ZRank, ZRem, ZScore};
+
+use crate::db::Db;
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+
+/// Enumeration of supported Redis commands.
+#[derive(Debug)]
+pub(crate) enum Command {
+    Ping(Ping),
+    Echo(Echo),
+    Quit(Quit),
+    Hello(Hello),
+    Auth(Auth),
+    Acl(Acl),
+    Get(Get),
+    Set(Set),
+    IncrBy(IncrBy),
+    IncrByFloat(IncrByFloat),
+    Append(Append),
+    Strlen(Strlen),
+    GetRange(GetRange),
+    SetRange(SetRange),
+    GetDel(GetDel),
+    GetEx(GetEx),
+    MGet(MGet),
+    MSet(MSet),
+    Del(Del),
+    Exists(Exists),
+    Expire(Expire),
+    Ttl(Ttl),
+    Persist(Persist),
+    Type(Type),
+    Push(Push),
+    Pop(Pop),
+    LRange(LRange),
+    LLen(LLen),
+    LIndex(LIndex),
+    LSet(LSet),
+    LTrim(LTrim),
+    LInsert(LInsert),
+    LRem(LRem),
+    LMove(LMove),
+    BPop(BPop),
+    BLMove(BLMove),
+    HSet(HSet),
+    HSetNx(HSetNx),
+    HGet(HGet),
+    HMGet(HMGet),
+    HGetAll(HGetAll),
+    HKeys(HKeys),
+    HLen(HLen),
+    HDel(HDel),
+    HExists(HExists),
+    HIncrBy(HIncrBy),
+    HIncrByFloat(HIncrByFloat),
+    HRandField(HRandField),
+    SAdd(SAdd),
+    SRem(SRem),
+    SMembers(SMembers),
+    SIsMember(SIsMember),
+    SCard(SCard),
+    SPop(SPop),
+    SRandMember(SRandMember),
+    SetCombine(SetCombine),
+    SInterCard(SInterCard),
+    ZAdd(ZAdd),
+    ZRem(ZRem),
+    ZScore(ZScore),
+    ZRank(ZRank),
+    ZCard(ZCard),
+    ZCount(ZCount),
+    ZRange(ZRange),
+    Subscribe(Subscribe),
+    Unsubscribe(Unsubscribe),
+    Publish(Publish),
+    PubSub(PubSub),
+    Multi(Multi),
+    Exec(Exec),
+    Discard(Discard),
+    Watch(Watch),
+    Unwatch(Unwatch),
+    Save(Save),
+    BgSave(BgSave),
+    LastSave(LastSave),
+    BgRewriteAof(BgRewriteAof),
+    Config(Config),
+    Shutdown(Shutdown),
+    ReplicaOf(ReplicaOf),
+    Psync(Psync),
+    ReplConf(ReplConf),
+    Role(Role),
+    Info(Info),
+    Wait(Wait),
+    Unknown(Unknown),
+}
+
+impl Command {
+    /// Parse a command from a received frame.
+    ///
+    /// The `Frame` must represent a Redis command supported by this server and
+    /// be the array variant.
+    ///
+    /// # Returns
+    ///
+    /// On success, the command value is returned, otherwise `Err` is returned.
+    /// The error's `Display` output is the error reply to send to the client.
+    pub(crate) fn from_frame(frame: Frame) -> crate::Result<Command> {
+        // The frame value is decorated with `Parse`. `Parse` provides a
+        // "cursor" like API which makes parsing the command easier.
+        //
+        // The frame value must be an array variant. Any other frame variants
+        // result in an error being returned.
+        let mut parse = Parse::new(frame).map_err(|e| format!("ERR {}", e))?;
+
+        // All redis commands begin with the command name as a string. The name
+        // is read and converted to lower case in order to do case sensitive
+        // matching.
+        let command_name = parse.next_string().map_err(|e| format!("ERR {}", e))?;
+        let command_name = command_name.to_lowercase();
+
+        // Match the command name, delegating the rest of the parsing to the
+        // specific command.
+        let command = match &command_name[..] {
+            "ping" => Ping::parse_frames(&mut parse).map(Command::Ping),
+            "echo" => Echo::parse_frames(&mut parse).map(Command::Echo),
+            "quit" => Quit::parse_frames(&mut parse).map(Command::Quit),
+            "hello" => Hello::parse_frames(&mut parse).map(Command::Hello),
+            "auth" => Auth::parse_frames(&mut parse).map(Command::Auth),
+            "acl" => Acl::parse_frames(&mut parse).map(Command::Acl),
+            "get" => Get::parse_frames(&mut parse).map(Command::Get),
+            "set" => Set::parse_frames(&mut parse).map(Command::Set),
+            "incr" | "decr" | "incrby" | "decrby" => {
+                IncrBy::parse_frames(&mut parse, &command_name).map(Command::IncrBy)
+            }
+            "incrbyfloat" => IncrByFloat::parse_frames(&mut parse).map(Command::IncrByFloat),
+            "append" => Append::parse_frames(&mut parse).map(Command::Append),
+            "strlen" => Strlen::parse_frames(&mut parse).map(Command::Strlen),
+            "getrange" => GetRange::parse_frames(&mut parse).map(Command::GetRange),
+            "setrange" => SetRange::parse_frames(&mut parse).map(Command::SetRange),
+            "getdel" => GetDel::parse_frames(&mut parse).map(Command::GetDel),
+            "getex" => GetEx::parse_frames(&mut parse).map(Command::GetEx),
+            "mget" => MGet::parse_frames(&mut parse).map(Command::MGet),
+            "mset" => MSet::parse_frames(&mut parse, false).map(Command::MSet),
+            "msetnx" => MSet::parse_frames(&mut parse, true).map(Command::MSet),
+            "del" => Del::parse_frames(&mut parse).map(Command::Del),
+            "exists" => Exists::parse_frames(&mut parse).map(Command::Exists),
+            "expire" => Expire::parse_frames(&mut parse, "expire").map(Command::Expire),
+            "pexpire" => Expire::parse_frames(&mut parse, "pexpire").map(Command::Expire),
+            "expireat" => Expire::parse_frames(&mut parse, "expireat").map(Command::Expire),
+            "pexpireat" => Expire::parse_frames(&mut parse, "pexpireat").map(Command::Expire),
+            "ttl" => Ttl::parse_frames(&mut parse, false).map(Command::Ttl),
+            "pttl" => Ttl::parse_frames(&mut parse, true).map(Command::Ttl),
+            "persist" => Persist::parse_frames(&mut parse).map(Command::Persist),
+            "type" => Type::parse_frames(&mut parse).map(Command::Type),
+            "lpush" => Push::parse_frames(&mut parse, End::Left, false).map(Command::Push),
+            "rpush" => Push::parse_frames(&mut parse, End::Right, false).map(Command::Push),
+            "lpushx" => Push::parse_frames(&mut parse, End::Left, true).map(Command::Push),
+            "rpushx" => Push::parse_frames(&mut parse, End::Right, true).map(Command::Push),
+            "lpop" => Pop::parse_frames(&mut parse, End::Left).map(Command::Pop),
+            "rpop" => Pop::parse_frames(&mut parse, End::Right).map(Command::Pop),
+            "lrange" => LRange::parse_frames(&mut parse).map(Command::LRange),
+            "llen" => LLen::parse_frames(&mut parse).map(Command::LLen),
+            "lindex" => LIndex::parse_frames(&mut parse).map(Command::LIndex),
+            "lset" => LSet::parse_frames(&mut parse).map(Command::LSet),
+            "ltrim" => LTrim::parse_frames(&mut parse).map(Command::LTrim),
+            "linsert" => LInsert::parse_frames(&mut parse).map(Command::LInsert),
+            "lrem" => LRem::parse_frames(&mut parse).map(Command::LRem),
+            "lmove" => LMove::parse_frames(&mut parse, false).map(Command::LMove),
+            "rpoplpush" => LMove::parse_frames(&mut parse, true).map(Command::LMove),
+            "blpop" => BPop::parse_frames(&mut parse, End::Left).map(Command::BPop),
+            "brpop" => BPop::parse_frames(&mut parse, End::Right).map(Command::BPop),
+            "blmove" => BLMove::parse_frames(&mut parse, false).map(Command::BLMove),
+            "brpoplpush" => BLMove::parse_frames(&mut parse, true).map(Command::BLMove),
+            "hset" => HSet::parse_frames(&mut parse, false).map(Command::HSet),
+            "hmset" => HSet::parse_frames(&mut parse, true).map(Command::HSet),
+            "hsetnx" => HSetNx::parse_frames(&mut parse).map(Command::HSetNx),
+            "hget" => HGet::parse_frames(&mut parse).map(Command::HGet),
+            "hmget" => HMGet::parse_frames(&mut parse).map(Command::HMGet),
+            "hgetall" => HGetAll::parse_frames(&mut parse).map(Command::HGetAll),
+            "hkeys" => HKeys::parse_frames(&mut parse, false).map(Command::HKeys),
+            "hvals" => HKeys::parse_frames(&mut parse, true).map(Command::HKeys),
+            "hlen" => HLen::parse_frames(&mut parse).map(Command::HLen),
+            "hdel" => HDel::parse_frames(&mut parse).map(Command::HDel),
+            "hexists" => HExists::parse_frames(&mut parse).map(Command::HExists),
+            "hincrby" => HIncrBy::parse_frames(&mut parse).map(Command::HIncrBy),
+            "hincrbyfloat" => HIncrByFloat::parse_frames(&mut parse).map(Command::HIncrByFloat),
+            "hrandfield" => HRandField::parse_frames(&mut parse).map(Command::HRandField),
+            "sadd" => SAdd::parse_frames(&mut parse).map(Command::SAdd),
+            "srem" => SRem::parse_frames(&mut parse).map(Command::SRem),
+            "smembers" => SMembers::parse_frames(&mut parse).map(Command::SMembers),
+            "sismember" => SIsMember::parse_frames(&mut parse, false).map(Command::SIsMember),
+            "smismember" => SIsMember::parse_frames(&mut parse, true).map(Command::SIsMember),
+            "scard" => SCard::parse_frames(&mut parse).map(Command::SCard),
+            "spop" => SPop::parse_frames(&mut parse).map(Command::SPop),
+            "srandmember" => SRandMember::parse_frames(&mut parse).map(Command::SRandMember),
+            "sinter" => {
+                SetCombine::parse_frames(&mut parse, SetOp::Inter, false).map(Command::SetCombine)
+            }
+            "sunion" => {
+                SetCombine::parse_frames(&mut parse, SetOp::Union, false).map(Command::SetCombine)
+            }
+            "sdiff" => {
+                SetCombine::parse_frames(&mut parse, SetOp::Diff, false).map(Command::SetCombine)
+            }
+            "sinterstore" => {
+                SetCombine::parse_frames(&mut parse, SetOp::Inter, true).map(Command::SetCombine)
+            }
+            "sunionstore" => {
+                SetCombine::parse_frames(&mut parse, SetOp::Union, true).map(Command::SetCombine)
+            }
+            "sdiffstore" => {
+                SetCombine::parse_frames(&mut parse, SetOp::Diff, true).map(Command::SetCombine)
+            }
+            "sintercard" => SInterCard::parse_frames(&mut parse).map(Command::SInterCard),
+            "zadd" => ZAdd::parse_frames(&mut parse).map(Command::ZAdd),
+            "zincrby" => ZAdd::parse_incrby(&mut parse).map(Command::ZAdd),
+            "zrem" => ZRem::parse_frames(&mut parse).map(Command::ZRem),
+            "zscore" => ZScore::parse_frames(&mut parse).map(Command::ZScore),
+            "zrank" => ZRank::parse_frames(&mut parse, false).map(Command::ZRank),
+            "zrevrank" => ZRank::parse_frames(&mut parse, true).map(Command::ZRank),
+            "zcard" => ZCard::parse_frames(&mut parse).map(Command::ZCard),
+            "zcount" => ZCount::parse_frames(&mut parse).map(Command::ZCount),
+            "zrange" => ZRange::parse_frames(&mut parse, RangeForm::Range).map(Command::ZRange),
+            "zrevrange" => {
+                ZRange::parse_frames(&mut parse, RangeForm::RevRange).map(Command::ZRange)
+            }
+            "zrangebyscore" => {
+                ZRange::parse_frames(&mut parse, RangeForm::RangeByScore).map(Command::ZRange)
+            }
+            "zrevrangebyscore" => {
+                ZRange::parse_frames(&mut parse, RangeForm::RevRangeByScore).map(Command::ZRange)
+            }
+            "subscribe" => Subscribe::parse_frames(&mut parse, false).map(Command::Subscribe),
+            "psubscribe" => Subscribe::parse_frames(&mut parse, true).map(Command::Subscribe),
+            "unsubscribe" => Unsubscribe::parse_frames(&mut parse, false).map(Command::Unsubscribe),
+            "punsubscribe" => Unsubscribe::parse_frames(&mut parse, true).map(Command::Unsubscribe),
+            "publish" => Publish::parse_frames(&mut parse).map(Command::Publish),
+            "pubsub" => PubSub::parse_frames(&mut parse).map(Command::PubSub),
+            "multi" => Multi::parse_frames(&mut parse).map(Command::Multi),
+            "exec" => Exec::parse_frames(&mut parse).map(Command::Exec),
+            "discard" => Discard::parse_frames(&mut parse).map(Command::Discard),
+            "watch" => Watch::parse_frames(&mut parse).map(Command::Watch),
+            "unwatch" => Unwatch::parse_frames(&mut parse).map(Command::Unwatch),
+            "save" => Save::parse_frames(&mut parse).map(Command::Save),
+            "bgsave" => BgSave::parse_frames(&mut parse).map(Command::BgSave),
+            "lastsave" => LastSave::parse_frames(&mut parse).map(Command::LastSave),
+            "bgrewriteaof" => BgRewriteAof::parse_frames(&mut parse).map(Command::BgRewriteAof),
+            "config" => Config::parse_frames(&mut parse).map(Command::Config),
+            "shutdown" => Shutdown::parse_frames(&mut parse).map(Command::Shutdown),
+            "replicaof" | "slaveof" => ReplicaOf::parse_frames(&mut parse).map(Command::ReplicaOf),
+            "psync" => Psync::parse_frames(&mut parse).map(Command::Psync),
+            "replconf" => ReplConf::parse_frames(&mut parse).map(Command::ReplConf),
+            "role" => Role::parse_frames(&mut parse).map(Command::Role),
+            "info" => Info::parse_frames(&mut parse).map(Command::Info),
+            "wait" => Wait::parse_frames(&mut parse).map(Command::Wait),
+            _ => {
+                // The command is not recognized and an Unknown command is
+                // returned. The remaining arguments are irrelevant, so `finish`
+                // is not called.
+                return Ok(Command::Unknown(Unknown::new(command_name)));
+            }
+        };
+
+        // Running out of arguments, or having some left over once the command
+        // is fully parsed, both mean the client sent the wrong number of them.
+        let wrong_arity = || {
+            format!(
+                "ERR wrong number of arguments for '{}' command",
+                command_name
+            )
+        };
+        match command {
+            Ok(command) => match parse.finish() {
+                Ok(()) => Ok(command),
+                Err(_) => Err(wrong_arity().into()),
+            },
+            Err(ParseError::EndOfStream) => Err(wrong_arity().into()),
+            Err(ParseError::Other(e)) => Err(format!("ERR {}", e).into()),
+        }
+    }
+
+    /// Apply the command to the specified `Db` instance, returning the reply.
+    ///
+    /// Blocking commands never block here and behave like their non-blocking
+    /// counterparts. The connection handler calls their `block` method
+    /// instead. Subscriptions likewise take over the connection, so the
+    /// handler passes them to a `Subscriber`, and transaction commands to the
+    /// connection's `Transaction`. `HELLO` changes the protocol of the
+    /// connection, `AUTH` and `ACL` depend on the user it is authenticated
+    /// as, replication commands deal with the connection or wait for
+    /// other clients, `CONFIG` may wait for the commands being applied, and
+    /// `SHUTDOWN` closes the connection, so the handler applies them too.
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        use Command::*;
+
+        match self {
+            Ping(cmd) => cmd.apply(),
+            Echo(cmd) => cmd.apply(),
+            Quit(cmd) => cmd.apply(),
+            Hello(_) => Frame::Error("ERR HELLO is not allowed in this context".to_string()),
+            Auth(_) => Frame::Error("ERR AUTH is not allowed in this context".to_string()),
+            Acl(_) => Frame::Error("ERR ACL is not allowed in this context".to_string()),
+            Get(cmd) => cmd.apply(db),
+            Set(cmd) => cmd.apply(db),
+            IncrBy(cmd) => cmd.apply(db),
+            IncrByFloat(cmd) => cmd.apply(db),
+            Append(cmd) => cmd.apply(db),
+            Strlen(cmd) => cmd.apply(db),
+            GetRange(cmd) => cmd.apply(db),
+            SetRange(cmd) => cmd.apply(db),
+            GetDel(cmd) => cmd.apply(db),
+            GetEx(cmd) => cmd.apply(db),
+            MGet(cmd) => cmd.apply(db),
+            MSet(cmd) => cmd.apply(db),
+            Del(cmd) => cmd.apply(db),
+            Exists(cmd) => cmd.apply(db),
+            Expire(cmd) => cmd.apply(db),
+            Ttl(cmd) => cmd.apply(db),
+            Persist(cmd) => cmd.apply(db),
+            Type(cmd) => cmd.apply(db),
+            Push(cmd) => cmd.apply(db),
+            Pop(cmd) => cmd.apply(db),
+            LRange(cmd) => cmd.apply(db),
+            LLen(cmd) => cmd.apply(db),
+            LIndex(cmd) => cmd.apply(db),
+            LSet(cmd) => cmd.apply(db),
+            LTrim(cmd) => cmd.apply(db),
+            LInsert(cmd) => cmd.apply(db),
+            LRem(cmd) => cmd.apply(db),
+            LMove(cmd) => cmd.apply(db),
+            BPop(cmd) => cmd.apply(db),
+            BLMove(cmd) => cmd.apply(db),
+            HSet(cmd) => cmd.apply(db),
+            HSetNx(cmd) => cmd.apply(db),
+            HGet(cmd) => cmd.apply(db),
+            HMGet(cmd) => cmd.apply(db),
+            HGetAll(cmd) => cmd.apply(db),
+            HKeys(cmd) => cmd.apply(db),
+            HLen(cmd) => cmd.apply(db),
+            HDel(cmd) => cmd.apply(db),
+            HExists(cmd) => cmd.apply(db),
+            HIncrBy(cmd) => cmd.apply(db),
+            HIncrByFloat(cmd) => cmd.apply(db),
+            HRandField(cmd) => cmd.apply(db),
+            SAdd(cmd) => cmd.apply(db),
+            SRem(cmd) => cmd.apply(db),
+            SMembers(cmd) => cmd.apply(db),
+            SIsMember(cmd) => cmd.apply(db),
+            SCard(cmd) => cmd.apply(db),
+            SPop(cmd) => cmd.apply(db),
+            SRandMember(cmd) => cmd.apply(db),
+            SetCombine(cmd) => cmd.apply(db),
+            SInterCard(cmd) => cmd.apply(db),
+            ZAdd(cmd) => cmd.apply(db),
+            ZRem(cmd) => cmd.apply(db),
+            ZScore(cmd) => cmd.apply(db),
+            ZRank(cmd) => cmd.apply(db),
+            ZCard(cmd) => cmd.apply(db),
+            ZCount(cmd) => cmd.apply(db),
+            ZRange(cmd) => cmd.apply(db),
+            Subscribe(_) | Unsubscribe(_) => {
+                Frame::Error("ERR subscriptions are not allowed in this context".to_string())
+            }
+            Publish(cmd) => cmd.apply(db),
+            PubSub(cmd) => cmd.apply(db),
+            Multi(_) | Exec(_) | Discard(_) | Watch(_) => {
+                Frame::Error("ERR transaction commands are not allowed in this context".to_string())
+            }
+            Unwatch(cmd) => cmd.apply(),
+            Save(cmd) => cmd.apply(db),
+            BgSave(cmd) => cmd.apply(db),
+            LastSave(cmd) => cmd.apply(db),
+            BgRewriteAof(cmd) => cmd.apply(db),
+            Config(_) => Frame::Error("ERR CONFIG is not allowed in this context".to_string()),
+            Shutdown(_) => Frame::Error("ERR SHUTDOWN is not allowed in this context".to_string()),
+            ReplicaOf(_) | Psync(_) | ReplConf(_) | Wait(_) => {
+                Frame::Error("ERR replication commands are not allowed in this context".to_string())
+            }
+            Role(cmd) => cmd.apply(db),
+            Info(cmd) => cmd.apply(db),
+            Unknown(cmd) => cmd.apply(),
+        }
+    }
+
+    /// Apply the command like `apply`, propagating it to the append-only file
+    /// and replicas if it is a write. `request` is the frame the command was
+    /// parsed from.
+    pub(crate) fn apply_logged(self, db: &Db, request: Frame) -> Frame {
+        let propagation = self.propagation();
+        if propagation == Propagation::None || !db.propagating() {
+            return self.apply(db);
+        }
+
+        db.logged(|| {
+            let reply = self.apply(db);
+            let frames = propagation.frames(request, &reply, db);
+            (reply, frames)
+        })
+    }
+
+    /// Returns `true` if the command may modify the keyspace.
+    pub(crate) fn is_write(&self) -> bool {
+        self.propagation() != Propagation::None
+    }
+
+    /// Returns how the command is logged to the append-only file.
+    pub(crate) fn propagation(&self) -> Propagation {
+        use Command::*;
+
+        match self {
+            Set(_) | GetEx(_) | Expire(_) => Propagation::Deadline,
+            IncrBy(_) | IncrByFloat(_) | Append(_) | SetRange(_) | GetDel(_) | MSet(_) | Del(_)
+            | Persist(_) | Push(_) | Pop(_) | LSet(_) | LTrim(_) | LInsert(_) | LRem(_)
+            | LMove(_) | HSet(_) | HSetNx(_) | HDel(_) | HIncrBy(_) | HIncrByFloat(_) | SAdd(_)
+            | SRem(_) | ZAdd(_) | ZRem(_) => Propagation::Verbatim,
+            SetCombine(cmd) if cmd.stores() => Propagation::Verbatim,
+            SPop(_) => Propagation::PoppedMembers,
+            BPop(_) | BLMove(_) => Propagation::Unblocked,
+            _ => Propagation::None,
+        }
+    }
+}
+
+/// How a command is propagated to the append-only file and replicas.
+///
+/// Most writes are logged as they were sent. Those whose effect depends on
+/// when they run, or on chance, are logged in a form replaying to the same
+/// keyspace instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Propagation {
+    /// The command never modifies the keyspace, and is not logged.
+    None,
+
+    /// The request is logged as is.
+    Verbatim,
+
+    /// The request is logged, followed by a `PEXPIREAT` pinning the deadline
+    /// of the key, since a relative expiry would start over on replay.
+    Deadline,
+
+    /// `SPOP` is logged as the `SREM` of the members it happened to pick.
+    PoppedMembers,
+
+    /// A blocking pop is logged as its non-blocking form, once it got
+    /// something.
+    Unblocked,
+}
+
+impl Propagation {
+    /// Returns the requests to log for a command sent as `request`, which
+    /// was already applied and got `reply`. Commands that failed or did not
+    /// end up modifying anything log nothing.
+    pub(crate) fn frames(self, request: Frame, reply: &Frame, db: &Db) -> Vec<Frame> {
+        let Frame::Array(mut args) = request else {
+            return vec![];
+        };
+        if matches!(reply, Frame::Error(_)) || args.len() < 2 {
+            return vec![];
+        }
+
+        match self {
+            Propagation::None => vec![],
+            Propagation::Verbatim => vec![Frame::Array(args)],
+            Propagation::Deadline => {
+                let deadline = arg(&args[1]).and_then(|key| db.deadline(&key));
+                let key = args[1].clone();
+                let mut frames = vec![Frame::Array(args)];
+                if let Some(when) = deadline {
+                    frames.push(Frame::Array(vec![
+                        Frame::Bulk(Bytes::from_static(b"PEXPIREAT")),
+                        key,
+                        Frame::Bulk(Bytes::from(when.to_string())),
+                    ]));
+                }
+                frames
+            }
+            Propagation::PoppedMembers => {
+                let members = match reply {
+                    Frame::Bulk(member) => vec![Frame::Bulk(member.clone())],
+                    Frame::Set(members) | Frame::Array(members) => members.clone(),
+                    _ => vec![],
+                };
+                if members.is_empty() {
+                    return vec![];
+                }
+
+                let mut srem = vec![
+                    Frame::Bulk(Bytes::from_static(b"SREM")),
+                    args.swap_remove(1),
+                ];
+                srem.extend(members);
+                vec![Frame::Array(srem)]
+            }
+            Propagation::Unblocked => {
+                // Strip the `B` off the name, and the timeout, which comes
+                // last.
+                let Some(name) = arg(&args[0]) else {
+                    return vec![];
+                };
+                let name = Frame::Bulk(name.slice(1..));
+                match reply {
+                    // `BLPOP` and `BRPOP` reply with the key they popped from.
+                    Frame::Array(popped) if !popped.is_empty() => {
+                        vec![Frame::Array(vec![name, popped[0].clone()])]
+                    }
+                    Frame::Bulk(_) => {
+                        args[0] = name;
+                        args.pop();
+                        vec![Frame::Array(args)]
+                    }
+                    _ => vec![],
+                }
+            }
+        }
+    }
+}
+
+/// Returns the bytes of a request argument.
+fn arg(frame: &Frame) -> Option<Bytes> {
+    match frame {
+        Frame::Bulk(bytes) => Some(bytes.clone()),
+        Frame::Simple(string) => Some(Bytes::copy_from_slice(string.as_bytes())),
+        _ => None,
+    }
+}
+
+/// Represents an "unknown" command. This is not a real `Redis` command.
+#[derive(Debug)]
+pub(crate) struct Unknown {
+    command_name: String,
+}
+
+impl Unknown {
+    /// Create a new `Unknown` command which responds to unknown commands
+    /// issued by clients
+    pub(crate) fn new(key: impl ToString) -> Unknown {
+        Unknown {
+            command_name: key.to_string(),
+        }
+    }
+
+    /// Responds to the client, indicating the command is not recognized.
+    ///
+    /// This usually means the command is not yet implemented by this server.
+    pub(crate) fn apply(self) -> Frame {
+        Frame::Error(format!("ERR unknown command '{}'", self.command_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn request(args: &[&str]) -> Frame {
+        Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn command_names_are_case_insensitive() {
+        let cmd = Command::from_frame(request(&["GeT", "foo"])).unwrap();
+        assert!(matches!(cmd, Command::Get(_)));
+    }
+
+    #[test]
+    fn arity_errors_name_the_command() {
+        let err = Command::from_frame(request(&["get"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+
+        let err = Command::from_frame(request(&["get", "a", "b"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_not_parse_errors() {
+        let cmd = Command::from_frame(request(&["frobnicate", "x"])).unwrap();
+        assert!(
+            matches!(cmd.apply(&Db::new()), Frame::Error(msg) if msg.starts_with("ERR unknown command"))
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use crate::acl::{self, CATEGORIES};
+use crate::db::Db;
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+
+/// Authenticates the connection as a user.
+#[derive(Debug)]
+pub(crate) struct Auth {
+    /// `None` stands for the `default` user.
+    username: Option<String>,
+    password: Bytes,
+}
+
+impl Auth {
+    /// # Format
+    ///
+    /// ```text
+    /// AUTH [username] password
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Auth, ParseError> {
+        let first = parse.next_bytes()?;
+        match parse.next_bytes() {
+            Ok(password) => Ok(Auth {
+                username: Some(String::from_utf8_lossy(&first).into_owned()),
+                password,
+            }),
+            Err(ParseError::EndOfStream) => Ok(Auth {
+                username: None,
+                password: first,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Apply the command, switching `user` to the user authenticated as if
+    /// the password is right.
+    pub(crate) fn apply(self, db: &Db, user: &mut Option<String>) -> Frame {
+        if self.username.is_none() && !db.default_user_has_password() {
+            return Frame::Error(
+                "ERR AUTH <password> called without any password configured for the default \
+                 user. Are you sure your configuration is correct?"
+                    .to_string(),
+            );
+        }
+
+        match login(db, self.username.as_deref(), &self.password) {
+            Some(name) => {
+                *user = Some(name);
+                Frame::Simple("OK".to_string())
+            }
+            None => wrong_pass(),
+        }
+    }
+}
+
+/// Returns the name of the user `username`, or `default`, if `password` lets
+/// a client in as it.
+pub(crate) fn login(db: &Db, username: Option<&str>, password: &[u8]) -> Option<String> {
+    let username = username.unwrap_or("default");
+    db.authenticate(username, password)
+        .then(|| username.to_string())
+}
+
+pub(crate) fn wrong_pass() -> Frame {
+    Frame::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string())
+}
+
+/// Manages users and their permissions.
+#[derive(Debug)]
+pub(crate) enum Acl {
+    SetUser(String, Vec<String>),
+    GetUser(String),
+    DelUser(Vec<String>),
+    List,
+    Users,
+    WhoAmI,
+    Cat(Option<String>),
+}
+
+impl Acl {
+    /// # Format
+    ///
+    /// ```text
+    /// ACL SETUSER username [rule [rule ...]]
+    /// ACL GETUSER username
+    /// ACL DELUSER username [username ...]
+    /// ACL LIST
+    /// ACL USERS
+    /// ACL WHOAMI
+    /// ACL CAT [category]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Acl, ParseError> {
+        let subcommand = parse.next_string()?;
+        match &subcommand.to_lowercase()[..] {
+            "setuser" => {
+                let name = parse.next_string()?;
+                Ok(Acl::SetUser(name, remaining_strings(parse)?))
+            }
+            "getuser" => Ok(Acl::GetUser(parse.next_string()?)),
+            "deluser" => {
+                let mut names = vec![parse.next_string()?];
+                names.extend(remaining_strings(parse)?);
+                Ok(Acl::DelUser(names))
+            }
+            "list" => Ok(Acl::List),
+            "users" => Ok(Acl::Users),
+            "whoami" => Ok(Acl::WhoAmI),
+            "cat" => match parse.next_string() {
+                Ok(category) => Ok(Acl::Cat(Some(category))),
+                Err(ParseError::EndOfStream) => Ok(Acl::Cat(None)),
+                Err(e) => Err(e),
+            },
+            _ => Err(format!("unknown subcommand '{}'. Try ACL HELP.", subcommand).into()),
+        }
+    }
+
+    /// Apply the command for a client authenticated as `user`.
+    pub(crate) fn apply(self, db: &Db, user: &str) -> Frame {
+        let bulk = |s: &str| Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()));
+        let bulks = |items: &mut dyn Iterator<Item = &str>| Frame::Array(items.map(bulk).collect());
+
+        match self {
+            Acl::SetUser(name, rules) => match db.set_user(&name, &rules) {
+                Ok(()) => Frame::Simple("OK".to_string()),
+                Err(e) => Frame::Error(format!("ERR {}", e)),
+            },
+            Acl::GetUser(name) => match db.user(&name) {
+                Some(user) => Frame::Map(vec![
+                    (bulk("flags"), bulks(&mut user.flags().into_iter())),
+                    (
+                        bulk("passwords"),
+                        bulks(&mut user.password_hashes().iter().map(String::as_str)),
+                    ),
+                    (bulk("commands"), bulk(&user.command_rules())),
+                    (bulk("keys"), bulk(&user.key_rules())),
+                    (bulk("channels"), bulk(&user.channel_rules())),
+                    (bulk("selectors"), Frame::Array(vec![])),
+                ]),
+                None => Frame::Null,
+            },
+            Acl::DelUser(names) => match db.delete_users(&names) {
+                Ok(deleted) => Frame::Integer(deleted as i64),
+                Err(e) => Frame::Error(format!("ERR {}", e)),
+            },
+            Acl::List => Frame::Array(
+                db.users()
+                    .iter()
+                    .map(|user| bulk(&user.describe()))
+                    .collect(),
+            ),
+            Acl::Users => Frame::Array(db.users().iter().map(|user| bulk(user.name())).collect()),
+            Acl::WhoAmI => bulk(user),
+            Acl::Cat(None) => bulks(&mut CATEGORIES.iter().copied()),
+            Acl::Cat(Some(category)) => match acl::category_commands(&category) {
+                Some(commands) => bulks(&mut commands.into_iter()),
+                None => Frame::Error(format!("ERR Unknown category '{}'", category)),
+            },
+        }
+    }
+}
+
+fn remaining_strings(parse: &mut Parse) -> Result<Vec<String>, ParseError> {
+    parse
+        .remaining_bytes()?
+        .into_iter()
+        .map(|arg| {
+            String::from_utf8(arg.to_vec()).map_err(|_| "protocol error; invalid string".into())
+        })
+        .collect()
+}
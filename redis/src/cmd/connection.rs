@@ -0,0 +1,214 @@
+use crate::cmd::{login, wrong_pass};
+use crate::connection::Connection;
+use crate::db::Db;
+use crate::frame::{Frame, Protocol};
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+
+/// Returns PONG if no argument is provided, otherwise
+/// return a copy of the argument as a bulk.
+///
+/// This command is often used to test if a connection
+/// is still alive, or to measure latency.
+#[derive(Debug, Default)]
+pub(crate) struct Ping {
+    /// optional message to be returned
+    msg: Option<Bytes>,
+}
+
+impl Ping {
+    /// Parse a `Ping` instance from a received frame.
+    ///
+    /// The `PING` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// Expects an array frame containing `PING` and an optional message.
+    ///
+    /// ```text
+    /// PING [message]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Ping, ParseError> {
+        match parse.next_bytes() {
+            Ok(msg) => Ok(Ping { msg: Some(msg) }),
+            Err(ParseError::EndOfStream) => Ok(Ping::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Apply the `Ping` command and return the message.
+    pub(crate) fn apply(self) -> Frame {
+        match self.msg {
+            None => Frame::Simple("PONG".to_string()),
+            Some(msg) => Frame::Bulk(msg),
+        }
+    }
+
+    /// Apply the `Ping` command on a connection in subscriber mode, where the
+    /// reply is a `pong` message carrying the argument, or an empty string.
+    pub(crate) fn apply_subscribed(self) -> Frame {
+        let mut response = Frame::array();
+        response.push_bulk(Bytes::from_static(b"pong"));
+        response.push_bulk(self.msg.unwrap_or_default());
+        response
+    }
+}
+
+/// Returns the given message.
+#[derive(Debug)]
+pub(crate) struct Echo {
+    msg: Bytes,
+}
+
+impl Echo {
+    /// Parse an `Echo` instance from a received frame.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// ECHO message
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Echo, ParseError> {
+        let msg = parse.next_bytes()?;
+        Ok(Echo { msg })
+    }
+
+    pub(crate) fn apply(self) -> Frame {
+        Frame::Bulk(self.msg)
+    }
+}
+
+/// Asks the server to close the connection once the OK reply is sent.
+#[derive(Debug)]
+pub(crate) struct Quit;
+
+impl Quit {
+    /// # Format
+    ///
+    /// ```text
+    /// QUIT
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<Quit, ParseError> {
+        Ok(Quit)
+    }
+
+    /// Returns the reply. Closing the connection is up to the caller.
+    pub(crate) fn apply(self) -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+}
+
+/// Switches the protocol spoken on the connection, and returns information
+/// about the server.
+#[derive(Debug, Default)]
+pub(crate) struct Hello {
+    /// Protocol version to switch to. The current one is kept if absent.
+    protover: Option<i64>,
+
+    /// Username and password to authenticate with.
+    auth: Option<(Bytes, Bytes)>,
+}
+
+impl Hello {
+    /// Parse a `Hello` instance from a received frame.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Hello, ParseError> {
+        let protover = match parse.next_int() {
+            Ok(protover) => protover,
+            Err(ParseError::EndOfStream) => return Ok(Hello::default()),
+            Err(_) => {
+                return Err("Protocol version is not an integer or out of range".into());
+            }
+        };
+
+        let mut hello = Hello {
+            protover: Some(protover),
+            auth: None,
+        };
+        loop {
+            let option = match parse.next_string() {
+                Ok(option) => option,
+                Err(ParseError::EndOfStream) => return Ok(hello),
+                Err(e) => return Err(e),
+            };
+
+            match &option.to_uppercase()[..] {
+                "AUTH" => hello.auth = Some((parse.next_bytes()?, parse.next_bytes()?)),
+                // Connections have no name to set, but the name is still
+                // checked so clients find out about mistakes.
+                "SETNAME" => {
+                    let name = parse.next_bytes()?;
+                    if name.iter().any(|&b| b <= b' ' || b > b'~') {
+                        return Err("Client names cannot contain spaces, newlines or special \
+                                    characters."
+                            .into());
+                    }
+                }
+                _ => return Err(format!("Syntax error in HELLO option '{}'", option).into()),
+            }
+        }
+    }
+
+    /// Apply the `Hello` command to `connection`, known to clients as `id`
+    /// and authenticated as `user`, which `AUTH` switches.
+    ///
+    /// The reply is sent with the protocol the client asked for, so the
+    /// protocol is switched before the caller writes it.
+    pub(crate) fn apply(
+        self,
+        db: &Db,
+        connection: &mut Connection,
+        id: u64,
+        user: &mut Option<String>,
+    ) -> Frame {
+        let protocol = match self.protover {
+            None => connection.protocol(),
+            Some(2) => Protocol::Resp2,
+            Some(3) => Protocol::Resp3,
+            Some(_) => return Frame::Error("NOPROTO unsupported protocol version".to_string()),
+        };
+
+        match &self.auth {
+            Some((username, password)) => {
+                let username = String::from_utf8_lossy(username);
+                match login(db, Some(&username), password) {
+                    Some(name) => *user = Some(name),
+                    None => return wrong_pass(),
+                }
+            }
+            None if user.is_none() => {
+                return Frame::Error(
+                    "NOAUTH HELLO must be called with the client already authenticated, \
+                     otherwise the HELLO <proto> AUTH <user> <pass> option can be used to \
+                     authenticate the client and select the RESP protocol version at the same \
+                     time"
+                        .to_string(),
+                );
+            }
+            None => {}
+        }
+
+        connection.set_protocol(protocol);
+
+        let proto = match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let field = |name: &'static str| Frame::Bulk(Bytes::from_static(name.as_bytes()));
+        Frame::Map(vec![
+            (field("server"), field("redis")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), Frame::Integer(proto)),
+            (field("id"), Frame::Integer(id as i64)),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), Frame::Array(vec![])),
+        ])
+    }
+}
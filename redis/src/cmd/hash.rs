@@ -0,0 +1,561 @@
+use crate::cmd::keys::parse_keys;
+use crate::cmd::string::parse_float;
+use crate::db::{Db, Entry, Slot, Value, WrongType};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError, parse_int};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Returns the hash stored in `slot`, or `None` if the key does not exist.
+fn hash(slot: &Option<Entry>) -> Result<Option<&HashMap<Bytes, Bytes>>, WrongType> {
+    slot.as_ref().map(|entry| entry.value.as_hash()).transpose()
+}
+
+/// Returns the hash stored in `slot`, creating an empty one if the key does
+/// not exist.
+fn hash_or_insert(slot: &mut Slot) -> Result<&mut HashMap<Bytes, Bytes>, WrongType> {
+    hash(slot)?;
+    slot.get_or_insert_with(|| Entry::new(Value::Hash(HashMap::new())))
+        .value
+        .as_hash_mut()
+}
+
+/// Sets the specified fields to their respective values in the hash stored at
+/// `key`, creating the hash if needed.
+///
+/// `HSET` replies with the number of fields that were added, `HMSET` with OK.
+#[derive(Debug)]
+pub(crate) struct HSet {
+    key: Bytes,
+    pairs: Vec<(Bytes, Bytes)>,
+
+    /// Reply like the deprecated `HMSET`.
+    legacy: bool,
+}
+
+impl HSet {
+    /// # Format
+    ///
+    /// ```text
+    /// HSET key field value [field value ...]
+    /// HMSET key field value [field value ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, legacy: bool) -> Result<HSet, ParseError> {
+        let key = parse.next_bytes()?;
+        let mut pairs = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+
+        let rest = parse.remaining_bytes()?;
+        if rest.len() % 2 != 0 {
+            return Err(ParseError::EndOfStream);
+        }
+
+        let mut rest = rest.into_iter();
+        while let (Some(field), Some(value)) = (rest.next(), rest.next()) {
+            pairs.push((field, value));
+        }
+
+        Ok(HSet { key, pairs, legacy })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let hash = hash_or_insert(slot)?;
+
+            let added = self
+                .pairs
+                .into_iter()
+                .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+                .count();
+
+            Ok(if self.legacy {
+                Frame::Simple("OK".to_string())
+            } else {
+                Frame::Integer(added as i64)
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Sets `field` in the hash stored at `key` only if it does not exist yet.
+///
+/// Replies with 1 if the field was set, and 0 if it already existed.
+#[derive(Debug)]
+pub(crate) struct HSetNx {
+    key: Bytes,
+    field: Bytes,
+    value: Bytes,
+}
+
+impl HSetNx {
+    /// # Format
+    ///
+    /// ```text
+    /// HSETNX key field value
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HSetNx, ParseError> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        let value = parse.next_bytes()?;
+        Ok(HSetNx { key, field, value })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let hash = hash_or_insert(slot)?;
+            if hash.contains_key(&self.field) {
+                return Ok(Frame::Integer(0));
+            }
+
+            hash.insert(self.field, self.value);
+            Ok(Frame::Integer(1))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the value associated with `field` in the hash stored at `key`, or
+/// nil when the field or the key does not exist.
+#[derive(Debug)]
+pub(crate) struct HGet {
+    key: Bytes,
+    field: Bytes,
+}
+
+impl HGet {
+    /// # Format
+    ///
+    /// ```text
+    /// HGET key field
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HGet, ParseError> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        Ok(HGet { key, field })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            Ok(match hash(slot)?.and_then(|hash| hash.get(&self.field)) {
+                Some(value) => Frame::Bulk(value.clone()),
+                None => Frame::Null,
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the values associated with the specified fields in the hash stored
+/// at `key`, with nil for every field that does not exist.
+#[derive(Debug)]
+pub(crate) struct HMGet {
+    key: Bytes,
+    fields: Vec<Bytes>,
+}
+
+impl HMGet {
+    /// # Format
+    ///
+    /// ```text
+    /// HMGET key field [field ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HMGet, ParseError> {
+        let key = parse.next_bytes()?;
+        let fields = parse_keys(parse)?;
+        Ok(HMGet { key, fields })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let hash = hash(slot)?;
+            let values = self
+                .fields
+                .iter()
+                .map(|field| match hash.and_then(|hash| hash.get(field)) {
+                    Some(value) => Frame::Bulk(value.clone()),
+                    None => Frame::Null,
+                })
+                .collect();
+            Ok(Frame::Array(values))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns all fields and values of the hash stored at `key`, as a flat array
+/// of field and value pairs.
+#[derive(Debug)]
+pub(crate) struct HGetAll {
+    key: Bytes,
+}
+
+impl HGetAll {
+    /// # Format
+    ///
+    /// ```text
+    /// HGETALL key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HGetAll, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(HGetAll { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let pairs = hash(slot)?.into_iter().flatten();
+            Ok(Frame::Map(
+                pairs
+                    .map(|(field, value)| (Frame::Bulk(field.clone()), Frame::Bulk(value.clone())))
+                    .collect(),
+            ))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns either all the field names (`HKEYS`) or all the values (`HVALS`) of
+/// the hash stored at `key`.
+#[derive(Debug)]
+pub(crate) struct HKeys {
+    key: Bytes,
+
+    /// Reply with the values rather than the field names.
+    values: bool,
+}
+
+impl HKeys {
+    /// # Format
+    ///
+    /// ```text
+    /// HKEYS key
+    /// HVALS key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, values: bool) -> Result<HKeys, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(HKeys { key, values })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let mut response = Frame::array();
+            for (field, value) in hash(slot)?.into_iter().flatten() {
+                response.push_bulk(if self.values { value } else { field }.clone());
+            }
+            Ok(response)
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the number of fields contained in the hash stored at `key`.
+#[derive(Debug)]
+pub(crate) struct HLen {
+    key: Bytes,
+}
+
+impl HLen {
+    /// # Format
+    ///
+    /// ```text
+    /// HLEN key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HLen, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(HLen { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let len = hash(slot)?.map_or(0, HashMap::len);
+            Ok(Frame::Integer(len as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Removes the specified fields from the hash stored at `key`. The key is
+/// deleted along with its last field.
+///
+/// Replies with the number of fields that were removed.
+#[derive(Debug)]
+pub(crate) struct HDel {
+    key: Bytes,
+    fields: Vec<Bytes>,
+}
+
+impl HDel {
+    /// # Format
+    ///
+    /// ```text
+    /// HDEL key field [field ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HDel, ParseError> {
+        let key = parse.next_bytes()?;
+        let fields = parse_keys(parse)?;
+        Ok(HDel { key, fields })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            hash(slot)?;
+            let Some(hash) = slot
+                .as_mut()
+                .map(|entry| entry.value.as_hash_mut())
+                .transpose()?
+            else {
+                return Ok(Frame::Integer(0));
+            };
+
+            let removed = self
+                .fields
+                .iter()
+                .filter(|field| hash.remove(*field).is_some())
+                .count();
+            Ok(Frame::Integer(removed as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns whether `field` exists in the hash stored at `key`.
+#[derive(Debug)]
+pub(crate) struct HExists {
+    key: Bytes,
+    field: Bytes,
+}
+
+impl HExists {
+    /// # Format
+    ///
+    /// ```text
+    /// HEXISTS key field
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HExists, ParseError> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        Ok(HExists { key, field })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let found = hash(slot)?.is_some_and(|hash| hash.contains_key(&self.field));
+            Ok(Frame::Integer(found as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Increments the integer stored at `field` in the hash stored at `key` by
+/// `increment`. Missing keys and fields start from 0.
+///
+/// Replies with the value after the increment.
+#[derive(Debug)]
+pub(crate) struct HIncrBy {
+    key: Bytes,
+    field: Bytes,
+    increment: i64,
+}
+
+impl HIncrBy {
+    /// # Format
+    ///
+    /// ```text
+    /// HINCRBY key field increment
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HIncrBy, ParseError> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        let increment = parse.next_int()?;
+        Ok(HIncrBy {
+            key,
+            field,
+            increment,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let hash = hash_or_insert(slot)?;
+
+            let current = match hash.get(&self.field) {
+                None => 0,
+                Some(value) => match parse_int(value) {
+                    Some(n) => n,
+                    None => return Ok(Frame::Error("ERR hash value is not an integer".into())),
+                },
+            };
+
+            let Some(updated) = current.checked_add(self.increment) else {
+                return Ok(Frame::Error(
+                    "ERR increment or decrement would overflow".into(),
+                ));
+            };
+
+            hash.insert(self.field, Bytes::from(updated.to_string()));
+            Ok(Frame::Integer(updated))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Increments the floating point number stored at `field` in the hash stored
+/// at `key` by `increment`. Missing keys and fields start from 0.
+///
+/// Replies with the value after the increment.
+#[derive(Debug)]
+pub(crate) struct HIncrByFloat {
+    key: Bytes,
+    field: Bytes,
+    increment: f64,
+}
+
+impl HIncrByFloat {
+    /// # Format
+    ///
+    /// ```text
+    /// HINCRBYFLOAT key field increment
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HIncrByFloat, ParseError> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        let increment = parse_float(&parse.next_bytes()?).ok_or("value is not a valid float")?;
+        Ok(HIncrByFloat {
+            key,
+            field,
+            increment,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let hash = hash_or_insert(slot)?;
+
+            let current = match hash.get(&self.field) {
+                None => 0.0,
+                Some(value) => match parse_float(value) {
+                    Some(n) => n,
+                    None => return Ok(Frame::Error("ERR hash value is not a float".into())),
+                },
+            };
+
+            let updated = current + self.increment;
+            if !updated.is_finite() {
+                return Ok(Frame::Error(
+                    "ERR increment would produce NaN or Infinity".into(),
+                ));
+            }
+
+            let value = Bytes::from(updated.to_string());
+            hash.insert(self.field, value.clone());
+            Ok(Frame::Bulk(value))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns random fields from the hash stored at `key`.
+///
+/// Without `count`, replies with a single field, or nil when the key does not
+/// exist. A positive `count` returns that many distinct fields, or the whole
+/// hash if it is smaller. A negative `count` returns exactly `-count` fields,
+/// which may repeat. `WITHVALUES` interleaves each field with its value.
+#[derive(Debug)]
+pub(crate) struct HRandField {
+    key: Bytes,
+    count: Option<i64>,
+    with_values: bool,
+}
+
+impl HRandField {
+    /// # Format
+    ///
+    /// ```text
+    /// HRANDFIELD key [count [WITHVALUES]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<HRandField, ParseError> {
+        let key = parse.next_bytes()?;
+
+        let count = match parse.next_int() {
+            Ok(count) => Some(count),
+            Err(ParseError::EndOfStream) => None,
+            Err(err) => return Err(err),
+        };
+
+        let with_values = match parse.next_string() {
+            Ok(option) if count.is_some() && option.eq_ignore_ascii_case("WITHVALUES") => true,
+            Ok(_) => return Err("syntax error".into()),
+            Err(ParseError::EndOfStream) => false,
+            Err(err) => return Err(err),
+        };
+
+        // Keep the reply length, two entries per field, within range.
+        if with_values && count.is_some_and(|count| count.unsigned_abs() > i64::MAX as u64 / 2) {
+            return Err("value is out of range".into());
+        }
+
+        Ok(HRandField {
+            key,
+            count,
+            with_values,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        // A negative count is not bounded by the size of the hash, so it is
+        // held to the longest array a reply may hold.
+        let per_field = if self.with_values { 2 } else { 1 };
+        if let Some(count) = self.count
+            && count < 0
+            && count.unsigned_abs() > (db.limits().max_array_len / per_field) as u64
+        {
+            return Frame::Error("ERR value is out of range".to_string());
+        }
+
+        db.with_entry(&self.key, |slot| {
+            let Some(hash) = hash(slot)? else {
+                return Ok(match self.count {
+                    None => Frame::Null,
+                    Some(_) => Frame::array(),
+                });
+            };
+
+            let pairs: Vec<(&Bytes, &Bytes)> = hash.iter().collect();
+            let picked = match self.count {
+                None => {
+                    let (field, _) = pairs[fastrand::usize(..pairs.len())];
+                    return Ok(Frame::Bulk(field.clone()));
+                }
+                Some(count) if count >= 0 => {
+                    let count = count as usize;
+                    if count >= pairs.len() {
+                        pairs
+                    } else {
+                        fastrand::choose_multiple(pairs, count)
+                    }
+                }
+                Some(count) => {
+                    let mut response = Frame::array();
+                    for _ in 0..count.unsigned_abs() {
+                        let (field, value) = pairs[fastrand::usize(..pairs.len())];
+                        response.push_bulk(field.clone());
+                        if self.with_values {
+                            response.push_bulk(value.clone());
+                        }
+                    }
+                    return Ok(response);
+                }
+            };
+
+            let mut response = Frame::array();
+            for (field, value) in picked {
+                response.push_bulk(field.clone());
+                if self.with_values {
+                    response.push_bulk(value.clone());
+                }
+            }
+            Ok(response)
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
@@ -0,0 +1,307 @@
+use crate::db::{Db, now_ms};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+
+/// Collect the remaining arguments as keys, requiring at least one.
+pub(crate) fn parse_keys(parse: &mut Parse) -> Result<Vec<Bytes>, ParseError> {
+    let mut keys = vec![parse.next_bytes()?];
+    keys.extend(parse.remaining_bytes()?);
+    Ok(keys)
+}
+
+/// Removes the specified keys. A key is ignored if it does not exist.
+///
+/// Replies with the number of keys that were removed.
+#[derive(Debug)]
+pub(crate) struct Del {
+    keys: Vec<Bytes>,
+}
+
+impl Del {
+    /// # Format
+    ///
+    /// ```text
+    /// DEL key [key ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Del, ParseError> {
+        Ok(Del {
+            keys: parse_keys(parse)?,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let removed = self
+            .keys
+            .iter()
+            .filter(|key| db.remove(key).is_some())
+            .count();
+        Frame::Integer(removed as i64)
+    }
+}
+
+/// Returns how many of the specified keys exist.
+///
+/// A key mentioned multiple times is counted multiple times.
+#[derive(Debug)]
+pub(crate) struct Exists {
+    keys: Vec<Bytes>,
+}
+
+impl Exists {
+    /// # Format
+    ///
+    /// ```text
+    /// EXISTS key [key ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Exists, ParseError> {
+        Ok(Exists {
+            keys: parse_keys(parse)?,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let found = self.keys.iter().filter(|key| db.contains(key)).count();
+        Frame::Integer(found as i64)
+    }
+}
+
+/// Set a timeout on `key`. After the timeout has expired, the key will
+/// automatically be deleted.
+///
+/// Backs `EXPIRE`, `PEXPIRE`, `EXPIREAT` and `PEXPIREAT`, which only differ in
+/// the unit of the time argument and whether it is relative to now. A deadline
+/// that is already in the past deletes the key.
+///
+/// Replies with 1 if the timeout was set, and 0 if the key does not exist or
+/// the condition option was not met.
+#[derive(Debug)]
+pub(crate) struct Expire {
+    key: Bytes,
+
+    /// The time argument converted to milliseconds.
+    millis: i64,
+
+    /// `true` if `millis` is a Unix time rather than a time to live.
+    absolute: bool,
+
+    condition: Option<ExpireCondition>,
+
+    /// Command name, used in error replies.
+    name: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExpireCondition {
+    /// Set expiry only when the key has no expiry.
+    Nx,
+    /// Set expiry only when the key has an existing expiry.
+    Xx,
+    /// Set expiry only when the new expiry is greater than current one.
+    Gt,
+    /// Set expiry only when the new expiry is less than current one.
+    Lt,
+}
+
+impl Expire {
+    /// # Format
+    ///
+    /// ```text
+    /// EXPIRE key seconds [NX | XX | GT | LT]
+    /// PEXPIRE key milliseconds [NX | XX | GT | LT]
+    /// EXPIREAT key unix-time-seconds [NX | XX | GT | LT]
+    /// PEXPIREAT key unix-time-milliseconds [NX | XX | GT | LT]
+    /// ```
+    pub(crate) fn parse_frames(
+        parse: &mut Parse,
+        name: &'static str,
+    ) -> Result<Expire, ParseError> {
+        let key = parse.next_bytes()?;
+        let amount = parse.next_int()?;
+
+        let scale = if name.starts_with('p') { 1 } else { 1000 };
+        let millis = amount
+            .checked_mul(scale)
+            .ok_or_else(|| format!("invalid expire time in '{}' command", name))?;
+
+        let mut condition = None;
+        loop {
+            let option = match parse.next_string() {
+                Ok(s) => s.to_uppercase(),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err),
+            };
+
+            let option = match &option[..] {
+                "NX" => ExpireCondition::Nx,
+                "XX" => ExpireCondition::Xx,
+                "GT" => ExpireCondition::Gt,
+                "LT" => ExpireCondition::Lt,
+                _ => return Err(format!("Unsupported option {}", option).into()),
+            };
+
+            if condition.is_some_and(|current| current != option) {
+                return Err(
+                    "NX and XX, GT or LT options at the same time are not compatible".into(),
+                );
+            }
+            condition = Some(option);
+        }
+
+        Ok(Expire {
+            key,
+            millis,
+            absolute: name.ends_with("at"),
+            condition,
+            name,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let now = now_ms() as i64;
+        let deadline = if self.absolute {
+            Some(self.millis)
+        } else {
+            now.checked_add(self.millis)
+        };
+
+        let Some(deadline) = deadline else {
+            return Frame::Error(format!(
+                "ERR invalid expire time in '{}' command",
+                self.name
+            ));
+        };
+
+        db.with_entry(&self.key, |slot| {
+            let Some(entry) = slot.as_ref() else {
+                return Frame::Integer(0);
+            };
+
+            // A key without a deadline is treated as having an infinite TTL.
+            let current = entry.expires_at.map(|when| when as i64);
+            let allowed = match self.condition {
+                None => true,
+                Some(ExpireCondition::Nx) => current.is_none(),
+                Some(ExpireCondition::Xx) => current.is_some(),
+                Some(ExpireCondition::Gt) => current.is_some_and(|when| deadline > when),
+                Some(ExpireCondition::Lt) => current.is_none_or(|when| deadline < when),
+            };
+
+            if !allowed {
+                return Frame::Integer(0);
+            }
+
+            if deadline <= now {
+                **slot = None;
+            } else if let Some(entry) = slot.as_mut() {
+                entry.expires_at = Some(deadline as u64);
+            }
+
+            Frame::Integer(1)
+        })
+    }
+}
+
+/// Returns the remaining time to live of a key that has a timeout.
+///
+/// Replies with -2 if the key does not exist, and -1 if the key exists but has
+/// no associated expire. `TTL` answers in seconds and `PTTL` in milliseconds.
+#[derive(Debug)]
+pub(crate) struct Ttl {
+    key: Bytes,
+
+    /// Reply in milliseconds rather than seconds.
+    millis: bool,
+}
+
+impl Ttl {
+    /// # Format
+    ///
+    /// ```text
+    /// TTL key
+    /// PTTL key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, millis: bool) -> Result<Ttl, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(Ttl { key, millis })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let remaining = db.with_entry(&self.key, |slot| match slot.as_ref() {
+            None => Err(-2),
+            Some(entry) => match entry.expires_at {
+                None => Err(-1),
+                Some(when) => Ok(when.saturating_sub(now_ms())),
+            },
+        });
+
+        match remaining {
+            Err(code) => Frame::Integer(code),
+            Ok(ms) if self.millis => Frame::Integer(ms as i64),
+            // Round to the nearest second, like Redis does.
+            Ok(ms) => Frame::Integer(((ms + 500) / 1000) as i64),
+        }
+    }
+}
+
+/// Remove the existing timeout on `key`.
+///
+/// Replies with 1 if the timeout was removed, and 0 if the key does not exist
+/// or does not have an associated timeout.
+#[derive(Debug)]
+pub(crate) struct Persist {
+    key: Bytes,
+}
+
+impl Persist {
+    /// # Format
+    ///
+    /// ```text
+    /// PERSIST key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Persist, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(Persist { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let removed = db.with_entry(&self.key, |slot| {
+            // A key without a deadline is left untouched.
+            if slot.as_ref().is_none_or(|entry| entry.expires_at.is_none()) {
+                return false;
+            }
+            slot.as_mut()
+                .and_then(|entry| entry.expires_at.take())
+                .is_some()
+        });
+        Frame::Integer(removed as i64)
+    }
+}
+
+/// Returns the string representation of the type of the value stored at
+/// `key`, or `none` when the key does not exist.
+#[derive(Debug)]
+pub(crate) struct Type {
+    key: Bytes,
+}
+
+impl Type {
+    /// # Format
+    ///
+    /// ```text
+    /// TYPE key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Type, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(Type { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let name = db.with_entry(&self.key, |slot| {
+            slot.as_ref()
+                .map_or("none", |entry| entry.value.type_name())
+        });
+        Frame::Simple(name.to_string())
+    }
+}
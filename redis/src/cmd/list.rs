@@ -0,0 +1,827 @@
+use crate::cmd::Propagation;
+use crate::cmd::string::parse_float;
+use crate::connection::Connection;
+use crate::db::{Blocked, BlockingPop, Db, Delivery, Entry, Slot, Value, WrongType};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Which end of a list an operation works on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    /// Parse a `LEFT` or `RIGHT` argument.
+    pub(crate) fn parse(parse: &mut Parse) -> Result<End, ParseError> {
+        match &parse.next_string()?.to_uppercase()[..] {
+            "LEFT" => Ok(End::Left),
+            "RIGHT" => Ok(End::Right),
+            _ => Err("syntax error".into()),
+        }
+    }
+
+    pub(crate) fn pop(self, list: &mut VecDeque<Bytes>) -> Option<Bytes> {
+        match self {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        }
+    }
+
+    pub(crate) fn push(self, list: &mut VecDeque<Bytes>, value: Bytes) {
+        match self {
+            End::Left => list.push_front(value),
+            End::Right => list.push_back(value),
+        }
+    }
+}
+
+/// Resolve a possibly negative index against a list of `len` elements.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+/// Resolve an inclusive `start..=stop` range the way Redis does: negative
+/// offsets count from the end, and out of range offsets are clamped. Returns
+/// `None` when the range selects nothing.
+pub(crate) fn resolve_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { start + len } else { start }.max(0);
+    let stop = if stop < 0 { stop + len } else { stop }.min(len - 1);
+
+    (start <= stop).then_some((start as usize, stop as usize))
+}
+
+/// Returns the list stored in `slot`, or `None` if the key does not exist.
+fn list(slot: &Option<Entry>) -> Result<Option<&VecDeque<Bytes>>, WrongType> {
+    slot.as_ref().map(|entry| entry.value.as_list()).transpose()
+}
+
+/// Returns the list stored in `slot`, or `None` if the key does not exist.
+fn list_mut(slot: &mut Slot) -> Result<Option<&mut VecDeque<Bytes>>, WrongType> {
+    // Checking the type before borrowing mutably keeps a failed command from
+    // counting as a modification.
+    list(slot)?;
+    slot.as_mut()
+        .map(|entry| entry.value.as_list_mut())
+        .transpose()
+}
+
+/// Insert all the specified values at the head or tail of the list stored at
+/// `key`.
+///
+/// Backs `LPUSH`, `RPUSH`, `LPUSHX` and `RPUSHX`. Unless the `X` variant is
+/// used, a missing key is created as an empty list first. Elements are
+/// inserted one after the other, so `LPUSH mylist a b c` leaves `c` at the
+/// head. Replies with the length of the list after the push operation.
+#[derive(Debug)]
+pub(crate) struct Push {
+    key: Bytes,
+    values: Vec<Bytes>,
+    end: End,
+
+    /// Only push when the list already exists.
+    existing_only: bool,
+}
+
+impl Push {
+    /// # Format
+    ///
+    /// ```text
+    /// LPUSH key element [element ...]
+    /// RPUSH key element [element ...]
+    /// LPUSHX key element [element ...]
+    /// RPUSHX key element [element ...]
+    /// ```
+    pub(crate) fn parse_frames(
+        parse: &mut Parse,
+        end: End,
+        existing_only: bool,
+    ) -> Result<Push, ParseError> {
+        let key = parse.next_bytes()?;
+        let mut values = vec![parse.next_bytes()?];
+        values.extend(parse.remaining_bytes()?);
+
+        Ok(Push {
+            key,
+            values,
+            end,
+            existing_only,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            if slot.is_none() {
+                if self.existing_only {
+                    return Ok(Frame::Integer(0));
+                }
+                **slot = Some(Entry::new(Value::List(VecDeque::new())));
+            }
+
+            let list = list_mut(slot)?.unwrap();
+            for value in self.values {
+                self.end.push(list, value);
+            }
+
+            Ok(Frame::Integer(list.len() as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Removes and returns the first (`LPOP`) or last (`RPOP`) elements of the
+/// list stored at `key`.
+///
+/// Without `count`, replies with a single element, or nil when the key does
+/// not exist. With `count`, replies with an array of up to `count` elements.
+#[derive(Debug)]
+pub(crate) struct Pop {
+    key: Bytes,
+    end: End,
+    count: Option<usize>,
+}
+
+impl Pop {
+    /// # Format
+    ///
+    /// ```text
+    /// LPOP key [count]
+    /// RPOP key [count]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, end: End) -> Result<Pop, ParseError> {
+        let key = parse.next_bytes()?;
+
+        let count = match parse.next_int() {
+            Ok(count) => Some(
+                usize::try_from(count).map_err(|_| "value is out of range, must be positive")?,
+            ),
+            Err(ParseError::EndOfStream) => None,
+            Err(err) => return Err(err),
+        };
+
+        Ok(Pop { key, end, count })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(list) = list_mut(slot)? else {
+                return Ok(Frame::Null);
+            };
+
+            Ok(match self.count {
+                None => Frame::Bulk(self.end.pop(list).unwrap()),
+                Some(count) => {
+                    let count = count.min(list.len());
+                    Frame::Array(
+                        (0..count)
+                            .map(|_| Frame::Bulk(self.end.pop(list).unwrap()))
+                            .collect(),
+                    )
+                }
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the specified elements of the list stored at `key`.
+///
+/// `start` and `stop` are inclusive zero-based offsets, and may be negative to
+/// count from the end of the list. Out of range offsets are clamped.
+#[derive(Debug)]
+pub(crate) struct LRange {
+    key: Bytes,
+    start: i64,
+    stop: i64,
+}
+
+impl LRange {
+    /// # Format
+    ///
+    /// ```text
+    /// LRANGE key start stop
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<LRange, ParseError> {
+        let key = parse.next_bytes()?;
+        let start = parse.next_int()?;
+        let stop = parse.next_int()?;
+        Ok(LRange { key, start, stop })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(list) = list(slot)? else {
+                return Ok(Frame::array());
+            };
+
+            let mut out = Frame::array();
+            if let Some((start, stop)) = resolve_range(self.start, self.stop, list.len()) {
+                for value in list.range(start..=stop) {
+                    out.push_bulk(value.clone());
+                }
+            }
+
+            Ok(out)
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the length of the list stored at `key`, or 0 when the key does not
+/// exist.
+#[derive(Debug)]
+pub(crate) struct LLen {
+    key: Bytes,
+}
+
+impl LLen {
+    /// # Format
+    ///
+    /// ```text
+    /// LLEN key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<LLen, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(LLen { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let len = list(slot)?.map_or(0, |list| list.len());
+            Ok(Frame::Integer(len as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the element at `index` in the list stored at `key`, or nil when
+/// the index is out of range.
+#[derive(Debug)]
+pub(crate) struct LIndex {
+    key: Bytes,
+    index: i64,
+}
+
+impl LIndex {
+    /// # Format
+    ///
+    /// ```text
+    /// LINDEX key index
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<LIndex, ParseError> {
+        let key = parse.next_bytes()?;
+        let index = parse.next_int()?;
+        Ok(LIndex { key, index })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let value = list(slot)?.and_then(|list| {
+                let index = resolve_index(self.index, list.len())?;
+                Some(list[index].clone())
+            });
+            Ok(value.map_or(Frame::Null, Frame::Bulk))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Sets the list element at `index` to `value`.
+#[derive(Debug)]
+pub(crate) struct LSet {
+    key: Bytes,
+    index: i64,
+    value: Bytes,
+}
+
+impl LSet {
+    /// # Format
+    ///
+    /// ```text
+    /// LSET key index element
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<LSet, ParseError> {
+        let key = parse.next_bytes()?;
+        let index = parse.next_int()?;
+        let value = parse.next_bytes()?;
+        Ok(LSet { key, index, value })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(list) = list_mut(slot)? else {
+                return Ok(Frame::Error("ERR no such key".to_string()));
+            };
+
+            Ok(match resolve_index(self.index, list.len()) {
+                Some(index) => {
+                    list[index] = self.value;
+                    Frame::Simple("OK".to_string())
+                }
+                None => Frame::Error("ERR index out of range".to_string()),
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Trim an existing list so that it will contain only the specified range of
+/// elements.
+///
+/// Offsets follow the `LRANGE` rules. A range selecting nothing empties the
+/// list, which deletes the key.
+#[derive(Debug)]
+pub(crate) struct LTrim {
+    key: Bytes,
+    start: i64,
+    stop: i64,
+}
+
+impl LTrim {
+    /// # Format
+    ///
+    /// ```text
+    /// LTRIM key start stop
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<LTrim, ParseError> {
+        let key = parse.next_bytes()?;
+        let start = parse.next_int()?;
+        let stop = parse.next_int()?;
+        Ok(LTrim { key, start, stop })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            if let Some(list) = list_mut(slot)? {
+                match resolve_range(self.start, self.stop, list.len()) {
+                    Some((start, stop)) => {
+                        list.truncate(stop + 1);
+                        list.drain(..start);
+                    }
+                    None => list.clear(),
+                }
+            }
+
+            Ok(Frame::Simple("OK".to_string()))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Inserts `value` in the list stored at `key` either before or after the
+/// first occurrence of `pivot`.
+///
+/// Replies with the list length after the insert, -1 when `pivot` was not
+/// found, and 0 when the key does not exist.
+#[derive(Debug)]
+pub(crate) struct LInsert {
+    key: Bytes,
+    before: bool,
+    pivot: Bytes,
+    value: Bytes,
+}
+
+impl LInsert {
+    /// # Format
+    ///
+    /// ```text
+    /// LINSERT key <BEFORE | AFTER> pivot element
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<LInsert, ParseError> {
+        let key = parse.next_bytes()?;
+        let before = match &parse.next_string()?.to_uppercase()[..] {
+            "BEFORE" => true,
+            "AFTER" => false,
+            _ => return Err("syntax error".into()),
+        };
+        let pivot = parse.next_bytes()?;
+        let value = parse.next_bytes()?;
+
+        Ok(LInsert {
+            key,
+            before,
+            pivot,
+            value,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(list) = list_mut(slot)? else {
+                return Ok(Frame::Integer(0));
+            };
+
+            let Some(pos) = list.iter().position(|value| *value == self.pivot) else {
+                return Ok(Frame::Integer(-1));
+            };
+
+            let pos = if self.before { pos } else { pos + 1 };
+            list.insert(pos, self.value);
+            Ok(Frame::Integer(list.len() as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Removes the first `count` occurrences of elements equal to `value` from the
+/// list stored at `key`.
+///
+/// A positive `count` removes elements moving from head to tail, a negative
+/// one from tail to head, and 0 removes all of them. Replies with the number
+/// of removed elements.
+#[derive(Debug)]
+pub(crate) struct LRem {
+    key: Bytes,
+    count: i64,
+    value: Bytes,
+}
+
+impl LRem {
+    /// # Format
+    ///
+    /// ```text
+    /// LREM key count element
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<LRem, ParseError> {
+        let key = parse.next_bytes()?;
+        let count = parse.next_int()?;
+        let value = parse.next_bytes()?;
+        Ok(LRem { key, count, value })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(list) = list_mut(slot)? else {
+                return Ok(Frame::Integer(0));
+            };
+
+            let limit = match self.count {
+                0 => usize::MAX,
+                count => count.unsigned_abs() as usize,
+            };
+
+            let mut removed = 0;
+            let mut kept = VecDeque::with_capacity(list.len());
+            let mut filter = |value: &Bytes| {
+                if removed < limit && *value == self.value {
+                    removed += 1;
+                    false
+                } else {
+                    true
+                }
+            };
+
+            if self.count >= 0 {
+                for value in list.drain(..) {
+                    if filter(&value) {
+                        kept.push_back(value);
+                    }
+                }
+            } else {
+                for value in list.drain(..).rev() {
+                    if filter(&value) {
+                        kept.push_front(value);
+                    }
+                }
+            }
+
+            *list = kept;
+            Ok(Frame::Integer(removed as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Atomically pops an element from one end of the `source` list and pushes it
+/// onto one end of the `destination` list.
+///
+/// Backs `LMOVE` and its older form `RPOPLPUSH`. `source` and `destination`
+/// may be the same key, which rotates the list. Replies with the moved
+/// element, or nil when `source` does not exist.
+#[derive(Debug)]
+pub(crate) struct LMove {
+    source: Bytes,
+    destination: Bytes,
+    from: End,
+    to: End,
+}
+
+impl LMove {
+    /// # Format
+    ///
+    /// ```text
+    /// LMOVE source destination <LEFT | RIGHT> <LEFT | RIGHT>
+    /// RPOPLPUSH source destination
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, rpoplpush: bool) -> Result<LMove, ParseError> {
+        let source = parse.next_bytes()?;
+        let destination = parse.next_bytes()?;
+
+        let (from, to) = if rpoplpush {
+            (End::Right, End::Left)
+        } else {
+            (End::parse(parse)?, End::parse(parse)?)
+        };
+
+        Ok(LMove {
+            source,
+            destination,
+            from,
+            to,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let keys = [self.source.clone(), self.destination.clone()];
+
+        db.with_entries(&keys, |slots| {
+            // Check the destination type up front so a failed move does not
+            // lose the popped element.
+            list(slots.slot(&self.destination))?;
+
+            let Some(source) = list_mut(slots.slot(&self.source))? else {
+                return Ok(Frame::Null);
+            };
+            let value = self.from.pop(source).unwrap();
+
+            let destination = slots
+                .slot(&self.destination)
+                .get_or_insert_with(|| Entry::new(Value::List(VecDeque::new())));
+            self.to
+                .push(destination.value.as_list_mut()?, value.clone());
+
+            Ok(Frame::Bulk(value))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Parse the timeout of a blocking command, in seconds. Zero blocks
+/// indefinitely and is returned as `None`.
+fn parse_timeout(arg: &[u8]) -> Result<Option<Duration>, ParseError> {
+    let secs = parse_float(arg).ok_or("timeout is not a float or out of range")?;
+    if secs < 0.0 {
+        return Err("timeout is negative".into());
+    }
+
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .map_err(|_| "timeout is out of range".into())
+}
+
+/// How a blocked client stopped waiting.
+enum Wakeup {
+    /// Another client's write handed something over. It was propagated along
+    /// with that write.
+    Served(Delivery),
+    TimedOut,
+    /// The client closed the connection.
+    Disconnected,
+}
+
+/// Wait until `blocked` is served, `timeout` elapses or the client goes away.
+///
+/// The socket is watched while waiting so a client that disconnects is
+/// unqueued right away instead of on the next push. Anything it sends in the
+/// meantime stays buffered for after the reply.
+async fn wait(
+    mut blocked: Blocked,
+    timeout: Option<Duration>,
+    conn: &mut Connection,
+) -> crate::Result<Wakeup> {
+    // Replies to requests pipelined before this one must not wait for it.
+    conn.flush().await?;
+
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let wakeup = tokio::select! {
+        delivery = blocked.recv() => Wakeup::Served(delivery),
+        () = deadline => Wakeup::TimedOut,
+        res = conn.wait_closed() => {
+            res?;
+            Wakeup::Disconnected
+        }
+    };
+
+    // Something may have been handed over right as the timeout elapsed.
+    Ok(match wakeup {
+        Wakeup::TimedOut => blocked.cancel().map_or(Wakeup::TimedOut, Wakeup::Served),
+        wakeup => wakeup,
+    })
+}
+
+/// Run `f`, the first attempt of a blocking command sent as `request`,
+/// propagating it like a write if it got something right away.
+fn try_logged(db: &Db, request: Frame, f: impl FnOnce() -> Frame) -> Frame {
+    if !db.propagating() {
+        return f();
+    }
+
+    db.logged(|| {
+        let reply = f();
+        let frames = Propagation::Unblocked.frames(request, &reply, db);
+        (reply, frames)
+    })
+}
+
+/// Blocking version of `LPOP` and `RPOP`.
+///
+/// Pops from the first non-empty list among `keys`, checked in the order they
+/// are given. When all of them are empty the client blocks until another one
+/// pushes to any of the keys, or until `timeout` elapses. Clients blocked on
+/// the same key are served in the order they started waiting, one element
+/// each.
+///
+/// Replies with a two-element array of the key and the popped element, or a
+/// nil array on timeout.
+#[derive(Debug)]
+pub(crate) struct BPop {
+    keys: Vec<Bytes>,
+    end: End,
+
+    /// `None` blocks indefinitely.
+    timeout: Option<Duration>,
+}
+
+impl BPop {
+    /// # Format
+    ///
+    /// ```text
+    /// BLPOP key [key ...] timeout
+    /// BRPOP key [key ...] timeout
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, end: End) -> Result<BPop, ParseError> {
+        let mut keys = vec![parse.next_bytes()?];
+        keys.extend(parse.remaining_bytes()?);
+        if keys.len() < 2 {
+            return Err(ParseError::EndOfStream);
+        }
+
+        let timeout = parse_timeout(&keys.pop().unwrap())?;
+        Ok(BPop { keys, end, timeout })
+    }
+
+    /// Serve the command without blocking, replying nil if all the lists are
+    /// empty.
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match db.pop_or_block(&self.keys, self.end, None) {
+            Ok(BlockingPop::Ready(key, value)) => {
+                Frame::Array(vec![Frame::Bulk(key), Frame::Bulk(value)])
+            }
+            // Dropping the handle unqueues the client again. Nothing can have
+            // been handed over, as this runs inside a transaction.
+            Ok(BlockingPop::Blocked(_)) => Frame::Null,
+            Err(err) => err.into(),
+        }
+    }
+
+    /// Serve the command, blocking the connection if needed. `request` is the
+    /// frame the command was parsed from.
+    ///
+    /// Returns `None` if the client disconnected while blocked.
+    pub(crate) async fn block(
+        self,
+        db: &Db,
+        request: Frame,
+        conn: &mut Connection,
+    ) -> crate::Result<Option<Frame>> {
+        let mut blocked = None;
+        let reply = db.concurrently(|| {
+            try_logged(db, request, || {
+                match db.pop_or_block(&self.keys, self.end, None) {
+                    Ok(BlockingPop::Ready(key, value)) => {
+                        Frame::Array(vec![Frame::Bulk(key), Frame::Bulk(value)])
+                    }
+                    Ok(BlockingPop::Blocked(handle)) => {
+                        blocked = Some(handle);
+                        Frame::Null
+                    }
+                    Err(err) => err.into(),
+                }
+            })
+        });
+        let Some(blocked) = blocked else {
+            return Ok(Some(reply));
+        };
+
+        Ok(match wait(blocked, self.timeout, conn).await? {
+            Wakeup::Served(Ok((key, value))) => {
+                Some(Frame::Array(vec![Frame::Bulk(key), Frame::Bulk(value)]))
+            }
+            Wakeup::Served(Err(err)) => Some(err.into()),
+            Wakeup::TimedOut => Some(Frame::Null),
+            Wakeup::Disconnected => None,
+        })
+    }
+}
+
+/// Blocking version of `LMOVE`.
+///
+/// When `source` is empty the client blocks like `BLPOP` does, and the element
+/// is pushed to `destination` once it arrives. Replies with the element, or nil
+/// on timeout.
+#[derive(Debug)]
+pub(crate) struct BLMove {
+    lmove: LMove,
+    timeout: Option<Duration>,
+}
+
+impl BLMove {
+    /// # Format
+    ///
+    /// ```text
+    /// BLMOVE source destination <LEFT | RIGHT> <LEFT | RIGHT> timeout
+    /// BRPOPLPUSH source destination timeout
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, brpoplpush: bool) -> Result<BLMove, ParseError> {
+        let lmove = LMove::parse_frames(parse, brpoplpush)?;
+        let timeout = parse_timeout(&parse.next_bytes()?)?;
+        Ok(BLMove { lmove, timeout })
+    }
+
+    /// Serve the command without blocking, exactly like `LMOVE`.
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        self.lmove.apply(db)
+    }
+
+    /// Serve the command, blocking the connection if needed. `request` is the
+    /// frame the command was parsed from.
+    ///
+    /// The element is popped and pushed in one step, with both lists locked,
+    /// whether or not the client had to wait for it.
+    ///
+    /// Returns `None` if the client disconnected while blocked.
+    pub(crate) async fn block(
+        self,
+        db: &Db,
+        request: Frame,
+        conn: &mut Connection,
+    ) -> crate::Result<Option<Frame>> {
+        let LMove {
+            source,
+            destination,
+            from,
+            to,
+        } = self.lmove;
+
+        let mut blocked = None;
+        let reply = db.concurrently(|| {
+            try_logged(db, request, || {
+                match db.pop_or_block(&[source], from, Some((destination, to))) {
+                    Ok(BlockingPop::Ready(_, value)) => Frame::Bulk(value),
+                    Ok(BlockingPop::Blocked(handle)) => {
+                        blocked = Some(handle);
+                        Frame::Null
+                    }
+                    Err(err) => err.into(),
+                }
+            })
+        });
+        let Some(blocked) = blocked else {
+            return Ok(Some(reply));
+        };
+
+        Ok(match wait(blocked, self.timeout, conn).await? {
+            Wakeup::Served(Ok((_, value))) => Some(Frame::Bulk(value)),
+            // The destination changed type while the client was blocked. The
+            // element was left in the source.
+            Wakeup::Served(Err(err)) => Some(err.into()),
+            Wakeup::TimedOut => Some(Frame::Null),
+            Wakeup::Disconnected => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_follow_redis_index_rules() {
+        assert_eq!(resolve_range(0, -1, 5), Some((0, 4)));
+        assert_eq!(resolve_range(-3, 2, 5), Some((2, 2)));
+        assert_eq!(resolve_range(-100, 100, 5), Some((0, 4)));
+        assert_eq!(resolve_range(3, 1, 5), None);
+        assert_eq!(resolve_range(5, 10, 5), None);
+        assert_eq!(resolve_range(0, -1, 0), None);
+
+        assert_eq!(resolve_index(-1, 3), Some(2));
+        assert_eq!(resolve_index(3, 3), None);
+        assert_eq!(resolve_index(-4, 3), None);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::cmd::Command;
+use crate::connection::Connection;
+use crate::db::{Db, Inbox, Message, Outbox};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Subscribes the client to the given channels, or with `PSUBSCRIBE` to the
+/// channels matching the given glob-style patterns.
+///
+/// Once the client is subscribed to anything, the connection enters
+/// subscriber mode: it receives published messages and only subscription
+/// commands, `PING` and `QUIT` are accepted until every subscription has been
+/// dropped again.
+#[derive(Debug)]
+pub(crate) struct Subscribe {
+    channels: Vec<Bytes>,
+    pattern: bool,
+}
+
+impl Subscribe {
+    /// # Format
+    ///
+    /// ```text
+    /// SUBSCRIBE channel [channel ...]
+    /// PSUBSCRIBE pattern [pattern ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, pattern: bool) -> Result<Subscribe, ParseError> {
+        let mut channels = vec![parse.next_bytes()?];
+        channels.extend(parse.remaining_bytes()?);
+        Ok(Subscribe { channels, pattern })
+    }
+}
+
+/// Unsubscribes the client from the given channels, or from all of them if
+/// none are given. `PUNSUBSCRIBE` does the same for pattern subscriptions.
+#[derive(Debug)]
+pub(crate) struct Unsubscribe {
+    channels: Vec<Bytes>,
+    pattern: bool,
+}
+
+impl Unsubscribe {
+    /// # Format
+    ///
+    /// ```text
+    /// UNSUBSCRIBE [channel [channel ...]]
+    /// PUNSUBSCRIBE [pattern [pattern ...]]
+    /// ```
+    pub(crate) fn parse_frames(
+        parse: &mut Parse,
+        pattern: bool,
+    ) -> Result<Unsubscribe, ParseError> {
+        Ok(Unsubscribe {
+            channels: parse.remaining_bytes()?,
+            pattern,
+        })
+    }
+}
+
+/// Posts a message to the given channel.
+///
+/// Replies with the number of subscriptions that received the message.
+#[derive(Debug)]
+pub(crate) struct Publish {
+    channel: Bytes,
+    message: Bytes,
+}
+
+impl Publish {
+    /// # Format
+    ///
+    /// ```text
+    /// PUBLISH channel message
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Publish, ParseError> {
+        let channel = parse.next_bytes()?;
+        let message = parse.next_bytes()?;
+        Ok(Publish { channel, message })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        Frame::Integer(db.publish(&self.channel, self.message) as i64)
+    }
+}
+
+/// Introspects the Pub/Sub state.
+///
+/// * `CHANNELS` lists the channels with subscribers, optionally only those
+///   matching a pattern
+/// * `NUMSUB` replies with each given channel followed by its subscriber count
+/// * `NUMPAT` replies with the number of patterns subscribed to
+#[derive(Debug)]
+pub(crate) enum PubSub {
+    Channels(Option<Bytes>),
+    NumSub(Vec<Bytes>),
+    NumPat,
+}
+
+impl PubSub {
+    /// # Format
+    ///
+    /// ```text
+    /// PUBSUB CHANNELS [pattern]
+    /// PUBSUB NUMSUB [channel [channel ...]]
+    /// PUBSUB NUMPAT
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<PubSub, ParseError> {
+        let subcommand = parse.next_string()?;
+        match &subcommand.to_lowercase()[..] {
+            "channels" => match parse.next_bytes() {
+                Ok(pattern) => Ok(PubSub::Channels(Some(pattern))),
+                Err(ParseError::EndOfStream) => Ok(PubSub::Channels(None)),
+                Err(e) => Err(e),
+            },
+            "numsub" => Ok(PubSub::NumSub(parse.remaining_bytes()?)),
+            "numpat" => Ok(PubSub::NumPat),
+            _ => Err(format!("unknown subcommand '{}'. Try PUBSUB HELP.", subcommand).into()),
+        }
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match self {
+            PubSub::Channels(pattern) => {
+                let mut response = Frame::array();
+                for channel in db.channels(pattern.as_deref()) {
+                    response.push_bulk(channel);
+                }
+                response
+            }
+            PubSub::NumSub(channels) => Frame::Map(
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        let count = db.subscriber_count(&channel);
+                        (Frame::Bulk(channel), Frame::Integer(count as i64))
+                    })
+                    .collect(),
+            ),
+            PubSub::NumPat => Frame::Integer(db.pattern_count() as i64),
+        }
+    }
+}
+
+/// The subscriptions of a single connection.
+///
+/// Every subscription feeds the same outbox, so messages are written to the
+/// connection in the order they were published. Dropping the subscriber
+/// releases the subscriptions.
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    db: Db,
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+    outbox: Arc<Outbox>,
+    inbox: Inbox,
+}
+
+impl Subscriber {
+    pub(crate) fn new(db: &Db) -> Subscriber {
+        let (outbox, inbox) = Outbox::new();
+        Subscriber {
+            db: db.clone(),
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            outbox,
+            inbox,
+        }
+    }
+
+    /// Apply `cmd`, a subscription command, and keep the connection in
+    /// subscriber mode for as long as the client has any subscriptions left.
+    ///
+    /// Returns `false` if the connection should be closed, because the peer
+    /// went away, sent `QUIT` or fell too far behind on messages. Later
+    /// commands are checked
+    /// against the permissions of `user`, which the client is authenticated
+    /// as.
+    pub(crate) async fn run(
+        mut self,
+        cmd: Command,
+        conn: &mut Connection,
+        user: Option<&str>,
+    ) -> crate::Result<bool> {
+        if !self.apply(cmd, conn).await? {
+            return Ok(false);
+        }
+
+        while self.count() > 0 {
+            tokio::select! {
+                message = self.inbox.recv() => {
+                    let Some(message) = message else {
+                        return Ok(false);
+                    };
+                    conn.write_frame(&message_frame(message)).await?;
+                }
+                frame = conn.read_frame() => {
+                    let Some(frame) = frame? else {
+                        return Ok(false);
+                    };
+                    let name = command_name(&frame);
+                    let cmd = self
+                        .db
+                        .check_access(user, &frame)
+                        .map_err(Into::into)
+                        .and_then(|()| Command::from_frame(frame));
+                    match cmd {
+                        Ok(
+                            cmd @ (Command::Subscribe(_)
+                            | Command::Unsubscribe(_)
+                            | Command::Ping(_)
+                            | Command::Quit(_)
+                            | Command::Unknown(_)),
+                        ) => {
+                            if !self.apply(cmd, conn).await? {
+                                return Ok(false);
+                            }
+                        }
+                        Ok(_) => {
+                            let response = Frame::Error(format!(
+                                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+                                name
+                            ));
+                            conn.write_frame(&response).await?;
+                        }
+                        Err(e) => conn.write_frame(&Frame::Error(e.to_string())).await?,
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Apply a command that is allowed in subscriber mode.
+    async fn apply(&mut self, cmd: Command, conn: &mut Connection) -> crate::Result<bool> {
+        match cmd {
+            Command::Subscribe(cmd) => {
+                for channel in cmd.channels {
+                    self.subscribe(channel.clone(), cmd.pattern);
+                    let kind = if cmd.pattern {
+                        "psubscribe"
+                    } else {
+                        "subscribe"
+                    };
+                    conn.write_frame(&self.reply(kind, Some(channel))).await?;
+                }
+            }
+            Command::Unsubscribe(cmd) => {
+                let kind = if cmd.pattern {
+                    "punsubscribe"
+                } else {
+                    "unsubscribe"
+                };
+                let mut channels = cmd.channels;
+                if channels.is_empty() {
+                    let subscribed = if cmd.pattern {
+                        &self.patterns
+                    } else {
+                        &self.channels
+                    };
+                    channels = subscribed.iter().cloned().collect();
+
+                    // Even without anything to unsubscribe from, the client
+                    // gets a reply.
+                    if channels.is_empty() {
+                        conn.write_frame(&self.reply(kind, None)).await?;
+                    }
+                }
+                for channel in channels {
+                    self.unsubscribe(&channel, cmd.pattern);
+                    conn.write_frame(&self.reply(kind, Some(channel))).await?;
+                }
+            }
+            Command::Ping(cmd) => conn.write_frame(&cmd.apply_subscribed()).await?,
+            Command::Quit(cmd) => {
+                conn.write_frame(&cmd.apply()).await?;
+                return Ok(false);
+            }
+            cmd => conn.write_frame(&cmd.apply(&self.db)).await?,
+        }
+        Ok(true)
+    }
+
+    fn subscribe(&mut self, channel: Bytes, pattern: bool) {
+        if pattern {
+            if self.patterns.insert(channel.clone()) {
+                self.db.psubscribe(channel, &self.outbox);
+            }
+        } else if self.channels.insert(channel.clone()) {
+            self.db.subscribe(channel, &self.outbox);
+        }
+    }
+
+    fn unsubscribe(&mut self, channel: &[u8], pattern: bool) {
+        if pattern {
+            if self.patterns.remove(channel) {
+                self.db.punsubscribe(channel, &self.outbox);
+            }
+        } else if self.channels.remove(channel) {
+            self.db.unsubscribe(channel, &self.outbox);
+        }
+    }
+
+    /// Returns the total number of channels and patterns subscribed to.
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Build a `[kind, channel, count]` confirmation.
+    fn reply(&self, kind: &'static str, channel: Option<Bytes>) -> Frame {
+        Frame::Push(vec![
+            Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+            channel.map_or(Frame::Null, Frame::Bulk),
+            Frame::Integer(self.count() as i64),
+        ])
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for channel in self.channels.drain() {
+            self.db.unsubscribe(&channel, &self.outbox);
+        }
+        for pattern in self.patterns.drain() {
+            self.db.punsubscribe(&pattern, &self.outbox);
+        }
+    }
+}
+
+/// Build the frame pushed to a subscriber for `message`.
+fn message_frame(message: Message) -> Frame {
+    match message {
+        Message::Channel { channel, message } => Frame::Push(vec![
+            Frame::Bulk(Bytes::from_static(b"message")),
+            Frame::Bulk(channel),
+            Frame::Bulk(message),
+        ]),
+        Message::Pattern {
+            pattern,
+            channel,
+            message,
+        } => Frame::Push(vec![
+            Frame::Bulk(Bytes::from_static(b"pmessage")),
+            Frame::Bulk(pattern),
+            Frame::Bulk(channel),
+            Frame::Bulk(message),
+        ]),
+    }
+}
+
+/// Returns the lowercase name of the command in a request frame, for error
+/// messages.
+fn command_name(frame: &Frame) -> String {
+    match frame {
+        Frame::Array(parts) => match parts.first() {
+            Some(Frame::Bulk(name)) => String::from_utf8_lossy(name).to_lowercase(),
+            Some(Frame::Simple(name)) => name.to_lowercase(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,512 @@
+use crate::cmd::Command;
+use crate::connection::Connection;
+use crate::db::{Db, LinkState, ReplicaLink, Resync};
+use crate::frame::{Frame, Limits};
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// How long a replica waits before reconnecting to its master.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often a replica tells its master how far along the stream it is.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Largest snapshot accepted from a master. The snapshot comes as a single
+/// bulk string as big as the keyspace, so it is only bounded by what a buffer
+/// can hold.
+const MAX_SNAPSHOT_LEN: usize = isize::MAX as usize - 2;
+
+/// Makes the server replicate another one, or stop replicating.
+#[derive(Debug)]
+pub(crate) struct ReplicaOf {
+    /// Address of the master, or `None` for `NO ONE`.
+    master: Option<(String, u16)>,
+}
+
+impl ReplicaOf {
+    /// # Format
+    ///
+    /// ```text
+    /// REPLICAOF host port
+    /// REPLICAOF NO ONE
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<ReplicaOf, ParseError> {
+        let host = parse.next_string()?;
+        let port = parse.next_string()?;
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            return Ok(ReplicaOf { master: None });
+        }
+
+        let port = port.parse().map_err(|_| "Invalid master port")?;
+        Ok(ReplicaOf {
+            master: Some((host, port)),
+        })
+    }
+
+    /// Apply the command. Switching to another master waits for the commands
+    /// being applied to finish, so this must not be called while applying
+    /// one.
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match self.master {
+            Some((host, port)) => replicate(db, host, port),
+            None => db.stop_replicating(),
+        }
+        Frame::Simple("OK".to_string())
+    }
+}
+
+/// Make `db` a replica of the master at `host:port`, following it in the
+/// background. Must not be called while applying a command.
+pub(crate) fn replicate(db: &Db, host: String, port: u16) {
+    let task = tokio::spawn(follow(db.clone(), host.clone(), port));
+    db.replicate(host, port, task.abort_handle());
+}
+
+/// Sent by a replica to start receiving the replication stream, from the
+/// given offset of the stream with the given ID if possible.
+#[derive(Debug)]
+pub(crate) struct Psync {
+    replid: String,
+    offset: i64,
+}
+
+impl Psync {
+    /// # Format
+    ///
+    /// ```text
+    /// PSYNC replid offset
+    /// ```
+    ///
+    /// A replica that has nothing to resume sends `PSYNC ? -1`.
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Psync, ParseError> {
+        let replid = parse.next_string()?;
+        let offset = parse.next_int()?;
+        Ok(Psync { replid, offset })
+    }
+
+    /// Stream the writes of `db` to the replica on the other end of `conn`,
+    /// until either end goes away. `port` is the port the replica announced
+    /// with `REPLCONF listening-port`.
+    ///
+    /// Unlike Redis, which sends the snapshot of a full resynchronization
+    /// without the trailing CRLF of a bulk string, it is sent as a regular
+    /// bulk string.
+    pub(crate) async fn serve(
+        self,
+        db: &Db,
+        conn: &mut Connection,
+        port: u16,
+    ) -> crate::Result<()> {
+        let ip = conn.peer_addr()?.ip().to_string();
+        let offset = u64::try_from(self.offset).unwrap_or(0);
+        let (resync, mut link) = db.attach_replica(&self.replid, offset, ip, port);
+
+        match resync {
+            Resync::Full {
+                replid,
+                offset,
+                snapshot,
+            } => {
+                let reply = format!("FULLRESYNC {} {}", replid, offset);
+                conn.feed_frame(&Frame::Simple(reply)).await?;
+                conn.feed_frame(&Frame::Bulk(Bytes::from(snapshot))).await?;
+            }
+            Resync::Partial { replid, missed } => {
+                let reply = format!("CONTINUE {}", replid);
+                conn.feed_frame(&Frame::Simple(reply)).await?;
+                conn.feed_encoded(&missed).await?;
+            }
+        }
+        conn.flush().await?;
+
+        stream_to_replica(conn, &mut link).await
+    }
+}
+
+/// Feed the stream to the replica on the other end of `conn`, and record its
+/// acknowledgments.
+async fn stream_to_replica(conn: &mut Connection, link: &mut ReplicaLink) -> crate::Result<()> {
+    loop {
+        tokio::select! {
+            data = link.feed.recv() => {
+                // The replica was detached.
+                let Some(data) = data else {
+                    return Ok(());
+                };
+                conn.feed_encoded(&data).await?;
+                while let Ok(data) = link.feed.try_recv() {
+                    conn.feed_encoded(&data).await?;
+                }
+                conn.flush().await?;
+            }
+            frame = conn.read_frame() => {
+                let Some(frame) = frame? else {
+                    return Ok(());
+                };
+                if let Ok(Command::ReplConf(cmd)) = Command::from_frame(frame)
+                    && let Some(offset) = cmd.ack()
+                {
+                    link.ack(offset);
+                }
+            }
+        }
+    }
+}
+
+/// Exchanges replication settings between a replica and its master.
+#[derive(Debug)]
+pub(crate) struct ReplConf {
+    option: String,
+    args: Vec<Bytes>,
+}
+
+impl ReplConf {
+    /// # Format
+    ///
+    /// ```text
+    /// REPLCONF listening-port port
+    /// REPLCONF capa capability
+    /// REPLCONF ACK offset
+    /// REPLCONF GETACK *
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<ReplConf, ParseError> {
+        let option = parse.next_string()?.to_lowercase();
+        let args = parse.remaining_bytes()?;
+        Ok(ReplConf { option, args })
+    }
+
+    /// Returns the offset acknowledged by `REPLCONF ACK`.
+    pub(crate) fn ack(&self) -> Option<u64> {
+        if self.option != "ack" {
+            return None;
+        }
+        atoi::atoi(self.args.first()?)
+    }
+
+    /// Returns `true` for `REPLCONF GETACK`, with which the master asks for
+    /// an acknowledgment.
+    pub(crate) fn is_getack(&self) -> bool {
+        self.option == "getack"
+    }
+
+    /// Apply the command sent by a replica before `PSYNC`, recording the port
+    /// it listens on in `listening_port`.
+    pub(crate) fn apply(self, listening_port: &mut u16) -> Frame {
+        match &self.option[..] {
+            "listening-port" => match self.args.first().and_then(|port| atoi::atoi(port)) {
+                Some(port) => *listening_port = port,
+                None => return Frame::Error("ERR Invalid listening port".to_string()),
+            },
+            "capa" | "ack" | "getack" => {}
+            option => {
+                return Frame::Error(format!("ERR Unrecognized REPLCONF option: {}", option));
+            }
+        }
+        Frame::Simple("OK".to_string())
+    }
+}
+
+/// Returns the replication role of the server, with the state of its
+/// replicas or of the link to its master.
+#[derive(Debug)]
+pub(crate) struct Role;
+
+impl Role {
+    /// # Format
+    ///
+    /// ```text
+    /// ROLE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<Role, ParseError> {
+        Ok(Role)
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let status = db.replication_status();
+        let mut response = Frame::array();
+
+        match status.master {
+            Some((host, port, link)) => {
+                response.push_bulk(Bytes::from_static(b"slave"));
+                response.push_bulk(Bytes::from(host));
+                response.push_int(port.into());
+                response.push_bulk(Bytes::from_static(link.as_str().as_bytes()));
+                response.push_int(status.offset as i64);
+            }
+            None => {
+                response.push_bulk(Bytes::from_static(b"master"));
+                response.push_int(status.offset as i64);
+                let replicas = status
+                    .replicas
+                    .into_iter()
+                    .map(|(ip, port, ack)| {
+                        Frame::Array(vec![
+                            Frame::Bulk(Bytes::from(ip)),
+                            Frame::Bulk(Bytes::from(port.to_string())),
+                            Frame::Bulk(Bytes::from(ack.to_string())),
+                        ])
+                    })
+                    .collect();
+                if let Frame::Array(entries) = &mut response {
+                    entries.push(Frame::Array(replicas));
+                }
+            }
+        }
+        response
+    }
+}
+
+/// Returns information about the server, as `field:value` lines grouped in
+/// sections. Only the replication section is supported.
+#[derive(Debug)]
+pub(crate) struct Info {
+    section: Option<String>,
+}
+
+impl Info {
+    /// # Format
+    ///
+    /// ```text
+    /// INFO [section]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Info, ParseError> {
+        let section = match parse.next_string() {
+            Ok(section) => Some(section.to_lowercase()),
+            Err(ParseError::EndOfStream) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Info { section })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let mut info = String::new();
+        if matches!(
+            self.section.as_deref(),
+            None | Some("replication" | "default" | "all" | "everything")
+        ) {
+            replication_info(db, &mut info);
+        }
+        Frame::Bulk(Bytes::from(info))
+    }
+}
+
+/// Append the replication section of `INFO` to `out`.
+fn replication_info(db: &Db, out: &mut String) {
+    let status = db.replication_status();
+    out.push_str("# Replication\r\n");
+    let mut field = |name: &str, value: &dyn std::fmt::Display| {
+        out.push_str(&format!("{}:{}\r\n", name, value));
+    };
+
+    match &status.master {
+        Some((host, port, link)) => {
+            field("role", &"slave");
+            field("master_host", host);
+            field("master_port", port);
+            let up = *link == LinkState::Connected;
+            field("master_link_status", &if up { "up" } else { "down" });
+            field(
+                "master_sync_in_progress",
+                &u8::from(*link == LinkState::Sync),
+            );
+            field("slave_repl_offset", &status.offset);
+        }
+        None => {
+            field("role", &"master");
+            field("connected_slaves", &status.replicas.len());
+            for (i, (ip, port, ack)) in status.replicas.iter().enumerate() {
+                let replica = format!("ip={},port={},state=online,offset={}", ip, port, ack);
+                field(&format!("slave{}", i), &replica);
+            }
+        }
+    }
+    field("master_replid", &status.replid);
+    field("master_repl_offset", &status.offset);
+
+    let (first, len) = status.backlog.unwrap_or_default();
+    field("repl_backlog_active", &u8::from(status.backlog.is_some()));
+    field("repl_backlog_size", &crate::db::BACKLOG_SIZE);
+    field("repl_backlog_first_byte_offset", &first);
+    field("repl_backlog_histlen", &len);
+}
+
+/// Waits until the given number of replicas acknowledged every write made
+/// so far, or the timeout, in milliseconds, elapsed. Replies with the number
+/// of replicas that did.
+#[derive(Debug)]
+pub(crate) struct Wait {
+    replicas: usize,
+    timeout: Option<Duration>,
+}
+
+impl Wait {
+    /// # Format
+    ///
+    /// ```text
+    /// WAIT numreplicas timeout
+    /// ```
+    ///
+    /// A timeout of 0 waits forever.
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Wait, ParseError> {
+        let replicas = parse.next_int()?;
+        let timeout = parse.next_int()?;
+        if timeout < 0 {
+            return Err("timeout is negative".into());
+        }
+
+        Ok(Wait {
+            replicas: replicas.max(0) as usize,
+            timeout: (timeout > 0).then(|| Duration::from_millis(timeout as u64)),
+        })
+    }
+
+    pub(crate) async fn wait(self, db: &Db) -> Frame {
+        if db.is_replica() {
+            return Frame::Error("ERR WAIT cannot be used with replica instances.".to_string());
+        }
+        let acked = db.wait_for_replicas(self.replicas, self.timeout).await;
+        Frame::Integer(acked as i64)
+    }
+}
+
+/// Routine of the task replicating the master at `host:port`, reconnecting
+/// whenever the link drops. Runs until aborted by `REPLICAOF`.
+async fn follow(db: Db, host: String, port: u16) {
+    loop {
+        if let Err(e) = sync_with_master(&db, &host, port).await {
+            eprintln!("replication link to {}:{} lost: {}", host, port, e);
+        }
+        db.set_link_state(LinkState::Connecting);
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connect to the master, catch up with it, then apply its stream until the
+/// connection drops.
+async fn sync_with_master(db: &Db, host: &str, port: u16) -> crate::Result<()> {
+    let socket = TcpStream::connect((host, port)).await?;
+    let limits = db.limits();
+    let mut conn = Connection::with_limits(socket, limits);
+
+    let config = db.config();
+    if !config.masterauth.is_empty() {
+        let mut auth = vec!["AUTH"];
+        if !config.masteruser.is_empty() {
+            auth.push(&config.masteruser);
+        }
+        auth.push(&config.masterauth);
+        request(&mut conn, &auth).await?;
+    }
+    request(&mut conn, &["PING"]).await?;
+    let listening_port = db.listening_port().to_string();
+    request(&mut conn, &["REPLCONF", "listening-port", &listening_port]).await?;
+
+    // Offsets in `PSYNC` are those of the next byte wanted.
+    let (replid, offset) = db.replication_position();
+    let offset = (offset + 1).to_string();
+    let reply = request(&mut conn, &["PSYNC", &replid, &offset]).await?;
+    let reply = reply.to_string();
+    let words: Vec<&str> = reply.split(' ').collect();
+    match &words[..] {
+        ["FULLRESYNC", replid, offset] => {
+            let offset = offset.parse().map_err(|_| "invalid FULLRESYNC offset")?;
+            db.set_link_state(LinkState::Sync);
+            conn.set_limits(Limits {
+                max_bulk_len: MAX_SNAPSHOT_LEN,
+                max_buffer_len: isize::MAX as usize,
+                ..limits
+            });
+            let Some(Frame::Bulk(snapshot)) = conn.read_frame().await? else {
+                return Err("expected a snapshot from the master".into());
+            };
+            conn.set_limits(limits);
+            db.load_full_resync(snapshot, replid.to_string(), offset)?;
+        }
+        ["CONTINUE", ..] => {}
+        _ => return Err(format!("unexpected reply to PSYNC: {}", reply).into()),
+    }
+    db.set_link_state(LinkState::Connected);
+
+    apply_stream(db, &mut conn).await
+}
+
+/// Send a request built from `args` to the master and return its reply.
+async fn request(conn: &mut Connection, args: &[&str]) -> crate::Result<Frame> {
+    let request = Frame::Array(
+        args.iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+            .collect(),
+    );
+    conn.write_frame(&request).await?;
+    match conn.read_frame().await? {
+        Some(Frame::Error(e)) => Err(e.into()),
+        Some(reply) => Ok(reply),
+        None => Err("connection closed by the master".into()),
+    }
+}
+
+/// Tell the master the stream was applied up to `offset`.
+async fn ack(conn: &mut Connection, offset: u64) -> crate::Result<()> {
+    let offset = offset.to_string();
+    let ack = Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"REPLCONF")),
+        Frame::Bulk(Bytes::from_static(b"ACK")),
+        Frame::Bulk(Bytes::from(offset)),
+    ]);
+    Ok(conn.write_frame(&ack).await?)
+}
+
+/// Apply the stream sent by the master, feeding it through to the
+/// append-only file and replicas of this server as is.
+async fn apply_stream(db: &Db, conn: &mut Connection) -> crate::Result<()> {
+    // The commands of a transaction being received, and the requests they
+    // came in, `MULTI` included.
+    let mut transaction: Option<(Vec<Command>, Vec<Frame>)> = None;
+    let mut acks = tokio::time::interval(ACK_PERIOD);
+
+    loop {
+        let frame = tokio::select! {
+            frame = conn.read_frame() => frame?.ok_or("connection closed by the master")?,
+            _ = acks.tick() => {
+                let (_, offset) = db.replication_position();
+                ack(conn, offset).await?;
+                continue;
+            }
+        };
+
+        match Command::from_frame(frame.clone())? {
+            Command::Multi(_) => transaction = Some((Vec::new(), vec![frame])),
+            Command::Exec(_) => {
+                let (queued, mut frames) = transaction.take().ok_or("EXEC without MULTI")?;
+                frames.push(frame);
+                db.exclusively(|| {
+                    db.logged(|| {
+                        for cmd in queued {
+                            cmd.apply(db);
+                        }
+                        (Frame::Null, frames)
+                    })
+                });
+            }
+            cmd if transaction.is_some() => {
+                let (queued, frames) = transaction.as_mut().expect("transaction in progress");
+                queued.push(cmd);
+                frames.push(frame);
+            }
+            // The acknowledgment is for the stream up to the request for it.
+            Command::ReplConf(cmd) if cmd.is_getack() => {
+                let (_, offset) = db.replication_position();
+                db.feed_replicas(&[frame]);
+                ack(conn, offset).await?;
+            }
+            cmd if cmd.is_write() => {
+                db.concurrently(|| db.logged(|| (cmd.apply(db), vec![frame])));
+            }
+            // `PING` and the like only count towards the offset.
+            _ => db.feed_replicas(&[frame]),
+        }
+    }
+}
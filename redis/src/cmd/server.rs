@@ -0,0 +1,197 @@
+use crate::db::{Db, ShutdownMode};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+
+/// Writes a snapshot of the keyspace to disk, replying once it is there.
+#[derive(Debug)]
+pub(crate) struct Save;
+
+impl Save {
+    /// # Format
+    ///
+    /// ```text
+    /// SAVE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<Save, ParseError> {
+        Ok(Save)
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match db.save() {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(e) => Frame::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+/// Starts writing a snapshot of the keyspace in the background and replies
+/// right away.
+#[derive(Debug)]
+pub(crate) struct BgSave;
+
+impl BgSave {
+    /// # Format
+    ///
+    /// ```text
+    /// BGSAVE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<BgSave, ParseError> {
+        Ok(BgSave)
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match db.bgsave() {
+            Ok(()) => Frame::Simple("Background saving started".to_string()),
+            Err(e) => Frame::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+/// Returns the Unix time of the last successful snapshot.
+#[derive(Debug)]
+pub(crate) struct LastSave;
+
+impl LastSave {
+    /// # Format
+    ///
+    /// ```text
+    /// LASTSAVE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<LastSave, ParseError> {
+        Ok(LastSave)
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        Frame::Integer(db.last_save() as i64)
+    }
+}
+
+/// Starts rewriting the append-only file from the current keyspace in the
+/// background and replies right away.
+#[derive(Debug)]
+pub(crate) struct BgRewriteAof;
+
+impl BgRewriteAof {
+    /// # Format
+    ///
+    /// ```text
+    /// BGREWRITEAOF
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<BgRewriteAof, ParseError> {
+        Ok(BgRewriteAof)
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match db.rewrite_aof() {
+            Ok(()) => Frame::Simple("Background append only file rewriting started".to_string()),
+            Err(e) => Frame::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+/// Reads and changes the settings of the server.
+#[derive(Debug)]
+pub(crate) enum Config {
+    Get(Vec<String>),
+    Set(Vec<(String, String)>),
+    Rewrite,
+}
+
+impl Config {
+    /// # Format
+    ///
+    /// ```text
+    /// CONFIG GET pattern [pattern ...]
+    /// CONFIG SET parameter value [parameter value ...]
+    /// CONFIG REWRITE
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Config, ParseError> {
+        let subcommand = parse.next_string()?;
+        match &subcommand.to_lowercase()[..] {
+            "get" => {
+                let mut patterns = vec![parse.next_string()?];
+                loop {
+                    match parse.next_string() {
+                        Ok(pattern) => patterns.push(pattern),
+                        Err(ParseError::EndOfStream) => return Ok(Config::Get(patterns)),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            "set" => {
+                let mut pairs = Vec::new();
+                loop {
+                    let name = match parse.next_string() {
+                        Ok(name) => name,
+                        Err(ParseError::EndOfStream) if !pairs.is_empty() => {
+                            return Ok(Config::Set(pairs));
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    pairs.push((name, parse.next_string()?));
+                }
+            }
+            "rewrite" => Ok(Config::Rewrite),
+            _ => Err(format!("unknown subcommand '{}'. Try CONFIG HELP.", subcommand).into()),
+        }
+    }
+
+    /// Apply the command. Changing settings may wait for the commands being
+    /// applied to finish, so this must not be called while applying one.
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let res = match self {
+            Config::Get(patterns) => {
+                return Frame::Map(
+                    db.config()
+                        .matching(&patterns)
+                        .into_iter()
+                        .map(|(name, value)| {
+                            (
+                                Frame::Bulk(Bytes::from(name)),
+                                Frame::Bulk(Bytes::from(value)),
+                            )
+                        })
+                        .collect(),
+                );
+            }
+            Config::Set(pairs) => db.set_config(pairs),
+            Config::Rewrite => db.config().rewrite(),
+        };
+        match res {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(e) => Frame::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+/// Stops the server, once every client has disconnected.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    mode: ShutdownMode,
+}
+
+impl Shutdown {
+    /// # Format
+    ///
+    /// ```text
+    /// SHUTDOWN [NOSAVE | SAVE]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Shutdown, ParseError> {
+        let mode = match parse.next_string() {
+            Ok(flag) if flag.eq_ignore_ascii_case("save") => ShutdownMode::Save,
+            Ok(flag) if flag.eq_ignore_ascii_case("nosave") => ShutdownMode::NoSave,
+            Ok(_) => return Err("syntax error".into()),
+            Err(ParseError::EndOfStream) => ShutdownMode::Default,
+            Err(e) => return Err(e),
+        };
+        Ok(Shutdown { mode })
+    }
+
+    /// Ask the server to shut down. There is no reply: the connection is
+    /// closed along with every other one.
+    pub(crate) fn apply(self, db: &Db) {
+        db.shutdown(self.mode);
+    }
+}
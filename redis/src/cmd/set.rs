@@ -0,0 +1,578 @@
+use crate::cmd::keys::parse_keys;
+use crate::db::{Db, Entry, Slot, Value, WrongType};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError, parse_int};
+
+use bytes::Bytes;
+use std::collections::HashSet;
+
+/// Returns the set stored in `slot`, or `None` if the key does not exist.
+fn set(slot: &Option<Entry>) -> Result<Option<&HashSet<Bytes>>, WrongType> {
+    slot.as_ref().map(|entry| entry.value.as_set()).transpose()
+}
+
+/// Returns the set stored in `slot`, or `None` if the key does not exist.
+fn set_mut(slot: &mut Slot) -> Result<Option<&mut HashSet<Bytes>>, WrongType> {
+    set(slot)?;
+    slot.as_mut()
+        .map(|entry| entry.value.as_set_mut())
+        .transpose()
+}
+
+/// Build a set reply of bulk strings.
+fn members<'a>(members: impl IntoIterator<Item = &'a Bytes>) -> Frame {
+    Frame::Set(members.into_iter().cloned().map(Frame::Bulk).collect())
+}
+
+/// Parse an optional positive count, as taken by `SPOP`.
+fn parse_count(parse: &mut Parse) -> Result<Option<usize>, ParseError> {
+    match parse.next_int() {
+        Ok(count) => Ok(Some(
+            usize::try_from(count).map_err(|_| "value is out of range, must be positive")?,
+        )),
+        Err(ParseError::EndOfStream) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Add the specified members to the set stored at `key`, creating the set if
+/// needed.
+///
+/// Replies with the number of members that were not already in the set.
+#[derive(Debug)]
+pub(crate) struct SAdd {
+    key: Bytes,
+    members: Vec<Bytes>,
+}
+
+impl SAdd {
+    /// # Format
+    ///
+    /// ```text
+    /// SADD key member [member ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SAdd, ParseError> {
+        let key = parse.next_bytes()?;
+        let members = parse_keys(parse)?;
+        Ok(SAdd { key, members })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            set(slot)?;
+            let set = slot
+                .get_or_insert_with(|| Entry::new(Value::Set(HashSet::new())))
+                .value
+                .as_set_mut()?;
+
+            let added = self
+                .members
+                .into_iter()
+                .filter(|member| set.insert(member.clone()))
+                .count();
+            Ok(Frame::Integer(added as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Remove the specified members from the set stored at `key`. The key is
+/// deleted along with its last member.
+///
+/// Replies with the number of members that were removed.
+#[derive(Debug)]
+pub(crate) struct SRem {
+    key: Bytes,
+    members: Vec<Bytes>,
+}
+
+impl SRem {
+    /// # Format
+    ///
+    /// ```text
+    /// SREM key member [member ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SRem, ParseError> {
+        let key = parse.next_bytes()?;
+        let members = parse_keys(parse)?;
+        Ok(SRem { key, members })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(set) = set_mut(slot)? else {
+                return Ok(Frame::Integer(0));
+            };
+
+            let removed = self
+                .members
+                .iter()
+                .filter(|member| set.remove(*member))
+                .count();
+            Ok(Frame::Integer(removed as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns all the members of the set stored at `key`.
+#[derive(Debug)]
+pub(crate) struct SMembers {
+    key: Bytes,
+}
+
+impl SMembers {
+    /// # Format
+    ///
+    /// ```text
+    /// SMEMBERS key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SMembers, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(SMembers { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            Ok(members(set(slot)?.into_iter().flatten()))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns whether each of the specified members belongs to the set stored at
+/// `key`.
+///
+/// `SISMEMBER` replies with a single 0 or 1, `SMISMEMBER` with an array of
+/// them, one per member.
+#[derive(Debug)]
+pub(crate) struct SIsMember {
+    key: Bytes,
+    members: Vec<Bytes>,
+
+    /// Reply with an array, as `SMISMEMBER` does.
+    multi: bool,
+}
+
+impl SIsMember {
+    /// # Format
+    ///
+    /// ```text
+    /// SISMEMBER key member
+    /// SMISMEMBER key member [member ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, multi: bool) -> Result<SIsMember, ParseError> {
+        let key = parse.next_bytes()?;
+        let members = if multi {
+            parse_keys(parse)?
+        } else {
+            vec![parse.next_bytes()?]
+        };
+
+        Ok(SIsMember {
+            key,
+            members,
+            multi,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let set = set(slot)?;
+            let mut found = self.members.iter().map(|member| {
+                let found = set.is_some_and(|set| set.contains(member));
+                Frame::Integer(found as i64)
+            });
+
+            Ok(if self.multi {
+                Frame::Array(found.collect())
+            } else {
+                found.next().unwrap()
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the number of members of the set stored at `key`.
+#[derive(Debug)]
+pub(crate) struct SCard {
+    key: Bytes,
+}
+
+impl SCard {
+    /// # Format
+    ///
+    /// ```text
+    /// SCARD key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SCard, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(SCard { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let len = set(slot)?.map_or(0, HashSet::len);
+            Ok(Frame::Integer(len as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Removes and returns random members of the set stored at `key`.
+///
+/// Without `count`, replies with a single member, or nil when the key does not
+/// exist. With `count`, replies with an array of up to `count` members.
+#[derive(Debug)]
+pub(crate) struct SPop {
+    key: Bytes,
+    count: Option<usize>,
+}
+
+impl SPop {
+    /// # Format
+    ///
+    /// ```text
+    /// SPOP key [count]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SPop, ParseError> {
+        let key = parse.next_bytes()?;
+        let count = parse_count(parse)?;
+        Ok(SPop { key, count })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(set) = set_mut(slot)? else {
+                return Ok(match self.count {
+                    None => Frame::Null,
+                    Some(_) => Frame::Set(vec![]),
+                });
+            };
+
+            let Some(count) = self.count else {
+                let member = set
+                    .iter()
+                    .nth(fastrand::usize(..set.len()))
+                    .unwrap()
+                    .clone();
+                set.remove(&member);
+                return Ok(Frame::Bulk(member));
+            };
+
+            let popped: Vec<Bytes> = if count >= set.len() {
+                set.drain().collect()
+            } else {
+                let popped = fastrand::choose_multiple(set.iter().cloned(), count);
+                for member in &popped {
+                    set.remove(member);
+                }
+                popped
+            };
+            Ok(members(&popped))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns random members of the set stored at `key`, without removing them.
+///
+/// Without `count`, replies with a single member, or nil when the key does not
+/// exist. A positive `count` returns that many distinct members, or the whole
+/// set if it is smaller. A negative `count` returns exactly `-count` members,
+/// which may repeat.
+#[derive(Debug)]
+pub(crate) struct SRandMember {
+    key: Bytes,
+    count: Option<i64>,
+}
+
+impl SRandMember {
+    /// # Format
+    ///
+    /// ```text
+    /// SRANDMEMBER key [count]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SRandMember, ParseError> {
+        let key = parse.next_bytes()?;
+
+        let count = match parse.next_int() {
+            Ok(count) => Some(count),
+            Err(ParseError::EndOfStream) => None,
+            Err(err) => return Err(err),
+        };
+
+        Ok(SRandMember { key, count })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        // A negative count is not bounded by the size of the set, so it is
+        // held to the longest array a reply may hold.
+        if let Some(count) = self.count
+            && count < 0
+            && count.unsigned_abs() > db.limits().max_array_len as u64
+        {
+            return Frame::Error("ERR value is out of range".to_string());
+        }
+
+        db.with_entry(&self.key, |slot| {
+            let Some(set) = set(slot)? else {
+                return Ok(match self.count {
+                    None => Frame::Null,
+                    Some(_) => Frame::array(),
+                });
+            };
+
+            let all: Vec<&Bytes> = set.iter().collect();
+            let picked = match self.count {
+                None => return Ok(Frame::Bulk(all[fastrand::usize(..all.len())].clone())),
+                Some(count) if count >= 0 => {
+                    let count = count as usize;
+                    if count >= all.len() {
+                        all
+                    } else {
+                        fastrand::choose_multiple(all, count)
+                    }
+                }
+                // A negative count may pick the same member more than once,
+                // so this is not a set.
+                Some(count) => {
+                    let mut response = Frame::array();
+                    for _ in 0..count.unsigned_abs() {
+                        response.push_bulk(all[fastrand::usize(..all.len())].clone());
+                    }
+                    return Ok(response);
+                }
+            };
+            Ok(Frame::Array(
+                picked.into_iter().cloned().map(Frame::Bulk).collect(),
+            ))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// An operation combining several sets.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+impl SetOp {
+    /// Combine `sets`, where `None` stands for a key that does not exist and
+    /// so behaves like an empty set.
+    fn combine(self, sets: &[Option<&HashSet<Bytes>>]) -> HashSet<Bytes> {
+        match self {
+            SetOp::Inter => {
+                let Some(sets) = sets.iter().copied().collect::<Option<Vec<_>>>() else {
+                    return HashSet::new();
+                };
+
+                // Probe the others with the members of the smallest set.
+                let smallest = sets.iter().min_by_key(|set| set.len()).unwrap();
+                smallest
+                    .iter()
+                    .filter(|member| sets.iter().all(|set| set.contains(*member)))
+                    .cloned()
+                    .collect()
+            }
+            SetOp::Union => sets
+                .iter()
+                .flatten()
+                .flat_map(|set| set.iter().cloned())
+                .collect(),
+            SetOp::Diff => {
+                let (first, rest) = sets.split_first().unwrap();
+                first
+                    .iter()
+                    .flat_map(|set| set.iter())
+                    .filter(|member| !rest.iter().flatten().any(|set| set.contains(*member)))
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Returns the intersection (`SINTER`), union (`SUNION`) or difference
+/// (`SDIFF`) of the sets stored at `keys`. Keys that do not exist are treated
+/// as empty sets.
+///
+/// The `STORE` variants save the result at `destination` instead, replacing
+/// whatever was there, and reply with its size. All the keys involved are
+/// locked together through `Db::with_entries`, so the result reflects a single
+/// point in time.
+#[derive(Debug)]
+pub(crate) struct SetCombine {
+    op: SetOp,
+    keys: Vec<Bytes>,
+    destination: Option<Bytes>,
+}
+
+impl SetCombine {
+    /// # Format
+    ///
+    /// ```text
+    /// SINTER key [key ...]
+    /// SUNION key [key ...]
+    /// SDIFF key [key ...]
+    /// SINTERSTORE destination key [key ...]
+    /// SUNIONSTORE destination key [key ...]
+    /// SDIFFSTORE destination key [key ...]
+    /// ```
+    pub(crate) fn parse_frames(
+        parse: &mut Parse,
+        op: SetOp,
+        store: bool,
+    ) -> Result<SetCombine, ParseError> {
+        let destination = if store {
+            Some(parse.next_bytes()?)
+        } else {
+            None
+        };
+        let keys = parse_keys(parse)?;
+
+        Ok(SetCombine {
+            op,
+            keys,
+            destination,
+        })
+    }
+
+    /// Returns `true` for the `*STORE` forms, which write their result to a
+    /// key.
+    pub(crate) fn stores(&self) -> bool {
+        self.destination.is_some()
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let mut locked = self.keys.clone();
+        locked.extend(self.destination.iter().cloned());
+
+        db.with_entries(&locked, |slots| {
+            let sets = self
+                .keys
+                .iter()
+                .map(|key| {
+                    slots
+                        .entry(key)
+                        .map(|entry| entry.value.as_set())
+                        .transpose()
+                })
+                .collect::<Result<Vec<_>, WrongType>>()?;
+            let result = self.op.combine(&sets);
+
+            Ok(match self.destination {
+                None => members(&result),
+                Some(destination) => {
+                    let len = result.len();
+                    **slots.slot(&destination) = Some(Entry::new(Value::Set(result)));
+                    Frame::Integer(len as i64)
+                }
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the size of the intersection of the sets stored at `keys`, without
+/// building it.
+///
+/// With `LIMIT`, counting stops as soon as `limit` members have been found. A
+/// limit of 0 means no limit.
+#[derive(Debug)]
+pub(crate) struct SInterCard {
+    keys: Vec<Bytes>,
+    limit: usize,
+}
+
+impl SInterCard {
+    /// # Format
+    ///
+    /// ```text
+    /// SINTERCARD numkeys key [key ...] [LIMIT limit]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SInterCard, ParseError> {
+        let numkeys = parse.next_int()?;
+        if numkeys <= 0 {
+            return Err("numkeys should be greater than 0".into());
+        }
+
+        let mut args = parse.remaining_bytes()?;
+        if args.len() < numkeys as usize {
+            return Err("Number of keys can't be greater than number of args".into());
+        }
+        let options = args.split_off(numkeys as usize);
+
+        let limit = match &options[..] {
+            [] => 0,
+            [option, limit] if option.eq_ignore_ascii_case(b"LIMIT") => {
+                let limit = parse_int(limit).ok_or("value is not an integer or out of range")?;
+                usize::try_from(limit).map_err(|_| "LIMIT can't be negative")?
+            }
+            _ => return Err("syntax error".into()),
+        };
+
+        Ok(SInterCard { keys: args, limit })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entries(&self.keys, |slots| {
+            let sets = self
+                .keys
+                .iter()
+                .map(|key| {
+                    slots
+                        .entry(key)
+                        .map(|entry| entry.value.as_set())
+                        .transpose()
+                })
+                .collect::<Result<Option<Vec<_>>, WrongType>>()?;
+            let Some(sets) = sets else {
+                return Ok(Frame::Integer(0));
+            };
+
+            let smallest = sets.iter().min_by_key(|set| set.len()).unwrap();
+            let common = smallest
+                .iter()
+                .filter(|member| sets.iter().all(|set| set.contains(*member)));
+            let count = match self.limit {
+                0 => common.count(),
+                limit => common.take(limit).count(),
+            };
+            Ok(Frame::Integer(count as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_of(members: &[&'static str]) -> HashSet<Bytes> {
+        members.iter().map(|member| Bytes::from(*member)).collect()
+    }
+
+    #[test]
+    fn combine_treats_missing_keys_as_empty() {
+        let a = set_of(&["a", "b", "c"]);
+        let b = set_of(&["b", "c", "d"]);
+
+        assert_eq!(
+            SetOp::Inter.combine(&[Some(&a), Some(&b)]),
+            set_of(&["b", "c"])
+        );
+        assert_eq!(SetOp::Inter.combine(&[Some(&a), None]), set_of(&[]));
+        assert_eq!(
+            SetOp::Union.combine(&[Some(&a), None, Some(&b)]),
+            set_of(&["a", "b", "c", "d"])
+        );
+        assert_eq!(SetOp::Diff.combine(&[Some(&a), Some(&b)]), set_of(&["a"]));
+        assert_eq!(SetOp::Diff.combine(&[Some(&a), None]), a);
+        assert_eq!(SetOp::Diff.combine(&[None, Some(&b)]), set_of(&[]));
+    }
+}
@@ -0,0 +1,758 @@
+use crate::cmd::keys::parse_keys;
+use crate::db::{Db, Entry, Value, WrongType, now_ms};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError, parse_int};
+
+use bytes::{Bytes, BytesMut};
+
+/// Get the value of key.
+///
+/// If the key does not exist the special value nil is returned.
+#[derive(Debug)]
+pub(crate) struct Get {
+    /// Name of the key to get
+    key: Bytes,
+}
+
+impl Get {
+    /// Parse a `Get` instance from a received frame.
+    ///
+    /// The `GET` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// GET key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Get, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(Get { key })
+    }
+
+    /// Apply the `Get` command to the specified `Db` instance.
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match db.get(&self.key) {
+            // If a value is present, it is written to the client in "bulk"
+            // format.
+            Ok(Some(value)) => Frame::Bulk(value),
+            // If there is no value, `Null` is written.
+            Ok(None) => Frame::Null,
+            Err(err) => err.into(),
+        }
+    }
+}
+
+/// Set `key` to hold the string `value`.
+///
+/// If `key` already holds a value, it is overwritten, regardless of its type.
+/// Any previous time to live associated with the key is discarded on successful
+/// SET operation, unless `KEEPTTL` is given.
+///
+/// # Options
+///
+/// Currently, the following options are supported:
+///
+/// * EX `seconds` / PX `milliseconds` -- Set the specified expire time.
+/// * EXAT `timestamp` / PXAT `timestamp` -- Set the Unix time at which the
+///   key will expire, in seconds or milliseconds.
+/// * KEEPTTL -- Retain the time to live associated with the key.
+/// * NX -- Only set the key if it does not already exist.
+/// * XX -- Only set the key if it already exists.
+/// * GET -- Return the old value stored at key, or nil when key did not exist.
+#[derive(Debug)]
+pub(crate) struct Set {
+    /// the lookup key
+    key: Bytes,
+
+    /// the value to be stored
+    value: Bytes,
+
+    /// When to expire the key
+    expire: Option<SetExpire>,
+
+    /// Only set the key if this holds
+    condition: Option<SetCondition>,
+
+    /// Reply with the previous value instead of `OK`
+    get: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SetExpire {
+    /// Relative time to live, in milliseconds.
+    After(u64),
+    /// Absolute Unix time, in milliseconds.
+    At(u64),
+    KeepTtl,
+}
+
+impl SetExpire {
+    /// The deadline for a key currently expiring at `current`.
+    fn deadline(self, current: Option<u64>) -> Option<u64> {
+        match self {
+            SetExpire::After(millis) => Some(now_ms().saturating_add(millis)),
+            SetExpire::At(when) => Some(when),
+            SetExpire::KeepTtl => current,
+        }
+    }
+}
+
+/// Parse the argument of an `EX`, `PX`, `EXAT` or `PXAT` option. The option
+/// itself, upper-cased, has already been consumed.
+fn parse_expire(parse: &mut Parse, option: &str, command: &str) -> Result<SetExpire, ParseError> {
+    let amount = match parse.next_int() {
+        Ok(amount) => amount,
+        Err(ParseError::EndOfStream) => return Err("syntax error".into()),
+        Err(err) => return Err(err),
+    };
+
+    let scale = if option.starts_with("EX") { 1000 } else { 1 };
+    let millis = u64::try_from(amount)
+        .ok()
+        .filter(|&amount| amount > 0)
+        .and_then(|amount| amount.checked_mul(scale))
+        .ok_or_else(|| format!("invalid expire time in '{}' command", command))?;
+
+    Ok(if option.ends_with("AT") {
+        SetExpire::At(millis)
+    } else {
+        SetExpire::After(millis)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SetCondition {
+    Nx,
+    Xx,
+}
+
+impl Set {
+    /// Parse a `Set` instance from a received frame.
+    ///
+    /// The `SET` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+    ///   EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Set, ParseError> {
+        use ParseError::EndOfStream;
+
+        // Read the key to set. This is a required field
+        let key = parse.next_bytes()?;
+
+        // Read the value to set. This is a required field.
+        let value = parse.next_bytes()?;
+
+        let mut expire = None;
+        let mut condition = None;
+        let mut get = false;
+
+        // Attempt to parse the options. Each option may appear at most once,
+        // and the expiration options are mutually exclusive.
+        loop {
+            let option = match parse.next_string() {
+                Ok(s) => s.to_uppercase(),
+                // The `EndOfStream` error indicates there is no further data
+                // to parse. In this case, it is a normal run time situation
+                // and indicates there are no more `SET` options.
+                Err(EndOfStream) => break,
+                Err(err) => return Err(err),
+            };
+
+            match &option[..] {
+                "EX" | "PX" | "EXAT" | "PXAT" if expire.is_none() => {
+                    expire = Some(parse_expire(parse, &option, "set")?);
+                }
+                "KEEPTTL" if expire.is_none() => expire = Some(SetExpire::KeepTtl),
+                "NX" if condition.is_none() => condition = Some(SetCondition::Nx),
+                "XX" if condition.is_none() => condition = Some(SetCondition::Xx),
+                "GET" => get = true,
+                _ => return Err("syntax error".into()),
+            }
+        }
+
+        Ok(Set {
+            key,
+            value,
+            expire,
+            condition,
+            get,
+        })
+    }
+
+    /// Apply the `Set` command to the specified `Db` instance.
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let Set {
+            key,
+            value,
+            expire,
+            condition,
+            get,
+        } = self;
+
+        // A plain `SET key value` needs none of the bookkeeping below.
+        if expire.is_none() && condition.is_none() && !get {
+            db.set(key, value);
+            return Frame::Simple("OK".to_string());
+        }
+
+        db.with_entry(&key, |slot| {
+            // `GET` needs the previous value to be a string, but a plain `SET`
+            // overwrites any type.
+            let prev = match slot.as_ref() {
+                Some(entry) if get => Some(entry.value.as_string()?.clone()),
+                _ => None,
+            };
+
+            let proceed = match condition {
+                None => true,
+                Some(SetCondition::Nx) => slot.is_none(),
+                Some(SetCondition::Xx) => slot.is_some(),
+            };
+
+            if proceed {
+                let current = slot.as_ref().and_then(|entry| entry.expires_at);
+                let expires_at = expire.and_then(|expire| expire.deadline(current));
+                **slot = Some(Entry {
+                    value: Value::String(value),
+                    expires_at,
+                });
+            }
+
+            Ok(if get {
+                prev.map_or(Frame::Null, Frame::Bulk)
+            } else if proceed {
+                Frame::Simple("OK".to_string())
+            } else {
+                Frame::Null
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Increments the number stored at `key` by `increment`.
+///
+/// Backs `INCR`, `DECR`, `INCRBY` and `DECRBY`. If the key does not exist, it
+/// is set to 0 before performing the operation. An error is returned if the
+/// key contains a value that cannot be represented as a 64 bit signed integer,
+/// or if the result would overflow. The time to live of the key is preserved.
+#[derive(Debug)]
+pub(crate) struct IncrBy {
+    key: Bytes,
+    increment: i64,
+}
+
+impl IncrBy {
+    /// # Format
+    ///
+    /// ```text
+    /// INCR key
+    /// DECR key
+    /// INCRBY key increment
+    /// DECRBY key decrement
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, name: &str) -> Result<IncrBy, ParseError> {
+        let key = parse.next_bytes()?;
+
+        let increment = match name {
+            "incr" => 1,
+            "decr" => -1,
+            "incrby" => parse.next_int()?,
+            _ => parse
+                .next_int()?
+                .checked_neg()
+                .ok_or("decrement would overflow")?,
+        };
+
+        Ok(IncrBy { key, increment })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let current = match slot.as_ref() {
+                None => 0,
+                Some(entry) => match entry.value.as_string().map(|value| parse_int(value)) {
+                    Ok(Some(n)) => n,
+                    Ok(None) => {
+                        return Frame::Error("ERR value is not an integer or out of range".into());
+                    }
+                    Err(err) => return err.into(),
+                },
+            };
+
+            let Some(updated) = current.checked_add(self.increment) else {
+                return Frame::Error("ERR increment or decrement would overflow".into());
+            };
+
+            let value = Bytes::from(updated.to_string());
+            match slot.as_mut() {
+                Some(entry) => entry.value = Value::String(value),
+                None => **slot = Some(Entry::new(value)),
+            }
+
+            Frame::Integer(updated)
+        })
+    }
+}
+
+/// Increment the string representing a floating point number stored at `key`
+/// by the specified `increment`.
+///
+/// If the key does not exist, it is set to 0 before performing the operation.
+/// The new value is stored, and returned, in its shortest exact decimal form.
+#[derive(Debug)]
+pub(crate) struct IncrByFloat {
+    key: Bytes,
+    increment: f64,
+}
+
+impl IncrByFloat {
+    /// # Format
+    ///
+    /// ```text
+    /// INCRBYFLOAT key increment
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<IncrByFloat, ParseError> {
+        let key = parse.next_bytes()?;
+        let increment = parse_float(&parse.next_bytes()?).ok_or("value is not a valid float")?;
+        Ok(IncrByFloat { key, increment })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let current = match slot.as_ref() {
+                None => 0.0,
+                Some(entry) => match entry.value.as_string().map(|value| parse_float(value)) {
+                    Ok(Some(n)) => n,
+                    Ok(None) => return Frame::Error("ERR value is not a valid float".into()),
+                    Err(err) => return err.into(),
+                },
+            };
+
+            let updated = current + self.increment;
+            if !updated.is_finite() {
+                return Frame::Error("ERR increment would produce NaN or Infinity".into());
+            }
+
+            let value = Bytes::from(updated.to_string());
+            match slot.as_mut() {
+                Some(entry) => entry.value = Value::String(value.clone()),
+                None => **slot = Some(Entry::new(value.clone())),
+            }
+
+            Frame::Bulk(value)
+        })
+    }
+}
+
+/// Parse a finite floating point number. Redis refuses to store NaN or the
+/// infinities, and so do we.
+pub(crate) fn parse_float(src: &[u8]) -> Option<f64> {
+    std::str::from_utf8(src)
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+}
+
+/// Largest string `SETRANGE` may grow a value to, matching Redis's default
+/// `proto-max-bulk-len`.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// Appends `value` at the end of the string stored at `key`, creating the key
+/// if it does not exist.
+///
+/// Replies with the length of the string after the append operation.
+#[derive(Debug)]
+pub(crate) struct Append {
+    key: Bytes,
+    value: Bytes,
+}
+
+impl Append {
+    /// # Format
+    ///
+    /// ```text
+    /// APPEND key value
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Append, ParseError> {
+        let key = parse.next_bytes()?;
+        let value = parse.next_bytes()?;
+        Ok(Append { key, value })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            if let Some(entry) = slot.as_ref()
+                && let Err(err) = entry.value.as_string()
+            {
+                return err.into();
+            }
+
+            match slot.as_mut() {
+                Some(entry) => {
+                    let value = entry.value.as_string_mut().unwrap();
+
+                    // Reuses the existing allocation when nobody else holds a
+                    // reference to the value, so repeated appends are amortized.
+                    let mut buf = BytesMut::from(std::mem::take(value));
+                    buf.extend_from_slice(&self.value);
+                    *value = buf.freeze();
+                    Frame::Integer(value.len() as i64)
+                }
+                None => {
+                    let len = self.value.len();
+                    **slot = Some(Entry::new(self.value));
+                    Frame::Integer(len as i64)
+                }
+            }
+        })
+    }
+}
+
+/// Returns the length of the string value stored at `key`, or 0 when the key
+/// does not exist.
+#[derive(Debug)]
+pub(crate) struct Strlen {
+    key: Bytes,
+}
+
+impl Strlen {
+    /// # Format
+    ///
+    /// ```text
+    /// STRLEN key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Strlen, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(Strlen { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        match db.get(&self.key) {
+            Ok(value) => Frame::Integer(value.map_or(0, |value| value.len()) as i64),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+/// Returns the substring of the string value stored at `key`, determined by
+/// the offsets `start` and `end` (both are inclusive).
+///
+/// Negative offsets count from the end of the string, and ranges exceeding the
+/// string are limited to its actual length.
+#[derive(Debug)]
+pub(crate) struct GetRange {
+    key: Bytes,
+    start: i64,
+    end: i64,
+}
+
+impl GetRange {
+    /// # Format
+    ///
+    /// ```text
+    /// GETRANGE key start end
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<GetRange, ParseError> {
+        let key = parse.next_bytes()?;
+        let start = parse.next_int()?;
+        let end = parse.next_int()?;
+        Ok(GetRange { key, start, end })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let value = match db.get(&self.key) {
+            Ok(value) => value.unwrap_or_default(),
+            Err(err) => return err.into(),
+        };
+        let len = value.len() as i64;
+
+        let start = if self.start < 0 {
+            len + self.start
+        } else {
+            self.start
+        }
+        .max(0);
+        let end = if self.end < 0 {
+            len + self.end
+        } else {
+            self.end
+        }
+        .min(len - 1);
+
+        if len == 0 || start > end {
+            return Frame::Bulk(Bytes::new());
+        }
+
+        // Slicing shares the stored allocation instead of copying.
+        Frame::Bulk(value.slice(start as usize..=end as usize))
+    }
+}
+
+/// Overwrites part of the string stored at `key`, starting at the specified
+/// offset, for the entire length of `value`.
+///
+/// If the offset is larger than the current length of the string, the string
+/// is padded with zero-bytes to make offset fit. Non-existing keys are
+/// considered as empty strings. Replies with the length of the string after
+/// it was modified.
+#[derive(Debug)]
+pub(crate) struct SetRange {
+    key: Bytes,
+    offset: usize,
+    value: Bytes,
+}
+
+impl SetRange {
+    /// # Format
+    ///
+    /// ```text
+    /// SETRANGE key offset value
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<SetRange, ParseError> {
+        let key = parse.next_bytes()?;
+        let offset = usize::try_from(parse.next_int()?).map_err(|_| "offset is out of range")?;
+        let value = parse.next_bytes()?;
+        Ok(SetRange { key, offset, value })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let end = self.offset.saturating_add(self.value.len());
+        if !self.value.is_empty() && end > MAX_STRING_LEN {
+            return Frame::Error(
+                "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+            );
+        }
+
+        db.with_entry(&self.key, |slot| {
+            let len = match slot.as_ref().map(|entry| entry.value.as_string()) {
+                Some(Ok(value)) => value.len(),
+                Some(Err(err)) => return err.into(),
+                None => 0,
+            };
+
+            // An empty value never creates or grows the key.
+            if self.value.is_empty() {
+                return Frame::Integer(len as i64);
+            }
+
+            let entry = slot.get_or_insert_with(|| Entry::new(Bytes::new()));
+            let value = entry.value.as_string_mut().unwrap();
+
+            let mut buf = BytesMut::from(std::mem::take(value));
+            if buf.len() < end {
+                buf.resize(end, 0);
+            }
+            buf[self.offset..end].copy_from_slice(&self.value);
+            *value = buf.freeze();
+
+            Frame::Integer(value.len() as i64)
+        })
+    }
+}
+
+/// Get the value of `key` and delete the key.
+#[derive(Debug)]
+pub(crate) struct GetDel {
+    key: Bytes,
+}
+
+impl GetDel {
+    /// # Format
+    ///
+    /// ```text
+    /// GETDEL key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<GetDel, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(GetDel { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(entry) = slot.as_ref() else {
+                return Frame::Null;
+            };
+
+            match entry.value.as_string() {
+                Ok(value) => {
+                    let value = value.clone();
+                    **slot = None;
+                    Frame::Bulk(value)
+                }
+                Err(err) => err.into(),
+            }
+        })
+    }
+}
+
+/// Get the value of `key` and optionally set its expiration.
+#[derive(Debug)]
+pub(crate) struct GetEx {
+    key: Bytes,
+
+    /// `None` leaves the deadline alone, `Some(None)` removes it (`PERSIST`).
+    expire: Option<Option<SetExpire>>,
+}
+
+impl GetEx {
+    /// # Format
+    ///
+    /// ```text
+    /// GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+    ///   PXAT unix-time-milliseconds | PERSIST]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<GetEx, ParseError> {
+        let key = parse.next_bytes()?;
+
+        let expire = match parse.next_string() {
+            Ok(option) => {
+                let option = option.to_uppercase();
+                match &option[..] {
+                    "EX" | "PX" | "EXAT" | "PXAT" => {
+                        Some(Some(parse_expire(parse, &option, "getex")?))
+                    }
+                    "PERSIST" => Some(None),
+                    _ => return Err("syntax error".into()),
+                }
+            }
+            Err(ParseError::EndOfStream) => None,
+            Err(err) => return Err(err),
+        };
+
+        // Only a single option is accepted.
+        match parse.next_bytes() {
+            Err(ParseError::EndOfStream) => Ok(GetEx { key, expire }),
+            Ok(_) => Err("syntax error".into()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(entry) = slot.as_ref() else {
+                return Frame::Null;
+            };
+
+            let value = match entry.value.as_string() {
+                Ok(value) => value.clone(),
+                Err(err) => return err.into(),
+            };
+
+            let Some(expire) = self.expire else {
+                return Frame::Bulk(value);
+            };
+
+            if let Some(entry) = slot.as_mut() {
+                entry.expires_at = expire.and_then(|expire| expire.deadline(entry.expires_at));
+                if entry.expires_at.is_some_and(|when| when <= now_ms()) {
+                    **slot = None;
+                }
+            }
+
+            Frame::Bulk(value)
+        })
+    }
+}
+
+/// Returns the values of all specified keys. For every key that does not
+/// exist, nil is returned.
+#[derive(Debug)]
+pub(crate) struct MGet {
+    keys: Vec<Bytes>,
+}
+
+impl MGet {
+    /// # Format
+    ///
+    /// ```text
+    /// MGET key [key ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<MGet, ParseError> {
+        let keys = parse_keys(parse)?;
+        Ok(MGet { keys })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        // Reading every key under one lock set gives the client a consistent
+        // snapshot, even while an `MSET` touching the same keys is running.
+        db.with_entries(&self.keys, |slots| {
+            let values = self
+                .keys
+                .iter()
+                .map(|key| match slots.entry(key) {
+                    // Keys holding other types are reported as missing.
+                    Some(Entry {
+                        value: Value::String(value),
+                        ..
+                    }) => Frame::Bulk(value.clone()),
+                    _ => Frame::Null,
+                })
+                .collect();
+            Frame::Array(values)
+        })
+    }
+}
+
+/// Sets the given keys to their respective values.
+///
+/// Backs `MSET` and `MSETNX`. Both are atomic, so all given keys are set at
+/// once. `MSETNX` does not perform any operation at all if even a single key
+/// already exists.
+#[derive(Debug)]
+pub(crate) struct MSet {
+    pairs: Vec<(Bytes, Bytes)>,
+
+    /// Only set the keys if none of them exists.
+    nx: bool,
+}
+
+impl MSet {
+    /// # Format
+    ///
+    /// ```text
+    /// MSET key value [key value ...]
+    /// MSETNX key value [key value ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, nx: bool) -> Result<MSet, ParseError> {
+        let mut pairs = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+
+        let rest = parse.remaining_bytes()?;
+        if rest.len() % 2 != 0 {
+            return Err(ParseError::EndOfStream);
+        }
+
+        let mut rest = rest.into_iter();
+        while let (Some(key), Some(value)) = (rest.next(), rest.next()) {
+            pairs.push((key, value));
+        }
+
+        Ok(MSet { pairs, nx })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        let keys: Vec<Bytes> = self.pairs.iter().map(|(key, _)| key.clone()).collect();
+
+        db.with_entries(&keys, |slots| {
+            if self.nx && keys.iter().any(|key| slots.slot(key).is_some()) {
+                return Frame::Integer(0);
+            }
+
+            // Later pairs win when a key is repeated, as with Redis.
+            for (key, value) in self.pairs {
+                **slots.slot(&key) = Some(Entry::new(value));
+            }
+
+            if self.nx {
+                Frame::Integer(1)
+            } else {
+                Frame::Simple("OK".to_string())
+            }
+        })
+    }
+}
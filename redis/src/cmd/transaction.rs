@@ -0,0 +1,241 @@
+use crate::cmd::Command;
+use crate::cmd::keys::parse_keys;
+use crate::db::{Db, WatchSet};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+
+use bytes::Bytes;
+use std::mem;
+
+/// Marks the start of a transaction. Subsequent commands are queued until
+/// `EXEC` runs them atomically, or `DISCARD` drops them.
+#[derive(Debug)]
+pub(crate) struct Multi;
+
+impl Multi {
+    /// # Format
+    ///
+    /// ```text
+    /// MULTI
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<Multi, ParseError> {
+        Ok(Multi)
+    }
+}
+
+/// Executes the commands queued since `MULTI`.
+///
+/// Replies with an array holding the reply of each command, or nil if a
+/// watched key was modified, in which case nothing is executed.
+#[derive(Debug)]
+pub(crate) struct Exec;
+
+impl Exec {
+    /// # Format
+    ///
+    /// ```text
+    /// EXEC
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<Exec, ParseError> {
+        Ok(Exec)
+    }
+}
+
+/// Drops the commands queued since `MULTI` and unwatches every key.
+#[derive(Debug)]
+pub(crate) struct Discard;
+
+impl Discard {
+    /// # Format
+    ///
+    /// ```text
+    /// DISCARD
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<Discard, ParseError> {
+        Ok(Discard)
+    }
+}
+
+/// Marks the given keys to be watched. The next `EXEC` only runs its
+/// transaction if none of them was modified in the meantime.
+#[derive(Debug)]
+pub(crate) struct Watch {
+    keys: Vec<Bytes>,
+}
+
+impl Watch {
+    /// # Format
+    ///
+    /// ```text
+    /// WATCH key [key ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Watch, ParseError> {
+        Ok(Watch {
+            keys: parse_keys(parse)?,
+        })
+    }
+}
+
+/// Forgets about all watched keys.
+#[derive(Debug)]
+pub(crate) struct Unwatch;
+
+impl Unwatch {
+    /// # Format
+    ///
+    /// ```text
+    /// UNWATCH
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> Result<Unwatch, ParseError> {
+        Ok(Unwatch)
+    }
+
+    /// Returns the reply. Inside a transaction, `EXEC` has already unwatched
+    /// every key by the time this runs, so there is nothing left to do.
+    pub(crate) fn apply(self) -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+}
+
+/// The request for a transaction command taking no arguments.
+fn marker(name: &'static str) -> Frame {
+    let mut frame = Frame::array();
+    frame.push_bulk(Bytes::from_static(name.as_bytes()));
+    frame
+}
+
+/// The transaction state of a single connection.
+#[derive(Debug)]
+pub(crate) struct Transaction {
+    /// Commands queued since `MULTI`, along with the request each was parsed
+    /// from, or `None` outside of a transaction.
+    queued: Option<Vec<(Command, Frame)>>,
+
+    /// Set when a command could not be queued, which makes `EXEC` fail.
+    aborted: bool,
+
+    watched: WatchSet,
+}
+
+impl Transaction {
+    pub(crate) fn new(db: &Db) -> Transaction {
+        Transaction {
+            queued: None,
+            aborted: false,
+            watched: WatchSet::new(db),
+        }
+    }
+
+    /// Returns `true` between `MULTI` and `EXEC` or `DISCARD`.
+    pub(crate) fn is_active(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    pub(crate) fn multi(&mut self) -> Frame {
+        if self.is_active() {
+            return Frame::Error("ERR MULTI calls can not be nested".to_string());
+        }
+
+        self.queued = Some(Vec::new());
+        Frame::Simple("OK".to_string())
+    }
+
+    /// Queue a command received inside the transaction. `cmd` is the result of
+    /// parsing the request; a command that failed to parse aborts the
+    /// transaction. `request` is the frame it was parsed from, to be
+    /// propagated once executed.
+    pub(crate) fn queue(&mut self, cmd: crate::Result<Command>, request: Frame) -> Frame {
+        let queued = self.queued.as_mut().expect("no transaction in progress");
+
+        match cmd {
+            Ok(Command::Unknown(cmd)) => {
+                self.aborted = true;
+                cmd.apply()
+            }
+            Ok(cmd) => {
+                queued.push((cmd, request));
+                Frame::Simple("QUEUED".to_string())
+            }
+            Err(e) => {
+                self.aborted = true;
+                Frame::Error(e.to_string())
+            }
+        }
+    }
+
+    /// Run the queued commands, with every other client held off, unless the
+    /// transaction was aborted or a watched key was modified.
+    pub(crate) fn exec(&mut self, db: &Db) -> Frame {
+        let Some(queued) = self.queued.take() else {
+            return Frame::Error("ERR EXEC without MULTI".to_string());
+        };
+
+        let response = if mem::take(&mut self.aborted) {
+            Frame::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+        } else {
+            db.exclusively(|| {
+                if self.watched.is_modified() {
+                    return Frame::Null;
+                }
+
+                // A command failing at runtime does not stop the others; its
+                // error is simply part of the reply.
+                if !db.propagating() {
+                    return Frame::Array(
+                        queued.into_iter().map(|(cmd, _)| cmd.apply(db)).collect(),
+                    );
+                }
+
+                // The writes are propagated between `MULTI` and `EXEC` so that
+                // they are replayed atomically too.
+                db.logged(|| {
+                    let mut log = Vec::new();
+                    let replies = queued
+                        .into_iter()
+                        .map(|(cmd, request)| {
+                            let propagation = cmd.propagation();
+                            let reply = cmd.apply(db);
+                            log.extend(propagation.frames(request, &reply, db));
+                            reply
+                        })
+                        .collect();
+
+                    if !log.is_empty() {
+                        log.insert(0, marker("MULTI"));
+                        log.push(marker("EXEC"));
+                    }
+                    (Frame::Array(replies), log)
+                })
+            })
+        };
+
+        self.watched.clear();
+        response
+    }
+
+    pub(crate) fn discard(&mut self) -> Frame {
+        if self.queued.take().is_none() {
+            return Frame::Error("ERR DISCARD without MULTI".to_string());
+        }
+
+        self.aborted = false;
+        self.watched.clear();
+        Frame::Simple("OK".to_string())
+    }
+
+    pub(crate) fn watch(&mut self, cmd: Watch) -> Frame {
+        if self.is_active() {
+            return Frame::Error("ERR WATCH inside MULTI is not allowed".to_string());
+        }
+
+        for key in cmd.keys {
+            self.watched.watch(key);
+        }
+        Frame::Simple("OK".to_string())
+    }
+
+    pub(crate) fn unwatch(&mut self, cmd: Unwatch) -> Frame {
+        self.watched.clear();
+        cmd.apply()
+    }
+}
@@ -0,0 +1,649 @@
+use crate::cmd::keys::parse_keys;
+use crate::cmd::list::resolve_range;
+use crate::db::{Db, Entry, SortedSet, Value, WrongType};
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError, parse_int};
+
+use bytes::Bytes;
+
+/// Returns the sorted set stored in `slot`, or `None` if the key does not
+/// exist.
+fn zset(slot: &Option<Entry>) -> Result<Option<&SortedSet>, WrongType> {
+    slot.as_ref().map(|entry| entry.value.as_zset()).transpose()
+}
+
+/// Parse a score. Unlike string increments, scores may be infinite; only NaN
+/// is refused.
+fn parse_score(src: &[u8]) -> Option<f64> {
+    std::str::from_utf8(src)
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|n| !n.is_nan())
+}
+
+/// Adds all the specified members with the specified scores to the sorted set
+/// stored at `key`, updating the score of members that already exist.
+///
+/// Backs `ZADD` and `ZINCRBY`, which is `ZADD` with the `INCR` option.
+///
+/// Replies with the number of members added, or added and updated with `CH`.
+/// With `INCR`, replies with the new score of the member instead, or nil if
+/// the update was blocked by one of the conditions.
+#[derive(Debug)]
+pub(crate) struct ZAdd {
+    key: Bytes,
+    pairs: Vec<(f64, Bytes)>,
+
+    /// Only add new members.
+    nx: bool,
+    /// Only update existing members.
+    xx: bool,
+    /// Only update to a greater score.
+    gt: bool,
+    /// Only update to a lesser score.
+    lt: bool,
+
+    /// Count updated members in the reply too.
+    ch: bool,
+
+    /// Increment the score rather than setting it.
+    incr: bool,
+}
+
+impl ZAdd {
+    /// # Format
+    ///
+    /// ```text
+    /// ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<ZAdd, ParseError> {
+        let key = parse.next_bytes()?;
+        let args = parse.remaining_bytes()?;
+        if args.len() < 2 {
+            return Err(ParseError::EndOfStream);
+        }
+
+        let mut zadd = ZAdd {
+            key,
+            pairs: Vec::new(),
+            nx: false,
+            xx: false,
+            gt: false,
+            lt: false,
+            ch: false,
+            incr: false,
+        };
+
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.peek() {
+            match &arg.to_ascii_uppercase()[..] {
+                b"NX" => zadd.nx = true,
+                b"XX" => zadd.xx = true,
+                b"GT" => zadd.gt = true,
+                b"LT" => zadd.lt = true,
+                b"CH" => zadd.ch = true,
+                b"INCR" => zadd.incr = true,
+                _ => break,
+            }
+            args.next();
+        }
+
+        let rest: Vec<Bytes> = args.collect();
+        if rest.is_empty() || !rest.len().is_multiple_of(2) {
+            return Err("syntax error".into());
+        }
+        for pair in rest.chunks(2) {
+            let score = parse_score(&pair[0]).ok_or("value is not a valid float")?;
+            zadd.pairs.push((score, pair[1].clone()));
+        }
+
+        if zadd.nx && zadd.xx {
+            return Err("XX and NX options at the same time are not compatible".into());
+        }
+        if (zadd.gt && zadd.lt) || (zadd.nx && (zadd.gt || zadd.lt)) {
+            return Err("GT, LT, and/or NX options at the same time are not compatible".into());
+        }
+        if zadd.incr && zadd.pairs.len() > 1 {
+            return Err("INCR option supports a single increment-element pair".into());
+        }
+
+        Ok(zadd)
+    }
+
+    /// # Format
+    ///
+    /// ```text
+    /// ZINCRBY key increment member
+    /// ```
+    pub(crate) fn parse_incrby(parse: &mut Parse) -> Result<ZAdd, ParseError> {
+        let key = parse.next_bytes()?;
+        let increment = parse_score(&parse.next_bytes()?).ok_or("value is not a valid float")?;
+        let member = parse.next_bytes()?;
+
+        Ok(ZAdd {
+            key,
+            pairs: vec![(increment, member)],
+            nx: false,
+            xx: false,
+            gt: false,
+            lt: false,
+            ch: false,
+            incr: true,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            zset(slot)?;
+            let zset = slot
+                .get_or_insert_with(|| Entry::new(Value::SortedSet(SortedSet::new())))
+                .value
+                .as_zset_mut()?;
+
+            let mut added = 0;
+            let mut updated = 0;
+            let mut last_score = None;
+
+            for (score, member) in self.pairs {
+                let current = zset.score(&member);
+
+                let score = match current {
+                    None if self.xx => continue,
+                    Some(_) if self.nx => continue,
+                    Some(current) if self.incr => current + score,
+                    _ => score,
+                };
+                if score.is_nan() {
+                    return Ok(Frame::Error(
+                        "ERR resulting score is not a number (NaN)".into(),
+                    ));
+                }
+
+                if current.is_some_and(|current| {
+                    (self.gt && score <= current) || (self.lt && score >= current)
+                }) {
+                    continue;
+                }
+
+                last_score = Some(score);
+                match zset.insert(member, score) {
+                    None => added += 1,
+                    Some(prev) if prev != score => updated += 1,
+                    Some(_) => {}
+                }
+            }
+
+            Ok(if self.incr {
+                match last_score {
+                    Some(score) => Frame::Double(score),
+                    None => Frame::Null,
+                }
+            } else if self.ch {
+                Frame::Integer(added + updated)
+            } else {
+                Frame::Integer(added)
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Removes the specified members from the sorted set stored at `key`. The key
+/// is deleted along with its last member.
+///
+/// Replies with the number of members that were removed.
+#[derive(Debug)]
+pub(crate) struct ZRem {
+    key: Bytes,
+    members: Vec<Bytes>,
+}
+
+impl ZRem {
+    /// # Format
+    ///
+    /// ```text
+    /// ZREM key member [member ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<ZRem, ParseError> {
+        let key = parse.next_bytes()?;
+        let members = parse_keys(parse)?;
+        Ok(ZRem { key, members })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            zset(slot)?;
+            let Some(entry) = slot.as_mut() else {
+                return Ok(Frame::Integer(0));
+            };
+
+            let zset = entry.value.as_zset_mut()?;
+            let removed = self
+                .members
+                .iter()
+                .filter(|member| zset.remove(member))
+                .count();
+            Ok(Frame::Integer(removed as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the score of `member` in the sorted set stored at `key`, or nil if
+/// the member or the key does not exist.
+#[derive(Debug)]
+pub(crate) struct ZScore {
+    key: Bytes,
+    member: Bytes,
+}
+
+impl ZScore {
+    /// # Format
+    ///
+    /// ```text
+    /// ZSCORE key member
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<ZScore, ParseError> {
+        let key = parse.next_bytes()?;
+        let member = parse.next_bytes()?;
+        Ok(ZScore { key, member })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            Ok(
+                match zset(slot)?.and_then(|zset| zset.score(&self.member)) {
+                    Some(score) => Frame::Double(score),
+                    None => Frame::Null,
+                },
+            )
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the rank of `member` in the sorted set stored at `key`, counting
+/// from the lowest score (`ZRANK`) or from the highest (`ZREVRANK`). Ranks
+/// are zero-based.
+///
+/// Replies with nil if the member or the key does not exist. With
+/// `WITHSCORE`, replies with an array of the rank and the score.
+#[derive(Debug)]
+pub(crate) struct ZRank {
+    key: Bytes,
+    member: Bytes,
+    rev: bool,
+    with_score: bool,
+}
+
+impl ZRank {
+    /// # Format
+    ///
+    /// ```text
+    /// ZRANK key member [WITHSCORE]
+    /// ZREVRANK key member [WITHSCORE]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, rev: bool) -> Result<ZRank, ParseError> {
+        let key = parse.next_bytes()?;
+        let member = parse.next_bytes()?;
+
+        let with_score = match parse.next_string() {
+            Ok(option) if option.eq_ignore_ascii_case("WITHSCORE") => true,
+            Ok(_) => return Err("syntax error".into()),
+            Err(ParseError::EndOfStream) => false,
+            Err(err) => return Err(err),
+        };
+
+        Ok(ZRank {
+            key,
+            member,
+            rev,
+            with_score,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let Some(zset) = zset(slot)? else {
+                return Ok(Frame::Null);
+            };
+            let Some(rank) = zset.rank(&self.member) else {
+                return Ok(Frame::Null);
+            };
+
+            let rank = if self.rev {
+                zset.len() - 1 - rank
+            } else {
+                rank
+            };
+            Ok(if self.with_score {
+                let score = zset.score(&self.member).unwrap();
+                Frame::Array(vec![Frame::Integer(rank as i64), Frame::Double(score)])
+            } else {
+                Frame::Integer(rank as i64)
+            })
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the number of members of the sorted set stored at `key`.
+#[derive(Debug)]
+pub(crate) struct ZCard {
+    key: Bytes,
+}
+
+impl ZCard {
+    /// # Format
+    ///
+    /// ```text
+    /// ZCARD key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<ZCard, ParseError> {
+        let key = parse.next_bytes()?;
+        Ok(ZCard { key })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let len = zset(slot)?.map_or(0, SortedSet::len);
+            Ok(Frame::Integer(len as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// One end of a score range: a score, optionally prefixed with `(` to exclude
+/// it.
+#[derive(Debug, Clone, Copy)]
+struct ScoreBound {
+    score: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(src: &[u8]) -> Result<ScoreBound, ParseError> {
+        let (src, exclusive) = match src.strip_prefix(b"(") {
+            Some(rest) => (rest, true),
+            None => (src, false),
+        };
+
+        let score = parse_score(src).ok_or("min or max is not a float")?;
+        Ok(ScoreBound { score, exclusive })
+    }
+
+    /// Number of members of `zset` that sort before this bound, used as the
+    /// minimum of a range.
+    fn rank_as_min(self, zset: &SortedSet) -> usize {
+        zset.count_while(|score, _| score < self.score || (self.exclusive && score == self.score))
+    }
+
+    /// Number of members of `zset` up to this bound, used as the maximum of a
+    /// range.
+    fn rank_as_max(self, zset: &SortedSet) -> usize {
+        zset.count_while(|score, _| score < self.score || (!self.exclusive && score == self.score))
+    }
+}
+
+/// One end of a lexicographic range: `-` and `+` for the lowest and highest
+/// possible strings, or a member prefixed with `[` to include it or `(` to
+/// exclude it.
+#[derive(Debug, Clone)]
+enum LexBound {
+    Lowest,
+    Highest,
+    Member { member: Bytes, exclusive: bool },
+}
+
+impl LexBound {
+    fn parse(src: Bytes) -> Result<LexBound, ParseError> {
+        match src.first() {
+            Some(b'-') if src.len() == 1 => Ok(LexBound::Lowest),
+            Some(b'+') if src.len() == 1 => Ok(LexBound::Highest),
+            Some(b'[') => Ok(LexBound::Member {
+                member: src.slice(1..),
+                exclusive: false,
+            }),
+            Some(b'(') => Ok(LexBound::Member {
+                member: src.slice(1..),
+                exclusive: true,
+            }),
+            _ => Err("min or max not valid string range item".into()),
+        }
+    }
+
+    /// Number of members of `zset` that sort before this bound, used as the
+    /// minimum of a range.
+    fn rank_as_min(&self, zset: &SortedSet) -> usize {
+        match self {
+            LexBound::Lowest => 0,
+            LexBound::Highest => zset.len(),
+            LexBound::Member { member, exclusive } => {
+                zset.count_while(|_, m| m < member || (*exclusive && m == member))
+            }
+        }
+    }
+
+    /// Number of members of `zset` up to this bound, used as the maximum of a
+    /// range.
+    fn rank_as_max(&self, zset: &SortedSet) -> usize {
+        match self {
+            LexBound::Lowest => 0,
+            LexBound::Highest => zset.len(),
+            LexBound::Member { member, exclusive } => {
+                zset.count_while(|_, m| m < member || (!*exclusive && m == member))
+            }
+        }
+    }
+}
+
+/// What a `ZRANGE` selects by.
+#[derive(Debug)]
+enum RangeBy {
+    Rank { start: i64, stop: i64 },
+    Score { min: ScoreBound, max: ScoreBound },
+    Lex { min: LexBound, max: LexBound },
+}
+
+/// Which form of the range command was used. The legacy forms imply some of
+/// the options that `ZRANGE` takes as arguments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RangeForm {
+    /// `ZRANGE`
+    Range,
+    /// `ZREVRANGE`
+    RevRange,
+    /// `ZRANGEBYSCORE`
+    RangeByScore,
+    /// `ZREVRANGEBYSCORE`
+    RevRangeByScore,
+}
+
+/// Returns the specified range of members of the sorted set stored at `key`.
+///
+/// The range is given by rank by default, or by score with `BYSCORE` or
+/// lexicographically with `BYLEX`. `REV` reverses the order, in which case the
+/// bounds are given from highest to lowest. `LIMIT` pages through a score or
+/// lexicographic range. Ranks are resolved in O(log n) on the skiplist, so the
+/// cost of a range is proportional to its size.
+///
+/// `WITHSCORES` interleaves each member with its score.
+#[derive(Debug)]
+pub(crate) struct ZRange {
+    key: Bytes,
+    by: RangeBy,
+    rev: bool,
+
+    /// `(offset, count)`, where a negative count means no limit.
+    limit: Option<(i64, i64)>,
+    with_scores: bool,
+}
+
+impl ZRange {
+    /// # Format
+    ///
+    /// ```text
+    /// ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+    /// ZREVRANGE key start stop [WITHSCORES]
+    /// ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]
+    /// ZREVRANGEBYSCORE key max min [WITHSCORES] [LIMIT offset count]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, form: RangeForm) -> Result<ZRange, ParseError> {
+        let key = parse.next_bytes()?;
+        let start = parse.next_bytes()?;
+        let stop = parse.next_bytes()?;
+
+        let mut by_score = matches!(form, RangeForm::RangeByScore | RangeForm::RevRangeByScore);
+        let mut by_lex = false;
+        let mut rev = matches!(form, RangeForm::RevRange | RangeForm::RevRangeByScore);
+        let mut limit = None;
+        let mut with_scores = false;
+
+        loop {
+            let option = match parse.next_string() {
+                Ok(option) => option.to_uppercase(),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err),
+            };
+
+            match &option[..] {
+                "WITHSCORES" => with_scores = true,
+                "LIMIT" if form != RangeForm::RevRange => {
+                    limit = Some((parse.next_int()?, parse.next_int()?));
+                }
+                "BYSCORE" if form == RangeForm::Range => by_score = true,
+                "BYLEX" if form == RangeForm::Range => by_lex = true,
+                "REV" if form == RangeForm::Range => rev = true,
+                _ => return Err("syntax error".into()),
+            }
+        }
+
+        if by_score && by_lex {
+            return Err("syntax error".into());
+        }
+        if limit.is_some() && !by_score && !by_lex {
+            return Err(
+                "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                    .into(),
+            );
+        }
+        if with_scores && by_lex {
+            return Err("syntax error, WITHSCORES not supported in combination with BYLEX".into());
+        }
+
+        let by = if by_score || by_lex {
+            // Reversed score and lexicographic ranges are given from max to min.
+            let (low, high) = if rev { (stop, start) } else { (start, stop) };
+            if by_score {
+                RangeBy::Score {
+                    min: ScoreBound::parse(&low)?,
+                    max: ScoreBound::parse(&high)?,
+                }
+            } else {
+                RangeBy::Lex {
+                    min: LexBound::parse(low)?,
+                    max: LexBound::parse(high)?,
+                }
+            }
+        } else {
+            let parse_rank =
+                |src: &Bytes| parse_int(src).ok_or("value is not an integer or out of range");
+            RangeBy::Rank {
+                start: parse_rank(&start)?,
+                stop: parse_rank(&stop)?,
+            }
+        };
+
+        Ok(ZRange {
+            key,
+            by,
+            rev,
+            limit,
+            with_scores,
+        })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let mut response = vec![];
+            let Some(zset) = zset(slot)? else {
+                return Ok(Frame::Array(response));
+            };
+
+            // Resolve the selection to ascending ranks `low..high`.
+            let len = zset.len();
+            let (low, high) = match &self.by {
+                RangeBy::Rank { start, stop } => match resolve_range(*start, *stop, len) {
+                    None => (0, 0),
+                    Some((start, stop)) if self.rev => (len - 1 - stop, len - start),
+                    Some((start, stop)) => (start, stop + 1),
+                },
+                RangeBy::Score { min, max } => (min.rank_as_min(zset), max.rank_as_max(zset)),
+                RangeBy::Lex { min, max } => (min.rank_as_min(zset), max.rank_as_max(zset)),
+            };
+            let high = high.max(low);
+
+            // Apply the limit in the order the range is walked.
+            let (low, high) = match self.limit {
+                None => (low, high),
+                Some((offset, _)) if offset < 0 => return Ok(Frame::Array(response)),
+                Some((offset, count)) => {
+                    let offset = (offset as usize).min(high - low);
+                    let count = usize::try_from(count).unwrap_or(usize::MAX);
+                    if self.rev {
+                        let high = high - offset;
+                        (high.saturating_sub(count).max(low), high)
+                    } else {
+                        let low = low + offset;
+                        (low, low.saturating_add(count).min(high))
+                    }
+                }
+            };
+
+            for (member, score) in zset.range(low, high, self.rev) {
+                response.push(Frame::Bulk(member.clone()));
+                if self.with_scores {
+                    response.push(Frame::Double(score));
+                }
+            }
+            Ok(Frame::Array(response))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
+
+/// Returns the number of members of the sorted set stored at `key` with a
+/// score between `min` and `max`.
+#[derive(Debug)]
+pub(crate) struct ZCount {
+    key: Bytes,
+    min: ScoreBound,
+    max: ScoreBound,
+}
+
+impl ZCount {
+    /// # Format
+    ///
+    /// ```text
+    /// ZCOUNT key min max
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<ZCount, ParseError> {
+        let key = parse.next_bytes()?;
+        let min = ScoreBound::parse(&parse.next_bytes()?)?;
+        let max = ScoreBound::parse(&parse.next_bytes()?)?;
+        Ok(ZCount { key, min, max })
+    }
+
+    pub(crate) fn apply(self, db: &Db) -> Frame {
+        db.with_entry(&self.key, |slot| {
+            let count = zset(slot)?.map_or(0, |zset| {
+                self.max
+                    .rank_as_max(zset)
+                    .saturating_sub(self.min.rank_as_min(zset))
+            });
+            Ok(Frame::Integer(count as i64))
+        })
+        .unwrap_or_else(|err: WrongType| err.into())
+    }
+}
@@ -0,0 +1,807 @@
+//! Server configuration.
+//!
+//! Settings are read from a `redis.conf`-style file, then overridden by
+//! command line flags, and some of them may be changed at runtime with
+//! `CONFIG SET`. `CONFIG REWRITE` writes the current settings back to the
+//! file.
+//!
+//! A config file holds one directive per line: the name of a setting followed
+//! by its arguments, separated by spaces. Arguments may be quoted, with C-style
+//! escapes inside double quotes. Lines starting with `#` are comments.
+//!
+//! ```text
+//! # Listen on every interface.
+//! bind 0.0.0.0
+//! port 6379
+//! appendonly yes
+//! client-query-buffer-limit 64mb
+//! ```
+//!
+//! On the command line, every setting is a flag taking the same arguments,
+//! after the path of the file if there is one:
+//!
+//! ```text
+//! redis [/path/to/redis.conf] [--port 6380] [--replicaof 127.0.0.1 6379]
+//! ```
+
+use crate::db::{AppendFsync, DEFAULT_SHARDS};
+use crate::frame::Limits;
+use crate::glob::glob_match;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::sync::Semaphore;
+
+/// Largest `maxclients`: clients are admitted through a semaphore, which
+/// holds at most `Semaphore::MAX_PERMITS` permits and takes permits back at
+/// most `u32::MAX` at a time.
+const MAX_CLIENTS: usize = if Semaphore::MAX_PERMITS < u32::MAX as usize {
+    Semaphore::MAX_PERMITS
+} else {
+    u32::MAX as usize
+};
+
+/// Largest `proto-max-bulk-len`: a bulk string and its trailing CRLF have to
+/// fit in a buffer, which holds at most `isize::MAX` bytes.
+const MAX_BULK_LEN: usize = isize::MAX as usize - 2;
+
+/// Settings of a server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address to listen on.
+    pub bind: String,
+
+    /// Port to listen on.
+    pub port: u16,
+
+    /// Maximum number of clients connected at the same time.
+    pub maxclients: usize,
+
+    /// Number of shards the keyspace is split into.
+    pub shards: usize,
+
+    /// Directory the snapshot and the append-only file are kept in.
+    pub dir: PathBuf,
+
+    /// Name of the snapshot file, in `dir`.
+    pub dbfilename: String,
+
+    /// Whether write commands are appended to a file.
+    pub appendonly: bool,
+
+    /// Name of the append-only file, in `dir`.
+    pub appendfilename: String,
+
+    /// When the append-only file is flushed to disk.
+    pub appendfsync: AppendFsync,
+
+    /// Protocol limits client connections are held to.
+    pub limits: Limits,
+
+    /// Master to replicate at startup, if any.
+    pub replicaof: Option<(String, u16)>,
+
+    /// User a replica authenticates as with its master, or the `default`
+    /// user if empty.
+    pub masteruser: String,
+
+    /// Password a replica authenticates with, if the master requires one.
+    pub masterauth: String,
+
+    /// Password of the `default` user, which clients are authenticated as
+    /// when they connect if empty.
+    pub requirepass: String,
+
+    /// The file the settings were read from, which `rewrite` writes to.
+    file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            maxclients: 10000,
+            shards: DEFAULT_SHARDS,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::default(),
+            limits: Limits::default(),
+            replicaof: None,
+            masteruser: String::new(),
+            masterauth: String::new(),
+            requirepass: String::new(),
+            file: None,
+        }
+    }
+}
+
+/// A setting, as named in config files, flags and `CONFIG` commands.
+struct Param {
+    name: &'static str,
+
+    /// Number of arguments it takes in a config file or on the command line.
+    /// They are joined with spaces into a single value.
+    args: usize,
+
+    /// Whether `CONFIG SET` may change it. The others only take effect at
+    /// startup.
+    mutable: bool,
+
+    get: fn(&Config) -> String,
+    set: fn(&mut Config, &str) -> crate::Result<()>,
+}
+
+/// Every setting, in the order `CONFIG GET` lists them.
+const PARAMS: &[Param] = &[
+    Param {
+        name: "bind",
+        args: 1,
+        mutable: false,
+        get: |config| config.bind.clone(),
+        set: |config, value| {
+            config.bind = value.to_string();
+            Ok(())
+        },
+    },
+    Param {
+        name: "port",
+        args: 1,
+        mutable: false,
+        get: |config| config.port.to_string(),
+        set: |config, value| {
+            config.port = number(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "maxclients",
+        args: 1,
+        mutable: true,
+        get: |config| config.maxclients.to_string(),
+        set: |config, value| {
+            config.maxclients = match positive(value)? {
+                n if n > MAX_CLIENTS => {
+                    return Err(format!(
+                        "argument must be between 1 and {} inclusive",
+                        MAX_CLIENTS
+                    )
+                    .into());
+                }
+                n => n,
+            };
+            Ok(())
+        },
+    },
+    Param {
+        name: "shards",
+        args: 1,
+        mutable: false,
+        get: |config| config.shards.to_string(),
+        set: |config, value| {
+            config.shards = positive(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "dir",
+        args: 1,
+        mutable: true,
+        get: |config| config.dir.display().to_string(),
+        set: |config, value| {
+            if !Path::new(value).is_dir() {
+                return Err(format!("no such directory '{}'", value).into());
+            }
+            config.dir = PathBuf::from(value);
+            Ok(())
+        },
+    },
+    Param {
+        name: "dbfilename",
+        args: 1,
+        mutable: true,
+        get: |config| config.dbfilename.clone(),
+        set: |config, value| {
+            config.dbfilename = file_name(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "appendonly",
+        args: 1,
+        mutable: true,
+        get: |config| yes_no(config.appendonly),
+        set: |config, value| {
+            config.appendonly = boolean(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "appendfilename",
+        args: 1,
+        mutable: false,
+        get: |config| config.appendfilename.clone(),
+        set: |config, value| {
+            config.appendfilename = file_name(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "appendfsync",
+        args: 1,
+        mutable: true,
+        get: |config| config.appendfsync.to_string(),
+        set: |config, value| {
+            config.appendfsync = value.parse()?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "proto-max-bulk-len",
+        args: 1,
+        mutable: true,
+        get: |config| config.limits.max_bulk_len.to_string(),
+        set: |config, value| {
+            config.limits.max_bulk_len = match memory(value)? {
+                n if n > MAX_BULK_LEN => {
+                    return Err(
+                        format!("argument must be a memory value up to {}", MAX_BULK_LEN).into(),
+                    );
+                }
+                n => n,
+            };
+            Ok(())
+        },
+    },
+    Param {
+        name: "proto-max-multibulk-len",
+        args: 1,
+        mutable: true,
+        get: |config| config.limits.max_array_len.to_string(),
+        set: |config, value| {
+            config.limits.max_array_len = positive(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "proto-max-nesting",
+        args: 1,
+        mutable: true,
+        get: |config| config.limits.max_depth.to_string(),
+        set: |config, value| {
+            config.limits.max_depth = positive(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "proto-inline-max-size",
+        args: 1,
+        mutable: true,
+        get: |config| config.limits.max_inline_len.to_string(),
+        set: |config, value| {
+            config.limits.max_inline_len = memory(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "client-query-buffer-limit",
+        args: 1,
+        mutable: true,
+        get: |config| config.limits.max_buffer_len.to_string(),
+        set: |config, value| {
+            config.limits.max_buffer_len = memory(value)?;
+            Ok(())
+        },
+    },
+    Param {
+        name: "replicaof",
+        args: 2,
+        mutable: false,
+        get: |config| match &config.replicaof {
+            Some((host, port)) => format!("{} {}", host, port),
+            None => String::new(),
+        },
+        set: |config, value| {
+            config.replicaof = match value.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => None,
+                [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => {
+                    None
+                }
+                [host, port] => Some((host.to_string(), number(port)?)),
+                _ => return Err("expected a host and a port".into()),
+            };
+            Ok(())
+        },
+    },
+    Param {
+        name: "masteruser",
+        args: 1,
+        mutable: true,
+        get: |config| config.masteruser.clone(),
+        set: |config, value| {
+            config.masteruser = value.to_string();
+            Ok(())
+        },
+    },
+    Param {
+        name: "masterauth",
+        args: 1,
+        mutable: true,
+        get: |config| config.masterauth.clone(),
+        set: |config, value| {
+            config.masterauth = value.to_string();
+            Ok(())
+        },
+    },
+    Param {
+        name: "requirepass",
+        args: 1,
+        mutable: true,
+        get: |config| config.requirepass.clone(),
+        set: |config, value| {
+            config.requirepass = value.to_string();
+            Ok(())
+        },
+    },
+];
+
+/// Returns the setting called `name`, in any case.
+fn param(name: &str) -> Option<&'static Param> {
+    PARAMS
+        .iter()
+        .find(|param| param.name.eq_ignore_ascii_case(name))
+}
+
+impl Config {
+    /// Build the configuration from the command line arguments, without the
+    /// name of the program.
+    ///
+    /// The first argument is the path of a config file, unless it is a flag.
+    /// Flags, named `--` followed by the name of a setting, override the file.
+    /// The arguments of a flag are those up to the next flag.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> crate::Result<Config> {
+        let mut args = args.into_iter().peekable();
+        let mut config = Config::default();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.load(&path)?;
+            config.file = Some(PathBuf::from(path));
+        }
+
+        while let Some(flag) = args.next() {
+            let Some(name) = flag.strip_prefix("--") else {
+                return Err(format!("unexpected argument '{}'", flag).into());
+            };
+            let mut values = Vec::new();
+            while let Some(value) = args.next_if(|arg| !arg.starts_with("--")) {
+                values.push(value);
+            }
+            config
+                .apply(name, &values)
+                .map_err(|e| format!("--{}: {}", name, e))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Apply the directives of the config file at `path`.
+    fn load(&mut self, path: &str) -> crate::Result<()> {
+        let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            split_args(line)
+                .and_then(|args| self.apply(&args[0], &args[1..]))
+                .map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Set the setting called `name` to `args`, as given in a config file or
+    /// on the command line.
+    fn apply(&mut self, name: &str, args: &[String]) -> crate::Result<()> {
+        let Some(param) = param(name) else {
+            return Err(format!("unknown setting '{}'", name).into());
+        };
+        if args.len() != param.args {
+            return Err(format!("'{}' takes {} argument(s)", param.name, param.args).into());
+        }
+        (param.set)(self, &args.join(" "))
+    }
+
+    /// Returns the name and value of every setting matching one of the glob
+    /// `patterns`, for `CONFIG GET`.
+    pub fn matching(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        PARAMS
+            .iter()
+            .filter(|param| {
+                patterns.iter().any(|pattern| {
+                    glob_match(pattern.to_lowercase().as_bytes(), param.name.as_bytes())
+                })
+            })
+            .map(|param| (param.name, (param.get)(self)))
+            .collect()
+    }
+
+    /// Set the setting called `name` to `value`, for `CONFIG SET`. Settings
+    /// that only take effect at startup cannot be set.
+    pub fn set(&mut self, name: &str, value: &str) -> crate::Result<()> {
+        let Some(param) = param(name) else {
+            return Err(format!(
+                "Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            )
+            .into());
+        };
+
+        let failed = |e: &dyn std::fmt::Display| -> crate::Error {
+            format!(
+                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                param.name, e
+            )
+            .into()
+        };
+        if !param.mutable {
+            return Err(failed(&"can't set immutable config"));
+        }
+        (param.set)(self, value).map_err(|e| failed(&e))
+    }
+
+    /// Returns the path of the snapshot file.
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    /// Returns the path of the append-only file.
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+
+    /// Write the current settings back to the config file they were read
+    /// from, for `CONFIG REWRITE`.
+    ///
+    /// Comments and blank lines are kept as they are. The first directive of
+    /// each setting is replaced with its current value and any other dropped.
+    /// Settings that are not in the file yet are appended, unless they still
+    /// have their default value.
+    pub fn rewrite(&self) -> crate::Result<()> {
+        let Some(path) = &self.file else {
+            return Err("The server is running without a config file".into());
+        };
+        let failed =
+            |e: io::Error| -> crate::Error { format!("Rewriting config file: {}", e).into() };
+
+        let old = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(failed(e)),
+        };
+
+        let mut text = String::new();
+        let mut written = Vec::new();
+        for line in old.lines() {
+            let trimmed = line.trim();
+            let param = match split_args(trimmed) {
+                Ok(args) if !trimmed.starts_with('#') => param(&args[0]),
+                _ => None,
+            };
+            match param {
+                Some(param) if !written.contains(&param.name) => {
+                    written.push(param.name);
+                    self.write_param(param, &mut text);
+                }
+                Some(_) => {}
+                None => {
+                    text.push_str(line);
+                    text.push('\n');
+                }
+            }
+        }
+
+        let defaults = Config::default();
+        let mut changed = PARAMS
+            .iter()
+            .filter(|param| !written.contains(&param.name))
+            .filter(|param| (param.get)(self) != (param.get)(&defaults))
+            .peekable();
+        if changed.peek().is_some() {
+            text.push_str("# Generated by CONFIG REWRITE\n");
+        }
+        for param in changed {
+            self.write_param(param, &mut text);
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let res = File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(text.as_bytes())?;
+                file.sync_data()
+            })
+            .and_then(|()| fs::rename(&tmp, path));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp);
+            return Err(failed(e));
+        }
+        Ok(())
+    }
+
+    /// Append the directive setting `param` to its current value to `text`.
+    /// A setting taking several arguments that has no value is left out.
+    fn write_param(&self, param: &Param, text: &mut String) {
+        let value = (param.get)(self);
+        if param.args == 1 {
+            text.push_str(&format!("{} {}\n", param.name, quote(&value)));
+        } else if !value.is_empty() {
+            let args: Vec<String> = value.split(' ').map(quote).collect();
+            text.push_str(&format!("{} {}\n", param.name, args.join(" ")));
+        }
+    }
+}
+
+/// Split a line of a config file into arguments, the way Redis does.
+///
+/// Arguments are separated by whitespace. Double-quoted arguments may contain
+/// `\n`, `\r`, `\t`, `\b`, `\a`, `\\`, `\"` and `\xHH` escapes, and
+/// single-quoted ones `\'`. A closing quote must end the argument.
+fn split_args(line: &str) -> crate::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.bytes().peekable();
+
+    loop {
+        while chars.next_if(u8::is_ascii_whitespace).is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+
+        let mut arg = Vec::new();
+        if first == b'"' || first == b'\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => return Err("unbalanced quotes".into()),
+                    Some(c) if c == first => break,
+                    Some(b'\\') if first == b'\'' => match chars.next_if_eq(&b'\'') {
+                        Some(quote) => arg.push(quote),
+                        None => arg.push(b'\\'),
+                    },
+                    Some(b'\\') => match chars.next() {
+                        Some(b'n') => arg.push(b'\n'),
+                        Some(b'r') => arg.push(b'\r'),
+                        Some(b't') => arg.push(b'\t'),
+                        Some(b'b') => arg.push(8),
+                        Some(b'a') => arg.push(7),
+                        Some(b'x') => {
+                            let hex = [chars.next(), chars.next()];
+                            let digits = hex.map(|c| c.and_then(|c| (c as char).to_digit(16)));
+                            match digits {
+                                [Some(high), Some(low)] => arg.push((high * 16 + low) as u8),
+                                _ => return Err("invalid \\x escape".into()),
+                            }
+                        }
+                        Some(c) => arg.push(c),
+                        None => return Err("unbalanced quotes".into()),
+                    },
+                    Some(c) => arg.push(c),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_ascii_whitespace()) {
+                return Err("closing quote must be followed by a space".into());
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_ascii_whitespace()) {
+                arg.push(c);
+            }
+        }
+
+        args.push(String::from_utf8(arg).map_err(|_| "invalid UTF-8")?);
+    }
+
+    if args.is_empty() {
+        return Err("empty line".into());
+    }
+    Ok(args)
+}
+
+/// Returns `value` as an argument `split_args` reads back as is, quoted if
+/// needed.
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_graphic() && !matches!(c, b'"' | b'\'' | b'\\'));
+    if plain {
+        return value.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn number<T: FromStr>(value: &str) -> crate::Result<T> {
+    value
+        .parse()
+        .map_err(|_| format!("argument couldn't be parsed into an integer: '{}'", value).into())
+}
+
+fn positive(value: &str) -> crate::Result<usize> {
+    match number(value)? {
+        0 => Err("argument must be greater than zero".into()),
+        n => Ok(n),
+    }
+}
+
+/// Parse an amount of memory, with an optional unit as in Redis: `k`, `m` and
+/// `g` are powers of 1000, `kb`, `mb` and `gb` powers of 1024.
+fn memory(value: &str) -> crate::Result<usize> {
+    let lower = value.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("argument must be a memory value: '{}'", value).into()),
+    };
+    number::<usize>(digits)?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("argument is out of range: '{}'", value).into())
+}
+
+fn boolean(value: &str) -> crate::Result<bool> {
+    match &value.to_lowercase()[..] {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".into()),
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+/// Settings naming a file in `dir` take a plain file name.
+fn file_name(value: &str) -> crate::Result<String> {
+    if value.is_empty() || value.contains('/') {
+        return Err(format!("'{}' must be a file name, not a path", value).into());
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("redis-config-test-{}.conf", fastrand::u64(..)))
+    }
+
+    #[test]
+    fn split_args_like_redis() {
+        assert_eq!(split_args("port   6380").unwrap(), ["port", "6380"]);
+        assert_eq!(
+            split_args(r#"dir "/tmp/a dir" 'it\'s' "\x41\n""#).unwrap(),
+            ["dir", "/tmp/a dir", "it's", "A\n"]
+        );
+        assert!(split_args(r#"dir "/tmp"x"#).is_err());
+        assert!(split_args(r#"dir "/tmp"#).is_err());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = temp_path();
+        fs::write(
+            &path,
+            "# comment\nport 7000\nappendonly yes\nclient-query-buffer-limit 64mb\n\
+             replicaof 10.0.0.1 6379\n",
+        )
+        .unwrap();
+
+        let config = Config::from_args(args(&[
+            path.to_str().unwrap(),
+            "--port",
+            "7001",
+            "--appendfsync",
+            "always",
+        ]))
+        .unwrap();
+        assert_eq!(config.port, 7001);
+        assert!(config.appendonly);
+        assert_eq!(config.appendfsync, AppendFsync::Always);
+        assert_eq!(config.limits.max_buffer_len, 64 * 1024 * 1024);
+        assert_eq!(config.replicaof, Some(("10.0.0.1".to_string(), 6379)));
+
+        let err = Config::from_args(args(&["--nope", "1"])).unwrap_err();
+        assert_eq!(err.to_string(), "--nope: unknown setting 'nope'");
+        fs::write(&path, "port 7000\nport\n").unwrap();
+        let err = Config::from_args(args(&[path.to_str().unwrap()])).unwrap_err();
+        assert!(err.to_string().ends_with(":2: 'port' takes 1 argument(s)"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn set_and_get() {
+        let mut config = Config::default();
+        config.set("MAXCLIENTS", "10").unwrap();
+        config.set("proto-max-bulk-len", "1k").unwrap();
+        assert_eq!(
+            config.matching(&args(&["maxclients", "proto-max-b*"])),
+            [
+                ("maxclients", "10".to_string()),
+                ("proto-max-bulk-len", "1000".to_string())
+            ]
+        );
+
+        let err = config.set("port", "1").unwrap_err();
+        assert!(err.to_string().ends_with("can't set immutable config"));
+        let err = config.set("appendonly", "maybe").unwrap_err();
+        assert!(err.to_string().ends_with("argument must be 'yes' or 'no'"));
+        let err = config
+            .set("maxclients", "18446744073709551615")
+            .unwrap_err();
+        assert!(err.to_string().contains("argument must be between 1 and"));
+        assert_eq!(config.maxclients, 10);
+        let err = config
+            .set("proto-max-bulk-len", "18446744073709551615")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("argument must be a memory value up to")
+        );
+        assert_eq!(config.limits.max_bulk_len, 1000);
+        assert!(config.set("nope", "1").is_err());
+    }
+
+    #[test]
+    fn rewrite_keeps_comments() {
+        let path = temp_path();
+        fs::write(
+            &path,
+            "# my settings\nport 7000\n\nmaxclients 5\nmaxclients 6\n",
+        )
+        .unwrap();
+
+        let mut config = Config::from_args(args(&[path.to_str().unwrap()])).unwrap();
+        config.set("maxclients", "50").unwrap();
+        config.set("appendfsync", "always").unwrap();
+        config.rewrite().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# my settings\nport 7000\n\nmaxclients 50\n\
+             # Generated by CONFIG REWRITE\nappendfsync always\n"
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(Config::default().rewrite().is_err());
+    }
+}
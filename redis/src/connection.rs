@@ -1,7 +1,8 @@
-use crate::frame::{self, Frame};
+use crate::frame::{self, Decoder, Frame, Limits, Protocol};
 
-use bytes::{Buf, BytesMut};
-use std::io::{self, Cursor};
+use bytes::BytesMut;
+use std::io;
+use std::net::SocketAddr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
 
@@ -16,7 +17,9 @@ use tokio::net::TcpStream;
 /// the `Connection` creates the frame and returns it to the caller.
 ///
 /// When sending frames, the frame is first encoded into the write buffer.
-/// The contents of the write buffer are then written to the socket.
+/// The contents of the write buffer are then written to the socket, either
+/// right away with `write_frame`, or with `feed_frame` followed by a single
+/// `flush` for a whole batch of frames.
 #[derive(Debug)]
 pub struct Connection {
     // The `TcpStream`. It is decorated with a `BufWriter`, which provides write
@@ -26,12 +29,30 @@ pub struct Connection {
 
     // The buffer for reading frames.
     buffer: BytesMut,
+
+    // Decodes the frames read into `buffer`. It holds on to the part of a
+    // frame decoded so far until the rest arrives.
+    decoder: Decoder,
+
+    // The protocol frames are encoded with. RESP3 types are downgraded to
+    // their RESP2 equivalent until the peer asks for RESP3.
+    protocol: Protocol,
+
+    // Scratch space for the frame being written.
+    encoded: Vec<u8>,
 }
 
 impl Connection {
-    /// Create a new `Connection`, backed by `socket`. Read and write buffers
-    /// are initialized.
+    /// Create a new `Connection`, backed by `socket`, with the default
+    /// limits. Read and write buffers are initialized.
+    #[cfg(test)]
     pub fn new(socket: TcpStream) -> Connection {
+        Connection::with_limits(socket, Limits::default())
+    }
+
+    /// Create a new `Connection`, backed by `socket`, that rejects frames
+    /// exceeding `limits`.
+    pub fn with_limits(socket: TcpStream, limits: Limits) -> Connection {
         Connection {
             stream: BufWriter::new(socket),
             // Default to a 4KB read buffer. For the use case of mini redis,
@@ -39,9 +60,32 @@ impl Connection {
             // value to their specific use case. There is a high likelihood that
             // a larger read buffer will work better.
             buffer: BytesMut::with_capacity(4 * 1024),
+            decoder: Decoder::new(limits),
+            protocol: Protocol::default(),
+            encoded: Vec::new(),
         }
     }
 
+    /// Returns the protocol frames are written with.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Write the frames that follow with `protocol`.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Reject the frames that follow if they exceed `limits`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.decoder.set_limits(limits);
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().peer_addr()
+    }
+
     /// Read a single `Frame` value from the underlying stream.
     ///
     /// The function waits until it has retrieved enough data to parse a frame.
@@ -64,14 +108,19 @@ impl Connection {
             // There is not enough buffered data to read a frame. Attempt to
             // read more data from the socket.
             //
+            // Frames split off the buffer may still share its allocation, in
+            // which case it has no spare capacity left. Make room for a decent
+            // amount of data rather than reading it a few bytes at a time.
+            self.buffer.reserve(4 * 1024);
+
             // On success, the number of bytes is returned. `0` indicates "end
             // of stream".
             if 0 == self.stream.read_buf(&mut self.buffer).await? {
                 // The remote closed the connection. For this to be a clean
-                // shutdown, there should be no data in the read buffer. If
-                // there is, this means that the peer closed the socket while
-                // sending a frame.
-                if self.buffer.is_empty() {
+                // shutdown, there should be no data in the read buffer nor a
+                // frame in progress. If there is, this means that the peer
+                // closed the socket while sending a frame.
+                if self.buffered_len() == 0 {
                     return Ok(None);
                 } else {
                     return Err("connection reset by peer".into());
@@ -80,6 +129,29 @@ impl Connection {
         }
     }
 
+    /// Return a frame only if one is already fully buffered, without reading
+    /// from the socket.
+    ///
+    /// This lets a server work through a batch of pipelined requests before
+    /// flushing the replies to all of them at once.
+    pub fn try_read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        self.parse_frame()
+    }
+
+    /// Wait until the peer closes the connection.
+    ///
+    /// Anything the peer sends in the meantime is buffered and returned by
+    /// later calls to `read_frame`. This is cancel safe, so it can be raced
+    /// against other events to notice a client going away.
+    pub async fn wait_closed(&mut self) -> io::Result<()> {
+        while self.stream.read_buf(&mut self.buffer).await? != 0 {
+            if self.buffered_len() >= self.decoder.limits().max_buffer_len {
+                return Err(io::Error::other("query buffer limit exceeded"));
+            }
+        }
+        Ok(())
+    }
+
     /// Tries to parse a frame from the buffer. If the buffer contains enough
     /// data, the frame is returned and the data removed from the buffer. If not
     /// enough data has been buffered yet, `Ok(None)` is returned. If the
@@ -87,56 +159,27 @@ impl Connection {
     fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
         use frame::Error::Incomplete;
 
-        // Cursor is used to track the "current" location in the
-        // buffer. Cursor also implements `Buf` from the `bytes` crate
-        // which provides a number of helpful utilities for working
-        // with bytes.
-        let mut buf = Cursor::new(&self.buffer[..]);
-
-        // The first step is to check if enough data has been buffered to parse
-        // a single frame. This step is usually much faster than doing a full
-        // parse of the frame, and allows us to skip allocating data structures
-        // to hold the frame data unless we know the full frame has been
-        // received.
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                // The `check` function will have advanced the cursor until the
-                // end of the frame. Since the cursor had position set to zero
-                // before `Frame::check` was called, we obtain the length of the
-                // frame by checking the cursor position.
-                let len = buf.position() as usize;
-
-                // Reset the position to zero before passing the cursor to
-                // `Frame::parse`.
-                buf.set_position(0);
-
-                // Parse the frame from the buffer. This allocates the necessary
-                // structures to represent the frame and returns the frame
-                // value.
-                //
-                // If the encoded frame representation is invalid, an error is
-                // returned. This should terminate the **current** connection
-                // but should not impact any other connected client.
-                let frame = Frame::parse(&mut buf)?;
-
-                // Discard the parsed data from the read buffer.
-                //
-                // When `advance` is called on the read buffer, all of the data
-                // up to `len` is discarded. The details of how this works is
-                // left to `BytesMut`. This is often done by moving an internal
-                // cursor, but it may be done by reallocating and copying data.
-                self.buffer.advance(len);
-
-                // Return the parsed frame to the caller.
-                Ok(Some(frame))
-            }
+        // The decoder consumes whatever it can from the read buffer and picks
+        // up where it left off on the next call, so each byte is only looked
+        // at once. Bulk payloads are split off the read buffer rather than
+        // copied out of it.
+        //
+        // If the encoded frame representation is invalid, an error is
+        // returned. This should terminate the **current** connection but
+        // should not impact any other connected client.
+        match self.decoder.decode(&mut self.buffer) {
+            Ok(frame) => Ok(Some(frame)),
             // There is not enough data present in the read buffer to parse a
             // single frame. We must wait for more data to be received from the
             // socket. Reading from the socket will be done in the statement
             // after this `match`.
             //
             // We do not want to return `Err` from here as this "error" is an
-            // expected runtime condition.
+            // expected runtime condition, unless the peer already sent more
+            // than it is allowed to have buffered.
+            Err(Incomplete) if self.buffered_len() >= self.decoder.limits().max_buffer_len => {
+                Err(frame::Error::from("protocol error; query buffer limit exceeded").into())
+            }
             Err(Incomplete) => Ok(None),
             // An error was encountered while parsing the frame. The connection
             // is now in an invalid state. Returning `Err` from here will result
@@ -145,92 +188,124 @@ impl Connection {
         }
     }
 
-    /// Write a single `Frame` value to the underlying stream.
+    /// Returns the amount of data received but not yet returned as a frame.
+    fn buffered_len(&self) -> usize {
+        self.decoder.pending_len() + self.buffer.len()
+    }
+
+    /// Write a single `Frame` value to the underlying stream and flush it.
     ///
-    /// The `Frame` value is written to the socket using the various `write_*`
-    /// functions provided by `AsyncWrite`. Calling these functions directly on
-    /// a `TcpStream` is **not** advised, as this will result in a large number of
-    /// syscalls. However, it is fine to call these functions on a *buffered*
-    /// write stream. The data will be written to the buffer. Once the buffer is
-    /// full, it is flushed to the underlying socket.
+    /// This is `feed_frame` followed by `flush`. When writing several frames
+    /// in a row, feeding them all and flushing once saves a syscall per frame.
     pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
-        // Arrays are encoded by encoding each entry. All other frame types are
-        // considered literals. For now, mini-redis is not able to encode
-        // recursive frame structures. See below for more details.
-        match frame {
-            Frame::Array(val) => {
-                // Encode the frame type prefix. For an array, it is `*`.
-                self.stream.write_u8(b'*').await?;
-
-                // Encode the length of the array.
-                self.write_decimal(val.len() as u64).await?;
-
-                // Iterate and encode each entry in the array.
-                for entry in &**val {
-                    self.write_value(entry).await?;
-                }
-            }
-            // The frame type is a literal. Encode the value directly.
-            _ => self.write_value(frame).await?,
-        }
+        self.feed_frame(frame).await?;
+        self.flush().await
+    }
 
-        // Ensure the encoded frame is written to the socket. The calls above
-        // are to the buffered stream and writes. Calling `flush` writes the
-        // remaining contents of the buffer to the socket.
+    /// Encode a single `Frame` value into the write buffer without flushing
+    /// it.
+    ///
+    /// The frame is encoded in memory first and then handed to the buffered
+    /// write stream. Writing to a `TcpStream` directly for every part of the
+    /// frame is **not** advised, as this would result in a large number of
+    /// syscalls. The data is written to the buffer instead, which is flushed
+    /// to the underlying socket once it is full. Anything still buffered is
+    /// only sent on the next call to `flush`.
+    pub async fn feed_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.encoded.clear();
+        frame.encode(self.protocol, &mut self.encoded);
+        self.stream.write_all(&self.encoded).await
+    }
+
+    /// Write already encoded frames into the write buffer without flushing
+    /// them.
+    pub async fn feed_encoded(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data).await
+    }
+
+    /// Write everything in the write buffer to the socket.
+    pub async fn flush(&mut self) -> io::Result<()> {
         self.stream.flush().await
     }
+}
 
-    /// Write a frame literal to the stream
-    async fn write_value(&mut self, frame: &Frame) -> io::Result<()> {
-        match frame {
-            Frame::Simple(val) => {
-                self.stream.write_u8(b'+').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Error(val) => {
-                self.stream.write_u8(b'-').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Integer(val) => {
-                self.stream.write_u8(b':').await?;
-                self.write_decimal(*val).await?;
-            }
-            Frame::Null => {
-                self.stream.write_all(b"$-1\r\n").await?;
-            }
-            Frame::Bulk(val) => {
-                let len = val.len();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
 
-                self.stream.write_u8(b'$').await?;
-                self.write_decimal(len as u64).await?;
-                self.stream.write_all(val).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            // Encoding an `Array` from within a value cannot be done using a
-            // recursive strategy. In general, async fns do not support
-            // recursion. Mini-redis has not needed to encode nested arrays yet,
-            // so for now it is skipped.
-            Frame::Array(_val) => unreachable!(),
-        }
+    /// Send `frame` over a loopback socket and return what the other end
+    /// decodes. The receiving end accepts any nesting depth.
+    async fn round_trip(frame: &Frame) -> Frame {
+        round_trip_with(frame, Protocol::Resp2).await
+    }
 
-        Ok(())
+    async fn round_trip_with(frame: &Frame, protocol: Protocol) -> Frame {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        client.set_protocol(protocol);
+        let limits = Limits {
+            max_depth: usize::MAX,
+            ..Limits::default()
+        };
+        let mut server = Connection::with_limits(listener.accept().await.unwrap().0, limits);
+
+        client.write_frame(frame).await.unwrap();
+        server.read_frame().await.unwrap().unwrap()
     }
 
-    /// Write a decimal frame to the stream
-    async fn write_decimal(&mut self, val: u64) -> io::Result<()> {
-        use std::io::Write;
+    #[tokio::test]
+    async fn nested_arrays_round_trip() {
+        // Every level holds a few literals around the next level down.
+        let mut frame = Frame::Array(vec![]);
+        for depth in 0..200 {
+            frame = Frame::Array(vec![
+                Frame::Integer(depth),
+                Frame::Bulk(Bytes::from(format!("level {depth}"))),
+                frame,
+                Frame::Null,
+                Frame::Array(vec![Frame::Simple("OK".to_string())]),
+            ]);
+        }
+
+        let decoded = round_trip(&frame).await;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", frame));
+    }
 
-        // Convert the value to a string
-        let mut buf = [0u8; 20];
-        let mut buf = Cursor::new(&mut buf[..]);
-        write!(&mut buf, "{}", val)?;
+    #[tokio::test]
+    async fn resp3_frames_are_downgraded_for_resp2() {
+        let bulk = |s: &'static str| Frame::Bulk(Bytes::from_static(s.as_bytes()));
+        let frame = Frame::Push(vec![
+            Frame::Map(vec![(bulk("a"), Frame::Double(1.5))]),
+            Frame::Set(vec![Frame::Boolean(true), Frame::Null]),
+            Frame::Attribute(
+                vec![(bulk("ttl"), Frame::Integer(3))],
+                Box::new(Frame::BigNumber("12345678901234567890".to_string())),
+            ),
+            Frame::VerbatimString {
+                format: *b"txt",
+                text: Bytes::from_static(b"some text"),
+            },
+        ]);
 
-        let pos = buf.position() as usize;
-        self.stream.write_all(&buf.get_ref()[..pos]).await?;
-        self.stream.write_all(b"\r\n").await?;
+        // RESP3 frames make it through unchanged.
+        let decoded = round_trip_with(&frame, Protocol::Resp3).await;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", frame));
 
-        Ok(())
+        let decoded = round_trip(&frame).await;
+        assert_eq!(
+            format!("{:?}", decoded),
+            format!(
+                "{:?}",
+                Frame::Array(vec![
+                    Frame::Array(vec![bulk("a"), bulk("1.5")]),
+                    Frame::Array(vec![Frame::Integer(1), Frame::Null]),
+                    bulk("12345678901234567890"),
+                    bulk("some text"),
+                ])
+            )
+        );
     }
 }
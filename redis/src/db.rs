@@ -0,0 +1,805 @@
+mod acl;
+mod aof;
+mod blocking;
+mod clients;
+mod config;
+mod pubsub;
+mod rdb;
+mod replication;
+mod value;
+mod watch;
+mod zset;
+
+pub use aof::AppendFsync;
+pub(crate) use blocking::{Blocked, BlockingPop, Delivery};
+pub(crate) use clients::Shutdown;
+pub use clients::ShutdownMode;
+pub(crate) use pubsub::{Inbox, Message, Outbox};
+pub(crate) use replication::{BACKLOG_SIZE, LinkState, ReplicaLink, Resync};
+pub(crate) use value::{Value, WrongType};
+pub(crate) use watch::WatchSet;
+pub(crate) use zset::SortedSet;
+
+use crate::config::Config;
+use crate::frame::{Frame, Limits};
+use acl::Users;
+use aof::{Aof, RewriteBase};
+use blocking::{WaitQueues, holds_list};
+use clients::ShutdownState;
+use pubsub::PubSub;
+use rdb::Snapshots;
+use replication::Replication;
+use watch::Watches;
+
+use bytes::Bytes;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Number of shards used by `Db::new`.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// How often the active expiry task runs. Matches the default `hz 10` of
+/// real Redis.
+const EXPIRE_CYCLE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Maximum number of keys purged from one shard per lock acquisition. Keeping
+/// the batch small bounds how long a cycle can stall clients of that shard.
+const EXPIRE_BATCH: usize = 20;
+
+/// Server state shared across all connections.
+///
+/// `Db` is a cheap handle to the keyspace: cloning it only bumps the
+/// reference count of the shared state, so every connection task gets its own
+/// clone.
+///
+/// Keys are spread over a fixed number of shards by hash, and each shard has
+/// its own lock. Two clients only contend with each other when they touch keys
+/// that land in the same shard. Locking is entirely internal: callers go
+/// through `get`, `set`, `remove` and `with_entry` and never see a guard.
+///
+/// Keys may carry a deadline. Expired keys are dropped lazily whenever they
+/// are accessed, and in the background by the task started with
+/// `spawn_expiry_task`.
+#[derive(Debug, Clone)]
+pub struct Db {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// The key-value data, split into independently locked shards. A std
+    /// `Mutex` is used rather than a Tokio one because a lock is never held
+    /// across an `.await` point and the critical sections are tiny.
+    shards: Box<[Mutex<Shard>]>,
+
+    /// Hasher used to pick the shard for a key. Randomly seeded so clients
+    /// cannot deliberately pile all their keys onto one shard.
+    hasher: RandomState,
+
+    /// Pub/Sub channels. These live outside the keyspace and are not affected
+    /// by sharding. Publishing holds the lock too, so that every subscriber
+    /// sees concurrent publishes in the same order.
+    pubsub: Mutex<PubSub>,
+
+    /// Held for writing while a transaction executes, and for reading by every
+    /// other command, so that a transaction never interleaves with them.
+    exec_gate: RwLock<()>,
+
+    /// Protocol limits applied to new client connections.
+    limits: RwLock<Limits>,
+
+    /// Identifier handed to the next client that asks for one.
+    next_client_id: AtomicU64,
+
+    /// Snapshot file and the state of saving to it.
+    snapshots: Mutex<Snapshots>,
+
+    /// The append-only file, if enabled. Write commands are applied with it
+    /// locked while they are propagated, so that they are propagated in
+    /// order.
+    aof: Mutex<Option<Aof>>,
+
+    /// Set when write commands are propagated, to the append-only file or to
+    /// replicas.
+    propagating: AtomicBool,
+
+    /// Pops made on behalf of blocked clients while propagating, to be
+    /// propagated right after the write that served them.
+    served: Mutex<Vec<Frame>>,
+
+    /// The replication stream, and the master this server replicates.
+    replication: Mutex<Replication>,
+
+    /// The settings the server runs with. Only locked outside of commands
+    /// being applied, so changing them may wait for those to finish.
+    config: Mutex<Config>,
+
+    /// Permits of the clients connected, up to `maxclients`.
+    clients: Arc<Semaphore>,
+
+    /// Whether the server was asked to shut down.
+    shutdown: Mutex<ShutdownState>,
+
+    /// The users clients authenticate as.
+    users: RwLock<Users>,
+}
+
+/// A single partition of the keyspace.
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<Bytes, Entry>,
+
+    /// Keys that have a deadline, ordered by that deadline. This lets the
+    /// expiry task find the keys that are due without scanning `entries`.
+    expirations: BTreeSet<(u64, Bytes)>,
+
+    /// Clients blocked until the list at a key gets an element.
+    blocked: WaitQueues,
+
+    /// Version counters of the keys clients are watching.
+    watched: Watches,
+
+    /// Where the shard goes in the append-only file being rewritten, if it
+    /// was not encoded for the rewrite yet.
+    rewrite: Option<RewriteBase>,
+}
+
+/// Entry in the key-value store
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    /// Stored data
+    pub(crate) value: Value,
+
+    /// Unix time, in milliseconds, at which the entry expires and should be
+    /// removed from the database.
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl Entry {
+    /// Create an entry without a deadline.
+    pub(crate) fn new(value: impl Into<Value>) -> Entry {
+        Entry {
+            value: value.into(),
+            expires_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|when| when <= now)
+    }
+}
+
+/// Returns the current Unix time in milliseconds.
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the Unix epoch")
+        .as_millis() as u64
+}
+
+impl Db {
+    /// Create a new, empty `Db` instance with `DEFAULT_SHARDS` shards.
+    pub fn new() -> Db {
+        Db::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Create a new, empty `Db` instance split into `shards` shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_shards(shards: usize) -> Db {
+        assert!(shards > 0, "a Db needs at least one shard");
+
+        let mut config = Config::default();
+        config.shards = shards;
+
+        let shards = (0..shards).map(|_| Mutex::new(Shard::default())).collect();
+        Db {
+            shared: Arc::new(Shared {
+                shards,
+                hasher: RandomState::new(),
+                pubsub: Mutex::default(),
+                exec_gate: RwLock::default(),
+                limits: RwLock::default(),
+                next_client_id: AtomicU64::new(1),
+                snapshots: Mutex::default(),
+                aof: Mutex::default(),
+                propagating: AtomicBool::new(false),
+                served: Mutex::default(),
+                replication: Mutex::default(),
+                clients: Arc::new(Semaphore::new(config.maxclients)),
+                config: Mutex::new(config),
+                shutdown: Mutex::default(),
+                users: RwLock::new(acl::default_users()),
+            }),
+        }
+    }
+
+    /// Returns the number of shards the keyspace is split into.
+    pub fn shard_count(&self) -> usize {
+        self.shared.shards.len()
+    }
+
+    /// Returns the protocol limits client connections are held to.
+    pub fn limits(&self) -> Limits {
+        *self.shared.limits.read().unwrap()
+    }
+
+    /// Change the protocol limits. Connections accepted from now on are held
+    /// to the new limits; established ones keep those they started with.
+    pub fn set_limits(&self, limits: Limits) {
+        *self.shared.limits.write().unwrap() = limits;
+    }
+
+    /// Returns a new client identifier, unique for the lifetime of the `Db`.
+    pub(crate) fn next_client_id(&self) -> u64 {
+        self.shared.next_client_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Start the background task that purges expired keys.
+    ///
+    /// Every cycle walks all shards and removes the keys whose deadline has
+    /// passed, in batches of at most `EXPIRE_BATCH` keys per lock acquisition.
+    /// As in Redis, a shard that fills a whole batch is sampled again straight
+    /// away, since it probably has more expired keys waiting.
+    ///
+    /// The task only holds a weak reference to the keyspace and exits once
+    /// every `Db` handle has been dropped.
+    pub fn spawn_expiry_task(&self) -> JoinHandle<()> {
+        let shared = Arc::downgrade(&self.shared);
+        tokio::spawn(purge_expired_keys(shared))
+    }
+
+    /// Returns the index of the shard responsible for `key`.
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let hash = self.shared.hasher.hash_one(key);
+        (hash % self.shared.shards.len() as u64) as usize
+    }
+
+    /// Returns the shard responsible for `key`.
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        &self.shared.shards[self.shard_index(key)]
+    }
+
+    /// Get the string value associated with a key.
+    ///
+    /// Returns `None` if there is no value associated with the key, and
+    /// `WrongType` if the key holds some other type.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        let mut shard = self.shard(key).lock().unwrap();
+
+        // `Bytes` clones are shallow, so returning an owned value is cheap.
+        match shard.live(key, now_ms()) {
+            Some(entry) => entry.value.as_string().cloned().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value associated with a key, returning the previous value.
+    ///
+    /// Any deadline the key previously had is discarded.
+    pub(crate) fn set(&self, key: Bytes, value: impl Into<Value>) -> Option<Value> {
+        let mut shard = self.shard(&key).lock().unwrap();
+
+        let prev = shard.take(&key, now_ms());
+        shard.touch(&key);
+        shard.put(key, Entry::new(value));
+        prev.map(|entry| entry.value)
+    }
+
+    /// Remove a key, returning its value if it was present.
+    pub(crate) fn remove(&self, key: &[u8]) -> Option<Value> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let entry = shard.take(key, now_ms())?;
+        shard.touch(key);
+        Some(entry.value)
+    }
+
+    /// Returns the deadline of a key, as a Unix time in milliseconds, if it is
+    /// present and has one.
+    pub(crate) fn deadline(&self, key: &[u8]) -> Option<u64> {
+        let mut shard = self.shard(key).lock().unwrap();
+        shard.live(key, now_ms())?.expires_at
+    }
+
+    /// Returns `true` if the key is present.
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        let mut shard = self.shard(key).lock().unwrap();
+        shard.live(key, now_ms()).is_some()
+    }
+
+    /// Run `f`, which applies a single command, while no transaction is
+    /// executing. Any number of clients may do so at the same time.
+    pub(crate) fn concurrently<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _gate = self.shared.exec_gate.read().unwrap();
+        f()
+    }
+
+    /// Run `f`, which executes a transaction, while every other client is held
+    /// off. `f` must not call `concurrently`.
+    pub(crate) fn exclusively<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _gate = self.shared.exec_gate.write().unwrap();
+        f()
+    }
+
+    /// Run `f` with exclusive access to the slot for `key`.
+    ///
+    /// The slot holds the current entry, or `None` if the key is absent or
+    /// expired. `f` may read it, replace it, change its deadline, or set it to
+    /// `None` to delete the key; whatever is left in the slot when `f` returns
+    /// is stored back, unless it is an empty collection. The shard stays locked
+    /// for the duration of the call, which makes read-modify-write sequences
+    /// atomic.
+    ///
+    /// Borrowing the slot mutably counts as modifying the key; see `Slot`.
+    /// Leaving a list at a key that held none wakes the clients blocked on
+    /// it, once the shard is unlocked again.
+    pub(crate) fn with_entry<R>(&self, key: &Bytes, f: impl FnOnce(&mut Slot) -> R) -> R {
+        let mut shard = self.shard(key).lock().unwrap();
+
+        let mut slot = Slot::new(shard.take(key, now_ms()));
+        let had_list = holds_list(&slot);
+        let ret = f(&mut slot);
+        if slot.is_modified() {
+            shard.touch(key);
+        }
+        let wakes = shard.wakes_blocked(key, had_list, &slot);
+        if let Some(entry) = slot.entry {
+            shard.put(key.clone(), entry);
+        }
+        drop(shard);
+
+        if wakes {
+            self.serve_blocked(key);
+        }
+        ret
+    }
+
+    /// Run `f` with exclusive access to the slots of all `keys` at once.
+    ///
+    /// This is the multi-key counterpart of `with_entry`: every shard owning
+    /// one of the keys stays locked until `f` returns, so no other client can
+    /// observe a state where only some of the keys were updated. Shards are
+    /// always locked in ascending index order, which keeps two overlapping
+    /// multi-key calls from deadlocking each other. Duplicate keys share a
+    /// single slot.
+    pub(crate) fn with_entries<R>(&self, keys: &[Bytes], f: impl FnOnce(&mut Slots) -> R) -> R {
+        let mut guards = self.lock_shards(keys);
+
+        let now = now_ms();
+        let mut slots = Slots {
+            slots: HashMap::with_capacity(keys.len()),
+        };
+        let mut lists = HashSet::new();
+        for key in keys {
+            if !slots.slots.contains_key(key) {
+                let entry = guards.shard(self.shard_index(key)).take(key, now);
+                if holds_list(&entry) {
+                    lists.insert(key.clone());
+                }
+                slots.slots.insert(key.clone(), Slot::new(entry));
+            }
+        }
+
+        let ret = f(&mut slots);
+
+        let mut woken = Vec::new();
+        for (key, slot) in slots.slots {
+            let shard = guards.shard(self.shard_index(&key));
+            if slot.is_modified() {
+                shard.touch(&key);
+            }
+            if shard.wakes_blocked(&key, lists.contains(&key), &slot) {
+                woken.push(key.clone());
+            }
+            if let Some(entry) = slot.entry {
+                shard.put(key, entry);
+            }
+        }
+        drop(guards);
+
+        for key in &woken {
+            self.serve_blocked(key);
+        }
+        ret
+    }
+
+    /// Lock every shard owning one of `keys`, in ascending index order.
+    fn lock_shards(&self, keys: &[Bytes]) -> ShardGuards<'_> {
+        let mut indices: Vec<usize> = keys.iter().map(|key| self.shard_index(key)).collect();
+        indices.sort_unstable();
+        indices.dedup();
+
+        ShardGuards {
+            guards: indices
+                .into_iter()
+                .map(|index| (index, self.shared.shards[index].lock().unwrap()))
+                .collect(),
+        }
+    }
+}
+
+/// Shards locked together by `Db::lock_shards`.
+struct ShardGuards<'a> {
+    /// Sorted by shard index.
+    guards: Vec<(usize, MutexGuard<'a, Shard>)>,
+}
+
+impl ShardGuards<'_> {
+    /// Returns the locked shard with the given index.
+    fn shard(&mut self, index: usize) -> &mut Shard {
+        let pos = self
+            .guards
+            .binary_search_by_key(&index, |(i, _)| *i)
+            .expect("shard was not locked");
+        &mut self.guards[pos].1
+    }
+}
+
+/// The slot for a key handed out by `Db::with_entry`: the current entry, or
+/// `None` if the key is absent or expired.
+///
+/// The slot derefs to the entry. Borrowing it mutably marks the key as
+/// modified, which is what `WATCH` goes by, so commands only reading the key
+/// must stick to shared borrows, and check the type of the entry before
+/// borrowing it mutably. A write that ends up changing nothing may still
+/// count, which at worst aborts a transaction that need not be.
+#[derive(Debug)]
+pub(crate) struct Slot {
+    entry: Option<Entry>,
+
+    /// Whether the key existed when the slot was handed out.
+    existed: bool,
+
+    /// Whether the entry was borrowed mutably.
+    borrowed_mut: bool,
+}
+
+impl Slot {
+    fn new(entry: Option<Entry>) -> Slot {
+        Slot {
+            existed: entry.is_some(),
+            entry,
+            borrowed_mut: false,
+        }
+    }
+
+    /// Returns `true` if the key may have been modified. A key that is still
+    /// missing was not, whatever happened to the slot in between.
+    fn is_modified(&self) -> bool {
+        self.borrowed_mut && (self.existed || self.entry.is_some())
+    }
+}
+
+impl Deref for Slot {
+    type Target = Option<Entry>;
+
+    fn deref(&self) -> &Option<Entry> {
+        &self.entry
+    }
+}
+
+impl DerefMut for Slot {
+    fn deref_mut(&mut self) -> &mut Option<Entry> {
+        self.borrowed_mut = true;
+        &mut self.entry
+    }
+}
+
+/// The slots of the keys locked by `Db::with_entries`.
+#[derive(Debug)]
+pub(crate) struct Slots {
+    slots: HashMap<Bytes, Slot>,
+}
+
+impl Slots {
+    /// Returns the slot for `key`, as `Db::with_entry` would.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was not one of the keys passed to `with_entries`.
+    pub(crate) fn slot(&mut self, key: &[u8]) -> &mut Slot {
+        self.slots.get_mut(key).expect("key was not locked")
+    }
+
+    /// Returns the entry for `key`, if any. Unlike `slot`, several entries can
+    /// be borrowed at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` was not one of the keys passed to `with_entries`.
+    pub(crate) fn entry(&self, key: &[u8]) -> Option<&Entry> {
+        self.slots.get(key).expect("key was not locked").as_ref()
+    }
+}
+
+impl Default for Db {
+    fn default() -> Db {
+        Db::new()
+    }
+}
+
+impl Shard {
+    /// Look up a key that has not expired yet. An expired entry found on the
+    /// way is removed.
+    fn live(&mut self, key: &[u8], now: u64) -> Option<&mut Entry> {
+        self.encode_for_rewrite();
+        if self.entries.get(key)?.is_expired(now) {
+            self.take(key, now);
+            return None;
+        }
+
+        self.entries.get_mut(key)
+    }
+
+    /// Remove a key, returning its entry only if it had not expired yet.
+    fn take(&mut self, key: &[u8], now: u64) -> Option<Entry> {
+        self.encode_for_rewrite();
+        let (key, entry) = self.entries.remove_entry(key)?;
+
+        // An expired key is gone for good, which counts as a modification.
+        let expired = entry.is_expired(now);
+        if expired {
+            self.touch(&key);
+        }
+
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key));
+        }
+
+        (!expired).then_some(entry)
+    }
+
+    /// Store an entry, indexing its deadline if it has one. The key must have
+    /// been taken out of the shard first.
+    ///
+    /// An empty collection is dropped instead, deleting the key.
+    fn put(&mut self, key: Bytes, entry: Entry) {
+        self.encode_for_rewrite();
+        if entry.value.is_empty_collection() {
+            return;
+        }
+
+        if let Some(when) = entry.expires_at {
+            self.expirations.insert((when, key.clone()));
+        }
+
+        self.entries.insert(key, entry);
+    }
+
+    /// Remove up to `limit` keys whose deadline is at or before `now`.
+    /// Returns the number of keys removed.
+    fn purge_expired(&mut self, now: u64, limit: usize) -> usize {
+        let mut purged = 0;
+
+        while purged < limit {
+            match self.expirations.first() {
+                Some((when, _)) if *when <= now => {}
+                _ => break,
+            }
+
+            let (_, key) = self.expirations.pop_first().unwrap();
+            self.entries.remove(&key);
+            self.touch(&key);
+            purged += 1;
+        }
+
+        purged
+    }
+}
+
+/// Routine executed by the background expiry task.
+async fn purge_expired_keys(shared: Weak<Shared>) {
+    let mut interval = tokio::time::interval(EXPIRE_CYCLE_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let Some(shared) = shared.upgrade() else {
+            // Every `Db` handle is gone, nothing left to expire.
+            return;
+        };
+
+        for shard in shared.shards.iter() {
+            let now = now_ms();
+            while shard.lock().unwrap().purge_expired(now, EXPIRE_BATCH) == EXPIRE_BATCH {
+                // Let other tasks make progress between batches.
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn keys_are_spread_across_shards() {
+        let db = Db::with_shards(4);
+        for i in 0..64 {
+            db.set(Bytes::from(format!("key:{i}")), Bytes::from_static(b"v"));
+        }
+
+        let populated = db
+            .shared
+            .shards
+            .iter()
+            .filter(|shard| !shard.lock().unwrap().entries.is_empty())
+            .count();
+        assert!(populated > 1);
+
+        for i in 0..64 {
+            assert!(db.contains(format!("key:{i}").as_bytes()));
+        }
+    }
+
+    #[test]
+    fn with_entry_can_insert_update_and_delete() {
+        let db = Db::with_shards(1);
+        let key = Bytes::from_static(b"k");
+
+        db.with_entry(&key, |slot| {
+            assert!(slot.is_none());
+            **slot = Some(Entry::new(Bytes::from_static(b"1")));
+        });
+        assert_eq!(db.get(&key).unwrap(), Some(Bytes::from_static(b"1")));
+
+        db.with_entry(&key, |slot| **slot = None);
+        assert!(!db.contains(&key));
+    }
+
+    #[test]
+    fn concurrent_read_modify_write_loses_no_updates() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 1000;
+
+        let db = Db::with_shards(4);
+        let key = Bytes::from_static(b"counter");
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let db = db.clone();
+                let key = key.clone();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        db.with_entry(&key, |slot| {
+                            let n: usize = slot
+                                .as_ref()
+                                .map(|e| {
+                                    std::str::from_utf8(e.value.as_string().unwrap())
+                                        .unwrap()
+                                        .parse()
+                                        .unwrap()
+                                })
+                                .unwrap_or(0);
+                            **slot = Some(Entry::new(Bytes::from((n + 1).to_string())));
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let expected = (THREADS * ROUNDS).to_string();
+        assert_eq!(db.get(&key).unwrap(), Some(Bytes::from(expected)));
+    }
+
+    #[test]
+    fn expired_keys_are_removed_on_access() {
+        let db = Db::with_shards(1);
+        let key = Bytes::from_static(b"k");
+
+        db.with_entry(&key, |slot| {
+            **slot = Some(Entry {
+                value: Value::String(Bytes::from_static(b"v")),
+                expires_at: Some(now_ms() - 1),
+            });
+        });
+
+        assert_eq!(db.get(&key).unwrap(), None);
+        let shard = db.shared.shards[0].lock().unwrap();
+        assert!(shard.entries.is_empty());
+        assert!(shard.expirations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expiry_task_purges_keys_nobody_reads() {
+        let db = Db::with_shards(2);
+        let deadline = now_ms() + 50;
+
+        for i in 0..100 {
+            let key = Bytes::from(format!("key:{i}"));
+            db.with_entry(&key, |slot| {
+                **slot = Some(Entry {
+                    value: Value::String(Bytes::from_static(b"v")),
+                    expires_at: Some(deadline),
+                });
+            });
+        }
+        db.set(Bytes::from_static(b"persistent"), Bytes::from_static(b"v"));
+
+        db.spawn_expiry_task();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        let remaining: usize = db
+            .shared
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum();
+        assert_eq!(remaining, 1);
+    }
+
+    #[test]
+    fn with_entries_updates_keys_in_different_shards_together() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 500;
+
+        let db = Db::with_shards(8);
+        let keys: Vec<Bytes> = (0..8).map(|i| Bytes::from(format!("key:{i}"))).collect();
+
+        // Writers bump every key in one call, in different key orders.
+        let writers: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let db = db.clone();
+                let mut keys = keys.clone();
+                keys.rotate_left(t);
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        db.with_entries(&keys, |slots| {
+                            for key in &keys {
+                                let slot = slots.slot(key);
+                                let n: usize = slot
+                                    .as_ref()
+                                    .map(|e| {
+                                        std::str::from_utf8(e.value.as_string().unwrap())
+                                            .unwrap()
+                                            .parse()
+                                            .unwrap()
+                                    })
+                                    .unwrap_or(0);
+                                **slot = Some(Entry::new(Bytes::from((n + 1).to_string())));
+                            }
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        // Readers must never see the keys disagree with each other.
+        for _ in 0..ROUNDS {
+            let values = db.with_entries(&keys, |slots| {
+                keys.iter()
+                    .map(|key| {
+                        slots
+                            .slot(key)
+                            .as_ref()
+                            .map(|e| e.value.as_string().unwrap().clone())
+                    })
+                    .collect::<Vec<_>>()
+            });
+            assert!(values.windows(2).all(|pair| pair[0] == pair[1]));
+        }
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let expected = Bytes::from((THREADS * ROUNDS).to_string());
+        for key in &keys {
+            assert_eq!(db.get(key).unwrap(), Some(expected.clone()));
+        }
+    }
+}
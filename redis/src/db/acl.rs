@@ -0,0 +1,124 @@
+//! The users clients authenticate as, and what they may do.
+
+use super::Db;
+use crate::acl::{NO_AUTH_COMMANDS, User};
+use crate::frame::Frame;
+
+use std::collections::HashMap;
+
+/// Users by name.
+pub(super) type Users = HashMap<String, User>;
+
+/// Returns the users a `Db` starts out with: just the `default` one.
+pub(super) fn default_users() -> Users {
+    HashMap::from([("default".to_string(), User::default_user())])
+}
+
+impl Db {
+    /// Returns the user clients are authenticated as when they connect, if
+    /// any: the `default` user, as long as it needs no password.
+    pub(crate) fn initial_user(&self) -> Option<String> {
+        let users = self.shared.users.read().unwrap();
+        let user = users.get("default")?;
+        user.is_open().then(|| user.name().to_string())
+    }
+
+    /// Returns `true` if a client may authenticate as `username` with
+    /// `password`.
+    pub(crate) fn authenticate(&self, username: &str, password: &[u8]) -> bool {
+        self.shared
+            .users
+            .read()
+            .unwrap()
+            .get(username)
+            .is_some_and(|user| user.accepts(password))
+    }
+
+    /// Returns `true` if the `default` user has a password, which `AUTH`
+    /// with just a password checks against.
+    pub(crate) fn default_user_has_password(&self) -> bool {
+        self.shared
+            .users
+            .read()
+            .unwrap()
+            .get("default")
+            .is_some_and(User::has_password)
+    }
+
+    /// Check that a client authenticated as `user`, or not authenticated if
+    /// `None`, may run the command `request`.
+    pub(crate) fn check_access(&self, user: Option<&str>, request: &Frame) -> Result<(), String> {
+        let Frame::Array(args) = request else {
+            return Ok(());
+        };
+        let no_auth = args.first().is_some_and(|name| {
+            let name = name.to_string().to_lowercase();
+            NO_AUTH_COMMANDS.contains(&&name[..])
+        });
+        if no_auth {
+            return Ok(());
+        }
+
+        let users = self.shared.users.read().unwrap();
+        match user.and_then(|name| users.get(name)) {
+            Some(user) => user.check(args),
+            // A user that was deleted no longer lets anyone in.
+            None => Err("NOAUTH Authentication required.".to_string()),
+        }
+    }
+
+    /// Returns the user called `name`.
+    pub(crate) fn user(&self, name: &str) -> Option<User> {
+        self.shared.users.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns every user, sorted by name.
+    pub(crate) fn users(&self) -> Vec<User> {
+        let mut users: Vec<User> = self
+            .shared
+            .users
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        users.sort_by(|a, b| a.name().cmp(b.name()));
+        users
+    }
+
+    /// Create the user called `name` if needed, then apply `rules` to it.
+    /// Either every rule applies, or the user is left as it was.
+    pub(crate) fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.shared.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply_rule(rule)?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Delete the users called `names`, returning how many existed.
+    pub(crate) fn delete_users(&self, names: &[String]) -> Result<usize, String> {
+        if names.iter().any(|name| name == "default") {
+            return Err("The 'default' user cannot be removed".to_string());
+        }
+        let mut users = self.shared.users.write().unwrap();
+        Ok(names
+            .iter()
+            .filter(|name| users.remove(&name[..]).is_some())
+            .count())
+    }
+
+    /// Make `password` the only password of the `default` user, or let
+    /// anyone in as it if empty, like `requirepass` does in Redis.
+    pub(crate) fn set_requirepass(&self, password: &str) {
+        let rules = if password.is_empty() {
+            vec!["nopass".to_string()]
+        } else {
+            vec!["resetpass".to_string(), format!(">{}", password)]
+        };
+        self.set_user("default", &rules)
+            .expect("password rules always apply");
+    }
+}
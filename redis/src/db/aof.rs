@@ -0,0 +1,478 @@
+//! Append-only file persistence, and propagation of writes in general.
+//!
+//! Every command that modifies the keyspace is appended to the file in RESP,
+//! just as a client would have sent it, so replaying the file through the
+//! command parser rebuilds the keyspace. Commands whose effect depends on
+//! when or where they run are logged in a form that does not: see
+//! `cmd::Propagation`.
+//!
+//! Like Redis, the server stops taking writes while the file cannot be
+//! written to or flushed, replying `MISCONF` until it can again.
+//!
+//! The same requests are fed to replicas, see `replication`. While writes
+//! are propagated anywhere, they are applied one at a time under the lock of
+//! the file, so that they are propagated in the order they took effect. Reads
+//! are not affected.
+//!
+//! `BGREWRITEAOF` replaces the file with the shortest sequence of commands
+//! that recreates the keyspace as it was when the rewrite started. Shards are
+//! encoded one at a time in the background, except that a shard about to be
+//! changed before its turn is encoded first, so that none of them is encoded
+//! with changes made after the start. Writes logged in the meantime go to the
+//! old file as usual and are also kept aside, like Redis's rewrite buffer, to
+//! be appended to the new file before it replaces the old one.
+
+use super::{Db, Shard, Value, now_ms};
+use crate::frame::{Frame, Protocol};
+
+use bytes::Bytes;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Maximum number of elements added by a single command of a rewritten file,
+/// so that large collections do not turn into huge commands.
+const REWRITE_BATCH: usize = 64;
+
+/// The commands recreating the keyspace as of the start of a rewrite, filled
+/// in shard by shard.
+pub(super) type RewriteBase = Arc<Mutex<Vec<u8>>>;
+
+/// When appended commands are flushed to disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendFsync {
+    /// After every write command, before replying to it.
+    Always,
+
+    /// Once per second, in the background. A crash loses at most the last
+    /// second of writes.
+    #[default]
+    EverySec,
+
+    /// Whenever the operating system sees fit.
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<AppendFsync> {
+        match &s.to_lowercase()[..] {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(format!("invalid appendfsync policy '{}'", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        })
+    }
+}
+
+/// The append-only file of a `Db`.
+#[derive(Debug)]
+pub(super) struct Aof {
+    path: PathBuf,
+    file: File,
+    fsync: AppendFsync,
+
+    /// Set when commands were written since the last `fsync`.
+    unsynced: bool,
+
+    /// Length of the file up to the last command written in full, which the
+    /// file is cut back to if writing one fails.
+    len: u64,
+
+    /// Why the file could not be written to or flushed, if it could not.
+    /// Write commands are refused until it is healthy again.
+    error: Option<String>,
+
+    /// Commands logged since a rewrite started, or `None` if none is in
+    /// progress.
+    rewrite: Option<Vec<u8>>,
+}
+
+impl Db {
+    /// Start appending every write command to the file at `path`, which is
+    /// created if needed.
+    ///
+    /// Commands are appended to whatever the file already holds, so it should
+    /// have been replayed with `replay_aof` first.
+    pub fn enable_aof(&self, path: impl Into<PathBuf>, fsync: AppendFsync) -> crate::Result<()> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+        let len = file
+            .metadata()
+            .map_err(|e| format!("cannot open {}: {}", path.display(), e))?
+            .len();
+
+        *self.shared.aof.lock().unwrap() = Some(Aof {
+            path,
+            file,
+            fsync,
+            unsynced: false,
+            len,
+            error: None,
+            rewrite: None,
+        });
+
+        self.update_propagating();
+
+        if fsync == AppendFsync::EverySec {
+            tokio::spawn(sync_every_second(Arc::downgrade(&self.shared)));
+        }
+        Ok(())
+    }
+
+    /// Stop appending write commands to the file, flushing what was written
+    /// to disk first. Must not be called while applying a command.
+    pub(crate) fn disable_aof(&self) {
+        if let Some(aof) = self.shared.aof.lock().unwrap().take()
+            && let Err(e) = aof.file.sync_data()
+        {
+            eprintln!("cannot fsync {}: {}", aof.path.display(), e);
+        }
+        self.update_propagating();
+    }
+
+    /// Switch the file, if enabled, to another fsync policy.
+    pub(crate) fn set_appendfsync(&self, fsync: AppendFsync) {
+        let mut aof = self.shared.aof.lock().unwrap();
+        let Some(aof) = aof.as_mut() else {
+            return;
+        };
+
+        let previous = std::mem::replace(&mut aof.fsync, fsync);
+        if fsync == AppendFsync::EverySec && previous != AppendFsync::EverySec {
+            tokio::spawn(sync_every_second(Arc::downgrade(&self.shared)));
+        }
+    }
+
+    /// Returns `true` if write commands are appended to a file.
+    pub(crate) fn aof_enabled(&self) -> bool {
+        self.shared.aof.lock().unwrap().is_some()
+    }
+
+    /// Check that write commands may be applied. They are not while the file
+    /// cannot be written to, as it would no longer match the keyspace; the
+    /// file is tried again first.
+    pub(crate) fn check_aof(&self) -> Result<(), String> {
+        match self.shared.aof.lock().unwrap().as_mut() {
+            Some(aof) => aof.recover(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `true` if write commands have to be propagated, to the file or
+    /// to replicas. Checked while applying a command, this stays accurate
+    /// until the command is done.
+    pub(crate) fn propagating(&self) -> bool {
+        self.shared.propagating.load(Ordering::SeqCst)
+    }
+
+    /// Recompute whether write commands have to be propagated.
+    ///
+    /// Switching it on lets the commands being applied finish first, so that
+    /// none that started out not being propagated is applied after this
+    /// returns. Must not be called while applying a command.
+    pub(super) fn update_propagating(&self) {
+        let propagating = self.shared.aof.lock().unwrap().is_some()
+            || self.shared.replication.lock().unwrap().is_active();
+        self.shared.propagating.store(propagating, Ordering::SeqCst);
+        self.exclusively(|| {});
+    }
+
+    /// Run `f`, which applies a write command, then propagate the frames it
+    /// returns along with the reply. Nothing else is propagated in between,
+    /// except for the pops made on behalf of clients blocked on a list that
+    /// `f` pushed to, which follow its frames.
+    ///
+    /// If the frames cannot be written to the file, the reply is replaced
+    /// with an error: the command took effect, but may not survive a
+    /// restart.
+    pub(crate) fn logged(&self, f: impl FnOnce() -> (Frame, Vec<Frame>)) -> Frame {
+        let mut aof = self.shared.aof.lock().unwrap();
+        let (reply, mut frames) = f();
+        frames.append(&mut self.shared.served.lock().unwrap());
+        match self.propagate(&mut aof, &frames) {
+            Ok(()) => reply,
+            Err(e) => Frame::Error(format!("MISCONF {}", e)),
+        }
+    }
+
+    /// Append `frames` to the file, if enabled, and feed them to replicas.
+    /// `aof` is the locked file.
+    fn propagate(&self, aof: &mut Option<Aof>, frames: &[Frame]) -> Result<(), String> {
+        let mut data = Vec::new();
+        for frame in frames {
+            frame.encode(Protocol::Resp2, &mut data);
+        }
+
+        self.shared.replication.lock().unwrap().feed(&data);
+        match aof {
+            Some(aof) => aof.append(&data),
+            None => Ok(()),
+        }
+    }
+
+    /// Start rewriting the file from the current keyspace, in the background.
+    pub(crate) fn rewrite_aof(&self) -> crate::Result<()> {
+        let mut guard = self.shared.aof.lock().unwrap();
+        let Some(aof) = guard.as_mut() else {
+            return Err("Append only file is not enabled".into());
+        };
+        if aof.rewrite.is_some() {
+            return Err("Background append only file rewriting already in progress".into());
+        }
+
+        // Writes are applied with the file locked, so each of them either
+        // comes before every shard is marked, and is encoded with them, or
+        // after, and is kept aside.
+        let base = RewriteBase::default();
+        for shard in self.shared.shards.iter() {
+            shard.lock().unwrap().rewrite = Some(base.clone());
+        }
+        aof.rewrite = Some(Vec::new());
+
+        let mut tmp = aof.path.as_os_str().to_owned();
+        tmp.push(".rewrite");
+        let tmp = PathBuf::from(tmp);
+        drop(guard);
+
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            for shard in db.shared.shards.iter() {
+                shard.lock().unwrap().encode_for_rewrite();
+            }
+            let base = std::mem::take(&mut *base.lock().unwrap());
+            if let Err(e) = db.finish_rewrite(&tmp, &base) {
+                eprintln!("append only file rewrite failed: {}", e);
+                let _ = fs::remove_file(&tmp);
+                if let Some(aof) = db.shared.aof.lock().unwrap().as_mut() {
+                    aof.rewrite = None;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Write the rewritten file to `tmp`, then swap it in for the current
+    /// one along with what was logged in the meantime.
+    fn finish_rewrite(&self, tmp: &PathBuf, base: &[u8]) -> io::Result<()> {
+        let mut file = File::create(tmp)?;
+        file.write_all(base)?;
+        file.sync_data()?;
+
+        let mut guard = self.shared.aof.lock().unwrap();
+        let Some(aof) = guard.as_mut().filter(|aof| aof.rewrite.is_some()) else {
+            // The file was disabled in the meantime.
+            return Err(io::Error::other("append only file disabled"));
+        };
+        file.write_all(aof.rewrite.as_deref().unwrap_or_default())?;
+        file.sync_data()?;
+        fs::rename(tmp, &aof.path)?;
+
+        // The new file is only ever appended to from here on, so the handle
+        // that wrote it can keep going.
+        aof.len = file.metadata()?.len();
+        aof.file = file;
+        aof.rewrite = None;
+        aof.unsynced = false;
+        aof.error = None;
+        Ok(())
+    }
+}
+
+impl Shard {
+    /// Encode the shard for the rewrite in progress, unless it already was.
+    /// Called before anything in the shard changes.
+    pub(super) fn encode_for_rewrite(&mut self) {
+        if let Some(base) = self.rewrite.take() {
+            encode_shard(self, now_ms(), &mut base.lock().unwrap());
+        }
+    }
+}
+
+impl Aof {
+    /// Append `data`, made of whole commands, flushing it to disk first if
+    /// the policy says so.
+    fn append(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // The rewritten file mirrors the keyspace, which has the commands
+        // whether they make it to this file or not.
+        if let Some(rewrite) = &mut self.rewrite {
+            rewrite.extend_from_slice(data);
+        }
+
+        let res = self.file.write_all(data).and_then(|()| match self.fsync {
+            AppendFsync::Always => self.file.sync_data(),
+            _ => Ok(()),
+        });
+        match res {
+            Ok(()) => {
+                self.len += data.len() as u64;
+                self.unsynced = true;
+                Ok(())
+            }
+            Err(e) => {
+                // Whatever part of the commands was written is cut off, so
+                // the file still replays.
+                let _ = self.file.set_len(self.len);
+                Err(self.fail(e))
+            }
+        }
+    }
+
+    /// Record that the file could not be written to or flushed, returning
+    /// the error replied to writes until it can.
+    fn fail(&mut self, e: io::Error) -> String {
+        eprintln!("cannot write to {}: {}", self.path.display(), e);
+        let error = format!("Errors writing to the AOF file: {}", e);
+        self.error = Some(error.clone());
+        error
+    }
+
+    /// Returns the error writing to the file, if any, unless the file can be
+    /// cut back to its last command and flushed to disk now.
+    fn recover(&mut self) -> Result<(), String> {
+        let Some(error) = &self.error else {
+            return Ok(());
+        };
+        match self
+            .file
+            .set_len(self.len)
+            .and_then(|()| self.file.sync_data())
+        {
+            Ok(()) => {
+                self.error = None;
+                self.unsynced = false;
+                Ok(())
+            }
+            Err(_) => Err(error.clone()),
+        }
+    }
+}
+
+/// Routine of the task flushing the file to disk once per second.
+///
+/// The task exits once every `Db` handle is gone, or the file was disabled or
+/// switched to another policy.
+async fn sync_every_second(shared: Weak<super::Shared>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+
+        // `fsync` can take a while, so it runs on a handle of its own rather
+        // than with the file locked.
+        let file = {
+            let mut aof = shared.aof.lock().unwrap();
+            match aof.as_mut() {
+                Some(aof) if aof.fsync == AppendFsync::EverySec => {
+                    if !aof.unsynced {
+                        continue;
+                    }
+                    aof.unsynced = false;
+                    aof.file.try_clone()
+                }
+                _ => return,
+            }
+        };
+
+        let res = match file {
+            Ok(file) => tokio::task::spawn_blocking(move || file.sync_data())
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e))),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res
+            && let Some(aof) = shared.aof.lock().unwrap().as_mut()
+        {
+            aof.fail(e);
+        }
+    }
+}
+
+/// Append the commands recreating the live keys of `shard` to `out`.
+fn encode_shard(shard: &Shard, now: u64, out: &mut Vec<u8>) {
+    for (key, entry) in &shard.entries {
+        if entry.is_expired(now) {
+            continue;
+        }
+
+        match &entry.value {
+            Value::String(value) => {
+                command("SET", key, [value.clone()]).encode(Protocol::Resp2, out)
+            }
+            Value::List(list) => batches("RPUSH", key, list.iter().cloned(), out),
+            Value::Set(set) => batches("SADD", key, set.iter().cloned(), out),
+            Value::Hash(hash) => batches(
+                "HSET",
+                key,
+                hash.iter()
+                    .flat_map(|(field, value)| [field.clone(), value.clone()]),
+                out,
+            ),
+            Value::SortedSet(zset) => batches(
+                "ZADD",
+                key,
+                zset.range(0, zset.len(), false)
+                    .flat_map(|(member, score)| [Bytes::from(score.to_string()), member.clone()]),
+                out,
+            ),
+        }
+
+        if let Some(when) = entry.expires_at {
+            command("PEXPIREAT", key, [Bytes::from(when.to_string())]).encode(Protocol::Resp2, out);
+        }
+    }
+}
+
+/// Append `name key args...` commands adding `args` to `key`, with no more
+/// than `REWRITE_BATCH` elements each. Hash fields and sorted set members
+/// take two arguments per element.
+fn batches(name: &str, key: &Bytes, args: impl Iterator<Item = Bytes>, out: &mut Vec<u8>) {
+    let per_element = if matches!(name, "HSET" | "ZADD") {
+        2
+    } else {
+        1
+    };
+    let args: Vec<Bytes> = args.collect();
+    for chunk in args.chunks(REWRITE_BATCH * per_element) {
+        command(name, key, chunk.iter().cloned()).encode(Protocol::Resp2, out);
+    }
+}
+
+/// Build the request frame for `name key args...`.
+fn command(name: &str, key: &Bytes, args: impl IntoIterator<Item = Bytes>) -> Frame {
+    let mut frame = Frame::array();
+    frame.push_bulk(Bytes::copy_from_slice(name.as_bytes()));
+    frame.push_bulk(key.clone());
+    for arg in args {
+        frame.push_bulk(arg);
+    }
+    frame
+}
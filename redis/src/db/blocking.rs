@@ -0,0 +1,434 @@
+//! Clients blocked on empty lists.
+//!
+//! `BLPOP` and friends register a `Waiter` on every key they wait for. The
+//! waiters of a key live in a FIFO queue on the key's shard. Whenever a write
+//! leaves a list at a key that has waiters and held none before, elements are
+//! handed over one waiter at a time, oldest first, until either the list or
+//! the queue runs dry. Each pushed element therefore wakes at most one client,
+//! and a client blocked on several keys is served by whichever key gets an
+//! element first.
+//!
+//! Every hand-off happens with the source and, for `BLMOVE`, the destination
+//! locked, and is propagated right after the write that caused it, so the log
+//! replays to the same keyspace.
+
+use super::{Db, Entry, Shard, ShardGuards, Value, WrongType, now_ms};
+use crate::cmd::End;
+use crate::frame::Frame;
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// What a blocked client gets: the key the element was popped from along with
+/// the element, or `WrongType` if it was to be moved to a key that no longer
+/// holds a list.
+pub(crate) type Delivery = Result<(Bytes, Bytes), WrongType>;
+
+/// A client blocked on one or more keys.
+///
+/// The same waiter is queued on every key the client waits for. The sender is
+/// taken by the first key to serve the client, so the other queues simply skip
+/// the waiter when they get to it.
+#[derive(Debug)]
+pub(crate) struct Waiter {
+    /// The end of the list elements are popped from.
+    end: End,
+
+    /// For `BLMOVE`, the list the element is pushed to, and at which end.
+    destination: Option<(Bytes, End)>,
+
+    tx: Mutex<Option<oneshot::Sender<Delivery>>>,
+}
+
+/// Queues of blocked clients, per key.
+pub(super) type WaitQueues = HashMap<Bytes, VecDeque<Arc<Waiter>>>;
+
+/// Outcome of `Db::pop_or_block`.
+#[derive(Debug)]
+pub(crate) enum BlockingPop {
+    /// One of the lists had an element, which was popped, or moved, right
+    /// away.
+    Ready(Bytes, Bytes),
+
+    /// All the lists were empty and the client is now queued on them.
+    Blocked(Blocked),
+}
+
+/// Handle to a client queued by `Db::pop_or_block`.
+///
+/// Dropping the handle unregisters the client from every queue it is on. If an
+/// element was popped for the client but never received, because it
+/// disconnected at the same moment, it is pushed back where it came from so it
+/// is not lost. A moved element stays where it was moved to.
+#[derive(Debug)]
+pub(crate) struct Blocked {
+    db: Db,
+    keys: Vec<Bytes>,
+    waiter: Arc<Waiter>,
+    rx: oneshot::Receiver<Delivery>,
+}
+
+/// Returns `true` if the slot holds a list. Empty lists are never stored, so
+/// the list has an element to hand over.
+pub(super) fn holds_list(slot: &Option<Entry>) -> bool {
+    matches!(slot, Some(Entry { value: Value::List(list), .. }) if !list.is_empty())
+}
+
+impl Db {
+    /// Pop an element from the first non-empty list among `keys`, or queue
+    /// the client on all of them if they are all empty. With a `destination`,
+    /// the element is pushed there in the same step, as `LMOVE` does.
+    ///
+    /// Checking the lists and queueing happen under the locks of all the
+    /// involved shards, so an element pushed concurrently is either seen here
+    /// or handed to the new waiter; it cannot slip through in between.
+    pub(crate) fn pop_or_block(
+        &self,
+        keys: &[Bytes],
+        end: End,
+        destination: Option<(Bytes, End)>,
+    ) -> Result<BlockingPop, WrongType> {
+        let mut locked = keys.to_vec();
+        locked.extend(destination.iter().map(|(key, _)| key.clone()));
+        let mut guards = self.lock_shards(&locked);
+        let now = now_ms();
+
+        for key in keys {
+            let shard = guards.shard(self.shard_index(key));
+            let Some(entry) = shard.live(key, now) else {
+                continue;
+            };
+            entry.value.as_list()?;
+
+            let (value, woken) = self.hand_off(&mut guards, key, end, destination.as_ref())?;
+            drop(guards);
+
+            if let Some(woken) = woken {
+                self.serve_blocked(&woken);
+            }
+            return Ok(BlockingPop::Ready(key.clone(), value));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let waiter = Arc::new(Waiter {
+            end,
+            destination,
+            tx: Mutex::new(Some(tx)),
+        });
+
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in &keys {
+            guards
+                .shard(self.shard_index(key))
+                .blocked
+                .entry(key.clone())
+                .or_default()
+                .push_back(waiter.clone());
+        }
+
+        Ok(BlockingPop::Blocked(Blocked {
+            db: self.clone(),
+            keys,
+            waiter,
+            rx,
+        }))
+    }
+
+    /// Hand elements of the list at `key` to the clients blocked on it,
+    /// oldest first, then do the same for any list this created by moving
+    /// elements.
+    ///
+    /// Called once the write that filled the list has released its locks.
+    /// Each hand-off locks the list again, along with the destination of a
+    /// `BLMOVE`.
+    pub(super) fn serve_blocked(&self, key: &Bytes) {
+        let mut ready = VecDeque::from([key.clone()]);
+
+        while let Some(key) = ready.pop_front() {
+            loop {
+                let Some(waiter) = self.shard(&key).lock().unwrap().next_waiter(&key) else {
+                    break;
+                };
+
+                let mut locked = vec![key.clone()];
+                locked.extend(waiter.destination.iter().map(|(key, _)| key.clone()));
+                let mut guards = self.lock_shards(&locked);
+                let now = now_ms();
+
+                // Someone else may have got here first while nothing was
+                // locked.
+                let shard = guards.shard(self.shard_index(&key));
+                if !shard
+                    .next_waiter(&key)
+                    .is_some_and(|next| Arc::ptr_eq(&next, &waiter))
+                {
+                    continue;
+                }
+                if shard
+                    .live(&key, now)
+                    .is_none_or(|entry| entry.value.as_list().is_err())
+                {
+                    break;
+                }
+                let queue = shard.blocked.get_mut(&key).expect("waiter is queued");
+                queue.pop_front();
+                if queue.is_empty() {
+                    shard.blocked.remove(&key);
+                }
+
+                // The sender stays locked until the element is sent, so that
+                // a client giving up at the same time is sure to find it.
+                let mut sender = waiter.tx.lock().unwrap();
+                let Some(tx) = sender.take() else {
+                    // Already served through another key.
+                    continue;
+                };
+
+                let delivery =
+                    match self.hand_off(&mut guards, &key, waiter.end, waiter.destination.as_ref())
+                    {
+                        Ok((value, woken)) => {
+                            self.log_served(&key, &waiter);
+                            if let Some(woken) = woken
+                                && !ready.contains(&woken)
+                            {
+                                ready.push_back(woken);
+                            }
+                            Ok((key.clone(), value))
+                        }
+                        Err(err) => Err(err),
+                    };
+
+                // The handle takes the sender before going away, so the
+                // client is still there.
+                tx.send(delivery)
+                    .expect("blocked client went away while being served");
+            }
+        }
+    }
+
+    /// Pop an element from the list at `key`, which must be a live list, and
+    /// push it to `destination` if there is one. Both keys must be locked in
+    /// `guards`.
+    ///
+    /// Returns the element, along with the destination if this created it
+    /// and clients are blocked on it. Fails without popping anything if the
+    /// destination holds another type.
+    fn hand_off(
+        &self,
+        guards: &mut ShardGuards<'_>,
+        key: &Bytes,
+        end: End,
+        destination: Option<&(Bytes, End)>,
+    ) -> Result<(Bytes, Option<Bytes>), WrongType> {
+        let now = now_ms();
+        if let Some((destination, _)) = destination
+            && let Some(entry) = guards
+                .shard(self.shard_index(destination))
+                .live(destination, now)
+        {
+            entry.value.as_list()?;
+        }
+
+        let shard = guards.shard(self.shard_index(key));
+        let list = shard.live(key, now).unwrap().value.as_list_mut()?;
+        let value = end.pop(list).unwrap();
+        if list.is_empty() {
+            shard.take(key, now);
+        }
+        shard.touch(key);
+
+        let Some((destination, to)) = destination else {
+            return Ok((value, None));
+        };
+
+        let shard = guards.shard(self.shard_index(destination));
+        let woken = match shard.live(destination, now) {
+            Some(entry) => {
+                to.push(entry.value.as_list_mut()?, value.clone());
+                None
+            }
+            None => {
+                let list = VecDeque::from([value.clone()]);
+                shard.put(destination.clone(), Entry::new(Value::List(list)));
+                shard
+                    .blocked
+                    .contains_key(destination)
+                    .then(|| destination.clone())
+            }
+        };
+        shard.touch(destination);
+
+        Ok((value, woken))
+    }
+
+    /// Queue the hand-off from `key` to `waiter`, to be propagated right after
+    /// the write that caused it. Logged as the command the waiter would have
+    /// run had the element been there already.
+    fn log_served(&self, key: &Bytes, waiter: &Waiter) {
+        if !self.propagating() {
+            return;
+        }
+
+        let args = match &waiter.destination {
+            None => vec![command(waiter.end, "LPOP", "RPOP"), key.clone()],
+            Some((destination, to)) => vec![
+                Bytes::from_static(b"LMOVE"),
+                key.clone(),
+                destination.clone(),
+                command(waiter.end, "LEFT", "RIGHT"),
+                command(*to, "LEFT", "RIGHT"),
+            ],
+        };
+        let request = Frame::Array(args.into_iter().map(Frame::Bulk).collect());
+        self.shared.served.lock().unwrap().push(request);
+    }
+}
+
+impl Blocked {
+    /// Wait until an element is handed over.
+    ///
+    /// Cancel safe: dropping the future before it completes leaves any element
+    /// in flight to `cancel` or the `Drop` implementation.
+    pub(crate) async fn recv(&mut self) -> Delivery {
+        // The sender is only dropped once the waiter is taken out of every
+        // queue, which does not happen while this handle is alive.
+        (&mut self.rx)
+            .await
+            .expect("waiter dropped while the client is blocked")
+    }
+
+    /// Stop waiting, returning what was handed over in the meantime, if
+    /// anything.
+    pub(crate) fn cancel(mut self) -> Option<Delivery> {
+        // Taking the sender first guarantees nothing can be handed over after
+        // the check below.
+        self.waiter.tx.lock().unwrap().take();
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        self.waiter.tx.lock().unwrap().take();
+        if let Ok(Ok((key, value))) = self.rx.try_recv()
+            && self.waiter.destination.is_none()
+        {
+            let end = self.waiter.end;
+            let db = &self.db;
+            let push_back = || {
+                db.with_entry(&key, |slot| {
+                    // Push the element back where it was popped from. The key
+                    // can only have been replaced by another type in the
+                    // meantime, in which case the element is dropped like
+                    // Redis would have.
+                    let entry =
+                        slot.get_or_insert_with(|| Entry::new(Value::List(VecDeque::new())));
+                    let Ok(list) = entry.value.as_list_mut() else {
+                        return (Frame::Null, vec![]);
+                    };
+                    end.push(list, value.clone());
+
+                    // The pop was propagated already, so the push is too.
+                    let push = [command(end, "LPUSH", "RPUSH"), key.clone(), value];
+                    (
+                        Frame::Null,
+                        vec![Frame::Array(push.map(Frame::Bulk).to_vec())],
+                    )
+                })
+            };
+            db.concurrently(|| {
+                if db.propagating() {
+                    db.logged(push_back);
+                } else {
+                    push_back();
+                }
+            });
+        }
+
+        for key in &self.keys {
+            let mut shard = self.db.shard(key).lock().unwrap();
+            if let Some(queue) = shard.blocked.get_mut(key) {
+                queue.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+                if queue.is_empty() {
+                    shard.blocked.remove(key);
+                }
+            }
+        }
+    }
+}
+
+impl Shard {
+    /// Returns the client blocked on `key` for the longest, if any.
+    fn next_waiter(&self, key: &[u8]) -> Option<Arc<Waiter>> {
+        self.blocked.get(key)?.front().cloned()
+    }
+
+    /// Returns `true` if storing `slot` back at `key`, which held a non-empty
+    /// list before only if `had_list`, makes an element available to clients
+    /// blocked on it.
+    pub(super) fn wakes_blocked(&self, key: &[u8], had_list: bool, slot: &Option<Entry>) -> bool {
+        !had_list && holds_list(slot) && self.blocked.contains_key(key)
+    }
+}
+
+/// Returns the name of the command, among the `left` and `right` variants,
+/// that works on `end`.
+fn command(end: End, left: &'static str, right: &'static str) -> Bytes {
+    Bytes::from_static(match end {
+        End::Left => left.as_bytes(),
+        End::Right => right.as_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(db: &Db, key: &str) -> Blocked {
+        match db.pop_or_block(&[Bytes::from(key.to_string())], End::Left, None) {
+            Ok(BlockingPop::Blocked(blocked)) => blocked,
+            other => panic!("expected to block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn each_element_wakes_one_waiter() {
+        let db = Db::new();
+        let key = Bytes::from("queue");
+        let mut first = block(&db, "queue");
+        let mut second = block(&db, "queue");
+
+        db.with_entry(&key, |slot| {
+            **slot = Some(Entry::new(Value::List(VecDeque::from([Bytes::from("a")]))));
+        });
+
+        assert_eq!(
+            first.rx.try_recv().unwrap().unwrap(),
+            (key.clone(), Bytes::from("a"))
+        );
+        assert!(second.rx.try_recv().is_err());
+        assert!(!db.contains(&key));
+
+        // A waiter that goes away hands back an element it never received.
+        db.with_entry(&key, |slot| {
+            **slot = Some(Entry::new(Value::List(VecDeque::from([Bytes::from("b")]))));
+        });
+        drop(second);
+        assert_eq!(
+            db.with_entry(&key, |slot| slot
+                .as_ref()
+                .unwrap()
+                .value
+                .as_list()
+                .unwrap()
+                .len()),
+            1
+        );
+        assert!(db.shard(&key).lock().unwrap().blocked.is_empty());
+    }
+}
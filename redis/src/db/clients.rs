@@ -0,0 +1,142 @@
+//! Client connections: how many may be connected at once, and telling them
+//! the server is shutting down.
+//!
+//! Every connection holds a permit of a semaphore sized to `maxclients` for
+//! as long as it is open. Changing the limit at runtime adds permits, or
+//! takes some back as connections close.
+//!
+//! A shutdown is broadcast to every connection. Connections that start
+//! listening for it after it was requested see it right away, so none is
+//! missed however a connection and a shutdown race.
+
+use super::Db;
+
+use std::sync::Arc;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+/// Whether the keyspace is saved to a snapshot on the way out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownMode {
+    /// Save, unless write commands are appended to a file, which is then up
+    /// to date already.
+    #[default]
+    Default,
+
+    /// Always save.
+    Save,
+
+    /// Never save.
+    NoSave,
+}
+
+/// Whether a shutdown was requested, and the channel announcing it.
+#[derive(Debug)]
+pub(super) struct ShutdownState {
+    requested: Option<ShutdownMode>,
+    notify: broadcast::Sender<()>,
+}
+
+impl Default for ShutdownState {
+    fn default() -> ShutdownState {
+        ShutdownState {
+            requested: None,
+            notify: broadcast::channel(1).0,
+        }
+    }
+}
+
+/// Listens for the shutdown of the server.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    requested: bool,
+    notify: broadcast::Receiver<()>,
+}
+
+impl Shutdown {
+    /// Returns `true` if the server is shutting down.
+    pub(crate) fn is_requested(&mut self) -> bool {
+        if !self.requested {
+            self.requested = !matches!(self.notify.try_recv(), Err(TryRecvError::Empty));
+        }
+        self.requested
+    }
+
+    /// Wait for the server to shut down.
+    pub(crate) async fn recv(&mut self) {
+        if !self.requested {
+            // Only one message is ever sent. Missing it or the sender going
+            // away both mean the server is shutting down as well.
+            let _ = self.notify.recv().await;
+            self.requested = true;
+        }
+    }
+}
+
+impl Db {
+    /// Claim a place for a new client connection, which it keeps until the
+    /// permit is dropped. Returns `None` if `maxclients` are connected
+    /// already.
+    pub(crate) fn admit_client(&self) -> Option<OwnedSemaphorePermit> {
+        self.shared.clients.clone().try_acquire_owned().ok()
+    }
+
+    /// Change the number of clients that may be connected at once from
+    /// `from` to `to`. Lowering it does not drop anyone: the extra permits
+    /// are taken back as connections close.
+    pub(super) fn resize_clients(&self, from: usize, to: usize) {
+        if to > from {
+            self.shared.clients.add_permits(to - from);
+        } else if to < from {
+            let excess = from - to - self.shared.clients.forget_permits(from - to);
+            if excess > 0 {
+                let clients = Arc::clone(&self.shared.clients);
+                let excess = u32::try_from(excess).unwrap_or(u32::MAX);
+                tokio::spawn(async move {
+                    if let Ok(permits) = clients.acquire_many_owned(excess).await {
+                        permits.forget();
+                    }
+                });
+            }
+        }
+    }
+
+    /// Ask the server to shut down. Only the first request counts.
+    pub fn shutdown(&self, mode: ShutdownMode) {
+        let mut state = self.shared.shutdown.lock().unwrap();
+        if state.requested.is_none() {
+            state.requested = Some(mode);
+            let _ = state.notify.send(());
+        }
+    }
+
+    /// Returns how the server is shutting down, or `None` if it is not.
+    pub(crate) fn shutdown_requested(&self) -> Option<ShutdownMode> {
+        self.shared.shutdown.lock().unwrap().requested
+    }
+
+    /// Returns a listener for the shutdown of the server.
+    pub(crate) fn shutdown_listener(&self) -> Shutdown {
+        let state = self.shared.shutdown.lock().unwrap();
+        Shutdown {
+            requested: state.requested.is_some(),
+            notify: state.notify.subscribe(),
+        }
+    }
+
+    /// Persist what needs to be before the process exits, once every client
+    /// is gone: flush the append-only file to disk, and save a snapshot if
+    /// `mode` says so.
+    pub(crate) fn finish_shutdown(&self, mode: ShutdownMode) -> crate::Result<()> {
+        let aof = self.aof_enabled();
+        if aof {
+            self.disable_aof();
+        }
+
+        match mode {
+            ShutdownMode::Save => self.save(),
+            ShutdownMode::Default if !aof => self.save(),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! The settings a `Db` runs with, and changing them at runtime.
+
+use super::Db;
+use crate::config::Config;
+
+impl Db {
+    /// Create a new, empty `Db` instance set up as `config` says.
+    ///
+    /// Persistence is not started: the caller loads the snapshot or replays
+    /// the append-only file first, then enables the latter.
+    pub fn with_config(config: Config) -> Db {
+        let db = Db::with_shards(config.shards);
+        db.set_limits(config.limits);
+        db.set_snapshot_path(config.snapshot_path());
+        db.set_listening_port(config.port);
+        db.resize_clients(db.config().maxclients, config.maxclients);
+        if !config.requirepass.is_empty() {
+            db.set_requirepass(&config.requirepass);
+        }
+        *db.shared.config.lock().unwrap() = config;
+        db
+    }
+
+    /// Returns the current settings.
+    pub fn config(&self) -> Config {
+        self.shared.config.lock().unwrap().clone()
+    }
+
+    /// Change the settings named in `pairs` to the values given along with
+    /// them, for `CONFIG SET`. Either every setting is changed, or none is.
+    ///
+    /// Switching the append-only file on rewrites it from the keyspace, as it
+    /// is not known to be up to date. Must not be called while applying a
+    /// command.
+    pub(crate) fn set_config(&self, pairs: Vec<(String, String)>) -> crate::Result<()> {
+        let mut config = self.shared.config.lock().unwrap();
+        let mut updated = config.clone();
+        for (name, value) in pairs {
+            updated.set(&name, &value)?;
+        }
+
+        // The only change that may fail goes first, so nothing is changed if
+        // it does.
+        if updated.appendonly != config.appendonly {
+            if updated.appendonly {
+                self.enable_aof(updated.aof_path(), updated.appendfsync)?;
+                self.rewrite_aof()?;
+            } else {
+                self.disable_aof();
+            }
+        } else if updated.appendfsync != config.appendfsync {
+            self.set_appendfsync(updated.appendfsync);
+        }
+        self.resize_clients(config.maxclients, updated.maxclients);
+        self.set_limits(updated.limits);
+        self.set_snapshot_path(updated.snapshot_path());
+        if updated.requirepass != config.requirepass {
+            self.set_requirepass(&updated.requirepass);
+        }
+
+        *config = updated;
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Pub/Sub channels.
+//!
+//! Every subscribed client has a single outbox, registered under each channel
+//! and pattern it subscribed to. Publishing pushes the message straight into
+//! the outboxes of the matching subscriptions, so a client sees messages in
+//! the order they were published whatever subscriptions they came through.
+//!
+//! Publishing never waits on subscribers: a client that falls more than
+//! `OUTBOX_CAPACITY` messages behind is disconnected instead of holding up the
+//! publisher, like Redis does once a subscriber exceeds its output buffer
+//! limit.
+//!
+//! This takes the place of a `broadcast` sender per channel, which is how
+//! fan-out was first asked for, for two reasons:
+//!
+//! - A client subscribed to several channels or patterns would have had to
+//!   poll one receiver per subscription. Messages published one after the
+//!   other could then reach it out of order whenever they came through
+//!   different subscriptions. A single queue per client keeps them in publish
+//!   order.
+//! - A `broadcast` receiver that falls behind loses the oldest messages and
+//!   carries on from the newest, so a slow subscriber would silently skip
+//!   messages. Here it is disconnected instead, so it can tell it missed some.
+
+use super::Db;
+use crate::glob::glob_match;
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Notify, mpsc};
+
+/// Number of messages a subscriber may lag behind before it is disconnected.
+const OUTBOX_CAPACITY: usize = 1024;
+
+/// A message delivered to a subscriber.
+#[derive(Debug)]
+pub(crate) enum Message {
+    /// Published to a channel the client subscribed to.
+    Channel { channel: Bytes, message: Bytes },
+
+    /// Published to a channel matching a pattern the client subscribed to.
+    Pattern {
+        pattern: Bytes,
+        channel: Bytes,
+        message: Bytes,
+    },
+}
+
+/// The queue of messages published to one client, shared by all of its
+/// subscriptions.
+#[derive(Debug)]
+pub(crate) struct Outbox {
+    tx: mpsc::Sender<Message>,
+
+    /// Signalled when a message could not be queued because the client fell
+    /// too far behind.
+    overflowed: Notify,
+}
+
+/// The receiving end of an `Outbox`, held by the client's connection.
+#[derive(Debug)]
+pub(crate) struct Inbox {
+    rx: mpsc::Receiver<Message>,
+    outbox: Arc<Outbox>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct PubSub {
+    channels: HashMap<Bytes, Vec<Arc<Outbox>>>,
+    patterns: HashMap<Bytes, Vec<Arc<Outbox>>>,
+}
+
+impl Outbox {
+    /// Create the outbox of a client about to subscribe, along with the inbox
+    /// its messages are read from.
+    pub(crate) fn new() -> (Arc<Outbox>, Inbox) {
+        let (tx, rx) = mpsc::channel(OUTBOX_CAPACITY);
+        let outbox = Arc::new(Outbox {
+            tx,
+            overflowed: Notify::new(),
+        });
+        let inbox = Inbox {
+            rx,
+            outbox: outbox.clone(),
+        };
+        (outbox, inbox)
+    }
+
+    /// Queue `message` without waiting.
+    fn send(&self, message: Message) {
+        if self.tx.try_send(message).is_err() {
+            // The inbox lives as long as the subscriptions, so the queue is
+            // full.
+            self.overflowed.notify_one();
+        }
+    }
+}
+
+impl Inbox {
+    /// Receive the next message, in publish order. Returns `None` once a
+    /// message had to be dropped, after which the client must be
+    /// disconnected.
+    pub(crate) async fn recv(&mut self) -> Option<Message> {
+        tokio::select! {
+            biased;
+
+            () = self.outbox.overflowed.notified() => None,
+            // The outbox holds a sender, so the queue never closes.
+            message = self.rx.recv() => message,
+        }
+    }
+}
+
+/// Register `outbox` under `name`.
+fn add(subscriptions: &mut HashMap<Bytes, Vec<Arc<Outbox>>>, name: Bytes, outbox: &Arc<Outbox>) {
+    subscriptions.entry(name).or_default().push(outbox.clone());
+}
+
+/// Unregister `outbox` from `name`, dropping the name along with its last
+/// subscriber.
+fn remove(subscriptions: &mut HashMap<Bytes, Vec<Arc<Outbox>>>, name: &[u8], outbox: &Arc<Outbox>) {
+    if let Some(outboxes) = subscriptions.get_mut(name) {
+        outboxes.retain(|subscribed| !Arc::ptr_eq(subscribed, outbox));
+        if outboxes.is_empty() {
+            subscriptions.remove(name);
+        }
+    }
+}
+
+impl Db {
+    /// Subscribe the client owning `outbox` to `channel`. Each subscription
+    /// must be released with `unsubscribe`.
+    pub(crate) fn subscribe(&self, channel: Bytes, outbox: &Arc<Outbox>) {
+        let mut pubsub = self.shared.pubsub.lock().unwrap();
+        add(&mut pubsub.channels, channel, outbox);
+    }
+
+    pub(crate) fn unsubscribe(&self, channel: &[u8], outbox: &Arc<Outbox>) {
+        let mut pubsub = self.shared.pubsub.lock().unwrap();
+        remove(&mut pubsub.channels, channel, outbox);
+    }
+
+    /// Subscribe the client owning `outbox` to every channel matching the
+    /// glob-style `pattern`. Each subscription must be released with
+    /// `punsubscribe`.
+    pub(crate) fn psubscribe(&self, pattern: Bytes, outbox: &Arc<Outbox>) {
+        let mut pubsub = self.shared.pubsub.lock().unwrap();
+        add(&mut pubsub.patterns, pattern, outbox);
+    }
+
+    pub(crate) fn punsubscribe(&self, pattern: &[u8], outbox: &Arc<Outbox>) {
+        let mut pubsub = self.shared.pubsub.lock().unwrap();
+        remove(&mut pubsub.patterns, pattern, outbox);
+    }
+
+    /// Publish `message` to `channel`, returning the number of subscriptions
+    /// it was delivered to. A client subscribed both to the channel and to a
+    /// matching pattern gets it twice, and is counted twice, as in Redis.
+    pub(crate) fn publish(&self, channel: &Bytes, message: Bytes) -> usize {
+        let pubsub = self.shared.pubsub.lock().unwrap();
+        let mut receivers = 0;
+
+        if let Some(outboxes) = pubsub.channels.get(channel) {
+            for outbox in outboxes {
+                outbox.send(Message::Channel {
+                    channel: channel.clone(),
+                    message: message.clone(),
+                });
+            }
+            receivers += outboxes.len();
+        }
+
+        for (pattern, outboxes) in &pubsub.patterns {
+            if glob_match(pattern, channel) {
+                for outbox in outboxes {
+                    outbox.send(Message::Pattern {
+                        pattern: pattern.clone(),
+                        channel: channel.clone(),
+                        message: message.clone(),
+                    });
+                }
+                receivers += outboxes.len();
+            }
+        }
+
+        receivers
+    }
+
+    /// Returns the channels that have at least one subscriber, optionally
+    /// only those matching `pattern`.
+    pub(crate) fn channels(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        let pubsub = self.shared.pubsub.lock().unwrap();
+        pubsub
+            .channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the number of subscribers of `channel`, not counting pattern
+    /// subscriptions.
+    pub(crate) fn subscriber_count(&self, channel: &[u8]) -> usize {
+        let pubsub = self.shared.pubsub.lock().unwrap();
+        pubsub.channels.get(channel).map_or(0, Vec::len)
+    }
+
+    /// Returns the number of distinct patterns subscribed to.
+    pub(crate) fn pattern_count(&self) -> usize {
+        self.shared.pubsub.lock().unwrap().patterns.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lagging_subscribers_are_cut_off() {
+        let db = Db::new();
+        let channel = Bytes::from("news");
+        let (outbox, mut inbox) = Outbox::new();
+        db.subscribe(channel.clone(), &outbox);
+        db.psubscribe(Bytes::from("n*"), &outbox);
+
+        // Messages arrive in publish order, whatever subscription they
+        // came through.
+        db.publish(&channel, Bytes::from("a"));
+        db.publish(&Bytes::from("nope"), Bytes::from("b"));
+        assert!(matches!(
+            inbox.recv().await,
+            Some(Message::Channel { message, .. }) if message == "a"
+        ));
+        assert!(matches!(
+            inbox.recv().await,
+            Some(Message::Pattern { message, .. }) if message == "a"
+        ));
+        assert!(matches!(
+            inbox.recv().await,
+            Some(Message::Pattern { channel, .. }) if channel == "nope"
+        ));
+
+        for _ in 0..=OUTBOX_CAPACITY {
+            db.publish(&Bytes::from("nope"), Bytes::from("c"));
+        }
+        assert!(inbox.recv().await.is_none());
+    }
+}
@@ -0,0 +1,540 @@
+//! Snapshot persistence for `SAVE` and `BGSAVE`.
+//!
+//! A snapshot file holds every live key along with its value and deadline:
+//!
+//! ```text
+//! "REDIS-RS" version:u16           header
+//! [0xFC unix-ms:u64] type:u8 key value   once per key
+//! 0xFF checksum:u64                end of file
+//! ```
+//!
+//! Integers are little endian. Strings are a `u64` length followed by that
+//! many bytes, collections a `u64` count followed by their elements: strings
+//! for lists and sets, member-score pairs for sorted sets, with the score as
+//! an `f64`, and field-value pairs for hashes. The checksum is the CRC-64 of
+//! everything before it, as in Redis.
+//!
+//! Shards are written out one at a time, each under its own lock, so writing
+//! a snapshot never holds up the whole keyspace. Every shard is captured in a
+//! consistent state, but a command touching keys in several shards while the
+//! snapshot is in progress may only be partly reflected in it.
+
+use super::{Db, Entry, Shard, SortedSet, Value, now_ms};
+
+use bytes::{Buf, BufMut, Bytes};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"REDIS-RS";
+const VERSION: u16 = 1;
+
+/// Opcode preceding the deadline of the next key.
+const OP_EXPIRE_MS: u8 = 0xFC;
+
+/// Opcode marking the end of the keys.
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+
+/// Where snapshots are written, and how the last one went.
+#[derive(Debug)]
+pub(super) struct Snapshots {
+    path: PathBuf,
+
+    /// Unix time, in seconds, of the last successful save. Starts out as the
+    /// time the `Db` was created, as in Redis.
+    last_save: u64,
+
+    /// Set while a snapshot is being written, so that only one is at a time.
+    in_progress: bool,
+}
+
+impl Default for Snapshots {
+    fn default() -> Snapshots {
+        Snapshots {
+            path: PathBuf::from("dump.rdb"),
+            last_save: now_ms() / 1000,
+            in_progress: false,
+        }
+    }
+}
+
+impl Db {
+    /// Returns the file snapshots are written to and loaded from.
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.shared.snapshots.lock().unwrap().path.clone()
+    }
+
+    /// Change the file snapshots are written to and loaded from. Defaults to
+    /// `dump.rdb` in the working directory.
+    pub fn set_snapshot_path(&self, path: impl Into<PathBuf>) {
+        self.shared.snapshots.lock().unwrap().path = path.into();
+    }
+
+    /// Load the snapshot file into the keyspace, returning the number of keys
+    /// loaded. Keys that expired in the meantime are skipped.
+    ///
+    /// A missing file is not an error, there is just nothing to load. A file
+    /// that cannot be decoded is, and nothing is loaded from it.
+    pub fn load_snapshot(&self) -> crate::Result<usize> {
+        let path = self.snapshot_path();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("cannot read {}: {}", path.display(), e).into()),
+        };
+        let entries = decode(Bytes::from(data))
+            .map_err(|e| format!("cannot load {}: {}", path.display(), e))?;
+
+        Ok(self.insert_all(entries))
+    }
+
+    /// Returns a snapshot of the keyspace, in the format of the snapshot file.
+    pub(crate) fn snapshot(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_snapshot(&mut out)
+            .expect("writing to a Vec cannot fail");
+        out
+    }
+
+    /// Replace the whole keyspace with the keys of `snapshot`, returning the
+    /// number of keys loaded. Nothing changes if it cannot be decoded.
+    pub(crate) fn replace_with_snapshot(&self, snapshot: Bytes) -> crate::Result<usize> {
+        let entries = decode(snapshot)?;
+
+        let now = now_ms();
+        for shard in self.shared.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<Bytes> = shard.entries.keys().cloned().collect();
+            for key in keys {
+                shard.take(&key, now);
+                shard.touch(&key);
+            }
+        }
+
+        Ok(self.insert_all(entries))
+    }
+
+    /// Store decoded entries, skipping those that expired in the meantime.
+    fn insert_all(&self, entries: Vec<(Bytes, Entry)>) -> usize {
+        let now = now_ms();
+        let mut loaded = 0;
+        for (key, entry) in entries {
+            if entry.is_expired(now) {
+                continue;
+            }
+
+            let mut shard = self.shard(&key).lock().unwrap();
+            shard.take(&key, now);
+            shard.touch(&key);
+            shard.put(key, entry);
+            loaded += 1;
+        }
+        loaded
+    }
+
+    /// Write a snapshot, returning once it is on disk.
+    pub(crate) fn save(&self) -> crate::Result<()> {
+        let path = self.begin_save()?;
+        let res = self.write_snapshot(&path);
+        self.end_save(res.is_ok());
+        Ok(res?)
+    }
+
+    /// Start writing a snapshot in the background.
+    pub(crate) fn bgsave(&self) -> crate::Result<()> {
+        let path = self.begin_save()?;
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let res = db.write_snapshot(&path);
+            if let Err(e) = &res {
+                eprintln!("background save to {} failed: {}", path.display(), e);
+            }
+            db.end_save(res.is_ok());
+        });
+        Ok(())
+    }
+
+    /// Returns the Unix time, in seconds, of the last successful save.
+    pub(crate) fn last_save(&self) -> u64 {
+        self.shared.snapshots.lock().unwrap().last_save
+    }
+
+    /// Claim the right to write a snapshot, returning the path to write it to.
+    fn begin_save(&self) -> crate::Result<PathBuf> {
+        let mut snapshots = self.shared.snapshots.lock().unwrap();
+        if snapshots.in_progress {
+            return Err("Background save already in progress".into());
+        }
+
+        snapshots.in_progress = true;
+        Ok(snapshots.path.clone())
+    }
+
+    fn end_save(&self, saved: bool) {
+        let mut snapshots = self.shared.snapshots.lock().unwrap();
+        snapshots.in_progress = false;
+        if saved {
+            snapshots.last_save = now_ms() / 1000;
+        }
+    }
+
+    /// Write a snapshot to a temporary file, then move it over `path`, so
+    /// that a crash halfway through never leaves a partial snapshot behind.
+    fn write_snapshot(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        self.encode_snapshot(&mut file)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Write a snapshot to `out`, one shard at a time.
+    fn encode_snapshot(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut crc = Crc64::default();
+        let mut write = |chunk: &[u8]| {
+            crc.update(chunk);
+            out.write_all(chunk)
+        };
+
+        let mut header = MAGIC.to_vec();
+        header.put_u16_le(VERSION);
+        write(&header)?;
+
+        let mut chunk = Vec::new();
+        for shard in self.shared.shards.iter() {
+            chunk.clear();
+            encode_shard(&shard.lock().unwrap(), now_ms(), &mut chunk);
+            write(&chunk)?;
+        }
+        write(&[OP_EOF])?;
+
+        let checksum = crc.finish();
+        out.write_all(&checksum.to_le_bytes())
+    }
+}
+
+/// Append the live keys of `shard` to `out`.
+fn encode_shard(shard: &Shard, now: u64, out: &mut Vec<u8>) {
+    for (key, entry) in &shard.entries {
+        if entry.is_expired(now) {
+            continue;
+        }
+
+        if let Some(when) = entry.expires_at {
+            out.put_u8(OP_EXPIRE_MS);
+            out.put_u64_le(when);
+        }
+
+        match &entry.value {
+            Value::String(value) => {
+                out.put_u8(TYPE_STRING);
+                put_bytes(out, key);
+                put_bytes(out, value);
+            }
+            Value::List(list) => {
+                out.put_u8(TYPE_LIST);
+                put_bytes(out, key);
+                out.put_u64_le(list.len() as u64);
+                for element in list {
+                    put_bytes(out, element);
+                }
+            }
+            Value::Set(set) => {
+                out.put_u8(TYPE_SET);
+                put_bytes(out, key);
+                out.put_u64_le(set.len() as u64);
+                for member in set {
+                    put_bytes(out, member);
+                }
+            }
+            Value::SortedSet(zset) => {
+                out.put_u8(TYPE_ZSET);
+                put_bytes(out, key);
+                out.put_u64_le(zset.len() as u64);
+                for (member, score) in zset.range(0, zset.len(), false) {
+                    put_bytes(out, member);
+                    out.put_f64_le(score);
+                }
+            }
+            Value::Hash(hash) => {
+                out.put_u8(TYPE_HASH);
+                put_bytes(out, key);
+                out.put_u64_le(hash.len() as u64);
+                for (field, value) in hash {
+                    put_bytes(out, field);
+                    put_bytes(out, value);
+                }
+            }
+        }
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.put_u64_le(bytes.len() as u64);
+    out.put_slice(bytes);
+}
+
+/// Decode a snapshot file. Strings are sliced out of `data` rather than
+/// copied.
+fn decode(mut data: Bytes) -> crate::Result<Vec<(Bytes, Entry)>> {
+    if !data.starts_with(MAGIC) || data.len() < MAGIC.len() + 2 {
+        return Err("not a snapshot file".into());
+    }
+    data.advance(MAGIC.len());
+    let version = data.get_u16_le();
+    if version != VERSION {
+        return Err(format!("unsupported snapshot version {}", version).into());
+    }
+
+    // Everything up to the checksum is covered by it, header included.
+    if data.len() < 9 {
+        return Err("unexpected end of file".into());
+    }
+    let mut checksum = data.split_off(data.len() - 8);
+    let mut crc = Crc64::default();
+    crc.update(MAGIC);
+    crc.update(&VERSION.to_le_bytes());
+    crc.update(&data);
+    if crc.finish() != checksum.get_u64_le() {
+        return Err("checksum mismatch, the file is corrupt or truncated".into());
+    }
+
+    let mut entries = Vec::new();
+    let mut expires_at = None;
+    loop {
+        let value = match get_u8(&mut data)? {
+            OP_EOF if data.is_empty() => return Ok(entries),
+            OP_EOF => return Err("unexpected data after the end of file marker".into()),
+            OP_EXPIRE_MS if expires_at.is_none() => {
+                expires_at = Some(get_u64(&mut data)?);
+                continue;
+            }
+            TYPE_STRING => {
+                let key = get_bytes(&mut data)?;
+                (key, Value::String(get_bytes(&mut data)?))
+            }
+            TYPE_LIST => {
+                let key = get_bytes(&mut data)?;
+                let mut list = VecDeque::new();
+                for _ in 0..get_u64(&mut data)? {
+                    list.push_back(get_bytes(&mut data)?);
+                }
+                (key, Value::List(list))
+            }
+            TYPE_SET => {
+                let key = get_bytes(&mut data)?;
+                let mut set = HashSet::new();
+                for _ in 0..get_u64(&mut data)? {
+                    set.insert(get_bytes(&mut data)?);
+                }
+                (key, Value::Set(set))
+            }
+            TYPE_ZSET => {
+                let key = get_bytes(&mut data)?;
+                let mut zset = SortedSet::new();
+                for _ in 0..get_u64(&mut data)? {
+                    let member = get_bytes(&mut data)?;
+                    let score = f64::from_bits(get_u64(&mut data)?);
+                    if score.is_nan() {
+                        return Err("invalid sorted set score".into());
+                    }
+                    zset.insert(member, score);
+                }
+                (key, Value::SortedSet(zset))
+            }
+            TYPE_HASH => {
+                let key = get_bytes(&mut data)?;
+                let mut hash = HashMap::new();
+                for _ in 0..get_u64(&mut data)? {
+                    let field = get_bytes(&mut data)?;
+                    hash.insert(field, get_bytes(&mut data)?);
+                }
+                (key, Value::Hash(hash))
+            }
+            byte => return Err(format!("invalid record type byte {:#04x}", byte).into()),
+        };
+
+        let (key, value) = value;
+        entries.push((
+            key,
+            Entry {
+                value,
+                expires_at: expires_at.take(),
+            },
+        ));
+    }
+}
+
+fn get_u8(data: &mut Bytes) -> crate::Result<u8> {
+    if data.is_empty() {
+        return Err("unexpected end of file".into());
+    }
+    Ok(data.get_u8())
+}
+
+fn get_u64(data: &mut Bytes) -> crate::Result<u64> {
+    if data.len() < 8 {
+        return Err("unexpected end of file".into());
+    }
+    Ok(data.get_u64_le())
+}
+
+fn get_bytes(data: &mut Bytes) -> crate::Result<Bytes> {
+    let len = get_u64(data)?;
+    if len > data.len() as u64 {
+        return Err("unexpected end of file".into());
+    }
+    Ok(data.split_to(len as usize))
+}
+
+/// Reflected polynomial of CRC-64/Jones, the variant Redis uses.
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Running CRC-64 checksum.
+#[derive(Debug, Default)]
+struct Crc64(u64);
+
+impl Crc64 {
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC64_TABLE[((self.0 ^ byte as u64) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Returns a path in the temporary directory no other test uses.
+    fn temp_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("redis-rdb-{}-{}.rdb", std::process::id(), n))
+    }
+
+    fn bytes(s: &'static str) -> Bytes {
+        Bytes::from_static(s.as_bytes())
+    }
+
+    #[test]
+    fn crc64_matches_redis() {
+        let mut crc = Crc64::default();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn snapshots_round_trip_every_type() {
+        let db = Db::with_shards(4);
+        db.set_snapshot_path(temp_path());
+        db.set(bytes("string"), bytes("value"));
+        db.with_entry(&bytes("list"), |slot| {
+            **slot = Some(Entry {
+                value: Value::List(VecDeque::from([bytes("a"), bytes("b")])),
+                expires_at: Some(now_ms() + 60_000),
+            })
+        });
+        db.with_entry(&bytes("set"), |slot| {
+            **slot = Some(Entry::new(Value::Set(HashSet::from([bytes("x")]))));
+        });
+        db.with_entry(&bytes("zset"), |slot| {
+            let mut zset = SortedSet::new();
+            zset.insert(bytes("m"), -1.5);
+            zset.insert(bytes("n"), f64::INFINITY);
+            **slot = Some(Entry::new(Value::SortedSet(zset)));
+        });
+        db.with_entry(&bytes("hash"), |slot| {
+            let hash = HashMap::from([(bytes("f"), bytes("v"))]);
+            **slot = Some(Entry::new(Value::Hash(hash)));
+        });
+        db.with_entry(&bytes("expired"), |slot| {
+            **slot = Some(Entry {
+                value: Value::String(bytes("gone")),
+                expires_at: Some(now_ms() - 1),
+            })
+        });
+        db.save().unwrap();
+
+        let loaded = Db::new();
+        loaded.set_snapshot_path(db.snapshot_path());
+        assert_eq!(loaded.load_snapshot().unwrap(), 5);
+        for key in ["string", "list", "set", "zset", "hash"] {
+            let key = bytes(key);
+            let original = db.with_entry(&key, |slot| slot.clone());
+            assert_eq!(loaded.with_entry(&key, |slot| slot.clone()), original);
+        }
+        assert!(!loaded.contains(b"expired"));
+
+        fs::remove_file(db.snapshot_path()).unwrap();
+    }
+
+    #[test]
+    fn corrupt_snapshots_are_rejected() {
+        let db = Db::new();
+        let path = temp_path();
+        db.set_snapshot_path(&path);
+
+        // Nothing to load is fine.
+        assert_eq!(db.load_snapshot().unwrap(), 0);
+
+        db.set(bytes("key"), bytes("value"));
+        db.save().unwrap();
+        let valid = fs::read(&path).unwrap();
+
+        let mut flipped = valid.clone();
+        flipped[MAGIC.len() + 5] ^= 1;
+        let truncated = &valid[..valid.len() - 3];
+        let mut bad_version = valid.clone();
+        bad_version[MAGIC.len()] = 9;
+        for (data, error) in [
+            (&flipped[..], "checksum mismatch"),
+            (truncated, "checksum mismatch"),
+            (&bad_version[..], "unsupported snapshot version"),
+            (b"garbage", "not a snapshot file"),
+        ] {
+            fs::write(&path, data).unwrap();
+            let loaded = Db::new();
+            loaded.set_snapshot_path(&path);
+            let err = loaded.load_snapshot().unwrap_err().to_string();
+            assert!(err.contains(error), "{}", err);
+            assert!(!loaded.contains(b"key"));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
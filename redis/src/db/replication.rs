@@ -0,0 +1,562 @@
+//! Replication.
+//!
+//! Every write propagated to the append-only file is also fed to replicas, as
+//! a single stream of RESP commands. The number of bytes fed so far is the
+//! replication offset, and the last `BACKLOG_SIZE` bytes are kept in a
+//! backlog. A replica that lost its link asks for the stream from the offset
+//! it got to, and only needs a full snapshot first if that part of the stream
+//! is no longer in the backlog.
+//!
+//! The stream is named by a replication ID. A replica takes on the ID and
+//! offset of its master, so that it can tell the master where it left off
+//! after reconnecting, and feeds what it applies to its own replicas in turn.
+//!
+//! As with the append-only file, the stream is fed under the lock of the
+//! latter, so that writes go out in the order they took effect.
+
+use super::Db;
+use crate::frame::{Frame, Protocol};
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::AbortHandle;
+
+/// Number of bytes of the stream kept around for partial resynchronization.
+pub(crate) const BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// Replication state of a `Db`.
+#[derive(Debug)]
+pub(super) struct Replication {
+    /// Names the stream the offset counts bytes of.
+    replid: String,
+
+    /// Number of bytes fed to the stream so far.
+    offset: u64,
+
+    /// The latest part of the stream. Created when the first replica attaches.
+    backlog: Option<Backlog>,
+
+    replicas: Vec<Replica>,
+    next_replica_id: u64,
+
+    /// The master this server replicates, if any.
+    master: Option<Master>,
+
+    /// Port this server tells its master it listens on.
+    listening_port: u16,
+
+    /// Notified whenever a replica acknowledges an offset.
+    acks: Arc<Notify>,
+}
+
+impl Default for Replication {
+    fn default() -> Replication {
+        Replication {
+            replid: new_replid(),
+            offset: 0,
+            backlog: None,
+            replicas: Vec::new(),
+            next_replica_id: 0,
+            master: None,
+            listening_port: 0,
+            acks: Arc::default(),
+        }
+    }
+}
+
+/// The tail of the replication stream.
+#[derive(Debug)]
+struct Backlog {
+    data: VecDeque<u8>,
+
+    /// Offset of the first byte of `data`. Offsets of bytes start at 1, as in
+    /// Redis: the offset of the stream is that of its last byte.
+    first_offset: u64,
+}
+
+/// A replica attached to this server.
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    ip: String,
+    port: u16,
+    feed: mpsc::UnboundedSender<Bytes>,
+
+    /// Last offset the replica acknowledged having applied.
+    ack: u64,
+}
+
+/// The master this server replicates.
+#[derive(Debug)]
+struct Master {
+    host: String,
+    port: u16,
+    link: LinkState,
+
+    /// Task maintaining the link to the master.
+    task: AbortHandle,
+}
+
+/// State of the link of a replica to its master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LinkState {
+    /// Not connected, or still handshaking.
+    Connecting,
+
+    /// Receiving a snapshot.
+    Sync,
+
+    /// Applying the stream.
+    Connected,
+}
+
+impl LinkState {
+    /// Returns the name of the state, as reported by `ROLE`.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            LinkState::Connecting => "connecting",
+            LinkState::Sync => "sync",
+            LinkState::Connected => "connected",
+        }
+    }
+}
+
+/// How a replica catches up with the stream when it attaches.
+#[derive(Debug)]
+pub(crate) enum Resync {
+    /// It loads `snapshot`, which is the keyspace as of `offset`, then follows
+    /// the stream from there.
+    Full {
+        replid: String,
+        offset: u64,
+        snapshot: Vec<u8>,
+    },
+
+    /// It follows the stream from where it left off, starting with the
+    /// `missed` part still in the backlog.
+    Partial { replid: String, missed: Vec<u8> },
+}
+
+/// Receiving end of the stream for a replica attached to this server.
+///
+/// The replica is detached once this is dropped.
+#[derive(Debug)]
+pub(crate) struct ReplicaLink {
+    db: Db,
+    id: u64,
+
+    /// Parts of the stream to send, in order. Closed if the replica gets
+    /// detached, because this server turned into a replica itself.
+    pub(crate) feed: mpsc::UnboundedReceiver<Bytes>,
+}
+
+/// A snapshot of the replication state, for `ROLE` and `INFO`.
+#[derive(Debug)]
+pub(crate) struct ReplicationStatus {
+    pub(crate) replid: String,
+    pub(crate) offset: u64,
+
+    /// Offset of the first byte and length of the backlog, if any.
+    pub(crate) backlog: Option<(u64, usize)>,
+
+    /// Address and acknowledged offset of every replica.
+    pub(crate) replicas: Vec<(String, u16, u64)>,
+
+    /// Address of the master, and state of the link to it.
+    pub(crate) master: Option<(String, u16, LinkState)>,
+}
+
+impl Db {
+    /// Change the port this server announces to its master when
+    /// replicating, which shows up in the master's `ROLE` and `INFO`.
+    pub fn set_listening_port(&self, port: u16) {
+        self.shared.replication.lock().unwrap().listening_port = port;
+    }
+
+    pub(crate) fn listening_port(&self) -> u16 {
+        self.shared.replication.lock().unwrap().listening_port
+    }
+
+    /// Returns `true` if this server replicates a master.
+    pub(crate) fn is_replica(&self) -> bool {
+        self.shared.replication.lock().unwrap().master.is_some()
+    }
+
+    /// Returns the replication ID and offset, which is how far along the
+    /// stream this server is.
+    pub(crate) fn replication_position(&self) -> (String, u64) {
+        let replication = self.shared.replication.lock().unwrap();
+        (replication.replid.clone(), replication.offset)
+    }
+
+    pub(crate) fn replication_status(&self) -> ReplicationStatus {
+        let replication = self.shared.replication.lock().unwrap();
+        ReplicationStatus {
+            replid: replication.replid.clone(),
+            offset: replication.offset,
+            backlog: replication
+                .backlog
+                .as_ref()
+                .map(|backlog| (backlog.first_offset, backlog.data.len())),
+            replicas: replication
+                .replicas
+                .iter()
+                .map(|replica| (replica.ip.clone(), replica.port, replica.ack))
+                .collect(),
+            master: replication
+                .master
+                .as_ref()
+                .map(|master| (master.host.clone(), master.port, master.link)),
+        }
+    }
+
+    /// Start replicating the master at `host:port`, through `task`. Whatever
+    /// master was replicated before is dropped, and so are the replicas of
+    /// this server, since the keyspace they copied is about to be replaced.
+    pub(crate) fn replicate(&self, host: String, port: u16, task: AbortHandle) {
+        // Recorded in the configuration so that `CONFIG REWRITE` keeps it.
+        self.shared.config.lock().unwrap().replicaof = Some((host.clone(), port));
+
+        let mut replication = self.shared.replication.lock().unwrap();
+        if let Some(master) = replication.master.take() {
+            master.task.abort();
+        }
+        replication.master = Some(Master {
+            host,
+            port,
+            link: LinkState::Connecting,
+            task,
+        });
+        replication.replicas.clear();
+        replication.backlog = None;
+        drop(replication);
+
+        // The stream from the master is fed through to replicas of this
+        // server, whenever it gets some.
+        self.update_propagating();
+    }
+
+    /// Stop replicating, turning this server into a master. It starts a
+    /// stream of its own, so replicas have to fully resynchronize.
+    pub(crate) fn stop_replicating(&self) {
+        self.shared.config.lock().unwrap().replicaof = None;
+
+        let mut replication = self.shared.replication.lock().unwrap();
+        if let Some(master) = replication.master.take() {
+            master.task.abort();
+            replication.replid = new_replid();
+            replication.backlog = None;
+        }
+        drop(replication);
+        self.update_propagating();
+    }
+
+    pub(crate) fn set_link_state(&self, link: LinkState) {
+        if let Some(master) = &mut self.shared.replication.lock().unwrap().master {
+            master.link = link;
+        }
+    }
+
+    /// Replace the keyspace with the `snapshot` sent by the master, which is
+    /// as of `offset` of its stream `replid`.
+    pub(crate) fn load_full_resync(
+        &self,
+        snapshot: Bytes,
+        replid: String,
+        offset: u64,
+    ) -> crate::Result<usize> {
+        let loaded = self.exclusively(|| {
+            let _aof = self.shared.aof.lock().unwrap();
+            let loaded = self.replace_with_snapshot(snapshot)?;
+
+            let mut replication = self.shared.replication.lock().unwrap();
+            replication.replid = replid;
+            replication.offset = offset;
+            replication.backlog = None;
+            replication.replicas.clear();
+            Ok::<_, crate::Error>(loaded)
+        })?;
+
+        // The append-only file describes the keyspace that was just dropped.
+        if self.aof_enabled()
+            && let Err(e) = self.rewrite_aof()
+        {
+            eprintln!("cannot rewrite the append only file after a resync: {}", e);
+        }
+        Ok(loaded)
+    }
+
+    /// Attach a replica at `ip`, announced as listening on `port`, which
+    /// asked for stream `replid` from `offset` on.
+    ///
+    /// Must not be called while applying a command.
+    pub(crate) fn attach_replica(
+        &self,
+        replid: &str,
+        offset: u64,
+        ip: String,
+        port: u16,
+    ) -> (Resync, ReplicaLink) {
+        // From now on, writes are propagated. Those already in progress
+        // may have decided not to be, and are let through first so that the
+        // snapshot, if any, includes them.
+        {
+            let mut replication = self.shared.replication.lock().unwrap();
+            let first_offset = replication.offset + 1;
+            replication.backlog.get_or_insert_with(|| Backlog {
+                data: VecDeque::new(),
+                first_offset,
+            });
+        }
+        self.update_propagating();
+
+        self.exclusively(|| {
+            let _aof = self.shared.aof.lock().unwrap();
+
+            let mut replication = self.shared.replication.lock().unwrap();
+            let replication = &mut *replication;
+            let missed = replication
+                .backlog
+                .as_ref()
+                .filter(|_| replid == replication.replid)
+                .and_then(|backlog| backlog.since(offset));
+            let resync = match missed {
+                Some(missed) => Resync::Partial {
+                    replid: replication.replid.clone(),
+                    missed,
+                },
+                None => Resync::Full {
+                    replid: replication.replid.clone(),
+                    offset: replication.offset,
+                    snapshot: self.snapshot(),
+                },
+            };
+
+            let (tx, rx) = mpsc::unbounded_channel();
+            let id = replication.next_replica_id;
+            replication.next_replica_id += 1;
+            replication.replicas.push(Replica {
+                id,
+                ip,
+                port,
+                feed: tx,
+                ack: 0,
+            });
+
+            let link = ReplicaLink {
+                db: self.clone(),
+                id,
+                feed: rx,
+            };
+            (resync, link)
+        })
+    }
+
+    /// Send `frames` to replicas, but not to the append-only file.
+    pub(crate) fn feed_replicas(&self, frames: &[Frame]) {
+        let _aof = self.shared.aof.lock().unwrap();
+        let mut data = Vec::new();
+        for frame in frames {
+            frame.encode(Protocol::Resp2, &mut data);
+        }
+        self.shared.replication.lock().unwrap().feed(&data);
+    }
+
+    /// Wait until `count` replicas acknowledged the current offset, or
+    /// `timeout` elapsed, returning the number that did. Replicas are asked
+    /// for an acknowledgment straight away rather than waiting for the next
+    /// one they send on their own.
+    pub(crate) async fn wait_for_replicas(&self, count: usize, timeout: Option<Duration>) -> usize {
+        let (offset, acks) = {
+            let replication = self.shared.replication.lock().unwrap();
+            (replication.offset, replication.acks.clone())
+        };
+        let acked = || {
+            let replication = self.shared.replication.lock().unwrap();
+            replication
+                .replicas
+                .iter()
+                .filter(|replica| replica.ack >= offset)
+                .count()
+        };
+
+        let n = acked();
+        if n >= count {
+            return n;
+        }
+        self.feed_replicas(&[Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"REPLCONF")),
+            Frame::Bulk(Bytes::from_static(b"GETACK")),
+            Frame::Bulk(Bytes::from_static(b"*")),
+        ])]);
+
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        loop {
+            // Register for the next acknowledgment before counting, so that
+            // one arriving in between is not missed.
+            let notified = acks.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let n = acked();
+            if n >= count {
+                return n;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                        return acked();
+                    }
+                }
+                None => notified.await,
+            }
+        }
+    }
+}
+
+impl ReplicaLink {
+    /// Record that the replica applied the stream up to `offset`.
+    pub(crate) fn ack(&self, offset: u64) {
+        let mut replication = self.db.shared.replication.lock().unwrap();
+        if let Some(replica) = replication
+            .replicas
+            .iter_mut()
+            .find(|replica| replica.id == self.id)
+        {
+            replica.ack = offset;
+            replication.acks.notify_waiters();
+        }
+    }
+}
+
+impl Drop for ReplicaLink {
+    fn drop(&mut self) {
+        let mut replication = self.db.shared.replication.lock().unwrap();
+        replication.replicas.retain(|replica| replica.id != self.id);
+    }
+}
+
+impl Replication {
+    /// Returns `true` if writes have to be fed to the stream: this server
+    /// has replicas, had some, or replicates a master.
+    pub(super) fn is_active(&self) -> bool {
+        self.backlog.is_some() || self.master.is_some()
+    }
+
+    /// Append `data` to the stream.
+    pub(super) fn feed(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        self.offset += data.len() as u64;
+        if let Some(backlog) = &mut self.backlog {
+            backlog.push(data);
+        }
+
+        let data = Bytes::copy_from_slice(data);
+        self.replicas
+            .retain(|replica| replica.feed.send(data.clone()).is_ok());
+    }
+}
+
+impl Backlog {
+    fn push(&mut self, data: &[u8]) {
+        self.data.extend(data);
+        let excess = self.data.len().saturating_sub(BACKLOG_SIZE);
+        self.data.drain(..excess);
+        self.first_offset += excess as u64;
+    }
+
+    /// Returns the stream from `offset` on, if the backlog still has all of
+    /// it.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let end = self.first_offset + self.data.len() as u64;
+        if offset < self.first_offset || offset > end {
+            return None;
+        }
+        Some(
+            self.data
+                .range((offset - self.first_offset) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+/// Returns a new random replication ID, 40 hexadecimal digits like in Redis.
+fn new_replid() -> String {
+    format!(
+        "{:016x}{:016x}{:08x}",
+        fastrand::u64(..),
+        fastrand::u64(..),
+        fastrand::u32(..)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_keeps_the_tail_of_the_stream() {
+        let mut backlog = Backlog {
+            data: VecDeque::new(),
+            first_offset: 1,
+        };
+        backlog.push(b"hello");
+        assert_eq!(backlog.since(1).unwrap(), b"hello");
+        assert_eq!(backlog.since(4).unwrap(), b"lo");
+        assert_eq!(backlog.since(6).unwrap(), b"");
+        assert_eq!(backlog.since(7), None);
+
+        backlog.push(&vec![b'x'; BACKLOG_SIZE]);
+        assert_eq!(backlog.data.len(), BACKLOG_SIZE);
+        assert_eq!(backlog.first_offset, 6);
+        assert_eq!(backlog.since(5), None);
+        assert_eq!(backlog.since(6).unwrap().len(), BACKLOG_SIZE);
+    }
+
+    #[test]
+    fn replicas_resume_from_the_backlog() {
+        let db = Db::new();
+        db.set(Bytes::from_static(b"a"), Bytes::from_static(b"1"));
+
+        let (resync, mut link) = db.attach_replica("?", 0, "127.0.0.1".to_string(), 1);
+        let Resync::Full { replid, offset, .. } = resync else {
+            panic!("expected a full resync");
+        };
+        assert_eq!(offset, 0);
+        assert!(db.propagating());
+
+        let set = Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"SET")),
+            Frame::Bulk(Bytes::from_static(b"b")),
+            Frame::Bulk(Bytes::from_static(b"2")),
+        ]);
+        db.logged(|| (Frame::Null, vec![set]));
+        let fed = link.feed.try_recv().unwrap();
+        assert_eq!(
+            db.replication_position(),
+            (replid.clone(), fed.len() as u64)
+        );
+        drop(link);
+
+        // The replica got the first byte, and wants the rest.
+        let (resync, _link) = db.attach_replica(&replid, 2, "127.0.0.1".to_string(), 1);
+        let Resync::Partial { missed, .. } = resync else {
+            panic!("expected a partial resync");
+        };
+        assert_eq!(missed, &fed[1..]);
+
+        // Another stream, or an offset past the end, cannot be resumed.
+        let past = fed.len() as u64 + 2;
+        for (replid, offset) in [("other", 1), (&replid[..], past)] {
+            let (resync, _link) = db.attach_replica(replid, offset, "127.0.0.1".to_string(), 1);
+            assert!(matches!(resync, Resync::Full { .. }));
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::db::SortedSet;
+use crate::frame::Frame;
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// A value stored in the keyspace.
+///
+/// Each variant is one of the Redis data types. Commands only operate on the
+/// types they were written for; applying one to any other type fails with
+/// `WrongType`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    SortedSet(SortedSet),
+}
+
+/// Error returned when a command is applied to a key holding the wrong kind of
+/// value.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WrongType;
+
+impl Value {
+    /// Name of the type, as reported by the `TYPE` command.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+
+    /// Returns `true` for a collection without any element. Redis never keeps
+    /// such values around: the key is deleted along with its last element.
+    pub(crate) fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(zset) => zset.is_empty(),
+        }
+    }
+
+    pub(crate) fn as_string(&self) -> Result<&Bytes, WrongType> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_string_mut(&mut self) -> Result<&mut Bytes, WrongType> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_list(&self) -> Result<&VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_list_mut(&mut self) -> Result<&mut VecDeque<Bytes>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_hash(&self) -> Result<&HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_hash_mut(&mut self) -> Result<&mut HashMap<Bytes, Bytes>, WrongType> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_set(&self) -> Result<&HashSet<Bytes>, WrongType> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_set_mut(&mut self) -> Result<&mut HashSet<Bytes>, WrongType> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_zset(&self) -> Result<&SortedSet, WrongType> {
+        match self {
+            Value::SortedSet(zset) => Ok(zset),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub(crate) fn as_zset_mut(&mut self) -> Result<&mut SortedSet, WrongType> {
+        match self {
+            Value::SortedSet(zset) => Ok(zset),
+            _ => Err(WrongType),
+        }
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(value: Bytes) -> Value {
+        Value::String(value)
+    }
+}
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(fmt)
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+impl From<WrongType> for Frame {
+    fn from(err: WrongType) -> Frame {
+        Frame::Error(err.to_string())
+    }
+}
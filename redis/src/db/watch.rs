@@ -0,0 +1,134 @@
+//! Optimistic locking for `WATCH`.
+//!
+//! Every key watched by at least one client has a version counter in its
+//! shard, bumped whenever the key is modified, deleted or expires. Commands
+//! going through `Db::with_entry` bump it by borrowing their `Slot` mutably,
+//! so nothing needs to be compared after the fact. A client remembers the
+//! versions it saw when it started watching, and its transaction is aborted
+//! if any of them moved on by the time it executes.
+
+use super::{Db, Shard, now_ms};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Version counters of the watched keys of a shard.
+pub(super) type Watches = HashMap<Bytes, Watch>;
+
+#[derive(Debug, Default)]
+pub(super) struct Watch {
+    version: u64,
+
+    /// Number of clients watching the key. The counter is dropped along with
+    /// the last of them.
+    watchers: usize,
+}
+
+/// The keys watched by a single client, along with their versions at the time
+/// they were watched.
+///
+/// Dropping the set stops watching all of them.
+#[derive(Debug)]
+pub(crate) struct WatchSet {
+    db: Db,
+    keys: HashMap<Bytes, u64>,
+}
+
+impl WatchSet {
+    pub(crate) fn new(db: &Db) -> WatchSet {
+        WatchSet {
+            db: db.clone(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Start watching `key`. Watching a key twice has no effect.
+    pub(crate) fn watch(&mut self, key: Bytes) {
+        if self.keys.contains_key(&key) {
+            return;
+        }
+
+        let mut shard = self.db.shard(&key).lock().unwrap();
+
+        // Purge the key first if it already expired, so that doing it later
+        // does not count as a modification.
+        shard.live(&key, now_ms());
+
+        let watch = shard.watched.entry(key.clone()).or_default();
+        watch.watchers += 1;
+        let version = watch.version;
+        drop(shard);
+
+        self.keys.insert(key, version);
+    }
+
+    /// Returns `true` if any of the keys was modified since it was watched.
+    pub(crate) fn is_modified(&self) -> bool {
+        self.keys.iter().any(|(key, &version)| {
+            let mut shard = self.db.shard(key).lock().unwrap();
+
+            // A key that expired counts as modified even if no command has
+            // touched it since.
+            shard.live(key, now_ms());
+            shard.watched[key].version != version
+        })
+    }
+
+    /// Stop watching every key.
+    pub(crate) fn clear(&mut self) {
+        for (key, _) in self.keys.drain() {
+            let mut shard = self.db.shard(&key).lock().unwrap();
+            let watch = shard.watched.get_mut(&key).unwrap();
+            watch.watchers -= 1;
+            if watch.watchers == 0 {
+                shard.watched.remove(&key);
+            }
+        }
+    }
+}
+
+impl Drop for WatchSet {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl Shard {
+    /// Record that `key` was modified.
+    pub(super) fn touch(&mut self, key: &[u8]) {
+        if let Some(watch) = self.watched.get_mut(key) {
+            watch.version += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_modifications_count() {
+        let db = Db::new();
+        let key = Bytes::from_static(b"key");
+        let missing = Bytes::from_static(b"missing");
+        db.set(key.clone(), Bytes::from_static(b"v"));
+
+        let mut watched = WatchSet::new(&db);
+        watched.watch(key.clone());
+        watched.watch(missing.clone());
+
+        // Reading leaves the key untouched, and so does anything leaving a
+        // missing key missing.
+        db.get(&key).unwrap();
+        db.with_entry(&key, |slot| assert!(slot.is_some()));
+        db.with_entry(&missing, |slot| assert!(slot.take().is_none()));
+        assert!(!watched.is_modified());
+
+        db.with_entry(&key, |slot| slot.as_mut().unwrap().expires_at = Some(0));
+        assert!(watched.is_modified());
+
+        // The counter goes away with the last watcher.
+        watched.clear();
+        assert!(db.shard(&key).lock().unwrap().watched.is_empty());
+    }
+}
@@ -0,0 +1,510 @@
+//! The sorted set value type.
+//!
+//! As in Redis, a sorted set pairs a hash map from member to score with a
+//! skiplist ordered by `(score, member)`. The map answers score lookups in
+//! O(1), and the skiplist keeps the members in order. Each skiplist link also
+//! records its span, the number of elements it skips over, which is what makes
+//! rank queries O(log n) instead of a linear walk.
+//!
+//! The skiplist nodes live in a `Vec` and refer to each other by index, so the
+//! structure needs no unsafe code and is trivially `Clone`. Freed slots are
+//! reused by later insertions.
+
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// A set of unique members, each associated with a score and kept ordered by
+/// score, then lexicographically by member for equal scores.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+}
+
+/// Two sorted sets are equal when they hold the same members with the same
+/// scores, regardless of how their skiplists happen to be laid out.
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &SortedSet) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl SortedSet {
+    pub(crate) fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Returns the score of `member`, if it belongs to the set.
+    pub(crate) fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Add `member` with `score`, or move it to `score` if it is already in
+    /// the set. Returns the previous score.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `score` is NaN.
+    pub(crate) fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        assert!(!score.is_nan(), "sorted set scores cannot be NaN");
+        // Fold -0 into 0 so both sort the same way.
+        let score = score + 0.0;
+
+        let prev = self.scores.insert(member.clone(), score);
+        match prev {
+            Some(prev) if prev == score => {}
+            Some(prev) => {
+                self.list.remove(prev, &member);
+                self.list.insert(score, member);
+            }
+            None => self.list.insert(score, member),
+        }
+        prev
+    }
+
+    /// Remove `member`, returning `true` if it was in the set.
+    pub(crate) fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the zero-based position of `member` in ascending order.
+    pub(crate) fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        self.list.rank(score, member)
+    }
+
+    /// Returns the number of elements for which `before` holds.
+    ///
+    /// `before` must hold for a prefix of the set in ascending order, and not
+    /// for any element after it. This is how score and lexicographic bounds
+    /// are turned into ranks.
+    pub(crate) fn count_while(&self, before: impl Fn(f64, &Bytes) -> bool) -> usize {
+        self.list.count_while(before)
+    }
+
+    /// Iterate over the elements whose ranks are in `start..end`, in
+    /// ascending order, or descending order if `rev` is set.
+    pub(crate) fn range(&self, start: usize, end: usize, rev: bool) -> Range<'_> {
+        let end = end.min(self.len());
+        let start = start.min(end);
+
+        let next = match (start == end, rev) {
+            (true, _) => NIL,
+            (false, false) => self.list.by_rank(start),
+            (false, true) => self.list.by_rank(end - 1),
+        };
+
+        Range {
+            list: &self.list,
+            next,
+            remaining: end - start,
+            rev,
+        }
+    }
+}
+
+/// Iterator over a range of a `SortedSet`, yielding members and their scores.
+#[derive(Debug)]
+pub(crate) struct Range<'a> {
+    list: &'a SkipList,
+    next: usize,
+    remaining: usize,
+    rev: bool,
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = (&'a Bytes, f64);
+
+    fn next(&mut self) -> Option<(&'a Bytes, f64)> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let node = &self.list.nodes[self.next];
+        self.next = if self.rev {
+            node.backward
+        } else {
+            node.levels[0].forward
+        };
+        self.remaining -= 1;
+
+        Some((&node.member, node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Range<'_> {}
+
+/// Marks the absence of a node.
+const NIL: usize = usize::MAX;
+
+/// Index of the header node, which holds no element.
+const HEAD: usize = 0;
+
+/// Enough levels for 4^32 elements.
+const MAX_LEVEL: usize = 32;
+
+#[derive(Debug, Clone)]
+struct SkipList {
+    /// All the nodes, starting with the header.
+    nodes: Vec<Node>,
+
+    /// Indices of unused slots in `nodes`.
+    free: Vec<usize>,
+
+    len: usize,
+
+    /// Number of levels in use.
+    level: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+
+    /// The previous node on the bottom level, or `NIL` for the first one.
+    backward: usize,
+
+    levels: Vec<Level>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    forward: usize,
+
+    /// Number of bottom-level links crossed by following `forward`.
+    span: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> SkipList {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            backward: NIL,
+            levels: vec![
+                Level {
+                    forward: NIL,
+                    span: 0,
+                };
+                MAX_LEVEL
+            ],
+        };
+
+        SkipList {
+            nodes: vec![head],
+            free: Vec::new(),
+            len: 0,
+            level: 1,
+        }
+    }
+}
+
+/// Pick the level of a new node: each extra level has a one in four chance.
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && fastrand::u8(..4) == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    /// Compare the element stored at `node` with `(score, member)`.
+    fn cmp(&self, node: usize, score: f64, member: &[u8]) -> Ordering {
+        let node = &self.nodes[node];
+        node.score
+            .total_cmp(&score)
+            .then_with(|| node.member[..].cmp(member))
+    }
+
+    /// Find the last node before `(score, member)` on every level, along with
+    /// the rank of each of those nodes.
+    fn predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+
+            loop {
+                let Level { forward, span } = self.nodes[x].levels[i];
+                if forward == NIL || self.cmp(forward, score, member) != Ordering::Less {
+                    break;
+                }
+                rank[i] += span;
+                x = forward;
+            }
+            update[i] = x;
+        }
+
+        (update, rank)
+    }
+
+    /// Insert an element. The member must not be in the list already.
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            backward: if update[0] == HEAD { NIL } else { update[0] },
+            levels: vec![
+                Level {
+                    forward: NIL,
+                    span: 0,
+                };
+                level
+            ],
+        };
+        let x = match self.free.pop() {
+            Some(x) => {
+                self.nodes[x] = node;
+                x
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let prev = self.nodes[update[i]].levels[i];
+            self.nodes[x].levels[i] = Level {
+                forward: prev.forward,
+                span: prev.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].levels[i] = Level {
+                forward: x,
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+
+        // Levels above the new node now skip over one more element.
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+
+        let next = self.nodes[x].levels[0].forward;
+        if next != NIL {
+            self.nodes[next].backward = x;
+        }
+        self.len += 1;
+    }
+
+    /// Remove an element, returning `true` if it was found.
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.predecessors(score, member);
+
+        let x = self.nodes[update[0]].levels[0].forward;
+        if x == NIL || self.cmp(x, score, member) != Ordering::Equal {
+            return false;
+        }
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            let removed = self.nodes[x].levels.get(i).copied();
+            let prev = &mut self.nodes[prev].levels[i];
+            match removed {
+                Some(removed) if prev.forward == x => {
+                    prev.span += removed.span;
+                    prev.span -= 1;
+                    prev.forward = removed.forward;
+                }
+                _ => prev.span -= 1,
+            }
+        }
+
+        let Node {
+            backward, levels, ..
+        } = &self.nodes[x];
+        let (backward, next) = (*backward, levels[0].forward);
+        if next != NIL {
+            self.nodes[next].backward = backward;
+        }
+
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward == NIL {
+            self.level -= 1;
+        }
+
+        // Release the member and the levels right away rather than when the
+        // slot is reused.
+        self.nodes[x] = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            backward: NIL,
+            levels: Vec::new(),
+        };
+        self.free.push(x);
+        self.len -= 1;
+        true
+    }
+
+    /// Returns the zero-based rank of an element.
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEAD;
+
+        for i in (0..self.level).rev() {
+            loop {
+                let Level { forward, span } = self.nodes[x].levels[i];
+                if forward == NIL || self.cmp(forward, score, member) == Ordering::Greater {
+                    break;
+                }
+                rank += span;
+                x = forward;
+            }
+
+            if x != HEAD && self.cmp(x, score, member) == Ordering::Equal {
+                return Some(rank - 1);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the node at a zero-based rank, which must be in range.
+    fn by_rank(&self, rank: usize) -> usize {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+
+        for i in (0..self.level).rev() {
+            loop {
+                let Level { forward, span } = self.nodes[x].levels[i];
+                if forward == NIL || traversed + span > target {
+                    break;
+                }
+                traversed += span;
+                x = forward;
+            }
+
+            if traversed == target {
+                return x;
+            }
+        }
+
+        unreachable!("rank {} out of range", rank)
+    }
+
+    /// See `SortedSet::count_while`.
+    fn count_while(&self, before: impl Fn(f64, &Bytes) -> bool) -> usize {
+        let mut count = 0;
+        let mut x = HEAD;
+
+        for i in (0..self.level).rev() {
+            loop {
+                let Level { forward, span } = self.nodes[x].levels[i];
+                if forward == NIL {
+                    break;
+                }
+
+                let node = &self.nodes[forward];
+                if !before(node.score, &node.member) {
+                    break;
+                }
+                count += span;
+                x = forward;
+            }
+        }
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_match_a_sorted_reference() {
+        let mut set = SortedSet::new();
+        let mut reference: Vec<(i64, Bytes)> = Vec::new();
+
+        for round in 0..2000 {
+            let member = Bytes::from(format!("m{}", fastrand::u32(..300)));
+            let score = fastrand::i64(-50..50);
+
+            reference.retain(|(_, m)| *m != member);
+            if round % 3 == 0 {
+                set.remove(&member);
+            } else {
+                set.insert(member.clone(), score as f64);
+                reference.push((score, member));
+            }
+        }
+        reference.sort();
+
+        assert_eq!(set.len(), reference.len());
+        for (rank, (score, member)) in reference.iter().enumerate() {
+            assert_eq!(set.rank(member), Some(rank));
+            assert_eq!(set.score(member), Some(*score as f64));
+        }
+
+        let forward: Vec<_> = set
+            .range(0, set.len(), false)
+            .map(|(m, s)| (s as i64, m.clone()))
+            .collect();
+        assert_eq!(forward, reference);
+
+        let middle: Vec<_> = set.range(10, 20, true).map(|(m, _)| m.clone()).collect();
+        let expected: Vec<_> = reference[10..20]
+            .iter()
+            .rev()
+            .map(|(_, m)| m.clone())
+            .collect();
+        assert_eq!(middle, expected);
+
+        let negative = set.count_while(|score, _| score < 0.0);
+        assert_eq!(negative, reference.iter().filter(|(s, _)| *s < 0).count());
+    }
+
+    #[test]
+    fn equal_scores_order_by_member() {
+        let mut set = SortedSet::new();
+        for member in ["c", "a", "b"] {
+            set.insert(Bytes::from(member), 1.0);
+        }
+        set.insert(Bytes::from("z"), -0.0);
+
+        let members: Vec<_> = set
+            .range(0, set.len(), false)
+            .map(|(m, _)| m.clone())
+            .collect();
+        assert_eq!(members, ["z", "a", "b", "c"]);
+        assert_eq!(set.insert(Bytes::from("a"), 5.0), Some(1.0));
+        assert_eq!(set.rank(b"a"), Some(3));
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        assert_eq!(set.rank(b"c"), Some(2));
+    }
+}
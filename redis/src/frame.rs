@@ -1,10 +1,19 @@
 //! Provides a type representing a Redis protocol frame as well as utilities for
 //! parsing frames from a byte array.
+//!
+//! Besides RESP frames, requests may be sent as inline commands: a single
+//! line of space-separated arguments, as typed into `telnet` or `nc`. An
+//! inline command is parsed into the same array frame the equivalent RESP
+//! request would produce.
+//!
+//! Frames cover both RESP2 and RESP3. The types RESP3 added are sent as is to
+//! clients that negotiated RESP3 with `HELLO`, and as their closest RESP2
+//! equivalent to everyone else.
 
-use bytes::{Buf, Bytes};
-use std::convert::TryInto;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::fmt;
-use std::io::Cursor;
+use std::io::Write;
+use std::iter;
 use std::num::TryFromIntError;
 use std::string::FromUtf8Error;
 
@@ -13,12 +22,95 @@ use std::string::FromUtf8Error;
 pub enum Frame {
     Simple(String),
     Error(String),
-    Integer(u64),
+    Integer(i64),
     Bulk(Bytes),
+
+    /// The absence of a value. RESP3 has a type of its own for it, RESP2 uses
+    /// a null bulk string.
     Null,
+
     Array(Vec<Frame>),
+
+    /// Key-value pairs, in order. Flattened into an array for RESP2.
+    Map(Vec<(Frame, Frame)>),
+
+    /// An unordered collection of distinct frames. An array for RESP2.
+    Set(Vec<Frame>),
+
+    /// A floating point number. A bulk string for RESP2.
+    Double(f64),
+
+    /// An integer 1 or 0 for RESP2.
+    Boolean(bool),
+
+    /// An integer of any size, as its decimal digits. A bulk string for RESP2.
+    BigNumber(String),
+
+    /// Text along with its format, such as `txt` or `mkd`. A bulk string of
+    /// the text alone for RESP2.
+    VerbatimString {
+        format: [u8; 3],
+        text: Bytes,
+    },
+
+    /// Out-of-band information about the frame that follows it. RESP2 clients
+    /// only get that frame.
+    Attribute(Vec<(Frame, Frame)>, Box<Frame>),
+
+    /// Data sent to the client without it being a reply to a command, such as
+    /// Pub/Sub messages. An array for RESP2.
+    Push(Vec<Frame>),
+}
+
+/// Version of the protocol spoken on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// Bounds on what a peer may send, so that a bogus length prefix cannot make
+/// the server allocate or buffer arbitrary amounts of memory.
+///
+/// A frame exceeding any of them is a protocol error.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum length of a bulk string, like Redis's `proto-max-bulk-len`.
+    pub max_bulk_len: usize,
+
+    /// Maximum number of entries in an array.
+    pub max_array_len: usize,
+
+    /// Maximum number of arrays nested in one another.
+    pub max_depth: usize,
+
+    /// Maximum length of an inline command line, and of the lines of RESP
+    /// frames other than bulk string payloads.
+    pub max_inline_len: usize,
+
+    /// Maximum amount of data buffered before a complete frame is received,
+    /// like Redis's `client-query-buffer-limit`.
+    pub max_buffer_len: usize,
+}
+
+impl Default for Limits {
+    /// The same defaults as Redis, where it has an equivalent setting.
+    fn default() -> Limits {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: i32::MAX as usize,
+            max_depth: 128,
+            max_inline_len: 64 * 1024,
+            max_buffer_len: 1024 * 1024 * 1024,
+        }
+    }
 }
 
+/// Arrays are only preallocated up to this many entries, since the length
+/// prefix is not trustworthy until the entries have actually arrived.
+const MAX_ARRAY_PREALLOC: usize = 1024;
+
 #[derive(Debug)]
 pub enum Error {
     /// Not enough data is available to parse a message
@@ -53,7 +145,7 @@ impl Frame {
     /// # Panics
     ///
     /// panics if `self` is not an array
-    pub(crate) fn push_int(&mut self, value: u64) {
+    pub(crate) fn push_int(&mut self, value: i64) {
         match self {
             Frame::Array(vec) => {
                 vec.push(Frame::Integer(value));
@@ -62,115 +154,65 @@ impl Frame {
         }
     }
 
-    /// Checks if an entire message can be decoded from `src`
-    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
-        match get_u8(src)? {
-            b'+' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b'-' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b':' => {
-                let _ = get_decimal(src)?;
-                Ok(())
-            }
-            b'$' => {
-                if b'-' == peek_u8(src)? {
-                    // Skip '-1\r\n'
-                    skip(src, 4)
-                } else {
-                    // Read the bulk string
-                    let len: usize = get_decimal(src)?.try_into()?;
-
-                    // skip that number of bytes + 2 (\r\n).
-                    skip(src, len + 2)
-                }
-            }
-            b'*' => {
-                let len = get_decimal(src)?;
-
-                for _ in 0..len {
-                    Frame::check(src)?;
-                }
+    /// Append the encoding of the frame to `out`. RESP3 types are downgraded
+    /// to their RESP2 equivalent unless `protocol` is RESP3.
+    pub(crate) fn encode(&self, protocol: Protocol, out: &mut Vec<u8>) {
+        // Aggregates may be nested arbitrarily deep. Rather than recursing,
+        // the aggregates being encoded are kept on an explicit stack, each
+        // with the entries still to write.
+        let mut stack: Vec<Entries<'_>> = vec![Box::new(iter::once(self))];
+        while let Some(entries) = stack.last_mut() {
+            let Some(entry) = entries.next() else {
+                // Done with this aggregate, carry on with the enclosing one.
+                stack.pop();
+                continue;
+            };
 
-                Ok(())
+            entry.encode_value(protocol, out);
+            if let Some(entries) = entries_of(entry, protocol) {
+                stack.push(entries);
             }
-            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
         }
     }
 
-    /// The message has already been validated with `check`.
-    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
-        match get_u8(src)? {
-            b'+' => {
-                // Read the line and convert it to `Vec<u8>`
-                let line = get_line(src)?.to_vec();
-
-                // Convert the line to a String
-                let string = String::from_utf8(line)?;
-
-                Ok(Frame::Simple(string))
-            }
-            b'-' => {
-                // Read the line and convert it to `Vec<u8>`
-                let line = get_line(src)?.to_vec();
-
-                // Convert the line to a String
-                let string = String::from_utf8(line)?;
+    /// Append a frame literal, or the header of an aggregate, to `out`.
+    fn encode_value(&self, protocol: Protocol, out: &mut Vec<u8>) {
+        let resp3 = protocol == Protocol::Resp3;
 
-                Ok(Frame::Error(string))
-            }
-            b':' => {
-                let len = get_decimal(src)?;
-                Ok(Frame::Integer(len))
+        match self {
+            Frame::Simple(val) => encode_line(out, b'+', val.as_bytes()),
+            Frame::Error(val) => encode_line(out, b'-', val.as_bytes()),
+            Frame::Integer(val) => encode_header(out, b':', *val),
+            Frame::Null if resp3 => out.put_slice(b"_\r\n"),
+            Frame::Null => out.put_slice(b"$-1\r\n"),
+            Frame::Bulk(val) => encode_bulk(out, b'$', &[], val),
+            Frame::Double(val) if resp3 => encode_line(out, b',', format_double(*val).as_bytes()),
+            Frame::Double(val) => encode_bulk(out, b'$', &[], format_double(*val).as_bytes()),
+            Frame::Boolean(val) if resp3 => out.put_slice(if *val { b"#t\r\n" } else { b"#f\r\n" }),
+            Frame::Boolean(val) => encode_header(out, b':', *val as i64),
+            Frame::BigNumber(val) if resp3 => encode_line(out, b'(', val.as_bytes()),
+            Frame::BigNumber(val) => encode_bulk(out, b'$', &[], val.as_bytes()),
+            Frame::VerbatimString { format, text } if resp3 => {
+                encode_bulk(out, b'=', &[&format[..], b":"].concat(), text)
             }
-            b'$' => {
-                if b'-' == peek_u8(src)? {
-                    let line = get_line(src)?;
-
-                    if line != b"-1" {
-                        return Err("protocol error; invalid frame format".into());
-                    }
-
-                    Ok(Frame::Null)
-                } else {
-                    // Read the bulk string
-                    let len = get_decimal(src)?.try_into()?;
-                    let n = len + 2;
-
-                    if src.remaining() < n {
-                        return Err(Error::Incomplete);
-                    }
-
-                    let data = Bytes::copy_from_slice(&src.chunk()[..len]);
-
-                    // skip that number of bytes + 2 (\r\n).
-                    skip(src, n)?;
-
-                    Ok(Frame::Bulk(data))
-                }
+            Frame::VerbatimString { text, .. } => encode_bulk(out, b'$', &[], text),
+            // Only the header is written here; `encode` takes care of the
+            // entries.
+            Frame::Array(val) => encode_header(out, b'*', val.len() as i64),
+            Frame::Set(val) => {
+                encode_header(out, if resp3 { b'~' } else { b'*' }, val.len() as i64)
             }
-            b'*' => {
-                let len = get_decimal(src)?.try_into()?;
-                let mut out = Vec::with_capacity(len);
-
-                for _ in 0..len {
-                    out.push(Frame::parse(src)?);
-                }
-
-                Ok(Frame::Array(out))
+            Frame::Push(val) => {
+                encode_header(out, if resp3 { b'>' } else { b'*' }, val.len() as i64)
             }
-            _ => unimplemented!(),
+            Frame::Map(val) if resp3 => encode_header(out, b'%', val.len() as i64),
+            Frame::Map(val) => encode_header(out, b'*', val.len() as i64 * 2),
+            Frame::Attribute(val, _) if resp3 => encode_header(out, b'|', val.len() as i64),
+            // RESP2 has no room for attributes, only the frame they are
+            // attached to is sent.
+            Frame::Attribute(..) => {}
         }
     }
-
-    /// Converts the frame to an "unexpected frame" error
-    pub(crate) fn to_error(&self) -> crate::Error {
-        format!("unexpected frame: {}", self).into()
-    }
 }
 
 impl PartialEq<&str> for Frame {
@@ -196,7 +238,7 @@ impl fmt::Display for Frame {
                 Err(_) => write!(fmt, "{:?}", msg),
             },
             Frame::Null => "(nil)".fmt(fmt),
-            Frame::Array(parts) => {
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
                 for (i, part) in parts.iter().enumerate() {
                     if i > 0 {
                         // use space as the array element display separator
@@ -208,62 +250,551 @@ impl fmt::Display for Frame {
 
                 Ok(())
             }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+
+                    write!(fmt, "{} {}", key, value)?;
+                }
+
+                Ok(())
+            }
+            Frame::Double(value) => format_double(*value).fmt(fmt),
+            Frame::Boolean(value) => value.fmt(fmt),
+            Frame::BigNumber(digits) => digits.fmt(fmt),
+            Frame::VerbatimString { text, .. } => String::from_utf8_lossy(text).fmt(fmt),
+            Frame::Attribute(_, frame) => frame.fmt(fmt),
+        }
+    }
+}
+
+/// Frames still to be encoded for an aggregate.
+type Entries<'a> = Box<dyn Iterator<Item = &'a Frame> + 'a>;
+
+/// Returns the frames to encode after the header of `frame` when it is an
+/// aggregate.
+fn entries_of(frame: &Frame, protocol: Protocol) -> Option<Entries<'_>> {
+    match frame {
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => {
+            Some(Box::new(items.iter()))
+        }
+        Frame::Map(items) => Some(Box::new(flatten(items))),
+        Frame::Attribute(items, frame) if protocol == Protocol::Resp3 => {
+            Some(Box::new(flatten(items).chain(iter::once(&**frame))))
+        }
+        Frame::Attribute(_, frame) => Some(Box::new(iter::once(&**frame))),
+        _ => None,
+    }
+}
+
+/// Flatten key-value pairs.
+fn flatten(pairs: &[(Frame, Frame)]) -> impl Iterator<Item = &Frame> {
+    pairs.iter().flat_map(|(key, value)| [key, value])
+}
+
+/// Append a type byte followed by a line of text.
+fn encode_line(out: &mut Vec<u8>, kind: u8, line: &[u8]) {
+    out.put_u8(kind);
+    out.put_slice(line);
+    out.put_slice(b"\r\n");
+}
+
+/// Append a type byte followed by a number, such as an integer or the length
+/// of an aggregate.
+fn encode_header(out: &mut Vec<u8>, kind: u8, val: i64) {
+    out.put_u8(kind);
+    // Writing to a `Vec` cannot fail.
+    write!(out, "{}\r\n", val).unwrap();
+}
+
+/// Append a string prefixed with its length, and with `prefix` in front of
+/// its contents.
+fn encode_bulk(out: &mut Vec<u8>, kind: u8, prefix: &[u8], val: &[u8]) {
+    encode_header(out, kind, (prefix.len() + val.len()) as i64);
+    out.put_slice(prefix);
+    out.put_slice(val);
+    out.put_slice(b"\r\n");
+}
+
+/// Format a double the way it is sent to clients.
+fn format_double(value: f64) -> String {
+    if value.is_nan() {
+        // RESP3 spells it in lowercase, unlike `f64`'s `Display`.
+        "nan".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Decodes the frames received on a connection.
+///
+/// Data is consumed from the read buffer as soon as it has been decoded, and
+/// the frame in progress is kept in the decoder: the aggregates still waiting
+/// for entries and the length of a bulk string whose payload has not fully
+/// arrived. When more data comes in, decoding resumes where it left off, so
+/// every byte is scanned once however many reads a frame is spread over.
+///
+/// After an error, the rest of the input cannot be made sense of and the
+/// decoder should be dropped along with the connection.
+#[derive(Debug)]
+pub struct Decoder {
+    limits: Limits,
+
+    /// Aggregates of the frame in progress still waiting for entries, the
+    /// innermost last.
+    aggregates: Vec<PartialAggregate>,
+
+    /// Type byte and length of the string whose payload is being waited for.
+    blob: Option<(u8, usize)>,
+
+    /// How much of the read buffer was already searched for the end of the
+    /// current line.
+    scanned: usize,
+
+    /// Number of bytes of the frame in progress consumed so far.
+    consumed: usize,
+}
+
+#[derive(Debug)]
+struct PartialAggregate {
+    kind: Aggregate,
+
+    /// Number of frames to collect, as given by `Aggregate::entry_count`.
+    len: usize,
+
+    entries: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Attribute,
+    Push,
+}
+
+impl Decoder {
+    /// Create a decoder rejecting frames that exceed `limits`.
+    pub fn new(limits: Limits) -> Decoder {
+        Decoder {
+            limits,
+            aggregates: Vec::new(),
+            blob: None,
+            scanned: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Returns the limits frames are held to.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Hold the frames that follow to `limits`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Returns the number of bytes of the frame in progress that were already
+    /// consumed from the read buffer.
+    pub fn pending_len(&self) -> usize {
+        self.consumed
+    }
+
+    /// Decode a frame off the front of `buf`.
+    ///
+    /// Bulk payloads are split off the buffer rather than copied, so they
+    /// share its allocation. If `buf` does not complete a frame, everything
+    /// that can be decoded is consumed and `Incomplete` is returned.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Frame, Error> {
+        loop {
+            let value = match self.blob {
+                Some((first, len)) => self.blob(buf, first, len)?,
+                None => match self.header(buf)? {
+                    Some(value) => value,
+                    // An aggregate or a string was started.
+                    None => continue,
+                },
+            };
+
+            if let Some(frame) = self.complete(value) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Decode the line starting the next value. Returns the value if that line
+    /// holds all of it.
+    fn header(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let Some(&first) = buf.first() else {
+            return Err(Error::Incomplete);
+        };
+
+        if !matches!(
+            first,
+            b'+' | b'-'
+                | b':'
+                | b'$'
+                | b'*'
+                | b'_'
+                | b','
+                | b'#'
+                | b'('
+                | b'='
+                | b'%'
+                | b'~'
+                | b'|'
+                | b'>'
+        ) {
+            // Not a RESP type byte, so this is an inline command, which can
+            // only make up a whole request.
+            if !self.aggregates.is_empty() {
+                return Err(format!("protocol error; invalid frame type byte `{}`", first).into());
+            }
+
+            let args = split_inline(&self.inline_line(buf)?)?;
+            return Ok(Some(Frame::Array(
+                args.into_iter().map(Frame::Bulk).collect(),
+            )));
+        }
+
+        let line = self.line(buf)?;
+        let line = &line[1..];
+        match first {
+            b'+' => Ok(Some(Frame::Simple(String::from_utf8(line.to_vec())?))),
+            b'-' => Ok(Some(Frame::Error(String::from_utf8(line.to_vec())?))),
+            b':' => Ok(Some(Frame::Integer(parse_integer(line)?))),
+            b'_' | b',' | b'#' | b'(' => Ok(Some(scalar(first, line)?)),
+            b'$' | b'=' => {
+                if first == b'$' && line.first() == Some(&b'-') {
+                    if line != b"-1" {
+                        return Err("protocol error; invalid frame format".into());
+                    }
+
+                    return Ok(Some(Frame::Null));
+                }
+
+                self.blob = Some((first, bulk_len(line, &self.limits)?));
+                Ok(None)
+            }
+            _ => {
+                let kind = Aggregate::from_type_byte(first);
+                let len = kind.entry_count(array_len(line, &self.limits, self.aggregates.len())?);
+                let aggregate = PartialAggregate {
+                    kind,
+                    len,
+                    entries: Vec::with_capacity(len.min(MAX_ARRAY_PREALLOC)),
+                };
+                if len == 0 {
+                    return Ok(Some(aggregate.into_frame()));
+                }
+
+                self.aggregates.push(aggregate);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Decode the payload of a bulk or verbatim string of `len` bytes.
+    fn blob(&mut self, buf: &mut BytesMut, first: u8, len: usize) -> Result<Frame, Error> {
+        // The length is only bounded by `max_bulk_len`, which may be anything.
+        let Some(end) = len.checked_add(2) else {
+            return Err("protocol error; invalid bulk length".into());
+        };
+        if buf.len() < end {
+            return Err(Error::Incomplete);
+        }
+
+        if &buf[len..end] != b"\r\n" {
+            return Err("protocol error; bulk data not followed by CRLF".into());
+        }
+
+        let data = buf.split_to(len).freeze();
+        buf.advance(2);
+        self.consumed += end;
+        self.blob = None;
+
+        if first == b'=' {
+            verbatim(data)
+        } else {
+            Ok(Frame::Bulk(data))
+        }
+    }
+
+    /// Add a decoded value to the innermost aggregate in progress, closing
+    /// every aggregate it completes. Returns the frame once the outermost one
+    /// is done.
+    fn complete(&mut self, mut value: Frame) -> Option<Frame> {
+        while let Some(aggregate) = self.aggregates.last_mut() {
+            aggregate.entries.push(value);
+            if aggregate.entries.len() < aggregate.len {
+                return None;
+            }
+
+            value = self.aggregates.pop().unwrap().into_frame();
+        }
+
+        self.consumed = 0;
+        Some(value)
+    }
+
+    /// Split a CRLF terminated line off `buf`, returning it without the CRLF.
+    /// Lines are held to the same length as inline commands, as in Redis.
+    fn line(&mut self, buf: &mut BytesMut) -> Result<BytesMut, Error> {
+        let too_big = || Error::from("protocol error; too big line");
+
+        let Some(end) = self.find(buf, b"\r\n") else {
+            if buf.len() > self.limits.max_inline_len {
+                return Err(too_big());
+            }
+            // The last byte may be the first half of the CRLF.
+            self.scanned = buf.len().saturating_sub(1);
+            return Err(Error::Incomplete);
+        };
+        if end > self.limits.max_inline_len {
+            return Err(too_big());
+        }
+
+        Ok(self.take_line(buf, end, 2))
+    }
+
+    /// Split the line of an inline command off `buf`. Unlike RESP lines it may
+    /// end with a bare `\n`, which is what `nc` sends.
+    fn inline_line(&mut self, buf: &mut BytesMut) -> Result<BytesMut, Error> {
+        let too_big = || Error::from("protocol error; too big inline request");
+
+        let Some(end) = self.find(buf, b"\n") else {
+            if buf.len() > self.limits.max_inline_len {
+                return Err(too_big());
+            }
+            self.scanned = buf.len();
+            return Err(Error::Incomplete);
+        };
+        if end > self.limits.max_inline_len {
+            return Err(too_big());
         }
+
+        let mut line = self.take_line(buf, end, 1);
+        if line.last() == Some(&b'\r') {
+            line.truncate(line.len() - 1);
+        }
+        Ok(line)
+    }
+
+    /// Returns the position of `delimiter` in `buf`, searching only the part
+    /// that was not searched before.
+    fn find(&self, buf: &[u8], delimiter: &[u8]) -> Option<usize> {
+        buf[self.scanned..]
+            .windows(delimiter.len())
+            .position(|window| window == delimiter)
+            .map(|i| self.scanned + i)
+    }
+
+    /// Split the first `end` bytes off `buf` along with the `skip` byte long
+    /// line terminator that follows them.
+    fn take_line(&mut self, buf: &mut BytesMut, end: usize, skip: usize) -> BytesMut {
+        let line = buf.split_to(end);
+        buf.advance(skip);
+        self.consumed += end + skip;
+        self.scanned = 0;
+        line
     }
 }
 
-fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
-    if !src.has_remaining() {
-        return Err(Error::Incomplete);
+impl Aggregate {
+    /// Returns the aggregate introduced by `first`, which must be one of the
+    /// aggregate type bytes.
+    fn from_type_byte(first: u8) -> Aggregate {
+        match first {
+            b'*' => Aggregate::Array,
+            b'%' => Aggregate::Map,
+            b'~' => Aggregate::Set,
+            b'|' => Aggregate::Attribute,
+            b'>' => Aggregate::Push,
+            _ => unreachable!("not an aggregate type byte"),
+        }
     }
 
-    Ok(src.chunk()[0])
+    /// Returns how many frames make up an aggregate of length `len`. Maps
+    /// hold both halves of every pair, and attributes also hold the frame
+    /// they are attached to.
+    fn entry_count(self, len: usize) -> usize {
+        match self {
+            Aggregate::Map => len.saturating_mul(2),
+            Aggregate::Attribute => len.saturating_mul(2).saturating_add(1),
+            _ => len,
+        }
+    }
 }
 
-fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
-    if !src.has_remaining() {
-        return Err(Error::Incomplete);
+impl PartialAggregate {
+    fn into_frame(self) -> Frame {
+        let mut entries = self.entries;
+        match self.kind {
+            Aggregate::Array => Frame::Array(entries),
+            Aggregate::Set => Frame::Set(entries),
+            Aggregate::Push => Frame::Push(entries),
+            Aggregate::Map => Frame::Map(pairs(entries)),
+            Aggregate::Attribute => {
+                let frame = entries.pop().unwrap();
+                Frame::Attribute(pairs(entries), Box::new(frame))
+            }
+        }
     }
+}
+
+/// Group `entries` two by two.
+fn pairs(entries: Vec<Frame>) -> Vec<(Frame, Frame)> {
+    let mut entries = entries.into_iter();
+    iter::from_fn(|| Some((entries.next()?, entries.next()?))).collect()
+}
 
-    Ok(src.get_u8())
+/// Parse the length of a bulk string.
+fn bulk_len(line: &[u8], limits: &Limits) -> Result<usize, Error> {
+    match usize::try_from(parse_decimal(line)?) {
+        Ok(len) if len <= limits.max_bulk_len => Ok(len),
+        _ => Err("protocol error; invalid bulk length".into()),
+    }
 }
 
-fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
-    if src.remaining() < n {
-        return Err(Error::Incomplete);
+/// Parse the length of an array nested in `depth` other arrays.
+fn array_len(line: &[u8], limits: &Limits, depth: usize) -> Result<usize, Error> {
+    if depth >= limits.max_depth {
+        return Err("protocol error; too many nested arrays".into());
     }
 
-    src.advance(n);
-    Ok(())
+    match usize::try_from(parse_decimal(line)?) {
+        Ok(len) if len <= limits.max_array_len => Ok(len),
+        _ => Err("protocol error; invalid multibulk length".into()),
+    }
 }
 
-/// Read a new-line terminated decimal
-fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+/// Parse a decimal
+fn parse_decimal(line: &[u8]) -> Result<u64, Error> {
     use atoi::atoi;
 
-    let line = get_line(src)?;
-
     atoi::<u64>(line).ok_or_else(|| "protocol error; invalid frame format".into())
 }
 
-/// Find a line
-fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
-    // Scan the bytes directly
-    let start = src.position() as usize;
-    // Scan to the second to last byte
-    let end = src.get_ref().len() - 1;
+/// Parse a possibly negative integer
+fn parse_integer(line: &[u8]) -> Result<i64, Error> {
+    use atoi::atoi;
 
-    for i in start..end {
-        if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
-            // We found a line, update the position to be *after* the \n
-            src.set_position((i + 2) as u64);
+    atoi::<i64>(line).ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+/// Parse a RESP3 value held entirely on the `line` following its type byte.
+fn scalar(first: u8, line: &[u8]) -> Result<Frame, Error> {
+    let invalid = || Error::from("protocol error; invalid frame format");
 
-            // Return the line
-            return Ok(&src.get_ref()[start..i]);
+    match (first, line) {
+        (b'_', b"") => Ok(Frame::Null),
+        (b'#', b"t") => Ok(Frame::Boolean(true)),
+        (b'#', b"f") => Ok(Frame::Boolean(false)),
+        (b',', _) => std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.parse().ok())
+            .map(Frame::Double)
+            .ok_or_else(invalid),
+        (b'(', _) => {
+            let digits = line.strip_prefix(b"-").unwrap_or(line);
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                return Err(invalid());
+            }
+            Ok(Frame::BigNumber(String::from_utf8(line.to_vec())?))
         }
+        _ => Err(invalid()),
     }
+}
+
+/// Split the format off the payload of a verbatim string.
+fn verbatim(mut data: Bytes) -> Result<Frame, Error> {
+    if data.len() < 4 || data[3] != b':' {
+        return Err("protocol error; invalid verbatim string".into());
+    }
+
+    let format = data[..3].try_into().unwrap();
+    data.advance(4);
+    Ok(Frame::VerbatimString { format, text: data })
+}
+
+/// Split an inline command into its arguments.
+///
+/// Arguments are separated by whitespace and may be quoted like Redis does.
+/// Within double quotes, `\n`, `\r`, `\t`, `\b`, `\a`, `\xHH` and `\` followed
+/// by any other byte are unescaped. Within single quotes, only `\'` is. A
+/// closing quote must be followed by whitespace or the end of the line.
+fn split_inline(line: &[u8]) -> Result<Vec<Bytes>, Error> {
+    let unbalanced = || Error::from("protocol error; unbalanced quotes in request");
+
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        match line[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                loop {
+                    match (line.get(i), line.get(i + 1)) {
+                        (None, _) => return Err(unbalanced()),
+                        (Some(&c), _) if c == quote => break,
+                        (Some(b'\\'), Some(&next)) if quote == b'"' => {
+                            let hex = line
+                                .get(i + 2..i + 4)
+                                .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+                                .map(|digits| {
+                                    let digits = std::str::from_utf8(digits).unwrap();
+                                    u8::from_str_radix(digits, 16).unwrap()
+                                });
+                            match (next, hex) {
+                                (b'x', Some(byte)) => {
+                                    arg.push(byte);
+                                    i += 2;
+                                }
+                                (b'n', _) => arg.push(b'\n'),
+                                (b'r', _) => arg.push(b'\r'),
+                                (b't', _) => arg.push(b'\t'),
+                                (b'b', _) => arg.push(0x08),
+                                (b'a', _) => arg.push(0x07),
+                                (other, _) => arg.push(other),
+                            }
+                            i += 1;
+                        }
+                        (Some(b'\\'), Some(b'\'')) if quote == b'\'' => {
+                            arg.push(b'\'');
+                            i += 1;
+                        }
+                        (Some(&c), _) => arg.push(c),
+                    }
+                    i += 1;
+                }
 
-    Err(Error::Incomplete)
+                // Step over the closing quote, which must end the argument.
+                i += 1;
+                if line.get(i).is_some_and(|c| !c.is_ascii_whitespace()) {
+                    return Err(unbalanced());
+                }
+            }
+            _ => {
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    arg.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        args.push(Bytes::from(arg));
+    }
 }
 
 impl From<String> for Error {
@@ -300,3 +831,231 @@ impl fmt::Display for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_negative_integers() {
+        assert!(matches!(decode(b":-5\r\n").unwrap(), Frame::Integer(-5)));
+    }
+
+    /// Decode a single frame out of `src`.
+    fn decode(src: &[u8]) -> Result<Frame, Error> {
+        decode_with(src, &Limits::default())
+    }
+
+    fn decode_with(src: &[u8], limits: &Limits) -> Result<Frame, Error> {
+        Decoder::new(*limits).decode(&mut BytesMut::from(src))
+    }
+
+    fn args(frame: Frame) -> Vec<String> {
+        match frame {
+            Frame::Array(args) => args.iter().map(|arg| arg.to_string()).collect(),
+            frame => panic!("expected an array, got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn parses_inline_commands() {
+        assert_eq!(args(decode(b"SET a b\r\n").unwrap()), ["SET", "a", "b"]);
+        assert_eq!(args(decode(b"  get   key \n").unwrap()), ["get", "key"]);
+        assert_eq!(
+            args(decode(b"SET \"a b\" 'c \"d\\' e'\r\n").unwrap()),
+            ["SET", "a b", "c \"d' e"]
+        );
+        assert_eq!(
+            args(decode(b"ECHO \"\\x41\\tb\\\\\" \"\"\r\n").unwrap()),
+            ["ECHO", "A\tb\\", ""]
+        );
+        assert!(args(decode(b"\r\n").unwrap()).is_empty());
+        assert!(matches!(decode(b"PING"), Err(Error::Incomplete)));
+        assert!(decode(b"*1\r\nPING\r\n").is_err());
+
+        for unbalanced in [&b"ECHO \"abc\r\n"[..], b"ECHO 'abc'def\r\n"] {
+            let err = decode(unbalanced).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "protocol error; unbalanced quotes in request"
+            );
+        }
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let limits = Limits {
+            max_bulk_len: 3,
+            max_array_len: 2,
+            max_depth: 2,
+            max_inline_len: 12,
+            ..Limits::default()
+        };
+        let rejected = |src: &[u8], reason: &str| {
+            let err = decode_with(src, &limits).unwrap_err().to_string();
+            assert_eq!(err, format!("protocol error; {}", reason));
+        };
+
+        assert!(decode_with(b"*2\r\n*1\r\n$3\r\nfoo\r\n:1\r\n", &limits).is_ok());
+        rejected(b"$4\r\nfour\r\n", "invalid bulk length");
+        rejected(b"*3\r\n:1\r\n:2\r\n:3\r\n", "invalid multibulk length");
+        rejected(b"*1\r\n*1\r\n*1\r\n:1\r\n", "too many nested arrays");
+        rejected(b"GET something\r\n", "too big inline request");
+        rejected(b"+a simple string\r\n", "too big line");
+        rejected(b"*1\r\n$00000000000003\r\nfoo\r\n", "too big line");
+        // Without waiting for the end of the line.
+        rejected(b"-an error without an end", "too big line");
+
+        // A bogus length is rejected up front rather than allocated for.
+        rejected(b"*9999999999\r\n", "invalid multibulk length");
+        assert!(matches!(decode(b"*2147483647\r\n"), Err(Error::Incomplete)));
+    }
+
+    #[test]
+    fn huge_bulk_lengths_do_not_overflow() {
+        let unlimited = Limits {
+            max_bulk_len: usize::MAX,
+            max_buffer_len: usize::MAX,
+            ..Limits::default()
+        };
+        let src = format!("*1\r\n${}\r\n", usize::MAX - 1);
+        let err = decode_with(src.as_bytes(), &unlimited).unwrap_err();
+        assert_eq!(err.to_string(), "protocol error; invalid bulk length");
+    }
+
+    #[test]
+    fn bulk_payloads_share_the_read_buffer() {
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n*1\r\n$1"[..]);
+        let start = buf.as_ptr() as usize;
+        let range = start..start + buf.len();
+
+        let mut decoder = Decoder::new(Limits::default());
+        let Frame::Array(args) = decoder.decode(&mut buf).unwrap() else {
+            panic!("expected an array");
+        };
+        assert_eq!(args[0], "GET");
+        assert_eq!(args[1], "hello");
+        for arg in &args {
+            let Frame::Bulk(data) = arg else {
+                panic!("expected a bulk string");
+            };
+            assert!(range.contains(&(data.as_ptr() as usize)));
+        }
+
+        // Of the incomplete frame that follows, the decoder keeps the part it
+        // could make sense of.
+        assert_eq!(&buf[..], b"*1\r\n$1");
+        assert!(matches!(decoder.decode(&mut buf), Err(Error::Incomplete)));
+        assert_eq!(&buf[..], b"$1");
+        assert_eq!(decoder.pending_len(), 4);
+    }
+
+    #[test]
+    fn resumes_one_byte_at_a_time() {
+        let requests: [&[u8]; 2] = [
+            b"*4\r\n$3\r\nSET\r\n*2\r\n:-12\r\n$-1\r\n*0\r\n$10\r\nhello\r\nyou\r\n",
+            b"ECHO \"a b\"\r\n",
+        ];
+        let mut decoder = Decoder::new(Limits::default());
+        let mut buf = BytesMut::new();
+
+        for request in requests {
+            let (last, head) = request.split_last().unwrap();
+            for &byte in head {
+                buf.extend_from_slice(&[byte]);
+                assert!(matches!(decoder.decode(&mut buf), Err(Error::Incomplete)));
+            }
+            buf.extend_from_slice(&[*last]);
+            let frame = decoder.decode(&mut buf).unwrap();
+
+            assert_eq!(
+                format!("{:?}", frame),
+                format!("{:?}", decode(request).unwrap())
+            );
+            assert!(buf.is_empty());
+            assert_eq!(decoder.pending_len(), 0);
+        }
+    }
+
+    #[test]
+    fn parses_resp3_types() {
+        let frame = decode(
+            b"%2\r\n+first\r\n~2\r\n,1.5\r\n,-inf\r\n$6\r\nsecond\r\n|1\r\n+ttl\r\n:3\r\n\
+              >3\r\n#t\r\n_\r\n=8\r\ntxt:some\r\n",
+        )
+        .unwrap();
+        let Frame::Map(pairs) = frame else {
+            panic!("expected a map, got {:?}", frame);
+        };
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].0, "first");
+        assert!(matches!(
+            &pairs[0].1,
+            Frame::Set(set) if matches!(set[..], [Frame::Double(1.5), Frame::Double(f64::NEG_INFINITY)])
+        ));
+        assert_eq!(pairs[1].0, "second");
+        let Frame::Attribute(attributes, data) = &pairs[1].1 else {
+            panic!("expected an attribute, got {:?}", pairs[1].1);
+        };
+        assert_eq!(attributes[0].0, "ttl");
+        assert!(matches!(attributes[0].1, Frame::Integer(3)));
+        assert!(matches!(
+            &**data,
+            Frame::Push(push) if matches!(
+                &push[..],
+                [Frame::Boolean(true), Frame::Null, Frame::VerbatimString { format, text }]
+                    if format == b"txt" && &text[..] == b"some"
+            )
+        ));
+
+        assert!(
+            matches!(decode(b"(-123456789012345678901234567890\r\n").unwrap(),
+            Frame::BigNumber(n) if n == "-123456789012345678901234567890")
+        );
+        assert!(decode(b",nan\r\n").is_ok_and(|f| matches!(f, Frame::Double(d) if d.is_nan())));
+        assert!(matches!(decode(b"%0\r\n").unwrap(), Frame::Map(pairs) if pairs.is_empty()));
+        for invalid in [
+            &b"#x\r\n"[..],
+            b"_x\r\n",
+            b",1.5x\r\n",
+            b"(12a\r\n",
+            b"=3\r\ntxt\r\n",
+        ] {
+            assert!(decode(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn malformed_input_never_panics() {
+        let valid: [&[u8]; 6] = [
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n:-12\r\n",
+            b"*2\r\n+OK\r\n*1\r\n$-1\r\n",
+            b"-ERR oops\r\n",
+            b"SET \"a\\x41\" 'b'\r\n",
+            b"%2\r\n+a\r\n,1.5\r\n~1\r\n#t\r\n>1\r\n_\r\n",
+            b"|1\r\n+k\r\n(12\r\n=7\r\ntxt:abc\r\n",
+        ];
+        let interesting = b"*$:+-%~>|=_,#(\r\n-0123456789\"'\\x ";
+
+        assert!(decode(b"$18446744073709551615\r\n").is_err());
+        assert!(decode(b"$3\r\nfooXY").is_err());
+
+        let mut rng = fastrand::Rng::with_seed(7);
+        for _ in 0..20_000 {
+            let mut input = valid[rng.usize(..valid.len())].to_vec();
+            for _ in 0..rng.usize(1..4) {
+                let at = rng.usize(..input.len());
+                match rng.u8(..3) {
+                    0 => input[at] = interesting[rng.usize(..interesting.len())],
+                    1 => input.truncate(at),
+                    _ => input.insert(at, rng.u8(..)),
+                }
+                if input.is_empty() {
+                    break;
+                }
+            }
+
+            let _ = decode(&input);
+        }
+    }
+}
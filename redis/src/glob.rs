@@ -0,0 +1,121 @@
+//! Glob-style pattern matching, as used by `PSUBSCRIBE` and `PUBSUB CHANNELS`.
+//!
+//! Supports the same syntax as Redis:
+//!
+//! * `?` matches any single byte
+//! * `*` matches any run of bytes, including an empty one
+//! * `[abc]` matches one of the listed bytes, `[^abc]` any byte but those, and
+//!   `[a-z]` a range
+//! * `\` escapes the next byte
+
+/// Returns `true` if `string` matches `pattern`.
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+
+    // Where to resume after a mismatch: the pattern position following the
+    // last `*` seen, and the string position that `*` currently extends to.
+    let mut backtrack = None;
+
+    while s < string.len() {
+        if let Some(consumed) = match_one(&pattern[p..], string[s]) {
+            p += consumed;
+            s += 1;
+            continue;
+        }
+
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, s));
+            continue;
+        }
+
+        // Let the last `*` swallow one more byte and try again.
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Match the first element of `pattern` against a single byte. Returns how
+/// many bytes of the pattern it spans, or `None` if it does not match. A `*`
+/// never matches here; the caller deals with it.
+fn match_one(pattern: &[u8], byte: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'*' => None,
+        b'?' => Some(1),
+        b'[' => {
+            let (matched, consumed) = match_class(pattern, byte);
+            matched.then_some(consumed)
+        }
+        b'\\' if pattern.len() > 1 => (pattern[1] == byte).then_some(2),
+        c => (c == byte).then_some(1),
+    }
+}
+
+/// Match a `[...]` class. Returns whether `byte` matched and the length of the
+/// class. An unterminated class extends to the end of the pattern.
+fn match_class(class: &[u8], byte: u8) -> (bool, usize) {
+    let mut i = 1;
+    let negate = class.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < class.len() && class[i] != b']' {
+        if class[i] == b'\\' && i + 1 < class.len() {
+            matched |= class[i + 1] == byte;
+            i += 2;
+        } else if i + 2 < class.len() && class[i + 1] == b'-' {
+            let (lo, hi) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+            matched |= (lo..=hi).contains(&byte);
+            i += 3;
+        } else {
+            matched |= class[i] == byte;
+            i += 1;
+        }
+    }
+
+    (matched != negate, (i + 1).min(class.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("news.*", "news.tech"));
+        assert!(!matches("news.*", "sports.tech"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b", "xxaxxbxxc"));
+        assert!(matches("a**", "a"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("h[c-a]llo", "hbllo"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("[\\]]", "]"));
+    }
+}
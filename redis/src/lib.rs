@@ -1,89 +1,2645 @@
+mod acl;
+mod cmd;
+mod config;
 mod connection;
-mod frame;
+mod db;
+pub mod frame;
+mod glob;
 mod parse;
 
-use tokio::io::{self, AsyncWriteExt};
-use tokio::net::TcpStream;
+pub use config::Config;
+pub use db::{AppendFsync, Db, ShutdownMode};
+pub use frame::Limits;
+
+use bytes::BytesMut;
+use cmd::{Command, Subscriber, Transaction};
+use connection::Connection;
+use db::Shutdown;
+use frame::{Decoder, Frame};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, Error>;
 
-/// Handle a single connection: read up to 3 bytes then echo them back.
-/// Returns the number of bytes echoed.
-pub async fn handle_connection(mut socket: TcpStream) -> io::Result<usize> {
-    let mut buf = [0u8; 3];
-    let n = loop {
-        socket.readable().await?;
-        match socket.try_read(&mut buf) {
-            Ok(0) => return Ok(0), // connection closed
-            Ok(n) => break n,
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(e) => return Err(e),
+/// How long accepting connections pauses after the first failure in a row.
+/// Every further failure doubles it, up to `MAX_ACCEPT_BACKOFF`.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Run a server set up as `config` says, until it is shut down with
+/// `SHUTDOWN`, SIGINT or SIGTERM.
+///
+/// The keyspace starts out with the keys of the append-only file if enabled,
+/// as it is the most up to date, or of the last snapshot.
+pub async fn run(config: Config) -> Result<()> {
+    let listener = TcpListener::bind((&config.bind[..], config.port))
+        .await
+        .map_err(|e| format!("cannot listen on {}:{}: {}", config.bind, config.port, e))?;
+
+    // A single keyspace shared by every connection.
+    let db = Db::with_config(config.clone());
+    if config.appendonly {
+        let path = config.aof_path();
+        let replayed = replay_aof(&db, &path)
+            .map_err(|e| format!("failed to load append only file: {}", e))?;
+        if replayed > 0 {
+            eprintln!("replayed {} commands from {}", replayed, path.display());
+        }
+        db.enable_aof(path, config.appendfsync)?;
+    } else {
+        let loaded = db
+            .load_snapshot()
+            .map_err(|e| format!("failed to load snapshot: {}", e))?;
+        if loaded > 0 {
+            eprintln!(
+                "loaded {} keys from {}",
+                loaded,
+                db.snapshot_path().display()
+            );
+        }
+    }
+    if let Some((host, port)) = config.replicaof {
+        cmd::replicate(&db, host, port);
+    }
+    db.spawn_expiry_task();
+    tokio::spawn(shutdown_on_signal(db.clone()));
+
+    listen(listener, db).await
+}
+
+/// Accept connections on `listener` and serve them against `db`, until the
+/// server is asked to shut down.
+///
+/// Shutting down stops accepting connections and tells every connection to
+/// close, which each does once it replied to the requests it already
+/// received. When all of them are gone, the append-only file is flushed to
+/// disk and a snapshot saved, as the shutdown was requested.
+pub async fn listen(listener: TcpListener, db: Db) -> Result<()> {
+    let mut shutdown = db.shutdown_listener();
+
+    // Every connection task holds a sender, so receiving fails once all of
+    // them are done.
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let mut backoff = ACCEPT_BACKOFF;
+
+    loop {
+        let socket = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((socket, _addr)) => socket,
+                Err(e) => {
+                    // Running out of file descriptors and the like does not
+                    // last, so accepting is retried after a pause.
+                    eprintln!("cannot accept a connection: {}", e);
+                    tokio::select! {
+                        () = tokio::time::sleep(backoff) => {}
+                        () = shutdown.recv() => break,
+                    }
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            },
+            () = shutdown.recv() => break,
+        };
+        backoff = ACCEPT_BACKOFF;
+
+        let db = db.clone();
+        let done = done_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(socket, db).await {
+                eprintln!("connection error: {}", e);
+            }
+            drop(done);
+        });
+    }
+
+    drop(listener);
+    drop(done_tx);
+    let _ = done_rx.recv().await;
+
+    db.finish_shutdown(db.shutdown_requested().unwrap_or_default())
+}
+
+/// Shut the server down on SIGINT or SIGTERM.
+async fn shutdown_on_signal(db: Db) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
         }
     };
-    socket.write_all(&buf[..n]).await?;
-    socket.shutdown().await?;
-    Ok(n)
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => {
+            if res.is_err() {
+                // Without a handler for SIGINT, the process is killed by it
+                // anyway.
+                return;
+            }
+        }
+        () = terminate => {}
+    }
+    db.shutdown(ShutdownMode::Default);
+}
+
+/// Serve a single client connection until it disconnects.
+///
+/// Request frames are read off the socket one at a time, parsed into a
+/// `Command` and applied to the shared `db`. Commands that fail to parse get an
+/// error reply; the connection itself is only dropped on I/O or protocol
+/// errors. A protocol error is reported to the client before closing.
+///
+/// Replies are buffered until every request already received has been
+/// processed and then flushed together, so a client pipelining many requests
+/// costs one write rather than one per reply.
+///
+/// A client connecting while `maxclients` others are gets an error and is
+/// disconnected. Once the server is shutting down, the connection is closed
+/// as soon as the requests received so far got their replies.
+pub async fn handle_connection(socket: TcpStream, db: Db) -> Result<()> {
+    let mut connection = Connection::with_limits(socket, db.limits());
+
+    // The place of the client is given up when the connection closes.
+    let Some(_client) = db.admit_client() else {
+        let error = Frame::Error("ERR max number of clients reached".to_string());
+        connection.write_frame(&error).await?;
+        return Ok(());
+    };
+
+    let mut shutdown = db.shutdown_listener();
+    let res = serve(&mut connection, &db, &mut shutdown).await;
+    if let Err(err) = &res
+        && let Some(err) = err.downcast_ref::<frame::Error>()
+    {
+        // The rest of the input cannot be made sense of, so the connection is
+        // closed anyway. Failing to send the reason changes nothing.
+        let _ = connection
+            .write_frame(&Frame::Error(format!("ERR {}", err)))
+            .await;
+    }
+    res
+}
+
+/// Replay the append-only file at `path` into `db`, returning the number of
+/// commands applied. A missing file is not an error.
+///
+/// A crash can leave the last command half-written. Such a tail is dropped
+/// and the file truncated to the last complete command, along with any
+/// transaction missing its `EXEC`. Anything else that does not parse as a
+/// command is an error.
+pub fn replay_aof(db: &Db, path: impl AsRef<Path>) -> Result<usize> {
+    let path = path.as_ref();
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(format!("cannot read {}: {}", path.display(), e).into()),
+    };
+
+    let mut buf = BytesMut::from(&data[..]);
+    let mut decoder = Decoder::new(db.limits());
+    let mut transaction: Option<Vec<Command>> = None;
+    let mut applied = 0;
+
+    // End of the last command applied, which is where the file gets
+    // truncated if what follows is incomplete.
+    let mut valid_len = 0;
+
+    let corrupt = |offset: usize, e: &dyn std::fmt::Display| -> Error {
+        format!("{} is corrupt at offset {}: {}", path.display(), offset, e).into()
+    };
+
+    loop {
+        let frame = match decoder.decode(&mut buf) {
+            Ok(frame) => frame,
+            Err(frame::Error::Incomplete) => break,
+            Err(e) => return Err(corrupt(valid_len, &e)),
+        };
+        let offset = data.len() - buf.len();
+
+        match Command::from_frame(frame) {
+            Ok(Command::Multi(_)) if transaction.is_none() => transaction = Some(Vec::new()),
+            Ok(Command::Exec(_)) if transaction.is_some() => {
+                for cmd in transaction.take().unwrap_or_default() {
+                    cmd.apply(db);
+                    applied += 1;
+                }
+                valid_len = offset;
+            }
+            Ok(Command::Unknown(cmd)) => return Err(corrupt(valid_len, &cmd.apply())),
+            Ok(cmd) => match &mut transaction {
+                Some(queued) => queued.push(cmd),
+                None => {
+                    cmd.apply(db);
+                    applied += 1;
+                    valid_len = offset;
+                }
+            },
+            Err(e) => return Err(corrupt(valid_len, &e)),
+        }
+    }
+
+    if valid_len < data.len() {
+        eprintln!(
+            "{}: dropping {} bytes of incomplete commands at the end",
+            path.display(),
+            data.len() - valid_len
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(valid_len as u64))
+            .map_err(|e| format!("cannot truncate {}: {}", path.display(), e))?;
+    }
+    Ok(applied)
+}
+
+async fn serve(connection: &mut Connection, db: &Db, shutdown: &mut Shutdown) -> Result<()> {
+    let mut transaction = Transaction::new(db);
+    let id = db.next_client_id();
+
+    // Port a replica connecting to this server said it listens on.
+    let mut replica_port = 0;
+
+    // User the client is authenticated as, if any.
+    let mut user = db.initial_user();
+
+    loop {
+        let frame = match connection.try_read_frame()? {
+            Some(frame) => frame,
+            None => {
+                // Nothing left to process, so send the pending replies before
+                // waiting for more requests.
+                connection.flush().await?;
+
+                // Once the server is shutting down, the requests received so
+                // far all got their replies.
+                if shutdown.is_requested() {
+                    return Ok(());
+                }
+
+                // `None` is returned once the peer closes the socket cleanly.
+                let frame = tokio::select! {
+                    res = connection.read_frame() => res?,
+                    () = shutdown.recv() => return Ok(()),
+                };
+                match frame {
+                    Some(frame) => frame,
+                    None => return Ok(()),
+                }
+            }
+        };
+
+        // Empty requests, such as a blank line sent by someone typing into
+        // `telnet`, are skipped.
+        if matches!(&frame, Frame::Array(args) if args.is_empty()) {
+            continue;
+        }
+
+        // Write commands are propagated as they were sent, so the request is
+        // kept around.
+        let request = frame.clone();
+
+        // Commands the user may not run are refused like malformed ones.
+        // Replicas only take writes from their master, and no server takes
+        // any while its append-only file cannot be written to.
+        let cmd = db
+            .check_access(user.as_deref(), &request)
+            .map_err(Into::into)
+            .and_then(|()| Command::from_frame(frame))
+            .and_then(|cmd| {
+                if cmd.is_write() && db.is_replica() {
+                    return Err("READONLY You can't write against a read only replica.".into());
+                }
+                if cmd.is_write() {
+                    db.check_aof().map_err(|e| format!("MISCONF {}", e))?;
+                }
+                Ok(cmd)
+            });
+
+        let response = match cmd {
+            Ok(Command::Quit(cmd)) => {
+                connection.write_frame(&cmd.apply()).await?;
+                return Ok(());
+            }
+            Ok(Command::Multi(_)) => transaction.multi(),
+            Ok(Command::Exec(_)) => transaction.exec(db),
+            Ok(Command::Discard(_)) => transaction.discard(),
+            Ok(Command::Watch(cmd)) => transaction.watch(cmd),
+            // Between MULTI and EXEC, everything else is queued, including
+            // commands that failed to parse so that EXEC can refuse to run.
+            cmd if transaction.is_active() => transaction.queue(cmd, request),
+            Ok(Command::Unwatch(cmd)) => transaction.unwatch(cmd),
+            Ok(Command::Hello(cmd)) => cmd.apply(db, connection, id, &mut user),
+            Ok(Command::Auth(cmd)) => cmd.apply(db, &mut user),
+            // Checking access made sure the client is authenticated.
+            Ok(Command::Acl(cmd)) => cmd.apply(db, user.as_deref().unwrap_or_default()),
+            Ok(Command::ReplicaOf(cmd)) => cmd.apply(db),
+            Ok(Command::ReplConf(cmd)) => cmd.apply(&mut replica_port),
+            // The connection turns into the link of a replica.
+            //
+            // This and the other commands that may wait indefinitely give way
+            // to a shutdown. They can be dropped at any point they wait at
+            // without losing anything.
+            Ok(Command::Psync(cmd)) => {
+                connection.flush().await?;
+                return tokio::select! {
+                    res = cmd.serve(db, connection, replica_port) => res,
+                    () = shutdown.recv() => Ok(()),
+                };
+            }
+            Ok(Command::Wait(cmd)) => tokio::select! {
+                response = cmd.wait(db) => response,
+                () = shutdown.recv() => return Ok(()),
+            },
+            Ok(Command::Config(cmd)) => cmd.apply(db),
+            Ok(Command::Shutdown(cmd)) => {
+                cmd.apply(db);
+                connection.flush().await?;
+                return Ok(());
+            }
+            // Blocking commands may wait for other clients, so they are the
+            // only ones that need the connection.
+            Ok(Command::BPop(cmd)) => match tokio::select! {
+                res = cmd.block(db, request, connection) => res?,
+                () = shutdown.recv() => None,
+            } {
+                Some(response) => response,
+                None => return Ok(()),
+            },
+            Ok(Command::BLMove(cmd)) => match tokio::select! {
+                res = cmd.block(db, request, connection) => res?,
+                () = shutdown.recv() => None,
+            } {
+                Some(response) => response,
+                None => return Ok(()),
+            },
+            // Subscribing switches the connection into subscriber mode until
+            // the client drops every subscription. `UNSUBSCRIBE` goes the same
+            // way so it gets the same replies outside of subscriber mode.
+            Ok(cmd @ (Command::Subscribe(_) | Command::Unsubscribe(_))) => {
+                let resumed = tokio::select! {
+                    res = Subscriber::new(db).run(cmd, connection, user.as_deref()) => res?,
+                    () = shutdown.recv() => false,
+                };
+                if !resumed {
+                    return Ok(());
+                }
+                continue;
+            }
+            Ok(cmd) => db.concurrently(|| cmd.apply_logged(db, request)),
+            Err(e) => Frame::Error(e.to_string()),
+        };
+
+        connection.feed_frame(&response).await?;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
     use tokio::net::TcpListener;
 
-    #[tokio::test]
-    async fn echoes_three_bytes_or_less() {
+    /// Start a server on an ephemeral port, serving every accepted connection
+    /// against a single shared `Db`.
+    async fn start_server() -> SocketAddr {
+        start_server_with(Db::new()).await
+    }
+
+    async fn start_server_with(db: Db) -> SocketAddr {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
+        db.spawn_expiry_task();
 
-        // Spawn server accept once
-        let server = tokio::spawn(async move {
-            let (socket, _addr) = listener.accept().await.unwrap();
-            handle_connection(socket).await.unwrap()
+        tokio::spawn(async move {
+            loop {
+                let (socket, _addr) = listener.accept().await.unwrap();
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let _ = handle_connection(socket, db).await;
+                });
+            }
         });
 
-        // Client
-        let mut client = TcpStream::connect(addr).await.unwrap();
-        client.write_all(b"abc").await.unwrap();
+        addr
+    }
 
-        let mut out = Vec::with_capacity(3);
-        let mut tmp = [0u8; 3];
-        let n = client.read(&mut tmp).await.unwrap();
-        out.extend_from_slice(&tmp[..n]);
+    async fn connect(addr: SocketAddr) -> Connection {
+        Connection::new(TcpStream::connect(addr).await.unwrap())
+    }
 
-        let echoed = server.await.unwrap();
-        assert_eq!(echoed, 3);
-        assert_eq!(&out, b"abc");
+    /// Queue a command built from `args` in the write buffer, without
+    /// flushing it.
+    async fn feed(conn: &mut Connection, args: &[&str]) {
+        let request = Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        conn.feed_frame(&request).await.unwrap();
+    }
+
+    /// Send a command built from `args` without waiting for its reply.
+    async fn send(conn: &mut Connection, args: &[&str]) {
+        feed(conn, args).await;
+        conn.flush().await.unwrap();
+    }
+
+    /// Send a command built from `args` and wait for its reply.
+    async fn call(conn: &mut Connection, args: &[&str]) -> Frame {
+        send(conn, args).await;
+        conn.read_frame().await.unwrap().unwrap()
     }
 
     #[tokio::test]
-    async fn many_connections() {
+    async fn ping_and_echo() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert_eq!(call(&mut conn, &["PING"]).await, "PONG");
+        assert_eq!(call(&mut conn, &["PING", "hi"]).await, "hi");
+        assert_eq!(
+            call(&mut conn, &["ECHO", "hello world"]).await,
+            "hello world"
+        );
+    }
+
+    #[tokio::test]
+    async fn set_get_del_exists() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["GET", "foo"]).await,
+            Frame::Null
+        ));
+        assert_eq!(call(&mut conn, &["SET", "foo", "bar"]).await, "OK");
+        assert_eq!(call(&mut conn, &["GET", "foo"]).await, "bar");
+        assert!(matches!(
+            call(&mut conn, &["EXISTS", "foo", "foo", "nope"]).await,
+            Frame::Integer(2)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["DEL", "foo", "nope"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["GET", "foo"]).await,
+            Frame::Null
+        ));
+    }
+
+    #[tokio::test]
+    async fn errors_keep_the_connection_open() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        let reply = call(&mut conn, &["NOPE", "x"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR unknown command 'nope'"));
+
+        let reply = call(&mut conn, &["SET", "foo"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.contains("wrong number of arguments")));
+
+        assert_eq!(call(&mut conn, &["PING"]).await, "PONG");
+    }
+
+    #[tokio::test]
+    async fn connections_share_the_keyspace() {
         const N: usize = 25; // keep test fast
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
+        let addr = start_server().await;
 
-        // server task
-        let server = tokio::spawn(async move {
-            for _ in 0..N {
-                let (socket, _addr) = listener.accept().await.unwrap();
+        for i in 0..N {
+            let mut conn = connect(addr).await;
+            let value = format!("{:03}", i);
+            assert_eq!(
+                call(&mut conn, &["SET", &format!("key:{i}"), &value]).await,
+                "OK"
+            );
+        }
+
+        let mut conn = connect(addr).await;
+        for i in 0..N {
+            let expected = format!("{:03}", i);
+            assert_eq!(
+                call(&mut conn, &["GET", &format!("key:{i}")]).await,
+                expected.as_str()
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_clients_lose_no_writes() {
+        const CLIENTS: usize = 16;
+        const KEYS: usize = 100;
+        let addr = start_server_with(Db::with_shards(4)).await;
+
+        let writers: Vec<_> = (0..CLIENTS)
+            .map(|c| {
                 tokio::spawn(async move {
-                    let _ = handle_connection(socket).await;
-                });
+                    let mut conn = connect(addr).await;
+                    for k in 0..KEYS {
+                        let key = format!("{c}:{k}");
+                        assert_eq!(call(&mut conn, &["SET", &key, &key]).await, "OK");
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        let mut conn = connect(addr).await;
+        for c in 0..CLIENTS {
+            for k in 0..KEYS {
+                let key = format!("{c}:{k}");
+                assert_eq!(call(&mut conn, &["GET", &key]).await, key.as_str());
             }
+        }
+    }
+
+    #[tokio::test]
+    async fn set_options_and_ttl() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["TTL", "k"]).await,
+            Frame::Integer(-2)
+        ));
+        assert_eq!(call(&mut conn, &["SET", "k", "v1", "NX"]).await, "OK");
+        assert!(matches!(
+            call(&mut conn, &["SET", "k", "v2", "NX"]).await,
+            Frame::Null
+        ));
+        assert!(matches!(
+            call(&mut conn, &["TTL", "k"]).await,
+            Frame::Integer(-1)
+        ));
+
+        assert_eq!(
+            call(&mut conn, &["SET", "k", "v2", "XX", "GET", "EX", "100"]).await,
+            "v1"
+        );
+        assert!(matches!(
+            call(&mut conn, &["TTL", "k"]).await,
+            Frame::Integer(100)
+        ));
+        assert_eq!(call(&mut conn, &["SET", "k", "v3", "KEEPTTL"]).await, "OK");
+        assert!(matches!(call(&mut conn, &["PTTL", "k"]).await, Frame::Integer(ms) if ms > 99_000));
+
+        assert!(matches!(
+            call(&mut conn, &["PERSIST", "k"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["PERSIST", "k"]).await,
+            Frame::Integer(0)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["TTL", "k"]).await,
+            Frame::Integer(-1)
+        ));
+
+        let reply = call(&mut conn, &["SET", "k", "v", "EX", "0"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg == "ERR invalid expire time in 'set' command")
+        );
+        let reply = call(&mut conn, &["SET", "k", "v", "EX", "10", "PX", "10"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR syntax error"));
+    }
+
+    #[tokio::test]
+    async fn expire_conditions_and_deadlines() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["EXPIRE", "k", "10"]).await,
+            Frame::Integer(0)
+        ));
+        call(&mut conn, &["SET", "k", "v"]).await;
+
+        assert!(matches!(
+            call(&mut conn, &["EXPIRE", "k", "10", "XX"]).await,
+            Frame::Integer(0)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["EXPIRE", "k", "10", "NX"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["EXPIRE", "k", "5", "GT"]).await,
+            Frame::Integer(0)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["EXPIRE", "k", "5", "LT"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["TTL", "k"]).await,
+            Frame::Integer(5)
+        ));
+
+        // A deadline in the past deletes the key straight away.
+        assert!(matches!(
+            call(&mut conn, &["EXPIREAT", "k", "1"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["EXISTS", "k"]).await,
+            Frame::Integer(0)
+        ));
+
+        call(&mut conn, &["SET", "k", "v", "PX", "50"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(matches!(call(&mut conn, &["GET", "k"]).await, Frame::Null));
+        assert!(matches!(
+            call(&mut conn, &["PTTL", "k"]).await,
+            Frame::Integer(-2)
+        ));
+    }
+
+    #[tokio::test]
+    async fn incr_decr_family() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["INCR", "n"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["DECRBY", "n", "5"]).await,
+            Frame::Integer(-4)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["INCRBY", "n", "-6"]).await,
+            Frame::Integer(-10)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["DECR", "n"]).await,
+            Frame::Integer(-11)
+        ));
+        assert_eq!(call(&mut conn, &["GET", "n"]).await, "-11");
+
+        call(&mut conn, &["SET", "n", "9223372036854775807", "EX", "100"]).await;
+        let reply = call(&mut conn, &["INCR", "n"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg == "ERR increment or decrement would overflow")
+        );
+        assert!(matches!(
+            call(&mut conn, &["TTL", "n"]).await,
+            Frame::Integer(100)
+        ));
+
+        call(&mut conn, &["SET", "s", "12abc"]).await;
+        let reply = call(&mut conn, &["INCR", "s"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg == "ERR value is not an integer or out of range")
+        );
+        let reply = call(&mut conn, &["INCRBY", "n", "one"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg == "ERR value is not an integer or out of range")
+        );
+
+        // Only the form INCR itself stores counts as an integer.
+        for value in ["-", "+", "+5", "007", "-0", ""] {
+            call(&mut conn, &["SET", "z", value]).await;
+            let reply = call(&mut conn, &["INCR", "z"]).await;
+            assert!(
+                matches!(reply, Frame::Error(msg) if msg == "ERR value is not an integer or out of range"),
+                "{:?} was taken for an integer",
+                value
+            );
+        }
+        call(&mut conn, &["SET", "z", "0"]).await;
+        assert!(matches!(
+            call(&mut conn, &["INCR", "z"]).await,
+            Frame::Integer(1)
+        ));
+
+        assert_eq!(call(&mut conn, &["INCRBYFLOAT", "f", "10.5"]).await, "10.5");
+        assert_eq!(call(&mut conn, &["INCRBYFLOAT", "f", "0.1"]).await, "10.6");
+        assert_eq!(call(&mut conn, &["INCRBYFLOAT", "f", "-5.6"]).await, "5");
+        let reply = call(&mut conn, &["INCRBYFLOAT", "s", "1"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR value is not a valid float"));
+    }
+
+    #[tokio::test]
+    async fn string_manipulation() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["APPEND", "s", "Hello"]).await,
+            Frame::Integer(5)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["APPEND", "s", " World"]).await,
+            Frame::Integer(11)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["STRLEN", "s"]).await,
+            Frame::Integer(11)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["STRLEN", "nope"]).await,
+            Frame::Integer(0)
+        ));
+
+        assert_eq!(call(&mut conn, &["GETRANGE", "s", "0", "4"]).await, "Hello");
+        assert_eq!(
+            call(&mut conn, &["GETRANGE", "s", "-5", "-1"]).await,
+            "World"
+        );
+        assert_eq!(
+            call(&mut conn, &["GETRANGE", "s", "-100", "100"]).await,
+            "Hello World"
+        );
+        assert_eq!(call(&mut conn, &["GETRANGE", "s", "5", "2"]).await, "");
+
+        assert!(matches!(
+            call(&mut conn, &["SETRANGE", "s", "6", "Redis"]).await,
+            Frame::Integer(11)
+        ));
+        assert_eq!(call(&mut conn, &["GET", "s"]).await, "Hello Redis");
+        assert!(matches!(
+            call(&mut conn, &["SETRANGE", "z", "3", "ab"]).await,
+            Frame::Integer(5)
+        ));
+        assert_eq!(call(&mut conn, &["GET", "z"]).await, "\0\0\0ab");
+        let reply = call(&mut conn, &["SETRANGE", "z", "-1", "ab"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR offset is out of range"));
+
+        assert_eq!(
+            call(&mut conn, &["GETEX", "s", "EX", "100"]).await,
+            "Hello Redis"
+        );
+        assert!(matches!(
+            call(&mut conn, &["TTL", "s"]).await,
+            Frame::Integer(100)
+        ));
+        assert_eq!(
+            call(&mut conn, &["GETEX", "s", "PERSIST"]).await,
+            "Hello Redis"
+        );
+        assert!(matches!(
+            call(&mut conn, &["TTL", "s"]).await,
+            Frame::Integer(-1)
+        ));
+
+        assert_eq!(call(&mut conn, &["GETDEL", "s"]).await, "Hello Redis");
+        assert!(matches!(
+            call(&mut conn, &["GETDEL", "s"]).await,
+            Frame::Null
+        ));
+    }
+
+    #[tokio::test]
+    async fn mset_mget_msetnx() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert_eq!(
+            call(&mut conn, &["MSET", "a", "1", "b", "2", "a", "3"]).await,
+            "OK"
+        );
+        let Frame::Array(values) = call(&mut conn, &["MGET", "a", "b", "c"]).await else {
+            panic!("expected an array reply");
+        };
+        assert_eq!(values[0], "3");
+        assert_eq!(values[1], "2");
+        assert!(matches!(values[2], Frame::Null));
+
+        assert!(matches!(
+            call(&mut conn, &["MSETNX", "c", "1", "a", "1"]).await,
+            Frame::Integer(0)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["EXISTS", "c"]).await,
+            Frame::Integer(0)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["MSETNX", "c", "1", "d", "1"]).await,
+            Frame::Integer(1)
+        ));
+
+        let reply = call(&mut conn, &["MSET", "a", "1", "b"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.contains("wrong number of arguments")));
+    }
+
+    /// Unwrap an array reply into its elements rendered as strings.
+    fn strings(frame: Frame) -> Vec<String> {
+        match frame {
+            Frame::Array(items) => items.iter().map(|item| item.to_string()).collect(),
+            frame => panic!("expected an array reply, got {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn list_commands() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["RPUSH", "l", "a", "b", "c"]).await,
+            Frame::Integer(3)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["LPUSH", "l", "y", "z"]).await,
+            Frame::Integer(5)
+        ));
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "l", "0", "-1"]).await),
+            ["z", "y", "a", "b", "c"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "l", "-2", "100"]).await),
+            ["b", "c"]
+        );
+        assert!(strings(call(&mut conn, &["LRANGE", "l", "4", "1"]).await).is_empty());
+
+        assert_eq!(call(&mut conn, &["LINDEX", "l", "-1"]).await, "c");
+        assert!(matches!(
+            call(&mut conn, &["LINDEX", "l", "5"]).await,
+            Frame::Null
+        ));
+        assert_eq!(call(&mut conn, &["LSET", "l", "0", "Z"]).await, "OK");
+        let reply = call(&mut conn, &["LSET", "l", "9", "x"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR index out of range"));
+
+        assert!(matches!(
+            call(&mut conn, &["LINSERT", "l", "BEFORE", "a", "b"]).await,
+            Frame::Integer(6)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["LINSERT", "l", "AFTER", "nope", "x"]).await,
+            Frame::Integer(-1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["LREM", "l", "-1", "b"]).await,
+            Frame::Integer(1)
+        ));
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "l", "0", "-1"]).await),
+            ["Z", "y", "b", "a", "c"]
+        );
+
+        assert_eq!(call(&mut conn, &["LTRIM", "l", "1", "-2"]).await, "OK");
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "l", "0", "-1"]).await),
+            ["y", "b", "a"]
+        );
+        assert_eq!(call(&mut conn, &["LPOP", "l"]).await, "y");
+        assert_eq!(
+            strings(call(&mut conn, &["RPOP", "l", "5"]).await),
+            ["a", "b"]
+        );
+
+        // Popping the last element deletes the key.
+        assert!(matches!(
+            call(&mut conn, &["LLEN", "l"]).await,
+            Frame::Integer(0)
+        ));
+        assert_eq!(call(&mut conn, &["TYPE", "l"]).await, "none");
+        assert!(matches!(call(&mut conn, &["LPOP", "l"]).await, Frame::Null));
+    }
+
+    #[tokio::test]
+    async fn lmove_between_and_within_lists() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        call(&mut conn, &["RPUSH", "src", "1", "2", "3"]).await;
+        assert_eq!(
+            call(&mut conn, &["LMOVE", "src", "dst", "LEFT", "RIGHT"]).await,
+            "1"
+        );
+        assert_eq!(call(&mut conn, &["RPOPLPUSH", "src", "dst"]).await, "3");
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "dst", "0", "-1"]).await),
+            ["3", "1"]
+        );
+
+        // Same source and destination rotates the list.
+        assert_eq!(
+            call(&mut conn, &["LMOVE", "dst", "dst", "LEFT", "RIGHT"]).await,
+            "3"
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "dst", "0", "-1"]).await),
+            ["1", "3"]
+        );
+        assert!(matches!(
+            call(&mut conn, &["LMOVE", "nope", "dst", "LEFT", "LEFT"]).await,
+            Frame::Null
+        ));
+    }
+
+    #[tokio::test]
+    async fn commands_reject_the_wrong_type() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+        let wrongtype =
+            |frame: Frame| matches!(frame, Frame::Error(msg) if msg.starts_with("WRONGTYPE"));
+
+        call(&mut conn, &["SET", "s", "v"]).await;
+        call(&mut conn, &["RPUSH", "l", "v"]).await;
+
+        assert!(wrongtype(call(&mut conn, &["LPUSH", "s", "x"]).await));
+        assert!(wrongtype(
+            call(&mut conn, &["LRANGE", "s", "0", "-1"]).await
+        ));
+        assert!(wrongtype(call(&mut conn, &["GET", "l"]).await));
+        assert!(wrongtype(call(&mut conn, &["INCR", "l"]).await));
+        assert!(wrongtype(call(&mut conn, &["APPEND", "l", "x"]).await));
+        assert!(wrongtype(
+            call(&mut conn, &["LMOVE", "l", "s", "LEFT", "LEFT"]).await
+        ));
+        assert!(wrongtype(call(&mut conn, &["HSET", "l", "f", "v"]).await));
+        assert!(wrongtype(call(&mut conn, &["HGETALL", "s"]).await));
+        assert!(wrongtype(call(&mut conn, &["SADD", "l", "m"]).await));
+        assert!(wrongtype(call(&mut conn, &["SUNION", "nope", "s"]).await));
+        assert!(wrongtype(call(&mut conn, &["ZADD", "s", "1", "m"]).await));
+        assert!(matches!(
+            call(&mut conn, &["LLEN", "l"]).await,
+            Frame::Integer(1)
+        ));
+
+        assert_eq!(call(&mut conn, &["TYPE", "s"]).await, "string");
+        assert_eq!(call(&mut conn, &["TYPE", "l"]).await, "list");
+        assert_eq!(call(&mut conn, &["SET", "l", "v"]).await, "OK");
+        assert_eq!(call(&mut conn, &["TYPE", "l"]).await, "string");
+    }
+
+    /// Give the server time to queue a client that was just sent a blocking
+    /// command.
+    async fn settle() {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn blocked_clients_are_served_in_order() {
+        let addr = start_server().await;
+        let mut first = connect(addr).await;
+        let mut second = connect(addr).await;
+        let mut pusher = connect(addr).await;
+
+        send(&mut first, &["BLPOP", "other", "queue", "0"]).await;
+        settle().await;
+        send(&mut second, &["BRPOP", "queue", "0"]).await;
+        settle().await;
+
+        // Both waiters are served from one push, oldest first, and the third
+        // element stays in the list.
+        assert!(matches!(
+            call(&mut pusher, &["RPUSH", "queue", "a", "b", "c"]).await,
+            Frame::Integer(3)
+        ));
+        let reply = first.read_frame().await.unwrap().unwrap();
+        assert_eq!(strings(reply), ["queue", "a"]);
+        let reply = second.read_frame().await.unwrap().unwrap();
+        assert_eq!(strings(reply), ["queue", "c"]);
+        assert_eq!(
+            strings(call(&mut pusher, &["LRANGE", "queue", "0", "-1"]).await),
+            ["b"]
+        );
+
+        // Non-empty lists are served straight away, in key order.
+        call(&mut pusher, &["RPUSH", "other", "x"]).await;
+        assert_eq!(
+            strings(call(&mut first, &["BLPOP", "nope", "other", "queue", "0"]).await),
+            ["other", "x"]
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_pops_time_out_and_validate() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["BLPOP", "nope", "0.05"]).await,
+            Frame::Null
+        ));
+        assert!(matches!(
+            call(
+                &mut conn,
+                &["BLMOVE", "nope", "dst", "LEFT", "LEFT", "0.05"]
+            )
+            .await,
+            Frame::Null
+        ));
+        let reply = call(&mut conn, &["BLPOP", "k", "-1"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR timeout is negative"));
+        let reply = call(&mut conn, &["BRPOP", "k", "soon"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg == "ERR timeout is not a float or out of range")
+        );
+        let reply = call(&mut conn, &["BLPOP", "k"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg == "ERR wrong number of arguments for 'blpop' command")
+        );
+
+        call(&mut conn, &["SET", "s", "v"]).await;
+        assert!(matches!(
+            call(&mut conn, &["BLPOP", "s", "0"]).await,
+            Frame::Error(msg) if msg.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[tokio::test]
+    async fn disconnected_waiters_are_skipped() {
+        let addr = start_server().await;
+        let mut gone = connect(addr).await;
+        let mut waiting = connect(addr).await;
+        let mut pusher = connect(addr).await;
+
+        send(&mut gone, &["BLPOP", "queue", "0"]).await;
+        settle().await;
+        send(
+            &mut waiting,
+            &["BLMOVE", "queue", "dst", "RIGHT", "LEFT", "0"],
+        )
+        .await;
+        settle().await;
+        drop(gone);
+        settle().await;
+
+        call(&mut pusher, &["RPUSH", "queue", "a", "b"]).await;
+        assert_eq!(waiting.read_frame().await.unwrap().unwrap(), "b");
+        assert_eq!(
+            strings(call(&mut pusher, &["LRANGE", "dst", "0", "-1"]).await),
+            ["b"]
+        );
+        assert_eq!(
+            strings(call(&mut pusher, &["LRANGE", "queue", "0", "-1"]).await),
+            ["a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_commands() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["HSET", "h", "a", "1", "b", "2"]).await,
+            Frame::Integer(2)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["HSET", "h", "a", "10", "c", "3"]).await,
+            Frame::Integer(1)
+        ));
+        assert_eq!(call(&mut conn, &["HMSET", "h", "d", "4"]).await, "OK");
+        assert!(matches!(
+            call(&mut conn, &["HSETNX", "h", "a", "x"]).await,
+            Frame::Integer(0)
+        ));
+        assert_eq!(call(&mut conn, &["HGET", "h", "a"]).await, "10");
+        assert!(matches!(
+            call(&mut conn, &["HGET", "h", "nope"]).await,
+            Frame::Null
+        ));
+
+        let reply = call(&mut conn, &["HMGET", "h", "b", "nope", "c"]).await;
+        let Frame::Array(values) = reply else {
+            panic!("expected an array, got {:?}", reply);
+        };
+        assert!(matches!(&values[..], [b, Frame::Null, c] if *b == "2" && *c == "3"));
+
+        let mut pairs: Vec<_> = strings(call(&mut conn, &["HGETALL", "h"]).await)
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            [("a", "10"), ("b", "2"), ("c", "3"), ("d", "4")]
+                .map(|(f, v)| (f.to_string(), v.to_string()))
+        );
+        let mut fields = strings(call(&mut conn, &["HKEYS", "h"]).await);
+        fields.sort();
+        assert_eq!(fields, ["a", "b", "c", "d"]);
+        assert_eq!(strings(call(&mut conn, &["HVALS", "h"]).await).len(), 4);
+
+        assert!(matches!(
+            call(&mut conn, &["HINCRBY", "h", "a", "-15"]).await,
+            Frame::Integer(-5)
+        ));
+        assert_eq!(
+            call(&mut conn, &["HINCRBYFLOAT", "h", "n", "1.5"]).await,
+            "1.5"
+        );
+        call(&mut conn, &["HSET", "h", "s", "text"]).await;
+        let reply = call(&mut conn, &["HINCRBY", "h", "s", "1"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR hash value is not an integer"));
+        let reply = call(&mut conn, &["HINCRBYFLOAT", "h", "s", "1"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR hash value is not a float"));
+
+        assert!(matches!(
+            call(&mut conn, &["HEXISTS", "h", "b"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["HDEL", "h", "a", "b", "c", "d", "nope"]).await,
+            Frame::Integer(4)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["HLEN", "h"]).await,
+            Frame::Integer(2)
+        ));
+
+        // Removing the last field removes the key.
+        call(&mut conn, &["HDEL", "h", "n", "s"]).await;
+        assert!(matches!(
+            call(&mut conn, &["EXISTS", "h"]).await,
+            Frame::Integer(0)
+        ));
+    }
+
+    #[tokio::test]
+    async fn hrandfield_counts() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["HRANDFIELD", "h"]).await,
+            Frame::Null
+        ));
+        assert!(strings(call(&mut conn, &["HRANDFIELD", "h", "3"]).await).is_empty());
+
+        call(&mut conn, &["HSET", "h", "a", "1", "b", "2", "c", "3"]).await;
+        let field = strings(Frame::Array(vec![
+            call(&mut conn, &["HRANDFIELD", "h"]).await,
+        ]));
+        assert!(["a", "b", "c"].contains(&&field[0][..]));
+
+        // Positive counts return distinct fields, capped at the hash size.
+        let mut fields = strings(call(&mut conn, &["HRANDFIELD", "h", "2"]).await);
+        fields.sort();
+        fields.dedup();
+        assert_eq!(fields.len(), 2);
+        let mut fields = strings(call(&mut conn, &["HRANDFIELD", "h", "10"]).await);
+        fields.sort();
+        assert_eq!(fields, ["a", "b", "c"]);
+
+        // Negative counts return exactly that many, possibly repeating.
+        let pairs = strings(call(&mut conn, &["HRANDFIELD", "h", "-7", "WITHVALUES"]).await);
+        assert_eq!(pairs.len(), 14);
+        for pair in pairs.chunks(2) {
+            let expected = (pair[0].as_bytes()[0] - b'a' + b'1') as char;
+            assert_eq!(pair[1], expected.to_string());
+        }
+
+        // Counts too large for any reply are refused without touching the
+        // hash.
+        for args in [
+            &["HRANDFIELD", "h", "-9223372036854775807"][..],
+            &["HRANDFIELD", "h", "-4611686018427387903", "WITHVALUES"],
+        ] {
+            assert!(matches!(
+                call(&mut conn, args).await,
+                Frame::Error(e) if e == "ERR value is out of range"
+            ));
+        }
+        assert!(matches!(
+            call(&mut conn, &["HLEN", "h"]).await,
+            Frame::Integer(3)
+        ));
+
+        let reply = call(&mut conn, &["HRANDFIELD", "h", "1", "VALUES"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR syntax error"));
+    }
+
+    /// Sorted members of a set reply.
+    fn sorted(frame: Frame) -> Vec<String> {
+        let mut members = strings(frame);
+        members.sort();
+        members
+    }
+
+    #[tokio::test]
+    async fn set_commands() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["SADD", "s", "a", "b", "c", "a"]).await,
+            Frame::Integer(3)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SADD", "s", "c", "d"]).await,
+            Frame::Integer(1)
+        ));
+        assert_eq!(
+            sorted(call(&mut conn, &["SMEMBERS", "s"]).await),
+            ["a", "b", "c", "d"]
+        );
+        assert!(matches!(
+            call(&mut conn, &["SISMEMBER", "s", "b"]).await,
+            Frame::Integer(1)
+        ));
+        let reply = call(&mut conn, &["SMISMEMBER", "s", "a", "x", "d"]).await;
+        assert!(matches!(
+            &reply,
+            Frame::Array(found) if matches!(
+                &found[..],
+                [Frame::Integer(1), Frame::Integer(0), Frame::Integer(1)]
+            )
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SREM", "s", "d", "x"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SCARD", "s"]).await,
+            Frame::Integer(3)
+        ));
+
+        let mut picked = sorted(call(&mut conn, &["SRANDMEMBER", "s", "2"]).await);
+        picked.dedup();
+        assert_eq!(picked.len(), 2);
+        assert_eq!(
+            strings(call(&mut conn, &["SRANDMEMBER", "s", "-5"]).await).len(),
+            5
+        );
+        assert!(matches!(
+            call(&mut conn, &["SRANDMEMBER", "s", "-9223372036854775807"]).await,
+            Frame::Error(e) if e == "ERR value is out of range"
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SCARD", "s"]).await,
+            Frame::Integer(3)
+        ));
+
+        // Popping every member removes the key.
+        let mut popped = strings(call(&mut conn, &["SPOP", "s", "2"]).await);
+        let last = call(&mut conn, &["SPOP", "s"]).await;
+        popped.extend(strings(Frame::Array(vec![last])));
+        popped.sort();
+        assert_eq!(popped, ["a", "b", "c"]);
+        assert!(matches!(call(&mut conn, &["SPOP", "s"]).await, Frame::Null));
+        assert!(matches!(
+            call(&mut conn, &["EXISTS", "s"]).await,
+            Frame::Integer(0)
+        ));
+    }
+
+    #[tokio::test]
+    async fn set_algebra() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        call(&mut conn, &["SADD", "a", "1", "2", "3", "4"]).await;
+        call(&mut conn, &["SADD", "b", "3", "4", "5"]).await;
+        call(&mut conn, &["SADD", "c", "4", "5", "6"]).await;
+
+        assert_eq!(
+            sorted(call(&mut conn, &["SINTER", "a", "b", "c"]).await),
+            ["4"]
+        );
+        assert_eq!(
+            sorted(call(&mut conn, &["SUNION", "a", "nope"]).await),
+            ["1", "2", "3", "4"]
+        );
+        assert_eq!(
+            sorted(call(&mut conn, &["SDIFF", "a", "b", "c"]).await),
+            ["1", "2"]
+        );
+        assert!(sorted(call(&mut conn, &["SINTER", "a", "nope"]).await).is_empty());
+
+        // The destination is overwritten whatever its type, and may also be
+        // one of the sources.
+        call(&mut conn, &["SET", "dst", "string"]).await;
+        assert!(matches!(
+            call(&mut conn, &["SUNIONSTORE", "dst", "b", "c"]).await,
+            Frame::Integer(4)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SDIFFSTORE", "a", "a", "dst"]).await,
+            Frame::Integer(2)
+        ));
+        assert_eq!(
+            sorted(call(&mut conn, &["SMEMBERS", "a"]).await),
+            ["1", "2"]
+        );
+        assert!(matches!(
+            call(&mut conn, &["SINTERSTORE", "dst", "a", "b"]).await,
+            Frame::Integer(0)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["EXISTS", "dst"]).await,
+            Frame::Integer(0)
+        ));
+
+        assert!(matches!(
+            call(&mut conn, &["SINTERCARD", "2", "b", "c"]).await,
+            Frame::Integer(2)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SINTERCARD", "2", "b", "c", "LIMIT", "1"]).await,
+            Frame::Integer(1)
+        ));
+        let reply = call(&mut conn, &["SINTERCARD", "3", "b", "c"]).await;
+        assert!(matches!(
+            reply,
+            Frame::Error(msg) if msg == "ERR Number of keys can't be greater than number of args"
+        ));
+        let reply = call(&mut conn, &["SINTERCARD", "1", "b", "LIMIT", "-1"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR LIMIT can't be negative"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn overlapping_set_stores_do_not_deadlock() {
+        let addr = start_server().await;
+        let mut setup = connect(addr).await;
+        call(&mut setup, &["SADD", "x", "1", "2"]).await;
+        call(&mut setup, &["SADD", "y", "2", "3"]).await;
+
+        // Each client locks the same keys, listed in a different order.
+        let orders = [["x", "y", "z"], ["z", "y", "x"], ["y", "z", "x"]];
+        let clients = orders.map(|[dst, a, b]| {
+            tokio::spawn(async move {
+                let mut conn = connect(addr).await;
+                for _ in 0..200 {
+                    call(&mut conn, &["SUNIONSTORE", dst, a, b, dst]).await;
+                }
+            })
         });
+        for client in clients {
+            client.await.unwrap();
+        }
 
-        // clients
-        for i in 0..N {
-            let mut c = TcpStream::connect(addr).await.unwrap();
-            let msg = format!("{:03}", i); // 3 bytes always
-            c.write_all(msg.as_bytes()).await.unwrap();
-            let mut buf = [0u8; 3];
-            let n = c.read(&mut buf).await.unwrap();
-            assert_eq!(n, 3);
-            assert_eq!(&buf, msg.as_bytes());
+        assert_eq!(
+            sorted(call(&mut setup, &["SUNION", "x", "y", "z"]).await),
+            ["1", "2", "3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn zadd_options() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert!(matches!(
+            call(&mut conn, &["ZADD", "z", "1", "a", "2", "b"]).await,
+            Frame::Integer(2)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZADD", "z", "CH", "5", "a", "3", "c"]).await,
+            Frame::Integer(2)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZADD", "z", "NX", "9", "a", "4", "d"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZADD", "z", "XX", "CH", "1", "d", "1", "e"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZADD", "z", "GT", "CH", "4", "a", "7", "b"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZADD", "z", "LT", "CH", "4", "a", "9", "b"]).await,
+            Frame::Integer(1)
+        ));
+        assert_eq!(call(&mut conn, &["ZSCORE", "z", "a"]).await, "4");
+        assert_eq!(call(&mut conn, &["ZSCORE", "z", "b"]).await, "7");
+        assert!(matches!(
+            call(&mut conn, &["ZSCORE", "z", "e"]).await,
+            Frame::Null
+        ));
+
+        assert_eq!(
+            call(&mut conn, &["ZADD", "z", "INCR", "2.5", "a"]).await,
+            "6.5"
+        );
+        assert!(matches!(
+            call(&mut conn, &["ZADD", "z", "GT", "INCR", "-1", "a"]).await,
+            Frame::Null
+        ));
+        assert_eq!(call(&mut conn, &["ZINCRBY", "z", "-0.5", "a"]).await, "6");
+        assert_eq!(
+            call(&mut conn, &["ZINCRBY", "z", "+inf", "new"]).await,
+            "inf"
+        );
+
+        let reply = call(&mut conn, &["ZADD", "z", "NX", "XX", "1", "a"]).await;
+        assert!(matches!(
+            reply,
+            Frame::Error(msg) if msg == "ERR XX and NX options at the same time are not compatible"
+        ));
+        let reply = call(&mut conn, &["ZADD", "z", "NX", "GT", "1", "a"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.starts_with("ERR GT, LT, and/or NX")));
+        let reply = call(&mut conn, &["ZADD", "z", "INCR", "1", "a", "2", "b"]).await;
+        assert!(matches!(
+            reply,
+            Frame::Error(msg) if msg == "ERR INCR option supports a single increment-element pair"
+        ));
+        let reply = call(&mut conn, &["ZADD", "z", "nan", "a"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR value is not a valid float"));
+        let reply = call(&mut conn, &["ZADD", "z", "1", "a", "2"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR syntax error"));
+        let reply = call(&mut conn, &["ZINCRBY", "z", "-inf", "new"]).await;
+        assert!(matches!(
+            reply,
+            Frame::Error(msg) if msg == "ERR resulting score is not a number (NaN)"
+        ));
+    }
+
+    #[tokio::test]
+    async fn zset_ranks_and_ranges() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        call(
+            &mut conn,
+            &[
+                "ZADD", "z", "1", "one", "2", "two", "3", "three", "4", "four", "5", "five",
+            ],
+        )
+        .await;
+
+        assert!(matches!(
+            call(&mut conn, &["ZCARD", "z"]).await,
+            Frame::Integer(5)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZRANK", "z", "three"]).await,
+            Frame::Integer(2)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZREVRANK", "z", "one"]).await,
+            Frame::Integer(4)
+        ));
+        let reply = call(&mut conn, &["ZRANK", "z", "two", "WITHSCORE"]).await;
+        assert!(matches!(
+            &reply,
+            Frame::Array(parts) if matches!(&parts[..], [Frame::Integer(1), score] if *score == "2")
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZRANK", "z", "nope"]).await,
+            Frame::Null
+        ));
+
+        let range = |args: &'static [&'static str]| {
+            let mut full = vec!["ZRANGE", "z"];
+            full.extend_from_slice(args);
+            full
+        };
+        assert_eq!(
+            strings(call(&mut conn, &range(&["1", "-2"])).await),
+            ["two", "three", "four"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &range(&["0", "1", "REV", "WITHSCORES"])).await),
+            ["five", "5", "four", "4"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &range(&["(1", "3", "BYSCORE"])).await),
+            ["two", "three"]
+        );
+        assert_eq!(
+            strings(
+                call(
+                    &mut conn,
+                    &range(&["+inf", "2", "BYSCORE", "REV", "LIMIT", "1", "2"])
+                )
+                .await
+            ),
+            ["four", "three"]
+        );
+        assert_eq!(
+            strings(
+                call(
+                    &mut conn,
+                    &range(&["-inf", "+inf", "BYSCORE", "LIMIT", "3", "-1"])
+                )
+                .await
+            ),
+            ["four", "five"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["ZRANGEBYSCORE", "z", "2", "(4", "WITHSCORES"]).await),
+            ["two", "2", "three", "3"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["ZREVRANGEBYSCORE", "z", "3", "-inf"]).await),
+            ["three", "two", "one"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["ZREVRANGE", "z", "0", "0"]).await),
+            ["five"]
+        );
+        assert!(matches!(
+            call(&mut conn, &["ZCOUNT", "z", "(1", "4"]).await,
+            Frame::Integer(3)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZCOUNT", "z", "4", "2"]).await,
+            Frame::Integer(0)
+        ));
+
+        call(
+            &mut conn,
+            &["ZADD", "lex", "0", "a", "0", "b", "0", "c", "0", "d"],
+        )
+        .await;
+        assert_eq!(
+            strings(call(&mut conn, &["ZRANGE", "lex", "[b", "(d", "BYLEX"]).await),
+            ["b", "c"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["ZRANGE", "lex", "+", "(b", "BYLEX", "REV"]).await),
+            ["d", "c"]
+        );
+        assert_eq!(
+            strings(
+                call(
+                    &mut conn,
+                    &["ZRANGE", "lex", "-", "+", "BYLEX", "LIMIT", "1", "2"]
+                )
+                .await
+            ),
+            ["b", "c"]
+        );
+
+        let reply = call(&mut conn, &range(&["0", "1", "LIMIT", "0", "1"])).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.starts_with("ERR syntax error, LIMIT")));
+        let reply = call(&mut conn, &["ZRANGE", "lex", "b", "d", "BYLEX"]).await;
+        assert!(matches!(
+            reply,
+            Frame::Error(msg) if msg == "ERR min or max not valid string range item"
+        ));
+        let reply = call(&mut conn, &["ZCOUNT", "z", "x", "1"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR min or max is not a float"));
+
+        assert!(matches!(
+            call(&mut conn, &["ZREM", "z", "one", "two", "nope"]).await,
+            Frame::Integer(2)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZRANK", "z", "five"]).await,
+            Frame::Integer(2)
+        ));
+    }
+
+    /// Read the next frame pushed to a subscribed connection.
+    async fn next_message(conn: &mut Connection) -> Vec<String> {
+        strings(conn.read_frame().await.unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn subscribe_and_publish() {
+        let addr = start_server().await;
+        let mut subscriber = connect(addr).await;
+        let mut publisher = connect(addr).await;
+
+        assert_eq!(
+            strings(call(&mut subscriber, &["SUBSCRIBE", "news", "sport"]).await),
+            ["subscribe", "news", "1"]
+        );
+        assert_eq!(
+            next_message(&mut subscriber).await,
+            ["subscribe", "sport", "2"]
+        );
+
+        assert!(matches!(
+            call(&mut publisher, &["PUBLISH", "news", "hello"]).await,
+            Frame::Integer(1)
+        ));
+        assert!(matches!(
+            call(&mut publisher, &["PUBLISH", "weather", "rain"]).await,
+            Frame::Integer(0)
+        ));
+        assert_eq!(
+            next_message(&mut subscriber).await,
+            ["message", "news", "hello"]
+        );
+
+        // Only subscription commands work in subscriber mode.
+        let reply = call(&mut subscriber, &["GET", "foo"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.starts_with("ERR Can't execute 'get'")));
+        assert_eq!(
+            strings(call(&mut subscriber, &["PING"]).await),
+            ["pong", ""]
+        );
+
+        assert_eq!(
+            strings(call(&mut subscriber, &["UNSUBSCRIBE", "news"]).await),
+            ["unsubscribe", "news", "1"]
+        );
+        assert!(matches!(
+            call(&mut publisher, &["PUBLISH", "news", "again"]).await,
+            Frame::Integer(0)
+        ));
+        assert_eq!(
+            strings(call(&mut subscriber, &["UNSUBSCRIBE"]).await),
+            ["unsubscribe", "sport", "0"]
+        );
+
+        // Back in normal mode.
+        assert_eq!(call(&mut subscriber, &["PING"]).await, "PONG");
+        assert_eq!(
+            strings(call(&mut subscriber, &["UNSUBSCRIBE"]).await),
+            ["unsubscribe", "(nil)", "0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn pattern_subscriptions() {
+        let addr = start_server().await;
+        let mut subscriber = connect(addr).await;
+        let mut publisher = connect(addr).await;
+
+        assert_eq!(
+            strings(call(&mut subscriber, &["PSUBSCRIBE", "news.*"]).await),
+            ["psubscribe", "news.*", "1"]
+        );
+        assert_eq!(
+            strings(call(&mut subscriber, &["SUBSCRIBE", "news.tech"]).await),
+            ["subscribe", "news.tech", "2"]
+        );
+
+        // Both subscriptions receive the message, the channel first, and
+        // messages keep their publish order across subscriptions.
+        assert!(matches!(
+            call(&mut publisher, &["PUBLISH", "news.tech", "rust"]).await,
+            Frame::Integer(2)
+        ));
+        call(&mut publisher, &["PUBLISH", "news.world", "later"]).await;
+        assert_eq!(
+            next_message(&mut subscriber).await,
+            ["message", "news.tech", "rust"]
+        );
+        assert_eq!(
+            next_message(&mut subscriber).await,
+            ["pmessage", "news.*", "news.tech", "rust"]
+        );
+        assert_eq!(
+            next_message(&mut subscriber).await,
+            ["pmessage", "news.*", "news.world", "later"]
+        );
+
+        assert!(matches!(
+            call(&mut publisher, &["PUBLISH", "sports", "nope"]).await,
+            Frame::Integer(0)
+        ));
+        assert_eq!(
+            strings(call(&mut subscriber, &["PUNSUBSCRIBE"]).await),
+            ["punsubscribe", "news.*", "1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn pubsub_introspection() {
+        let addr = start_server().await;
+        let mut a = connect(addr).await;
+        let mut b = connect(addr).await;
+        let mut conn = connect(addr).await;
+
+        call(&mut a, &["SUBSCRIBE", "one"]).await;
+        call(&mut b, &["SUBSCRIBE", "one"]).await;
+        call(&mut b, &["SUBSCRIBE", "two"]).await;
+        call(&mut b, &["PSUBSCRIBE", "t*"]).await;
+
+        assert_eq!(
+            sorted(call(&mut conn, &["PUBSUB", "CHANNELS"]).await),
+            ["one", "two"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["PUBSUB", "CHANNELS", "t*"]).await),
+            ["two"]
+        );
+        assert_eq!(
+            strings(call(&mut conn, &["PUBSUB", "NUMSUB", "one", "two", "three"]).await),
+            ["one", "2", "two", "1", "three", "0"]
+        );
+        assert!(matches!(
+            call(&mut conn, &["PUBSUB", "NUMPAT"]).await,
+            Frame::Integer(1)
+        ));
+
+        // Disconnecting drops every subscription of the client.
+        drop(b);
+        settle().await;
+        assert_eq!(
+            strings(call(&mut conn, &["PUBSUB", "CHANNELS"]).await),
+            ["one"]
+        );
+        assert!(matches!(
+            call(&mut conn, &["PUBSUB", "NUMPAT"]).await,
+            Frame::Integer(0)
+        ));
+        let reply = call(&mut conn, &["PUBSUB", "FOO"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg.starts_with("ERR unknown subcommand 'FOO'"))
+        );
+
+        assert_eq!(call(&mut a, &["QUIT"]).await, "OK");
+        assert!(a.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn transactions_queue_and_execute() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        let reply = call(&mut conn, &["EXEC"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR EXEC without MULTI"));
+
+        assert_eq!(call(&mut conn, &["MULTI"]).await, "OK");
+        let reply = call(&mut conn, &["MULTI"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR MULTI calls can not be nested"));
+        assert_eq!(call(&mut conn, &["SET", "a", "1"]).await, "QUEUED");
+        assert_eq!(call(&mut conn, &["INCR", "a"]).await, "QUEUED");
+        assert_eq!(call(&mut conn, &["LPUSH", "a", "x"]).await, "QUEUED");
+        assert_eq!(call(&mut conn, &["GET", "a"]).await, "QUEUED");
+
+        // A runtime error only fails its own command.
+        let Frame::Array(replies) = call(&mut conn, &["EXEC"]).await else {
+            panic!("expected an array reply");
+        };
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0], "OK");
+        assert!(matches!(replies[1], Frame::Integer(2)));
+        assert!(matches!(&replies[2], Frame::Error(msg) if msg.starts_with("WRONGTYPE")));
+        assert_eq!(replies[3], "2");
+
+        assert_eq!(call(&mut conn, &["MULTI"]).await, "OK");
+        assert_eq!(call(&mut conn, &["INCR", "a"]).await, "QUEUED");
+        assert_eq!(call(&mut conn, &["DISCARD"]).await, "OK");
+        assert_eq!(call(&mut conn, &["GET", "a"]).await, "2");
+        let reply = call(&mut conn, &["DISCARD"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR DISCARD without MULTI"));
+
+        // Replies that are arrays themselves nest inside the EXEC reply.
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["RPUSH", "l", "x", "y"]).await;
+        call(&mut conn, &["LRANGE", "l", "0", "-1"]).await;
+        let Frame::Array(replies) = call(&mut conn, &["EXEC"]).await else {
+            panic!("expected an array reply");
+        };
+        assert!(matches!(replies[0], Frame::Integer(2)));
+        assert_eq!(strings(replies[1].clone()), ["x", "y"]);
+    }
+
+    #[tokio::test]
+    async fn queueing_errors_abort_the_transaction() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        assert_eq!(call(&mut conn, &["MULTI"]).await, "OK");
+        assert_eq!(call(&mut conn, &["SET", "a", "1"]).await, "QUEUED");
+        let reply = call(&mut conn, &["SET", "a"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.starts_with("ERR wrong number")));
+        let reply = call(&mut conn, &["NOPE"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg == "ERR unknown command 'nope'"));
+
+        let reply = call(&mut conn, &["EXEC"]).await;
+        assert!(matches!(
+            reply,
+            Frame::Error(msg) if msg == "EXECABORT Transaction discarded because of previous errors."
+        ));
+        assert!(matches!(call(&mut conn, &["GET", "a"]).await, Frame::Null));
+
+        // The connection is back to normal.
+        assert_eq!(call(&mut conn, &["SET", "a", "1"]).await, "OK");
+    }
+
+    #[tokio::test]
+    async fn watched_keys_abort_exec() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+        let mut other = connect(addr).await;
+
+        call(&mut conn, &["SET", "k", "1"]).await;
+        assert_eq!(call(&mut conn, &["WATCH", "k", "missing"]).await, "OK");
+
+        // Reads by other clients do not count as modifications, and neither
+        // do writes failing on the type of the key.
+        assert_eq!(call(&mut other, &["GET", "k"]).await, "1");
+        assert!(matches!(
+            call(&mut other, &["LPUSH", "k", "x"]).await,
+            Frame::Error(msg) if msg.starts_with("WRONGTYPE")
+        ));
+        assert_eq!(call(&mut conn, &["MULTI"]).await, "OK");
+        let reply = call(&mut conn, &["WATCH", "k"]).await;
+        assert!(
+            matches!(reply, Frame::Error(msg) if msg == "ERR WATCH inside MULTI is not allowed")
+        );
+        assert_eq!(call(&mut conn, &["SET", "k", "2"]).await, "QUEUED");
+        assert_eq!(strings(call(&mut conn, &["EXEC"]).await), ["OK"]);
+
+        // EXEC unwatched the keys, so watch again.
+        call(&mut conn, &["WATCH", "k"]).await;
+        call(&mut other, &["SET", "k", "other"]).await;
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["SET", "k", "3"]).await;
+        assert!(matches!(call(&mut conn, &["EXEC"]).await, Frame::Null));
+        assert_eq!(call(&mut conn, &["GET", "k"]).await, "other");
+
+        // A key created by someone else counts too, and UNWATCH forgets it.
+        call(&mut conn, &["WATCH", "missing"]).await;
+        call(&mut other, &["RPUSH", "missing", "x"]).await;
+        assert_eq!(call(&mut conn, &["UNWATCH"]).await, "OK");
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["LLEN", "missing"]).await;
+        assert_eq!(strings(call(&mut conn, &["EXEC"]).await), ["1"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn transactions_are_atomic() {
+        let addr = start_server().await;
+        let mut writer = connect(addr).await;
+        let mut reader = connect(addr).await;
+
+        let writes = tokio::spawn(async move {
+            for _ in 0..200 {
+                call(&mut writer, &["MULTI"]).await;
+                call(&mut writer, &["INCR", "a"]).await;
+                // Widen the window in which a reader could get in between.
+                for _ in 0..20 {
+                    call(&mut writer, &["RPUSH", "filler", "x"]).await;
+                }
+                call(&mut writer, &["INCR", "b"]).await;
+                call(&mut writer, &["EXEC"]).await;
+            }
+        });
+
+        // `a` and `b` live in different shards in general, yet no reader
+        // ever sees one incremented without the other.
+        while !writes.is_finished() {
+            let reply = strings(call(&mut reader, &["MGET", "a", "b"]).await);
+            assert_eq!(reply[0], reply[1]);
+        }
+        writes.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+
+        for _ in 0..500 {
+            feed(&mut conn, &["INCR", "n"]).await;
+        }
+        feed(&mut conn, &["NOPE"]).await;
+        feed(&mut conn, &["GET", "n"]).await;
+        conn.flush().await.unwrap();
+
+        for i in 1..=500 {
+            let reply = conn.read_frame().await.unwrap().unwrap();
+            assert!(matches!(reply, Frame::Integer(n) if n == i));
+        }
+        assert!(matches!(
+            conn.read_frame().await.unwrap().unwrap(),
+            Frame::Error(_)
+        ));
+        assert_eq!(conn.read_frame().await.unwrap().unwrap(), "500");
+    }
+
+    #[tokio::test]
+    async fn blocking_flushes_earlier_replies() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+        let mut other = connect(addr).await;
+
+        feed(&mut conn, &["SET", "a", "1"]).await;
+        feed(&mut conn, &["BLPOP", "list", "0"]).await;
+        conn.flush().await.unwrap();
+
+        // The SET reply arrives while BLPOP is still blocked.
+        assert_eq!(conn.read_frame().await.unwrap().unwrap(), "OK");
+        call(&mut other, &["RPUSH", "list", "x"]).await;
+        assert_eq!(
+            strings(conn.read_frame().await.unwrap().unwrap()),
+            ["list", "x"]
+        );
+    }
+
+    #[tokio::test]
+    async fn inline_commands() {
+        use tokio::io::AsyncWriteExt;
+
+        let addr = start_server().await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"SET greeting \"hello world\"\r\n\r\nGET greeting\nPING\r\n")
+            .await
+            .unwrap();
+
+        let mut conn = Connection::new(socket);
+        assert_eq!(conn.read_frame().await.unwrap().unwrap(), "OK");
+        assert_eq!(conn.read_frame().await.unwrap().unwrap(), "hello world");
+        assert_eq!(conn.read_frame().await.unwrap().unwrap(), "PONG");
+    }
+
+    #[tokio::test]
+    async fn protocol_errors_are_reported_before_closing() {
+        use tokio::io::AsyncWriteExt;
+
+        let addr = start_server().await;
+        for input in [&b"$x\r\n"[..], b"*1\r\n$3\r\nfooXY", b"ECHO \"oops\r\n"] {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            socket.write_all(input).await.unwrap();
+
+            let mut conn = Connection::new(socket);
+            let reply = conn.read_frame().await.unwrap().unwrap();
+            assert!(matches!(reply, Frame::Error(msg) if msg.starts_with("ERR protocol error")));
+            assert!(conn.read_frame().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_close_the_connection() {
+        use tokio::io::AsyncWriteExt;
+
+        let db = Db::new();
+        db.set_limits(Limits {
+            max_bulk_len: 16,
+            max_buffer_len: 64,
+            ..Limits::default()
+        });
+        let addr = start_server_with(db).await;
+
+        // Within the limits, requests are served as usual.
+        let mut conn = connect(addr).await;
+        let reply = call(&mut conn, &["ECHO", "sixteen chars..."]).await;
+        assert_eq!(reply, "sixteen chars...");
+
+        // A request that never completes stops being buffered at some point.
+        let unfinished = format!("*100\r\n{}", "$1\r\nx\r\n".repeat(20));
+        for (input, reason) in [
+            (&b"*2\r\n$4\r\nECHO\r\n$17\r\n"[..], "invalid bulk length"),
+            (
+                &b"*2\r\n$4\r\nECHO\r\n*9999999999\r\n"[..],
+                "invalid multibulk length",
+            ),
+            (unfinished.as_bytes(), "query buffer limit exceeded"),
+        ] {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            socket.write_all(input).await.unwrap();
+
+            let mut conn = Connection::new(socket);
+            let reply = conn.read_frame().await.unwrap().unwrap();
+            assert!(
+                matches!(reply, Frame::Error(msg) if msg == format!("ERR protocol error; {}", reason))
+            );
+            assert!(conn.read_frame().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_switches_protocol() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+        call(&mut conn, &["HSET", "h", "f", "v"]).await;
+        call(&mut conn, &["ZADD", "z", "1.5", "m"]).await;
+        call(&mut conn, &["SADD", "s", "x"]).await;
+
+        // RESP2 clients get the closest equivalent to RESP3 types.
+        assert_eq!(
+            strings(call(&mut conn, &["HGETALL", "h"]).await),
+            ["f", "v"]
+        );
+        assert_eq!(call(&mut conn, &["ZSCORE", "z", "m"]).await, "1.5");
+
+        let Frame::Map(info) = call(&mut conn, &["HELLO", "3"]).await else {
+            panic!("expected a map reply");
+        };
+        let proto = info.iter().find(|(key, _)| *key == "proto").unwrap();
+        assert!(matches!(proto.1, Frame::Integer(3)));
+
+        assert!(matches!(
+            call(&mut conn, &["HGETALL", "h"]).await,
+            Frame::Map(pairs) if pairs.len() == 1 && pairs[0].0 == "f" && pairs[0].1 == "v"
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ZSCORE", "z", "m"]).await,
+            Frame::Double(1.5)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SMEMBERS", "s"]).await,
+            Frame::Set(members) if members.len() == 1 && members[0] == "x"
+        ));
+        assert!(matches!(
+            call(&mut conn, &["GET", "missing"]).await,
+            Frame::Null
+        ));
+
+        let reply = call(&mut conn, &["HELLO", "4"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.starts_with("NOPROTO")));
+        let reply = call(&mut conn, &["HELLO", "3", "AUTH", "someone", "secret"]).await;
+        assert!(matches!(reply, Frame::Error(msg) if msg.starts_with("WRONGPASS")));
+
+        // Back to RESP2, where the server info is a flat array.
+        let info = strings(call(&mut conn, &["HELLO", "2", "SETNAME", "tests"]).await);
+        assert_eq!(&info[..2], ["server", "redis"]);
+        assert_eq!(strings(call(&mut conn, &["SMEMBERS", "s"]).await), ["x"]);
+    }
+
+    #[tokio::test]
+    async fn snapshots_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("redis-lib-{}.rdb", std::process::id()));
+        let db = Db::new();
+        db.set_snapshot_path(&path);
+        let addr = start_server_with(db).await;
+        let mut conn = connect(addr).await;
+
+        call(&mut conn, &["SET", "a", "1"]).await;
+        call(&mut conn, &["RPUSH", "list", "x", "y"]).await;
+        let Frame::Integer(started) = call(&mut conn, &["LASTSAVE"]).await else {
+            panic!("expected an integer reply");
+        };
+        assert_eq!(call(&mut conn, &["SAVE"]).await, "OK");
+
+        // A background save replaces the file once done, and is noticed by
+        // LASTSAVE.
+        call(&mut conn, &["SET", "b", "2"]).await;
+        assert_eq!(
+            call(&mut conn, &["BGSAVE"]).await,
+            "Background saving started"
+        );
+        loop {
+            let restarted = Db::new();
+            restarted.set_snapshot_path(&path);
+            if restarted.load_snapshot().unwrap() == 3 {
+                assert!(matches!(
+                    call(&mut conn, &["LASTSAVE"]).await,
+                    Frame::Integer(last) if last >= started
+                ));
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let restarted = start_server_with({
+            let db = Db::new();
+            db.set_snapshot_path(&path);
+            db.load_snapshot().unwrap();
+            db
+        })
+        .await;
+        let mut conn = connect(restarted).await;
+        assert_eq!(call(&mut conn, &["GET", "b"]).await, "2");
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "list", "0", "-1"]).await),
+            ["x", "y"]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn aof_replays_writes_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("redis-lib-{}.aof", std::process::id()));
+        let db = Db::new();
+        db.enable_aof(&path, AppendFsync::Always).unwrap();
+        let addr = start_server_with(db).await;
+        let mut conn = connect(addr).await;
+
+        call(&mut conn, &["SET", "a", "1", "EX", "100"]).await;
+        call(&mut conn, &["GET", "a"]).await;
+        call(&mut conn, &["SADD", "set", "x", "y", "z"]).await;
+        let Frame::Bulk(popped) = call(&mut conn, &["SPOP", "set"]).await else {
+            panic!("expected a bulk reply");
+        };
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["INCR", "n"]).await;
+        call(&mut conn, &["INCR", "n"]).await;
+        call(&mut conn, &["EXEC"]).await;
+        call(&mut conn, &["RPUSH", "list", "p", "q"]).await;
+        call(&mut conn, &["BLPOP", "list", "0"]).await;
+        call(&mut conn, &["LPUSH", "a", "x"]).await; // fails, and is not logged
+        let Frame::Integer(ttl) = call(&mut conn, &["PTTL", "a"]).await else {
+            panic!("expected an integer reply");
+        };
+
+        // A crash in the middle of writing a command leaves it incomplete.
+        let complete = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        io::Write::write_all(&mut file, b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1").unwrap();
+
+        let restarted = Db::new();
+        assert_eq!(replay_aof(&restarted, &path).unwrap(), 8);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete);
+        let mut conn = connect(start_server_with(restarted).await).await;
+
+        assert_eq!(call(&mut conn, &["GET", "a"]).await, "1");
+        assert!(matches!(
+            call(&mut conn, &["PTTL", "a"]).await,
+            Frame::Integer(left) if left <= ttl && left > ttl - 10_000
+        ));
+        assert!(matches!(
+            call(&mut conn, &["SCARD", "set"]).await,
+            Frame::Integer(2)
+        ));
+        let popped = String::from_utf8(popped.to_vec()).unwrap();
+        assert!(matches!(
+            call(&mut conn, &["SISMEMBER", "set", &popped]).await,
+            Frame::Integer(0)
+        ));
+        assert_eq!(call(&mut conn, &["GET", "n"]).await, "2");
+        assert_eq!(
+            strings(call(&mut conn, &["LRANGE", "list", "0", "-1"]).await),
+            ["q"]
+        );
+        assert!(matches!(
+            call(&mut conn, &["EXISTS", "b"]).await,
+            Frame::Integer(0)
+        ));
+
+        // Garbage in the middle of the file is not mistaken for a crash.
+        std::fs::write(
+            &path,
+            b"*1\r\n$4\r\nPING\r\n*1\r\n$5\r\nBOGUS\r\n*1\r\n$4\r\nPING\r\n",
+        )
+        .unwrap();
+        assert!(replay_aof(&Db::new(), &path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn served_pops_are_logged_after_the_push() {
+        let path = std::env::temp_dir().join(format!("redis-served-{}.aof", std::process::id()));
+        let db = Db::new();
+        db.enable_aof(&path, AppendFsync::Always).unwrap();
+        let addr = start_server_with(db).await;
+        let mut mover = connect(addr).await;
+        let mut popper = connect(addr).await;
+        let mut pusher = connect(addr).await;
+
+        // The moved element wakes the client blocked on the destination in
+        // turn, all within the push.
+        send(&mut mover, &["BLMOVE", "src", "dst", "RIGHT", "LEFT", "0"]).await;
+        send(&mut popper, &["BLPOP", "dst", "0"]).await;
+        settle().await;
+        call(&mut pusher, &["RPUSH", "src", "a"]).await;
+        assert_eq!(mover.read_frame().await.unwrap().unwrap(), "a");
+        assert_eq!(
+            strings(popper.read_frame().await.unwrap().unwrap()),
+            ["dst", "a"]
+        );
+
+        // A destination of the wrong type fails the move, which leaves the
+        // element in the source.
+        send(&mut mover, &["BLMOVE", "q", "s", "LEFT", "LEFT", "0"]).await;
+        settle().await;
+        call(&mut pusher, &["SET", "s", "v"]).await;
+        call(&mut pusher, &["RPUSH", "q", "x"]).await;
+        assert!(matches!(
+            mover.read_frame().await.unwrap().unwrap(),
+            Frame::Error(msg) if msg.starts_with("WRONGTYPE")
+        ));
+        assert_eq!(
+            strings(call(&mut pusher, &["LRANGE", "q", "0", "-1"]).await),
+            ["x"]
+        );
+
+        let mut expected = String::new();
+        for args in [
+            &["RPUSH", "src", "a"][..],
+            &["LMOVE", "src", "dst", "RIGHT", "LEFT"],
+            &["LPOP", "dst"],
+            &["SET", "s", "v"],
+            &["RPUSH", "q", "x"],
+        ] {
+            expected.push_str(&format!("*{}\r\n", args.len()));
+            for arg in args {
+                expected.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+            }
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn aof_write_errors_refuse_writes() {
+        // Every write to `/dev/full` fails with ENOSPC.
+        let db = Db::new();
+        db.enable_aof("/dev/full", AppendFsync::Always).unwrap();
+        let addr = start_server_with(db).await;
+        let mut conn = connect(addr).await;
+
+        // The write that failed is told so, and later ones are refused.
+        for args in [&["SET", "a", "1"][..], &["SET", "b", "2"]] {
+            assert!(matches!(
+                call(&mut conn, args).await,
+                Frame::Error(e) if e.starts_with("MISCONF Errors writing to the AOF file")
+            ));
+        }
+        assert_eq!(call(&mut conn, &["GET", "a"]).await, "1");
+        assert!(matches!(call(&mut conn, &["GET", "b"]).await, Frame::Null));
+    }
+
+    #[tokio::test]
+    async fn bgrewriteaof_compacts_the_log() {
+        let path =
+            std::env::temp_dir().join(format!("redis-lib-rewrite-{}.aof", std::process::id()));
+        let db = Db::new();
+        db.enable_aof(&path, AppendFsync::No).unwrap();
+        let addr = start_server_with(db).await;
+        let mut conn = connect(addr).await;
+
+        for _ in 0..100 {
+            call(&mut conn, &["INCR", "counter"]).await;
+        }
+        call(&mut conn, &["HSET", "hash", "f", "v"]).await;
+        call(&mut conn, &["ZADD", "zset", "1.5", "m"]).await;
+        call(&mut conn, &["PEXPIRE", "hash", "100000"]).await;
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        assert_eq!(
+            call(&mut conn, &["BGREWRITEAOF"]).await,
+            "Background append only file rewriting started"
+        );
+        // Writes made while the rewrite runs make it into the new file, once.
+        call(&mut conn, &["SET", "late", "1"]).await;
+        for _ in 0..10 {
+            call(&mut conn, &["INCR", "counter"]).await;
+        }
+        while std::fs::metadata(&path).unwrap().len() >= before {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
         }
+        call(&mut conn, &["SET", "later", "2"]).await;
+
+        let restarted = Db::new();
+        replay_aof(&restarted, &path).unwrap();
+        let mut conn = connect(start_server_with(restarted).await).await;
+        assert_eq!(call(&mut conn, &["GET", "counter"]).await, "110");
+        assert_eq!(call(&mut conn, &["HGET", "hash", "f"]).await, "v");
+        assert!(matches!(
+            call(&mut conn, &["PTTL", "hash"]).await,
+            Frame::Integer(left) if left > 0
+        ));
+        assert_eq!(call(&mut conn, &["ZSCORE", "zset", "m"]).await, "1.5");
+        assert_eq!(call(&mut conn, &["GET", "late"]).await, "1");
+        assert_eq!(call(&mut conn, &["GET", "later"]).await, "2");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn config_get_set_and_rewrite() {
+        let dir = std::env::temp_dir();
+        let file = dir.join(format!("redis-lib-{}.conf", std::process::id()));
+        let aof = format!("redis-lib-config-{}.aof", std::process::id());
+        std::fs::write(
+            &file,
+            format!(
+                "dir {}\nappendfilename {}\nmaxclients 100\n",
+                dir.display(),
+                aof
+            ),
+        )
+        .unwrap();
+        let config = Config::from_args([file.display().to_string()]).unwrap();
+        let addr = start_server_with(Db::with_config(config)).await;
+        let mut conn = connect(addr).await;
+
+        assert_eq!(
+            strings(call(&mut conn, &["CONFIG", "GET", "maxclients", "append*"]).await),
+            [
+                "maxclients",
+                "100",
+                "appendonly",
+                "no",
+                "appendfilename",
+                &aof,
+                "appendfsync",
+                "everysec"
+            ]
+        );
+        assert!(matches!(
+            call(&mut conn, &["CONFIG", "SET", "port", "1"]).await,
+            Frame::Error(e) if e.ends_with("can't set immutable config")
+        ));
+
+        // Switching the append-only file on writes out the keyspace.
+        call(&mut conn, &["SET", "a", "1"]).await;
+        let reply = call(
+            &mut conn,
+            &["CONFIG", "SET", "maxclients", "200", "appendonly", "yes"],
+        )
+        .await;
+        assert_eq!(reply, "OK");
+        let path = dir.join(&aof);
+        for _ in 0..500 {
+            if std::fs::read(&path)
+                .unwrap()
+                .starts_with(b"*3\r\n$3\r\nSET")
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let restarted = Db::new();
+        assert_eq!(replay_aof(&restarted, &path).unwrap(), 1);
+
+        assert_eq!(call(&mut conn, &["CONFIG", "REWRITE"]).await, "OK");
+        let text = std::fs::read_to_string(&file).unwrap();
+        assert!(text.contains("maxclients 200\n"), "{}", text);
+        assert!(text.contains("appendonly yes\n"), "{}", text);
+
+        assert_eq!(
+            call(&mut conn, &["CONFIG", "SET", "appendonly", "no"]).await,
+            "OK"
+        );
+        std::fs::remove_file(&file).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_connections_and_saves() {
+        let path =
+            std::env::temp_dir().join(format!("redis-lib-shutdown-{}.rdb", std::process::id()));
+        let db = Db::new();
+        db.set_snapshot_path(&path);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(listen(listener, db));
+
+        let mut conn = connect(addr).await;
+        let mut blocked = connect(addr).await;
+        call(&mut conn, &["SET", "a", "1"]).await;
+        send(&mut blocked, &["BLPOP", "list", "0"]).await;
+
+        // Requests pipelined before SHUTDOWN still get their replies.
+        feed(&mut conn, &["SET", "b", "2"]).await;
+        send(&mut conn, &["SHUTDOWN", "SAVE"]).await;
+        assert_eq!(conn.read_frame().await.unwrap().unwrap(), "OK");
+        assert!(conn.read_frame().await.unwrap().is_none());
+        assert!(blocked.read_frame().await.unwrap().is_none());
+
+        server.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+        let restarted = Db::new();
+        restarted.set_snapshot_path(&path);
+        assert_eq!(restarted.load_snapshot().unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_replies_to_buffered_requests() {
+        let addr = start_server().await;
+        let mut conn = connect(addr).await;
+        let value = "x".repeat(1024 * 1024);
+        call(&mut conn, &["SET", "big", &value]).await;
+
+        // The replies are too big for the socket, so the server is still
+        // working through the pipeline when the shutdown comes.
+        const REQUESTS: usize = 50;
+        for _ in 0..REQUESTS {
+            feed(&mut conn, &["GET", "big"]).await;
+        }
+        conn.flush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        send(&mut connect(addr).await, &["SHUTDOWN", "NOSAVE"]).await;
+
+        for _ in 0..REQUESTS {
+            assert_eq!(conn.read_frame().await.unwrap().unwrap(), &value[..]);
+        }
+        assert!(conn.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn maxclients_limits_connections() {
+        let mut config = Config::default();
+        config.maxclients = 1;
+        let addr = start_server_with(Db::with_config(config)).await;
+
+        let mut first = connect(addr).await;
+        assert_eq!(call(&mut first, &["PING"]).await, "PONG");
+        let mut second = connect(addr).await;
+        assert!(matches!(
+            second.read_frame().await.unwrap().unwrap(),
+            Frame::Error(e) if e == "ERR max number of clients reached"
+        ));
+
+        // Raising the limit lets another client in.
+        assert_eq!(
+            call(&mut first, &["CONFIG", "SET", "maxclients", "2"]).await,
+            "OK"
+        );
+        let mut third = connect(addr).await;
+        assert_eq!(call(&mut third, &["PING"]).await, "PONG");
+    }
+
+    #[tokio::test]
+    async fn auth_and_acl_users() {
+        let mut config = Config::default();
+        config.requirepass = "secret".to_string();
+        let addr = start_server_with(Db::with_config(config)).await;
+        let mut conn = connect(addr).await;
+
+        // Only a few commands work before authenticating.
+        assert!(matches!(
+            call(&mut conn, &["GET", "a"]).await,
+            Frame::Error(e) if e == "NOAUTH Authentication required."
+        ));
+        assert!(matches!(
+            call(&mut conn, &["HELLO", "3"]).await,
+            Frame::Error(e) if e.starts_with("NOAUTH HELLO must be called")
+        ));
+        assert!(matches!(
+            call(&mut conn, &["AUTH", "wrong"]).await,
+            Frame::Error(e) if e.starts_with("WRONGPASS")
+        ));
+        assert_eq!(call(&mut conn, &["AUTH", "secret"]).await, "OK");
+        assert_eq!(call(&mut conn, &["ACL", "WHOAMI"]).await, "default");
+
+        let reply = call(
+            &mut conn,
+            &[
+                "ACL",
+                "SETUSER",
+                "alice",
+                "on",
+                ">pw",
+                "~cache:*",
+                "&news",
+                "+@read",
+                "+set",
+                "+publish",
+                "+@transaction",
+            ],
+        )
+        .await;
+        assert_eq!(reply, "OK");
+        assert!(matches!(
+            call(&mut conn, &["ACL", "SETUSER", "bob", "+nosuchcommand"]).await,
+            Frame::Error(e) if e.starts_with("ERR Error in ACL SETUSER modifier")
+        ));
+        assert_eq!(
+            strings(call(&mut conn, &["ACL", "USERS"]).await),
+            ["alice", "default"]
+        );
+
+        let mut alice = connect(addr).await;
+        let reply = call(&mut alice, &["HELLO", "2", "AUTH", "alice", "pw"]).await;
+        assert!(matches!(reply, Frame::Array(_)));
+        assert_eq!(call(&mut alice, &["SET", "cache:1", "x"]).await, "OK");
+        assert_eq!(call(&mut alice, &["GET", "cache:1"]).await, "x");
+        assert!(matches!(
+            call(&mut alice, &["GET", "secret"]).await,
+            Frame::Error(e) if e == "NOPERM No permissions to access a key"
+        ));
+        assert!(matches!(
+            call(&mut alice, &["DEL", "cache:1"]).await,
+            Frame::Error(e) if e.starts_with("NOPERM User alice has no permissions to run the 'del'")
+        ));
+        assert!(matches!(
+            call(&mut alice, &["PUBLISH", "sports", "goal"]).await,
+            Frame::Error(e) if e == "NOPERM No permissions to access a channel"
+        ));
+
+        // Denied commands abort a transaction like malformed ones.
+        assert_eq!(call(&mut alice, &["MULTI"]).await, "OK");
+        assert!(matches!(
+            call(&mut alice, &["DEL", "cache:1"]).await,
+            Frame::Error(_)
+        ));
+        assert!(matches!(
+            call(&mut alice, &["EXEC"]).await,
+            Frame::Error(e) if e.starts_with("EXECABORT")
+        ));
+
+        let list = strings(call(&mut conn, &["ACL", "LIST"]).await);
+        assert!(list[0].starts_with("user alice on #"));
+        assert!(list[0].contains(" ~cache:* &news -@all "));
+        assert!(list[0].contains(" +get ") && list[0].contains(" +set"));
+        assert_eq!(
+            list[1],
+            "user default on #2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b ~* &* +@all"
+        );
+        assert!(matches!(
+            call(&mut conn, &["ACL", "DELUSER", "default"]).await,
+            Frame::Error(_)
+        ));
+        assert!(matches!(
+            call(&mut conn, &["ACL", "DELUSER", "alice", "nobody"]).await,
+            Frame::Integer(1)
+        ));
+
+        // Deleted users no longer let their clients in.
+        assert!(matches!(
+            call(&mut alice, &["GET", "cache:1"]).await,
+            Frame::Error(e) if e == "NOAUTH Authentication required."
+        ));
+    }
+
+    /// Poll `args` on `conn` until the reply is `expected`.
+    async fn eventually(conn: &mut Connection, args: &[&str], expected: &str) {
+        for _ in 0..500 {
+            if call(conn, args).await == expected {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{:?} never replied {:?}", args, expected);
+    }
+
+    #[tokio::test]
+    async fn replicas_follow_their_master() {
+        let master = start_server().await;
+        let mut conn = connect(master).await;
+        call(&mut conn, &["SET", "a", "1"]).await;
+        call(&mut conn, &["RPUSH", "list", "x", "y"]).await;
+
+        let db = Db::new();
+        let replica = start_server_with(db.clone()).await;
+        db.set_listening_port(replica.port());
+        let mut replica_conn = connect(replica).await;
+        let port = master.port().to_string();
+        assert_eq!(
+            call(&mut replica_conn, &["REPLICAOF", "127.0.0.1", &port]).await,
+            "OK"
+        );
+
+        // The replica starts out with a snapshot, then follows the stream.
+        eventually(&mut replica_conn, &["GET", "a"], "1").await;
+        assert_eq!(
+            strings(call(&mut replica_conn, &["LRANGE", "list", "0", "-1"]).await),
+            ["x", "y"]
+        );
+        call(&mut conn, &["SET", "b", "2", "EX", "100"]).await;
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["INCR", "n"]).await;
+        call(&mut conn, &["LPOP", "list"]).await;
+        call(&mut conn, &["EXEC"]).await;
+        assert!(matches!(
+            call(&mut conn, &["WAIT", "1", "5000"]).await,
+            Frame::Integer(1)
+        ));
+        assert_eq!(call(&mut replica_conn, &["GET", "b"]).await, "2");
+        assert_eq!(call(&mut replica_conn, &["GET", "n"]).await, "1");
+        assert!(matches!(
+            call(&mut replica_conn, &["TTL", "b"]).await,
+            Frame::Integer(ttl) if ttl > 90
+        ));
+
+        assert!(matches!(
+            call(&mut replica_conn, &["SET", "c", "3"]).await,
+            Frame::Error(e) if e.starts_with("READONLY")
+        ));
+
+        let Frame::Array(role) = call(&mut conn, &["ROLE"]).await else {
+            panic!("expected an array reply");
+        };
+        assert_eq!(role[0], "master");
+        // The replica acknowledged everything up to the request for an
+        // acknowledgment sent by WAIT.
+        let Frame::Array(replicas) = &role[2] else {
+            panic!("expected an array of replicas");
+        };
+        let Frame::Array(replica_role) = &replicas[0] else {
+            panic!("expected an array");
+        };
+        assert_eq!(replica_role[0], "127.0.0.1");
+        assert_eq!(replica_role[1], &replica.port().to_string()[..]);
+        let acked: u64 = replica_role[2].to_string().parse().unwrap();
+        let offset: u64 = role[1].to_string().parse().unwrap();
+        assert!(acked > 0 && acked < offset);
+        let Frame::Array(role) = call(&mut replica_conn, &["ROLE"]).await else {
+            panic!("expected an array reply");
+        };
+        assert_eq!(role[0], "slave");
+        assert_eq!(role[3], "connected");
+        let info = call(&mut replica_conn, &["INFO", "replication"]).await;
+        assert!(info.to_string().contains("master_link_status:up"));
+
+        // A replica that reconnects picks up where it left off.
+        assert_eq!(
+            call(&mut replica_conn, &["REPLICAOF", "127.0.0.1", &port]).await,
+            "OK"
+        );
+        call(&mut conn, &["SET", "d", "4"]).await;
+        eventually(&mut replica_conn, &["GET", "d"], "4").await;
+        assert_eq!(call(&mut replica_conn, &["GET", "a"]).await, "1");
 
-        server.await.unwrap();
+        // Once promoted, it takes writes again.
+        assert_eq!(
+            call(&mut replica_conn, &["REPLICAOF", "NO", "ONE"]).await,
+            "OK"
+        );
+        assert_eq!(call(&mut replica_conn, &["SET", "c", "3"]).await, "OK");
     }
 }
@@ -1,16 +1,7 @@
-use tokio::net::TcpListener;
-
-use redis::handle_connection;
+use redis::Config;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    loop {
-        let (socket, _addr) = listener.accept().await?;
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(socket).await {
-                eprintln!("connection error: {e}");
-            }
-        });
-    }
+    let config = Config::from_args(std::env::args().skip(1)).map_err(|e| anyhow::anyhow!("{e}"))?;
+    redis::run(config).await.map_err(|e| anyhow::anyhow!("{e}"))
 }
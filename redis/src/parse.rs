@@ -91,6 +91,18 @@ impl Parse {
         }
     }
 
+    /// Return every remaining entry as raw bytes, leaving the parser at the
+    /// end of the frame.
+    pub(crate) fn remaining_bytes(&mut self) -> Result<Vec<Bytes>, ParseError> {
+        let mut out = Vec::with_capacity(self.parts.len());
+
+        while self.parts.len() > 0 {
+            out.push(self.next_bytes()?);
+        }
+
+        Ok(out)
+    }
+
     /// Return the next entry as an integer.
     ///
     /// This includes `Simple`, `Bulk`, and `Integer` frame types. `Simple` and
@@ -98,18 +110,16 @@ impl Parse {
     ///
     /// If the next entry cannot be represented as an integer, then an error is
     /// returned.
-    pub(crate) fn next_int(&mut self) -> Result<u64, ParseError> {
-        use atoi::atoi;
-
-        const MSG: &str = "protocol error; invalid number";
+    pub(crate) fn next_int(&mut self) -> Result<i64, ParseError> {
+        const MSG: &str = "value is not an integer or out of range";
 
         match self.next()? {
             // An integer frame type is already stored as an integer.
             Frame::Integer(v) => Ok(v),
             // Simple and bulk frames must be parsed as integers. If the parsing
             // fails, an error is returned.
-            Frame::Simple(data) => atoi::<u64>(data.as_bytes()).ok_or_else(|| MSG.into()),
-            Frame::Bulk(data) => atoi::<u64>(&data).ok_or_else(|| MSG.into()),
+            Frame::Simple(data) => parse_int(data.as_bytes()).ok_or_else(|| MSG.into()),
+            Frame::Bulk(data) => parse_int(&data).ok_or_else(|| MSG.into()),
             frame => Err(format!("protocol error; expected int frame but got {:?}", frame).into()),
         }
     }
@@ -124,6 +134,28 @@ impl Parse {
     }
 }
 
+/// Parse a signed decimal integer, rejecting anything `atoi` would silently
+/// truncate such as trailing garbage (`"12abc"`).
+///
+/// Like Redis, only the canonical form is accepted: no `+` sign, no leading
+/// zeros and no `-0`, so a value parses only if it is what `INCR` would
+/// store.
+pub(crate) fn parse_int(src: &[u8]) -> Option<i64> {
+    use atoi::FromRadix10SignedChecked;
+
+    let digits = src.strip_prefix(b"-").unwrap_or(src);
+    match digits {
+        b"0" if digits.len() == src.len() => {}
+        [b'1'..=b'9', ..] => {}
+        _ => return None,
+    }
+
+    match i64::from_radix_10_signed_checked(src) {
+        (Some(n), used) if used == src.len() && used > 0 => Some(n),
+        _ => None,
+    }
+}
+
 impl From<String> for ParseError {
     fn from(src: String) -> ParseError {
         ParseError::Other(src.into())